@@ -0,0 +1,112 @@
+use crate::close::finish_write;
+use crate::connections::{ConnectionInfo, Connections};
+use crate::metrics::Registry;
+use crate::shutdown::ShutdownSignal;
+use prometheus::proto::MetricType;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// Writes a snapshot of `registry` and the open `connections` to every connection and closes it,
+/// until `shutdown` fires. Nothing is read from the client, so `nc`/`telnet` to the port is all
+/// it takes.
+pub async fn serve_admin(
+    listener: TcpListener,
+    registry: Registry,
+    connections: Connections,
+    started: Instant,
+    mut shutdown: ShutdownSignal,
+) {
+    info!("Serving admin stats on {:?}", listener.local_addr());
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.recv() => break,
+        };
+        let mut stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Error accepting admin connection, {:?}", e);
+                continue;
+            }
+        };
+        let report = snapshot(&registry, &connections.list(), started.elapsed());
+        tokio::spawn(async move {
+            if let Err(e) = stream.write_all(report.as_bytes()).await {
+                info!("Couldn't write admin snapshot: {:?}", e);
+            }
+            finish_write(&mut stream).await;
+        });
+    }
+}
+
+/// One `name value` line for uptime and then every counter and gauge in `registry`, labels
+/// included. Histograms show up as their sample count. Each of `connections` gets a
+/// `connection{peer="..."}` line with how many seconds it's been open.
+pub fn snapshot(registry: &Registry, connections: &[ConnectionInfo], uptime: Duration) -> String {
+    let mut report = format!("uptime_secs {}\n", uptime.as_secs());
+    for family in registry.gather() {
+        for metric in family.get_metric() {
+            let value = match family.get_field_type() {
+                MetricType::COUNTER => metric.get_counter().get_value(),
+                MetricType::GAUGE => metric.get_gauge().get_value(),
+                MetricType::HISTOGRAM => metric.get_histogram().get_sample_count() as f64,
+                _ => continue,
+            };
+            let labels: Vec<String> = metric
+                .get_label()
+                .iter()
+                .map(|label| format!("{}=\"{}\"", label.get_name(), label.get_value()))
+                .collect();
+            if labels.is_empty() {
+                let _ = writeln!(report, "{} {}", family.get_name(), value);
+            } else {
+                let _ = writeln!(
+                    report,
+                    "{}{{{}}} {}",
+                    family.get_name(),
+                    labels.join(","),
+                    value
+                );
+            }
+        }
+    }
+    for connection in connections {
+        let _ = writeln!(
+            report,
+            "connection{{peer=\"{}\"}} {}",
+            connection.peer,
+            connection.connected_at.elapsed().as_secs()
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{register_counter, register_gauge};
+    use crate::stream::Peer;
+
+    #[test]
+    fn test_snapshot() {
+        let registry = Registry::new();
+        register_counter(&registry, "things_total", "Things seen").inc_by(3);
+        register_gauge(&registry, "things_active", "Things right now").set(2);
+
+        let report = snapshot(&registry, &[], Duration::from_secs(61));
+        assert_eq!("uptime_secs 61\nthings_active 2\nthings_total 3\n", report);
+
+        let connection = ConnectionInfo {
+            peer: Peer::Tcp("127.0.0.1:4000".parse().unwrap()),
+            connected_at: Instant::now(),
+        };
+        let report = snapshot(&Registry::new(), &[connection], Duration::from_secs(1));
+        assert_eq!(
+            "uptime_secs 1\nconnection{peer=\"127.0.0.1:4000\"} 0\n",
+            report
+        );
+    }
+}
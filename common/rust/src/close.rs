@@ -0,0 +1,45 @@
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::info;
+
+/// Flushes anything still buffered in `writer`, then shuts down its write side, so the peer reads
+/// everything it was sent before it sees the end of the stream. The read side is left open. Errors
+/// are logged rather than returned, by now the peer has usually gone away and there's nothing
+/// else to do about it.
+pub async fn finish_write<W: AsyncWrite + Unpin>(writer: &mut W) {
+    if let Err(e) = writer.flush().await {
+        info!("Couldn't flush before closing: {:?}", e);
+        return;
+    }
+    if let Err(e) = writer.shutdown().await {
+        info!("Couldn't shut down write side: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, BufWriter};
+
+    #[tokio::test]
+    async fn test_finish_write() {
+        let (server, mut client) = tokio::io::duplex(64);
+        let mut writer = BufWriter::new(server);
+        // sits in the BufWriter until it's flushed
+        writer.write_all(b"still buffered").await.unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        finish_write(&mut writer).await;
+        assert_eq!(b"still buffered".to_vec(), reader.await.unwrap());
+
+        // nobody left to read it, logged and carried on from
+        let (server, client) = tokio::io::duplex(64);
+        drop(client);
+        let mut writer = BufWriter::new(server);
+        writer.write_all(b"lost").await.unwrap();
+        finish_write(&mut writer).await;
+    }
+}
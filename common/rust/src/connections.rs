@@ -0,0 +1,211 @@
+use crate::stream::Peer;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::{AbortHandle, Id};
+
+// each shard has its own lock, so connects and disconnects rarely wait on each other
+const SHARDS: usize = 16;
+
+/// Who a connection is and how long it's been open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer: Peer,
+    pub connected_at: Instant,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: ConnectionInfo,
+    abort: AbortHandle,
+}
+
+/// Every connection task that's currently running, keyed by its task id. The server registers
+/// tasks as it spawns them and deregisters them as they finish, so anything holding a clone can
+/// count, list or abort live connections.
+#[derive(Debug, Clone)]
+pub struct Connections {
+    shards: Arc<[Mutex<HashMap<Id, Entry>>]>,
+    len: Arc<AtomicUsize>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::new()
+    }
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            len: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Tracks the task behind `abort` as a connection from `peer`.
+    pub fn register(&self, abort: AbortHandle, peer: Peer) {
+        let entry = Entry {
+            info: ConnectionInfo {
+                peer,
+                connected_at: Instant::now(),
+            },
+            abort,
+        };
+        let id = entry.abort.id();
+        if self.shard(id).insert(id, entry).is_none() {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops tracking task `id`, `None` if it wasn't registered.
+    pub fn deregister(&self, id: Id) -> Option<ConnectionInfo> {
+        let entry = self.shard(id).remove(&id)?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(entry.info)
+    }
+
+    /// Changes who task `id` is recorded as serving, for when the real client only turns up
+    /// after the connection is accepted (e.g. a PROXY header). False if it isn't registered.
+    pub fn set_peer(&self, id: Id, peer: Peer) -> bool {
+        match self.shard(id).get_mut(&id) {
+            Some(entry) => {
+                entry.info.peer = peer;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Connections currently registered.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every registered connection, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .values()
+                    .map(|entry| entry.info.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        connections.sort_by_key(|info| info.connected_at);
+        connections
+    }
+
+    /// Aborts every connection from `peer`. They stay registered until the server reaps them.
+    /// False if there weren't any.
+    pub fn abort(&self, peer: Peer) -> bool {
+        let mut found = false;
+        for shard in self.shards.iter() {
+            for entry in shard.lock().unwrap().values() {
+                if entry.info.peer == peer {
+                    entry.abort.abort();
+                    found = true;
+                }
+            }
+        }
+        found
+    }
+
+    fn shard(&self, id: Id) -> std::sync::MutexGuard<'_, HashMap<Id, Entry>> {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+    use tokio::task::JoinSet;
+
+    fn peer(port: u16) -> Peer {
+        Peer::Tcp(([127, 0, 0, 1], port).into())
+    }
+
+    #[tokio::test]
+    async fn test_register_deregister() {
+        let connections = Connections::new();
+        let mut tasks = JoinSet::new();
+        let first = tasks.spawn(future::pending::<()>());
+        let second = tasks.spawn(future::pending::<()>());
+        connections.register(first.clone(), peer(1));
+        connections.register(second.clone(), peer(2));
+        assert_eq!(2, connections.len());
+        let mut peers: Vec<Peer> = connections.list().iter().map(|info| info.peer).collect();
+        peers.sort();
+        assert_eq!(vec![peer(1), peer(2)], peers);
+
+        assert_eq!(
+            Some(peer(1)),
+            connections.deregister(first.id()).map(|info| info.peer)
+        );
+        assert_eq!(None, connections.deregister(first.id()));
+        assert_eq!(1, connections.len());
+
+        assert!(connections.set_peer(second.id(), peer(3)));
+        assert!(!connections.set_peer(first.id(), peer(3)));
+        assert_eq!(peer(3), connections.list()[0].peer);
+
+        assert!(!connections.abort(peer(1)));
+        assert!(!connections.abort(peer(2)));
+        assert!(connections.abort(peer(3)));
+        let joined = tasks.join_next_with_id().await.unwrap();
+        let aborted = joined.unwrap_err();
+        assert!(aborted.is_cancelled());
+        assert_eq!(second.id(), aborted.id());
+        connections.deregister(aborted.id());
+        assert!(connections.is_empty());
+        tasks.abort_all();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_connects_and_disconnects() {
+        let connections = Connections::new();
+        let mut workers = JoinSet::new();
+        for worker in 0..8 {
+            let connections = connections.clone();
+            workers.spawn(async move {
+                let mut tasks = JoinSet::new();
+                let mut handles = Vec::new();
+                for round in 0..200 {
+                    let abort = tasks.spawn(future::pending::<()>());
+                    connections.register(abort.clone(), peer(worker * 1000 + round));
+                    handles.push(abort);
+                    // every other connection hangs up again straight away
+                    if round % 2 == 1 {
+                        let abort = handles.swap_remove(0);
+                        abort.abort();
+                        assert!(connections.deregister(abort.id()).is_some());
+                    }
+                    tokio::task::yield_now().await;
+                }
+                tasks
+            });
+        }
+        // the tasks that are still open stay that way until the end of the test
+        let mut still_open = Vec::new();
+        while let Some(tasks) = workers.join_next().await {
+            still_open.push(tasks.unwrap());
+        }
+        assert_eq!(8 * 100, connections.len());
+        assert_eq!(8 * 100, connections.list().len());
+    }
+}
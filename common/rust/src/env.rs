@@ -0,0 +1,61 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use tracing::warn;
+
+/// The environment variable `name` parsed as a `T`, `None` when it isn't set. See
+/// [`parse_var`] for what happens to a value that doesn't parse.
+pub fn env_var<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_var(name, std::env::var(name).ok())
+}
+
+/// `value` as set for the variable `name`. A value that doesn't parse is logged and left out,
+/// so a typo like `PRIME_ALGO=millerrabin` doesn't quietly leave the default in place.
+pub fn parse_var<T>(name: &str, value: Option<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = value?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            warn!("Ignoring {}={:?}, {}", name, value, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::capture_logs;
+    use tracing::Level;
+
+    #[test]
+    fn test_parse_var() {
+        let (logs, _guard) = capture_logs(Level::WARN);
+        assert_eq!(
+            Some(4),
+            parse_var::<u32>("ACCEPTORS", Some(String::from("4")))
+        );
+        assert_eq!(None, parse_var::<u32>("ACCEPTORS", None));
+        assert!(logs.contents().is_empty());
+
+        assert_eq!(
+            None,
+            parse_var::<bool>("DUAL_STACK", Some(String::from("yes")))
+        );
+        let contents = logs.contents();
+        assert!(
+            contents.contains(
+                r#"Ignoring DUAL_STACK="yes", provided string was not `true` or `false`"#
+            ),
+            "{}",
+            contents
+        );
+    }
+}
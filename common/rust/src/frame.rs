@@ -0,0 +1,167 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A message that always takes up exactly `LEN` bytes on the wire.
+pub trait Frame: Sized {
+    const LEN: usize;
+
+    /// Decodes one complete frame. `fields` holds exactly `LEN` bytes, read them off with
+    /// `bytes::Buf`'s getters, which are big endian (`get_u8`, `get_i32`, ...).
+    fn decode(fields: &mut Bytes) -> io::Result<Self>;
+}
+
+/// Splits a stream into fixed length big endian `Frame`s and writes big endian responses.
+/// Nothing is decoded until a whole frame is buffered, so it doesn't matter how the peer's
+/// writes get split up into TCP segments.
+pub struct BigEndianFrameCodec<T> {
+    frame: PhantomData<fn() -> T>,
+}
+
+impl<T> BigEndianFrameCodec<T> {
+    pub fn new() -> BigEndianFrameCodec<T> {
+        BigEndianFrameCodec { frame: PhantomData }
+    }
+}
+
+impl<T> Default for BigEndianFrameCodec<T> {
+    fn default() -> Self {
+        BigEndianFrameCodec::new()
+    }
+}
+
+impl<T> fmt::Debug for BigEndianFrameCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BigEndianFrameCodec").finish()
+    }
+}
+
+impl<T: Frame> Decoder for BigEndianFrameCodec<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<T>> {
+        if src.len() < T::LEN {
+            src.reserve(T::LEN - src.len());
+            return Ok(None);
+        }
+        let mut fields = src.split_to(T::LEN).freeze();
+        T::decode(&mut fields).map(Some)
+    }
+
+    /// The peer hanging up between frames is a normal disconnect (`Ok(None)`), hanging up
+    /// part way through one is reported as an `UnexpectedEof` truncated frame.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<T>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("truncated frame, {} of {} bytes", src.len(), T::LEN),
+            )),
+        }
+    }
+}
+
+impl<T> Encoder<i32> for BigEndianFrameCodec<T> {
+    type Error = io::Error;
+
+    fn encode(&mut self, value: i32, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_i32(value);
+        Ok(())
+    }
+}
+
+impl<T> Encoder<u8> for BigEndianFrameCodec<T> {
+    type Error = io::Error;
+
+    fn encode(&mut self, value: u8, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u8(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Buf;
+
+    // a tag and a big endian u16, with tag 0 rejected
+    #[derive(Debug, PartialEq)]
+    struct Pair(u8, u16);
+
+    impl Frame for Pair {
+        const LEN: usize = 3;
+
+        fn decode(fields: &mut Bytes) -> io::Result<Pair> {
+            match fields.get_u8() {
+                0 => Err(io::Error::new(io::ErrorKind::InvalidData, "tag 0")),
+                tag => Ok(Pair(tag, fields.get_u16())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_byte_by_byte() {
+        let stream = [0x01, 0x00, 0x02, 0x7f, 0xff, 0xff];
+        let mut codec = BigEndianFrameCodec::<Pair>::new();
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        // like a peer with one byte tcp segments
+        for byte in stream {
+            buffer.put_u8(byte);
+            if let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(frame);
+            }
+            // nothing is consumed until a whole frame is in
+            assert!(buffer.len() < Pair::LEN);
+        }
+        assert_eq!(vec![Pair(1, 2), Pair(0x7f, 0xffff)], decoded);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_several_frames_at_once() {
+        let mut codec = BigEndianFrameCodec::<Pair>::new();
+        let mut buffer = BytesMut::from(&[0x01, 0x00, 0x02, 0x03, 0x00, 0x04, 0x05][..]);
+        assert_eq!(Some(Pair(1, 2)), codec.decode(&mut buffer).unwrap());
+        assert_eq!(Some(Pair(3, 4)), codec.decode(&mut buffer).unwrap());
+        assert_eq!(None, codec.decode(&mut buffer).unwrap());
+        assert_eq!(&[0x05][..], &buffer[..]);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let mut codec = BigEndianFrameCodec::<Pair>::new();
+        let mut buffer = BytesMut::from(&[0x00, 0x00, 0x02][..]);
+        let result = codec.decode(&mut buffer);
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_decode_eof() {
+        let mut codec = BigEndianFrameCodec::<Pair>::new();
+
+        // closed between frames
+        let mut buffer = BytesMut::from(&[0x01, 0x00, 0x02][..]);
+        assert_eq!(Some(Pair(1, 2)), codec.decode_eof(&mut buffer).unwrap());
+        assert_eq!(None, codec.decode_eof(&mut buffer).unwrap());
+
+        // closed mid frame
+        let mut buffer = BytesMut::from(&[0x01, 0x00][..]);
+        let result = codec.decode_eof(&mut buffer);
+        assert_eq!(io::ErrorKind::UnexpectedEof, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut codec = BigEndianFrameCodec::<Pair>::new();
+        let mut buffer = BytesMut::new();
+        codec.encode(-2_i32, &mut buffer).unwrap();
+        codec.encode(0x41_u8, &mut buffer).unwrap();
+        assert_eq!(&[0xff, 0xff, 0xff, 0xfe, 0x41][..], &buffer[..]);
+    }
+}
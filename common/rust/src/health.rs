@@ -0,0 +1,35 @@
+use crate::close::finish_write;
+use crate::shutdown::ShutdownSignal;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// What every probe gets back.
+pub const HEALTHY: &[u8] = b"OK\n";
+
+/// Answers every connection with `OK` and closes it, until `shutdown` fires. Only start it once
+/// the server it speaks for is ready, then any answer at all means the process is alive and
+/// its main listener is bound. Bind `listener` before that though, so a probe that turns up
+/// early waits in the backlog instead of being refused.
+pub async fn serve_health(listener: TcpListener, mut shutdown: ShutdownSignal) {
+    info!("Answering health probes on {:?}", listener.local_addr());
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.recv() => break,
+        };
+        let mut stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Error accepting health probe, {:?}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = stream.write_all(HEALTHY).await {
+                info!("Couldn't answer health probe: {:?}", e);
+            }
+            finish_write(&mut stream).await;
+        });
+    }
+}
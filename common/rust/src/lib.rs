@@ -0,0 +1,28 @@
+pub mod admin;
+pub mod close;
+pub mod connections;
+pub mod env;
+pub mod frame;
+pub mod health;
+pub mod limiter;
+pub mod metrics;
+pub mod observability;
+pub mod proxy;
+pub mod record;
+pub mod runtime;
+pub mod server;
+pub mod shutdown;
+pub mod stream;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+pub mod udp;
+#[cfg(unix)]
+pub mod unix;
+
+pub use close::finish_write;
+pub use connections::Connections;
+pub use frame::{BigEndianFrameCodec, Frame};
+pub use limiter::{ConnectionLimiter, IpLimiter, TokenBucket};
+pub use server::{run_tcp_server, ServerConfig, WhenFull};
+pub use shutdown::{ShutdownSignal, ShutdownToken};
+pub use stream::{Peer, Stream};
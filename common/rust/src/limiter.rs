@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+
+/// Caps how many connections are handled at once.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    semaphore: Arc<Semaphore>,
+    max_connections: usize,
+}
+
+/// Holds one of the limiter's slots until it is dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            max_connections,
+        }
+    }
+
+    /// Waits until a slot is free.
+    pub async fn acquire(&self) -> ConnectionPermit {
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Connection limiter semaphore was closed");
+        ConnectionPermit { _permit: permit }
+    }
+
+    /// `None` when every slot is taken, instead of waiting for one.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+        Some(ConnectionPermit { _permit: permit })
+    }
+
+    /// Number of permits currently handed out.
+    pub fn in_flight(&self) -> usize {
+        self.max_connections - self.semaphore.available_permits()
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Waits until every permit has been handed back.
+    pub async fn wait_idle(&self) {
+        let _all = self
+            .semaphore
+            .acquire_many(self.max_connections as u32)
+            .await
+            .expect("Connection limiter semaphore was closed");
+    }
+}
+
+/// Caps how many connections a single IP address can have open at once, so one client can't
+/// take every slot in the `ConnectionLimiter`.
+#[derive(Debug, Clone)]
+pub struct IpLimiter {
+    max_per_ip: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Counts against its IP address until it is dropped.
+#[derive(Debug)]
+pub struct IpPermit {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl IpLimiter {
+    pub fn new(max_per_ip: usize) -> IpLimiter {
+        IpLimiter {
+            max_per_ip,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// `None` when `ip` already has as many connections open as it's allowed.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        let mut open = self.open.lock().expect("IP limiter lock was poisoned");
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit {
+            ip,
+            open: self.open.clone(),
+        })
+    }
+
+    /// Connections currently open from `ip`.
+    pub fn open(&self, ip: IpAddr) -> usize {
+        let open = self.open.lock().expect("IP limiter lock was poisoned");
+        open.get(&ip).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().expect("IP limiter lock was poisoned");
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            // don't keep an entry around for every address that ever connected
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Lets `rate` things a second through on average, up to `burst` of them at once after a
+/// quiet spell. One per connection is enough to keep a single client from hogging the server.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full. A `burst` of 0 is taken as 1, there has to be room for something.
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        assert!(
+            rate > 0.0,
+            "Token bucket rate has to be positive, got {}",
+            rate
+        );
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there's one, without waiting.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_many(1)
+    }
+
+    /// Waits for a token and takes it.
+    pub async fn take(&mut self) {
+        self.take_many(1).await
+    }
+
+    /// Takes `count` tokens at once if they're all there, without waiting.
+    pub fn try_take_many(&mut self, count: u32) -> bool {
+        self.refill();
+        let count = count as f64;
+        if self.tokens < count {
+            return false;
+        }
+        self.tokens -= count;
+        true
+    }
+
+    /// Waits for `count` tokens and takes them together, for when a token stands for something
+    /// smaller than a request, like a byte. There's never more than `burst` in the bucket, so
+    /// asking for more than that would wait forever.
+    pub async fn take_many(&mut self, count: u32) {
+        assert!(
+            count as f64 <= self.burst,
+            "Can't take {} tokens from a bucket that holds {}",
+            count,
+            self.burst
+        );
+        while !self.try_take_many(count) {
+            let missing = count as f64 - self.tokens;
+            time::sleep(Duration::from_secs_f64(missing / self.rate)).await;
+        }
+    }
+
+    /// The most tokens the bucket holds.
+    pub fn burst(&self) -> u32 {
+        self.burst as u32
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let limiter = ConnectionLimiter::new(3);
+        assert_eq!(0, limiter.in_flight());
+
+        let first = limiter.acquire().await;
+        let second = limiter.acquire().await;
+        assert_eq!(2, limiter.in_flight());
+
+        drop(first);
+        assert_eq!(1, limiter.in_flight());
+        drop(second);
+        assert_eq!(0, limiter.in_flight());
+    }
+
+    #[tokio::test]
+    async fn test_try_acquire() {
+        let limiter = ConnectionLimiter::new(1);
+        let permit = limiter.try_acquire().expect("Limiter should have room");
+        assert!(limiter.try_acquire().is_none());
+        drop(permit);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_when_full() {
+        let limiter = ConnectionLimiter::new(1);
+        let permit = limiter.acquire().await;
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limiter.acquire()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limiter.acquire()).await;
+        assert!(waiting.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wait_idle() {
+        let limiter = ConnectionLimiter::new(2);
+        limiter.wait_idle().await;
+
+        let permit = limiter.acquire().await;
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limiter.wait_idle()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limiter.wait_idle()).await;
+        assert!(waiting.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_under_load() {
+        let limiter = ConnectionLimiter::new(10);
+        let mut handles = Vec::new();
+        // more tasks than slots, each holding its permit for a moment
+        for _ in 0..50 {
+            let limiter = limiter.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = limiter.acquire().await;
+                assert!(limiter.in_flight() <= limiter.max_connections());
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(0, limiter.in_flight());
+    }
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(20.0, 3);
+        // the burst is there straight away
+        for _ in 0..3 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+
+        // after that it's one every 50ms
+        let started = Instant::now();
+        for _ in 0..4 {
+            bucket.take().await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        // a quiet spell only ever refills up to the burst
+        time::sleep(Duration::from_millis(300)).await;
+        for _ in 0..3 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test]
+    async fn test_take_many() {
+        let mut bucket = TokenBucket::new(1000.0, 100);
+        assert!(bucket.try_take_many(60));
+        assert!(!bucket.try_take_many(60));
+
+        // 40 left, so the next 100 are 60ms away, and another 100 after that
+        let started = Instant::now();
+        bucket.take_many(100).await;
+        bucket.take_many(100).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_ip_limiter() {
+        let limiter = IpLimiter::new(2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.try_acquire(ip).expect("first connection refused");
+        let _second = limiter.try_acquire(ip).expect("second connection refused");
+        assert!(limiter.try_acquire(ip).is_none());
+        assert_eq!(2, limiter.open(ip));
+        // other addresses have their own count
+        let _other = limiter.try_acquire(other).expect("other address refused");
+
+        drop(first);
+        assert_eq!(1, limiter.open(ip));
+        assert!(limiter.try_acquire(ip).is_some());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::close::finish_write;
+use crate::shutdown::ShutdownSignal;
+use prometheus::{Encoder, TextEncoder};
+use std::io::{Read, Write};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+pub use prometheus::{Histogram, IntCounter, IntGauge, Registry};
+
+// anything bigger than this isn't a scrape
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Registers a new counter. Names have to be unique within a registry, reusing one is a bug.
+pub fn register_counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).expect("Invalid counter name");
+    registry
+        .register(Box::new(counter.clone()))
+        .expect("Couldn't register counter");
+    counter
+}
+
+/// Registers a new gauge. Names have to be unique within a registry, reusing one is a bug.
+pub fn register_gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).expect("Invalid gauge name");
+    registry
+        .register(Box::new(gauge.clone()))
+        .expect("Couldn't register gauge");
+    gauge
+}
+
+/// Registers a new histogram with upper bounds `buckets`. Names have to be unique within a
+/// registry, reusing one is a bug.
+pub fn register_histogram(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    buckets: Vec<f64>,
+) -> Histogram {
+    let opts = prometheus::HistogramOpts::new(name, help).buckets(buckets);
+    let histogram = Histogram::with_opts(opts).expect("Invalid histogram");
+    registry
+        .register(Box::new(histogram.clone()))
+        .expect("Couldn't register histogram");
+    histogram
+}
+
+/// Everything in `registry` in the Prometheus text format.
+pub fn render(registry: &Registry) -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&registry.gather(), &mut buffer)
+        .expect("Couldn't encode metrics");
+    String::from_utf8(buffer).expect("Metrics weren't utf8")
+}
+
+/// Answers `GET /metrics` with the contents of `registry` until `shutdown` fires. Every other
+/// request gets a 404. One request per connection, this is only meant for scrapers.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    registry: Registry,
+    mut shutdown: ShutdownSignal,
+) {
+    info!("Serving metrics on {:?}", listener.local_addr());
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.recv() => break,
+        };
+        let mut stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Error accepting metrics connection, {:?}", e);
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let mut head = Vec::new();
+            let mut buffer = [0; 1024];
+            while !is_complete(&head) && head.len() < MAX_REQUEST_HEAD {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => head.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let response = http_response(&head, &registry);
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                info!("Couldn't write metrics response: {:?}", e);
+            }
+            finish_write(&mut stream).await;
+        });
+    }
+}
+
+/// Blocking version of `serve_metrics` for servers that aren't running on tokio. Never returns.
+pub fn serve_metrics_blocking(listener: std::net::TcpListener, registry: Registry) {
+    info!("Serving metrics on {:?}", listener.local_addr());
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Error accepting metrics connection, {:?}", e);
+                continue;
+            }
+        };
+        let mut head = Vec::new();
+        let mut buffer = [0; 1024];
+        while !is_complete(&head) && head.len() < MAX_REQUEST_HEAD {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => head.extend_from_slice(&buffer[..read]),
+            }
+        }
+        let response = http_response(&head, &registry);
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            info!("Couldn't write metrics response: {:?}", e);
+        }
+        // same as `finish_write`, the read side is left for the client to close
+        let _ = stream.flush();
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+    }
+}
+
+fn is_complete(head: &[u8]) -> bool {
+    head.windows(4).any(|window| window == b"\r\n\r\n")
+}
+
+fn http_response(head: &[u8], registry: &Registry) -> String {
+    let head = String::from_utf8_lossy(head);
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(registry)),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::new();
+        let counter = register_counter(&registry, "things_total", "Things seen");
+        let gauge = register_gauge(&registry, "things_active", "Things right now");
+        let histogram =
+            register_histogram(&registry, "thing_seconds", "Thing length", vec![1.0, 10.0]);
+        counter.inc_by(3);
+        histogram.observe(5.0);
+        gauge.inc();
+        gauge.inc();
+        gauge.dec();
+
+        let rendered = render(&registry);
+        assert!(rendered.contains("# HELP things_total Things seen"));
+        assert!(rendered.contains("things_total 3"));
+        assert!(rendered.contains("things_active 1"));
+        assert!(rendered.contains("thing_seconds_bucket{le=\"1\"} 0"));
+        assert!(rendered.contains("thing_seconds_bucket{le=\"10\"} 1"));
+        assert!(rendered.contains("thing_seconds_count 1"));
+    }
+
+    #[test]
+    fn test_http_response() {
+        let registry = Registry::new();
+        register_counter(&registry, "things_total", "Things seen").inc();
+
+        let response = http_response(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n", &registry);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("things_total 1\n"));
+
+        let response = http_response(b"GET / HTTP/1.1\r\n\r\n", &registry);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = http_response(b"garbage", &registry);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
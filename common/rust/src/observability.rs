@@ -0,0 +1,182 @@
+use std::env;
+use std::sync::Once;
+use tracing::dispatcher::DefaultGuard;
+use tracing::{info, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::Layer;
+
+static GLOBAL_DEFAULT: Once = Once::new();
+
+/// How log events get written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable lines, the default.
+    #[default]
+    Text,
+    /// One json object per event, along with the fields of the spans it happened in.
+    Json,
+}
+
+impl LogFormat {
+    /// Reads `LOG_FORMAT`, `json` picks [`LogFormat::Json`] and anything else (or nothing) gets
+    /// the default text output.
+    pub fn from_env() -> Self {
+        match env::var("LOG_FORMAT") {
+            Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// Reads the level to log at from `LOG_LEVEL`, or `RUST_LOG` if that isn't set, see
+/// [`level_from`].
+pub fn level_from_env() -> Level {
+    level_from(env::var("LOG_LEVEL").ok(), env::var("RUST_LOG").ok())
+}
+
+/// Picks the level from a `LOG_LEVEL` and `RUST_LOG` value, the first one set wins. Either takes
+/// a level name in any case (`debug`, `WARN`, ...). Nothing set, or a value that isn't a level
+/// (like a `RUST_LOG` directive such as `prime_time=debug`), gets INFO.
+pub fn level_from(log_level: Option<String>, rust_log: Option<String>) -> Level {
+    log_level
+        .or(rust_log)
+        .and_then(|level| level.trim().parse().ok())
+        .unwrap_or(Level::INFO)
+}
+
+/// [`init_tracing`] at the level picked by `LOG_LEVEL` or `RUST_LOG`, see [`level_from_env`].
+/// This is what the binaries call, so the verbosity can change without a rebuild.
+pub fn init_tracing_from_env() -> DefaultGuard {
+    init_tracing(level_from_env())
+}
+
+/**
+ * Sets up logging at `level` for the current thread until the guard is dropped.
+ *
+ * Only the first call also installs a global default, which is what the worker threads of a
+ * multi threaded runtime end up logging through. Every later call just swaps the thread local
+ * default, so tests can call this as often as they like without tripping over
+ * "global default already set". With the `console` feature, the global default also
+ * carries the tokio-console layer.
+ *
+ * Events are written in the format picked by `LOG_FORMAT`, see [`LogFormat::from_env`].
+ */
+pub fn init_tracing(level: Level) -> DefaultGuard {
+    let format = LogFormat::from_env();
+    GLOBAL_DEFAULT.call_once(|| {
+        let registry =
+            tracing_subscriber::registry().with(fmt_layer(level, format, std::io::stdout));
+        #[cfg(feature = "console")]
+        let registry = registry.with(console_subscriber::spawn());
+        // something else (e.g. a test harness) may already own the global default
+        let _ = registry.try_init();
+    });
+    let guard = tracing_subscriber::registry()
+        .with(fmt_layer(level, format, std::io::stdout))
+        .set_default();
+    info!("Tracing has been setup");
+    guard
+}
+
+fn fmt_layer<S, W>(level: Level, format: LogFormat, writer: W) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    let layer = match format {
+        LogFormat::Text => layer.boxed(),
+        // the span list is what carries connection fields like p1's peer_addr
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    layer.with_filter(LevelFilter::from_level(level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CapturedLogs;
+    use std::net::SocketAddr;
+    use tracing::{debug, debug_span, info_span};
+
+    #[test]
+    fn test_init_tracing_repeatedly() {
+        let guard = init_tracing(Level::DEBUG);
+        drop(guard);
+        let _guard = init_tracing(Level::INFO);
+        let _nested_guard = init_tracing(Level::TRACE);
+        info!("still logging");
+    }
+
+    #[test]
+    fn test_level_from() {
+        assert_eq!(Level::INFO, level_from(None, None));
+        assert_eq!(Level::DEBUG, level_from(Some("debug".into()), None));
+        assert_eq!(Level::WARN, level_from(None, Some("WARN".into())));
+        // LOG_LEVEL wins over RUST_LOG
+        assert_eq!(
+            Level::TRACE,
+            level_from(Some("trace".into()), Some("error".into()))
+        );
+        assert_eq!(
+            Level::INFO,
+            level_from(None, Some("prime_time=debug".into()))
+        );
+    }
+
+    #[test]
+    fn test_level_filters_events_and_spans() {
+        for (log_level, debug_shown) in [(Some("debug"), true), (None, false)] {
+            let logs = CapturedLogs::default();
+            let level = level_from(log_level.map(String::from), None);
+            let _guard = tracing_subscriber::registry()
+                .with(fmt_layer(level, LogFormat::Text, logs.clone()))
+                .set_default();
+
+            let span = debug_span!("handler");
+            span.in_scope(|| {
+                debug!("debug line");
+                info!("info line");
+            });
+
+            let output = logs.contents();
+            assert!(output.contains("info line"), "{}", output);
+            assert_eq!(debug_shown, output.contains("debug line"), "{}", output);
+            // a span below the level isn't attached to the events inside it either
+            assert_eq!(debug_shown, output.contains("handler"), "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing_subscriber::registry()
+            .with(fmt_layer(Level::INFO, LogFormat::Json, logs.clone()))
+            .set_default();
+
+        let peer_addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let span = info_span!("process", peer_addr = %peer_addr);
+        span.in_scope(|| info!(number = 7, "answered"));
+        debug!("filtered out");
+
+        let output = logs.contents();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("every line is json"))
+            .collect();
+        assert_eq!(1, events.len());
+        let event = &events[0];
+        assert_eq!("INFO", event["level"]);
+        assert_eq!("answered", event["fields"]["message"]);
+        assert_eq!(7, event["fields"]["number"]);
+        assert_eq!("process", event["span"]["name"]);
+        assert_eq!("127.0.0.1:4000", event["span"]["peer_addr"]);
+        assert_eq!("127.0.0.1:4000", event["spans"][0]["peer_addr"]);
+    }
+}
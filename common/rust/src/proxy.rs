@@ -0,0 +1,159 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// the longest a v1 header can be, `\r\n` included
+const MAX_HEADER_LEN: usize = 107;
+
+const SIGNATURE: &[u8] = b"PROXY ";
+
+/// Reads a PROXY protocol v1 header (`PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`) off
+/// the front of `stream` and returns the client's address it carries. `None` for
+/// `PROXY UNKNOWN`, where the connection's own address is all there is. Nothing past the
+/// header is read, the handler still gets the rest of the stream.
+///
+/// A header that's missing, too long or doesn't parse is an `InvalidData` error.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = Vec::with_capacity(MAX_HEADER_LEN);
+    // a byte at a time, the header's short and whatever follows it isn't ours to read
+    while !header.ends_with(b"\r\n") {
+        if header.len() == MAX_HEADER_LEN {
+            return Err(invalid("PROXY header is too long"));
+        }
+        header.push(stream.read_u8().await?);
+        // give up on a client that isn't sending a header as soon as that's clear, rather
+        // than waiting on a `\r\n` that may never come
+        let signature = &header[..header.len().min(SIGNATURE.len())];
+        if !SIGNATURE.starts_with(signature) {
+            return Err(invalid("missing PROXY signature"));
+        }
+        if header.ends_with(b"\n") && !header.ends_with(b"\r\n") {
+            return Err(invalid("PROXY header doesn't end in \\r\\n"));
+        }
+    }
+    let header = std::str::from_utf8(&header).map_err(|_| invalid("PROXY header isn't ascii"))?;
+    parse_header(header)
+}
+
+/// The client address in a whole v1 header line, see `read_header`.
+pub fn parse_header(header: &str) -> io::Result<Option<SocketAddr>> {
+    let line = header
+        .strip_suffix("\r\n")
+        .ok_or_else(|| invalid("PROXY header doesn't end in \\r\\n"))?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid("missing PROXY signature"));
+    }
+    let ipv6 = match fields.next() {
+        Some("TCP4") => false,
+        Some("TCP6") => true,
+        // the rest of the line is meant to be ignored
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown PROXY protocol")),
+    };
+    let (Some(source), Some(destination), Some(source_port), Some(destination_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid("PROXY header needs two addresses and two ports"));
+    };
+    let source = address(source, ipv6)?;
+    address(destination, ipv6)?;
+    let source_port = port(source_port)?;
+    port(destination_port)?;
+    Ok(Some(SocketAddr::new(source, source_port)))
+}
+
+fn address(field: &str, ipv6: bool) -> io::Result<IpAddr> {
+    match field.parse::<IpAddr>() {
+        Ok(address) if address.is_ipv6() == ipv6 => Ok(address),
+        _ => Err(invalid(format!("bad PROXY address {:?}", field))),
+    }
+}
+
+fn port(field: &str) -> io::Result<u16> {
+    // plain digits only, `parse` would take a leading `+`
+    if field.is_empty() || !field.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(invalid(format!("bad PROXY port {:?}", field)));
+    }
+    field
+        .parse()
+        .map_err(|_| invalid(format!("bad PROXY port {:?}", field)))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            Some("192.168.0.1:56324".parse().unwrap()),
+            parse_header("PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n").unwrap()
+        );
+        assert_eq!(
+            Some("[2001:db8::1]:56324".parse().unwrap()),
+            parse_header("PROXY TCP6 2001:db8::1 ::1 56324 443\r\n").unwrap()
+        );
+        assert_eq!(None, parse_header("PROXY UNKNOWN\r\n").unwrap());
+        assert_eq!(
+            None,
+            parse_header("PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n").unwrap()
+        );
+
+        for malformed in [
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\n",
+            "proxy TCP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+            "PROXY TCP5 192.168.0.1 192.168.0.11 56324 443\r\n",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443 80\r\n",
+            "PROXY TCP4  192.168.0.1 192.168.0.11 56324 443\r\n",
+            "PROXY TCP4 2001:db8::1 ::1 56324 443\r\n",
+            "PROXY TCP6 192.168.0.1 192.168.0.11 56324 443\r\n",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 65536 443\r\n",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 +80 443\r\n",
+            "GET / HTTP/1.1\r\n",
+        ] {
+            let error = parse_header(malformed).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind(), "{:?}", malformed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 10.0.0.1 10.0.0.2 4000 8000\r\n{\"method\"";
+        assert_eq!(
+            Some("10.0.0.1:4000".parse().unwrap()),
+            read_header(&mut stream).await.unwrap()
+        );
+        // whatever came after the header is left for the handler
+        assert_eq!(b"{\"method\"", stream);
+
+        let mut stream: &[u8] = b"{\"method\":\"isPrime\",\"number\":7}\n";
+        let error = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        // given up on straight away, without waiting for the rest of the line
+        assert_eq!(b"\"method\":\"isPrime\",\"number\":7}\n", stream);
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\n";
+        let error = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        let too_long = format!("PROXY {}", "0".repeat(MAX_HEADER_LEN));
+        let mut stream = too_long.as_bytes();
+        let error = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        let mut stream: &[u8] = b"PROXY TCP4 10.0.0.1";
+        let error = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+    }
+}
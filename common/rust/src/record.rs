@@ -0,0 +1,175 @@
+use crate::stream::{Peer, Stream};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+use tracing::{debug, error, info};
+
+// chunks read from a client that can be waiting on the capture file before reading from it
+// waits too
+const CAPTURE_BACKLOG: usize = 64;
+
+/// Wraps the client's `stream` so everything read from it is also written to a new file in
+/// `dir`, and the handler gets the wrapped stream in its place. The file is written on its own
+/// task, which comes back too so the capture can be waited on once the handler's done with the
+/// stream. If the file can't be created the client's own stream comes back and the connection
+/// just isn't recorded.
+pub async fn record(stream: Stream, peer: Peer, dir: &Path) -> (Stream, Option<JoinHandle<()>>) {
+    let path = capture_path(dir, peer);
+    let file = match File::create(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Couldn't start recording {:?}, {:?}", peer, e);
+            return (stream, None);
+        }
+    };
+    info!("Recording {:?} to {:?}", peer, path);
+    let (sender, receiver) = mpsc::channel(CAPTURE_BACKLOG);
+    let writer = tokio::spawn(write_capture(BufWriter::new(file), receiver, peer));
+    let tee = Tee {
+        stream,
+        capture: Some(PollSender::new(sender)),
+    };
+    (Stream::Recorded(Box::new(tee)), Some(writer))
+}
+
+/// Where a capture of `peer` connecting now goes, unique enough for one file per connection.
+fn capture_path(dir: &Path, peer: Peer) -> PathBuf {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    // colons aren't allowed in file names everywhere
+    dir.join(format!("{}-{}.bin", millis, peer).replace(':', "_"))
+}
+
+/// Writes out what the client sent, chunk by chunk, until the stream it came from is dropped.
+async fn write_capture(mut file: BufWriter<File>, mut chunks: mpsc::Receiver<Vec<u8>>, peer: Peer) {
+    let mut written = 0;
+    while let Some(chunk) = chunks.recv().await {
+        if let Err(e) = file.write_all(&chunk).await {
+            error!("Couldn't write to capture, no longer recording: {:?}", e);
+            return;
+        }
+        written += chunk.len();
+    }
+    match file.flush().await {
+        Ok(()) => debug!("Recorded {} bytes from {:?}", written, peer),
+        Err(e) => error!("Couldn't finish capture of {:?}, {:?}", peer, e),
+    }
+}
+
+/// The client's stream, with every byte read from it also sent off to be written to its
+/// capture.
+#[derive(Debug)]
+pub struct Tee {
+    pub(crate) stream: Stream,
+    // dropped once the capture stops being written, the connection carries on unrecorded
+    capture: Option<PollSender<Vec<u8>>>,
+}
+
+impl AsyncRead for Tee {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // room for what's about to be read first, so a slow disk holds up reading the client
+        // rather than piling up in memory
+        if let Some(capture) = &mut this.capture {
+            if ready!(capture.poll_reserve(cx)).is_err() {
+                this.capture = None;
+            }
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if let Some(capture) = &mut this.capture {
+            if !read.is_empty() && capture.send_item(read.to_vec()).is_err() {
+                this.capture = None;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Tee {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_path() {
+        let dir = Path::new("/tmp/captures");
+        let path = capture_path(dir, Peer::Tcp("127.0.0.1:4000".parse().unwrap()));
+        assert_eq!(Some(dir), path.parent());
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-127.0.0.1_4000.bin"), "{}", name);
+
+        let path = capture_path(dir, Peer::Tcp("[::1]:4000".parse().unwrap()));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-[__1]_4000.bin"), "{}", name);
+
+        let path = capture_path(dir, Peer::Unix(2));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-unix#2.bin"), "{}", name);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_record() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::UnixStream;
+
+        let dir = std::env::temp_dir().join(format!("record-unit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut client, server_side) = UnixStream::pair().unwrap();
+        let (mut stream, writer) = record(Stream::Unix(server_side), Peer::Unix(0), &dir).await;
+        // the handler reads the client's own stream, nothing in between
+        let Stream::Recorded(tee) = &stream else {
+            panic!("Not recording: {:?}", stream);
+        };
+        assert!(matches!(tee.stream, Stream::Unix(_)));
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(b"hello", &received[..]);
+        drop(stream);
+        writer.unwrap().await.unwrap();
+
+        let captures: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(1, captures.len());
+        assert_eq!(b"hello".to_vec(), std::fs::read(&captures[0]).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
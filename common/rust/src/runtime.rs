@@ -0,0 +1,48 @@
+use crate::env::env_var;
+use std::num::NonZeroUsize;
+use std::thread;
+use tokio::runtime::{Builder, Runtime};
+use tracing::info;
+
+/// Builds the multi threaded runtime a problem runs on. `WORKER_THREADS` sets how many worker
+/// threads it gets, otherwise it's one per CPU.
+pub fn from_env() -> Runtime {
+    let worker_threads = env_var("WORKER_THREADS")
+        .filter(|worker_threads| *worker_threads > 0)
+        .unwrap_or_else(default_worker_threads);
+    build(worker_threads)
+}
+
+pub fn build(worker_threads: usize) -> Runtime {
+    info!("Starting runtime with {} worker threads", worker_threads);
+    Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+        .expect("Couldn't start the tokio runtime")
+}
+
+// same default #[tokio::main] uses
+fn default_worker_threads() -> usize {
+    thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_threads() {
+        let runtime = build(3);
+        assert_eq!(3, runtime.metrics().num_workers());
+        // and it runs things
+        assert_eq!(4, runtime.block_on(async { 2 + 2 }));
+    }
+
+    #[test]
+    fn test_default_worker_threads() {
+        assert!(default_worker_threads() >= 1);
+    }
+}
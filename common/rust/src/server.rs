@@ -0,0 +1,1628 @@
+use crate::admin;
+use crate::close::finish_write;
+use crate::connections::Connections;
+use crate::health;
+use crate::limiter::{ConnectionLimiter, ConnectionPermit, IpLimiter, IpPermit};
+use crate::metrics::{self, Histogram, IntCounter, Registry};
+use crate::proxy;
+use crate::record;
+use crate::shutdown::{ShutdownSignal, ShutdownToken};
+use crate::stream::{Peer, Stream};
+#[cfg(unix)]
+use crate::unix::{self, UnixSocket};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time;
+use tracing::{error, info, info_span, warn, Instrument};
+
+// upper bounds of the connection duration histogram, in seconds
+const CONNECTION_SECONDS_BUCKETS: [f64; 12] = [
+    0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+// first and longest waits before accepting again after a failed accept, doubling in between
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// how long a client behind a proxy gets to send its PROXY header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long a connection turned away for being over `max_connections` gets to take its message
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What the server does with a new connection while it's already at `max_connections`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Leave it waiting to be accepted until a slot frees up.
+    #[default]
+    Queue,
+    /// Accept it, write these bytes and close it.
+    Reject(Vec<u8>),
+}
+
+/// How `run_tcp_server` listens and what each connection goes through on its way to the handler.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// An ipv6 address like `[::]:8000` listens on ipv6 only, see `dual_stack`.
+    /// `unix:/path/to/socket` listens on a unix domain socket instead. Its clients are a
+    /// `Peer::Unix`, their streams are handed over as they are and the socket file is removed on
+    /// the way out.
+    pub address: String,
+    /// Lets an ipv6 listener take ipv4 clients too (as v4 mapped addresses).
+    pub dual_stack: bool,
+    /// Connections handled at once, the accept loop waits once this many are open. What happens
+    /// to the ones past it is up to `when_full`.
+    pub max_connections: usize,
+    /// Tasks accepting off the listener side by side, for when one can't keep up with how fast
+    /// connections arrive. Each sets up the connections it accepts on its own, and logs say which
+    /// one took a connection. A failed accept (e.g. out of file descriptors) is logged and
+    /// retried after a pause that doubles with each failure in a row, up to a second.
+    pub acceptors: usize,
+    /// How many connections the OS queues up for us before turning clients away.
+    pub backlog: u32,
+    /// Whether connections over `max_connections` wait their turn or get turned away.
+    pub when_full: WhenFull,
+    /// Connections one IP address can have open at once, extra ones are closed without reaching
+    /// the handler. Unix clients don't have one, they're only held to `max_connections`.
+    pub max_connections_per_ip: Option<usize>,
+    /// Turns off Nagle's algorithm, so small writes go out straight away. Set on every accepted
+    /// stream before it's handed over.
+    pub nodelay: bool,
+    /// How long a connection sits idle before keepalive probes start, no keepalive when unset.
+    pub keepalive: Option<Duration>,
+    /// How long a new connection has to send something before it's dropped, no limit when unset.
+    /// The handler only starts once the client has sent something, so this is only for protocols
+    /// where the client speaks first.
+    pub first_byte_timeout: Option<Duration>,
+    /// Once shutdown fires the server stops accepting and waits for every handler to finish.
+    /// Handlers get their own `ShutdownSignal` to wrap up early, any still running after this
+    /// long are aborted.
+    pub drain_timeout: Duration,
+    /// Where to serve `GET /metrics` from, no metrics listener when unset.
+    pub metrics_address: Option<String>,
+    /// Where to serve a plain text snapshot of the metrics and uptime from, none when unset.
+    pub admin_address: Option<String>,
+    /// Where to answer liveness probes with `OK`, from when the server is ready. None when unset.
+    pub health_address: Option<String>,
+    /// Connection metrics get added here, problems can register their own alongside them.
+    pub registry: Registry,
+    /// Every connection being handled, for as long as its handler runs. Keep a clone to list or
+    /// abort them from outside.
+    pub connections: Connections,
+    /// For debugging, a copy of everything each client sends goes in its own file in here on its
+    /// way to the handler, see `common::record`. Off when unset.
+    pub record_dir: Option<PathBuf>,
+    /// Every connection has to open with a PROXY protocol v1 header from a load balancer, naming
+    /// the client it's passing along. That client stands in for the connection's own address
+    /// from then on, it's who's logged, limited and handed to the handler. A missing or
+    /// malformed header closes the connection.
+    pub proxy_protocol: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            acceptors: 1,
+            backlog: 1024,
+            when_full: WhenFull::Queue,
+            max_connections_per_ip: None,
+            nodelay: false,
+            keepalive: None,
+            first_byte_timeout: None,
+            drain_timeout: Duration::from_secs(30),
+            metrics_address: None,
+            admin_address: None,
+            health_address: None,
+            registry: Registry::new(),
+            connections: Connections::new(),
+            record_dir: None,
+            proxy_protocol: false,
+        }
+    }
+}
+
+/// Listens on `config.address`, fires `ready_signal` once it's up, then hands every accepted
+/// connection to `handler` on its own task. See [`ServerConfig`] for what a connection goes
+/// through on the way. A handler that panics is logged at `error` along with who it was serving,
+/// and the server carries on.
+///
+/// Once `shutdown` fires the server stops accepting and returns after every handler has
+/// finished, or been aborted past `drain_timeout`.
+pub async fn run_tcp_server<F, Fut>(
+    config: &ServerConfig,
+    ready_signal: oneshot::Sender<bool>,
+    shutdown: ShutdownToken,
+    handler: F,
+) where
+    F: Fn(Stream, Peer, ShutdownSignal) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let started = Instant::now();
+    let handler = Arc::new(handler);
+    let listener = listen(config)
+        .await
+        .expect("Couldn't start listener on address");
+    listener.log_address();
+    let active_connections = metrics::register_gauge(
+        &config.registry,
+        "active_connections",
+        "Connections currently being handled",
+    );
+    let connections_total = metrics::register_counter(
+        &config.registry,
+        "connections_total",
+        "Connections accepted since the server started",
+    );
+    let connections_rejected_total = metrics::register_counter(
+        &config.registry,
+        "connections_rejected_total",
+        "Connections closed straight away for going over a connection limit",
+    );
+    let connection_seconds = metrics::register_histogram(
+        &config.registry,
+        "connection_duration_seconds",
+        "How long connections were open, from being accepted to their handler finishing",
+        CONNECTION_SECONDS_BUCKETS.to_vec(),
+    );
+    if let Some(metrics_address) = &config.metrics_address {
+        let metrics_listener = TcpListener::bind(metrics_address)
+            .await
+            .expect("Couldn't start metrics listener on address");
+        tokio::spawn(metrics::serve_metrics(
+            metrics_listener,
+            config.registry.clone(),
+            shutdown.subscribe(),
+        ));
+    }
+    if let Some(admin_address) = &config.admin_address {
+        let admin_listener = TcpListener::bind(admin_address)
+            .await
+            .expect("Couldn't start admin listener on address");
+        tokio::spawn(admin::serve_admin(
+            admin_listener,
+            config.registry.clone(),
+            config.connections.clone(),
+            started,
+            shutdown.subscribe(),
+        ));
+    }
+    let health_listener = match &config.health_address {
+        Some(health_address) => Some(
+            TcpListener::bind(health_address)
+                .await
+                .expect("Couldn't start health listener on address"),
+        ),
+        None => None,
+    };
+    ready_signal
+        .send(true)
+        .expect("Couldn't send ready signal after server has started");
+    if let Some(health_listener) = health_listener {
+        tokio::spawn(health::serve_health(health_listener, shutdown.subscribe()));
+    }
+
+    let limiter = ConnectionLimiter::new(config.max_connections);
+    let ip_limiter = config.max_connections_per_ip.map(IpLimiter::new);
+    // acceptors hand over connections that are ready to be handled, they're all set up and
+    // counted against the limits by then
+    let acceptor_count = config.acceptors.max(1);
+    let (accepted_sender, mut accepted_receiver) = mpsc::channel(acceptor_count);
+    let listener = Arc::new(listener);
+    let mut acceptors = JoinSet::new();
+    for id in 0..acceptor_count {
+        let acceptor = Acceptor {
+            config: config.clone(),
+            listener: listener.clone(),
+            limiter: limiter.clone(),
+            ip_limiter: ip_limiter.clone(),
+            connections_rejected_total: connections_rejected_total.clone(),
+            accepted: accepted_sender.clone(),
+        };
+        acceptors.spawn(
+            acceptor
+                .run(shutdown.subscribe())
+                .instrument(info_span!("acceptor", id)),
+        );
+    }
+    drop(accepted_sender);
+    // handlers run in here so a panic comes back to us instead of vanishing with its task
+    let mut tasks = JoinSet::new();
+    let mut shutdown_signal = shutdown.subscribe();
+    loop {
+        let accepted = tokio::select! {
+            accepted = accepted_receiver.recv() => accepted,
+            Some(joined) = tasks.join_next_with_id() => {
+                active_connections.dec();
+                log_finished(&config.connections, &connection_seconds, joined);
+                continue;
+            }
+            _ = shutdown_signal.recv() => break,
+        };
+        let Some(Accepted {
+            stream,
+            peer,
+            permit,
+            ip_permit,
+        }) = accepted
+        else {
+            break;
+        };
+        connections_total.inc();
+        active_connections.inc();
+        let handler = handler.clone();
+        let mut connection_shutdown = shutdown.subscribe();
+        let first_byte_timeout = config.first_byte_timeout;
+        let record_dir = config.record_dir.clone();
+        // the task only changes its peer once it's been registered with it
+        let (registered, wait_registered) = oneshot::channel::<()>();
+        let proxy_protocol = config.proxy_protocol;
+        let ip_limiter = ip_limiter.clone();
+        let connections = config.connections.clone();
+        let connections_rejected_total = connections_rejected_total.clone();
+        // the permits go with the task, so they're given back even if the handler panics
+        let task = tasks.spawn(async move {
+            let mut stream = stream;
+            let mut peer = peer;
+            let mut ip_permit = ip_permit;
+            if let Some(deadline) = first_byte_timeout {
+                if !first_byte(&stream, deadline, &mut connection_shutdown).await {
+                    info!("Nothing from {:?} within {:?}, closing", peer, deadline);
+                    return;
+                }
+            }
+            if proxy_protocol {
+                let header = time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(&mut stream));
+                match header.await {
+                    Ok(Ok(Some(client))) => {
+                        info!("{:?} is proxying for {:?}", peer, client);
+                        let _ = wait_registered.await;
+                        peer = Peer::Tcp(client);
+                        connections.set_peer(task::id(), peer);
+                    }
+                    // the proxy doesn't know who it is either
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => {
+                        info!("Bad PROXY header from {:?}, closing: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        info!("No PROXY header from {:?}, closing", peer);
+                        return;
+                    }
+                }
+                ip_permit = match acquire_ip(ip_limiter.as_ref(), peer, &connections_rejected_total)
+                {
+                    Ok(ip_permit) => ip_permit,
+                    Err(()) => return,
+                };
+            }
+            let (stream, recording) = match &record_dir {
+                Some(dir) => record::record(stream, peer, dir).await,
+                None => (stream, None),
+            };
+            handler(stream, peer, connection_shutdown).await;
+            // the connection isn't over until its capture is all written out
+            if let Some(recording) = recording {
+                let _ = recording.await;
+            }
+            drop(ip_permit);
+            drop(permit);
+        });
+        config.connections.register(task, peer);
+        let _ = registered.send(());
+    }
+
+    // connections still waiting to be handed over are closed along with the receiver
+    drop(accepted_receiver);
+    while acceptors.join_next().await.is_some() {}
+    // nobody else can connect now, and a unix socket's file goes with it
+    drop(listener);
+    info!(
+        "Shutting down, waiting on {} connections",
+        limiter.in_flight()
+    );
+    let drain = async {
+        while let Some(joined) = tasks.join_next_with_id().await {
+            active_connections.dec();
+            log_finished(&config.connections, &connection_seconds, joined);
+        }
+    };
+    if time::timeout(config.drain_timeout, drain).await.is_err() {
+        warn!(
+            "{} connections still running after {:?}, aborting them",
+            tasks.len(),
+            config.drain_timeout
+        );
+        tasks.abort_all();
+        while let Some(joined) = tasks.join_next_with_id().await {
+            active_connections.dec();
+            log_finished(&config.connections, &connection_seconds, joined);
+        }
+    }
+    info!("Server stopped");
+}
+
+/// Counts `peer` against its address's limit, if there is one and it has an address. `Err` when
+/// that address is already at the limit and the connection should be closed.
+fn acquire_ip(
+    ip_limiter: Option<&IpLimiter>,
+    peer: Peer,
+    connections_rejected_total: &IntCounter,
+) -> Result<Option<IpPermit>, ()> {
+    let (Some(ip_limiter), Some(ip)) = (ip_limiter, peer.ip()) else {
+        return Ok(None);
+    };
+    match ip_limiter.try_acquire(ip) {
+        Some(ip_permit) => Ok(Some(ip_permit)),
+        None => {
+            info!(
+                "Rejecting connection for {:?}, too many open from that address",
+                peer
+            );
+            connections_rejected_total.inc();
+            Err(())
+        }
+    }
+}
+
+/// Tells a connection the server is full, without letting a slow client hold up the accept loop.
+async fn reject(mut stream: Stream, message: Vec<u8>) {
+    let write = async {
+        if let Err(e) = stream.write_all(&message).await {
+            info!("Couldn't write rejection: {:?}", e);
+            return;
+        }
+        finish_write(&mut stream).await;
+    };
+    let _ = time::timeout(REJECT_WRITE_TIMEOUT, write).await;
+}
+
+/// A connection an acceptor has taken, with the permits it holds against the limits.
+struct Accepted {
+    stream: Stream,
+    peer: Peer,
+    permit: ConnectionPermit,
+    ip_permit: Option<IpPermit>,
+}
+
+/// One of `config.acceptors` tasks taking connections off the shared listener. Each waits for
+/// room under `max_connections` (unless connections over it are turned away), accepts, checks the
+/// limits and sets up the stream, then hands it to the server to be handled.
+struct Acceptor {
+    config: ServerConfig,
+    listener: Arc<Listener>,
+    limiter: ConnectionLimiter,
+    ip_limiter: Option<IpLimiter>,
+    connections_rejected_total: IntCounter,
+    accepted: mpsc::Sender<Accepted>,
+}
+
+impl Acceptor {
+    /// Accepts until `shutdown` fires or the server stops taking connections from it.
+    async fn run(self, mut shutdown: ShutdownSignal) {
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            // hold off accepting until there's room for another connection, unless connections
+            // over the limit are being turned away, then it's checked once one has been accepted
+            let queued_permit = match self.config.when_full {
+                WhenFull::Queue => tokio::select! {
+                    permit = self.limiter.acquire() => Some(permit),
+                    _ = shutdown.recv() => break,
+                },
+                WhenFull::Reject(_) => None,
+            };
+            let (stream, peer) = tokio::select! {
+                accepted = accept(|| self.listener.accept(), &mut backoff) => accepted,
+                _ = shutdown.recv() => break,
+            };
+            let permit = match queued_permit.or_else(|| self.limiter.try_acquire()) {
+                Some(permit) => permit,
+                None => {
+                    info!(
+                        "Rejecting connection for {:?}, already at {} connections",
+                        peer,
+                        self.limiter.max_connections()
+                    );
+                    self.connections_rejected_total.inc();
+                    if let WhenFull::Reject(message) = &self.config.when_full {
+                        tokio::spawn(reject(stream, message.clone()));
+                    }
+                    continue;
+                }
+            };
+            // behind a proxy every connection comes from the proxy, the client's own
+            // address is only known once its header has been read
+            let ip_permit = if self.config.proxy_protocol {
+                None
+            } else {
+                match acquire_ip(
+                    self.ip_limiter.as_ref(),
+                    peer,
+                    &self.connections_rejected_total,
+                ) {
+                    Ok(ip_permit) => ip_permit,
+                    Err(()) => continue,
+                }
+            };
+            if let Err(e) = configure_stream(&stream, &self.config) {
+                // the connection still works, just without the tuning
+                error!("Couldn't set socket options for {:?}, {:?}", peer, e);
+            }
+            info!(
+                "Accepted connection for {:?}, {} in flight",
+                peer,
+                self.limiter.in_flight()
+            );
+            let accepted = Accepted {
+                stream,
+                peer,
+                permit,
+                ip_permit,
+            };
+            if self.accepted.send(accepted).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Where connections come from: a tcp port, or a unix domain socket when asked for.
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(address)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => socket.accept().await,
+        }
+    }
+
+    fn log_address(&self) {
+        match self {
+            Listener::Tcp(listener) => info!("Listening on address: {:?}", listener.local_addr()),
+            #[cfg(unix)]
+            Listener::Unix(socket) => info!("Listening on unix socket: {:?}", socket.path()),
+        }
+    }
+}
+
+/// Binds a unix socket for a `unix:` address, a tcp listener for anything else.
+async fn listen(config: &ServerConfig) -> io::Result<Listener> {
+    if config.address.starts_with("unix:") {
+        #[cfg(unix)]
+        return UnixSocket::bind(unix::socket_path(&config.address).unwrap()).map(Listener::Unix);
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets aren't supported here",
+        ));
+    }
+    Ok(Listener::Tcp(bind(config).await?))
+}
+
+/// Listens with `config.backlog`. An ipv6 listener is only dual stack when asked for, rather than
+/// whatever the OS defaults to. A name is looked up and the first address that binds is used.
+async fn bind(config: &ServerConfig) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for address in lookup_host(&config.address).await? {
+        match bind_address(address, config) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "address didn't resolve to anything",
+        )
+    }))
+}
+
+fn bind_address(address: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!config.dual_stack)?;
+    }
+    // what TcpListener::bind does on unix
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(config.backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// How long to wait before accepting again, growing while accepts keep failing.
+#[derive(Debug, Default)]
+struct AcceptBackoff {
+    // failed accepts in a row
+    failures: u32,
+}
+
+impl AcceptBackoff {
+    /// The pause before the next try, after another failure.
+    fn failed(&mut self) -> Duration {
+        self.failures += 1;
+        ACCEPT_BACKOFF_MIN
+            .saturating_mul(2_u32.saturating_pow(self.failures - 1))
+            .min(ACCEPT_BACKOFF_MAX)
+    }
+
+    /// Back to trying straight away. How many failures it took to get here.
+    fn succeeded(&mut self) -> u32 {
+        std::mem::take(&mut self.failures)
+    }
+}
+
+/// Keeps calling `accept` until it works, pausing between failures rather than spinning on an
+/// error that won't clear straight away, like running out of file descriptors.
+async fn accept<T, F, Fut>(mut accept: F, backoff: &mut AcceptBackoff) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    loop {
+        match accept().await {
+            Ok(accepted) => {
+                let failures = backoff.succeeded();
+                if failures > 0 {
+                    info!("Accepting again after {} failed accepts", failures);
+                }
+                return accepted;
+            }
+            Err(e) => {
+                let pause = backoff.failed();
+                warn!(
+                    "Error when listening for connection, trying again in {:?}, {:?}",
+                    pause, e
+                );
+                time::sleep(pause).await;
+            }
+        }
+    }
+}
+
+/// Waits for the client to send something (or hang up) without reading it, so the handler still
+/// sees everything. False if the deadline passed or the server is shutting down first.
+async fn first_byte(stream: &Stream, deadline: Duration, shutdown: &mut ShutdownSignal) -> bool {
+    tokio::select! {
+        sent = stream.wait_for_data(deadline) => sent,
+        _ = shutdown.recv() => false,
+    }
+}
+
+/// Tunes a tcp stream, there's nothing to set on a unix one.
+fn configure_stream(stream: &Stream, config: &ServerConfig) -> io::Result<()> {
+    match stream {
+        Stream::Tcp(stream) => configure_tcp(stream, config),
+        #[cfg(unix)]
+        Stream::Unix(_) => Ok(()),
+        Stream::Recorded(tee) => configure_stream(&tee.stream, config),
+    }
+}
+
+fn configure_tcp(stream: &TcpStream, config: &ServerConfig) -> io::Result<()> {
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(idle) = config.keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+    }
+    Ok(())
+}
+
+/// Deregisters a finished handler and records how long its connection lasted, however it ended.
+fn log_finished(
+    connections: &Connections,
+    connection_seconds: &Histogram,
+    joined: Result<(task::Id, ()), JoinError>,
+) {
+    let id = match &joined {
+        Ok((id, ())) => *id,
+        Err(e) => e.id(),
+    };
+    let info = connections.deregister(id);
+    if let Some(info) = &info {
+        connection_seconds.observe(info.connected_at.elapsed().as_secs_f64());
+    }
+    if let Err(e) = joined {
+        let peer = info.map(|info| info.peer);
+        if e.is_panic() {
+            error!("Connection handler for {:?} panicked: {}", peer, e);
+        } else {
+            info!("Connection handler for {:?} was aborted", peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{capture_logs, replay, TestClient};
+    use std::io;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_run_tcp_server() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9000"),
+            ..ServerConfig::default()
+        };
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, socket_addr, _| async move {
+                    // tell the client who we think it is
+                    let reply = socket_addr.to_string();
+                    stream.write_all(reply.as_bytes()).await.unwrap();
+                },
+            )
+            .await
+        });
+        assert_eq!(Ok(true), ready_receiver.await);
+
+        // every connection gets its own handler
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect("127.0.0.1:9000")
+                .await
+                .expect("Couldn't connect to test server");
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).await.unwrap();
+            assert_eq!(stream.local_addr().unwrap().to_string(), reply);
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9001"),
+            max_connections: 1,
+            ..ServerConfig::default()
+        };
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, _, _| async move {
+                    // answer once the client says something, holding the only slot until then
+                    let mut buffer = [0; 1];
+                    stream.read_exact(&mut buffer).await.unwrap();
+                    stream.write_all(&buffer).await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut first = TcpStream::connect("127.0.0.1:9001").await.unwrap();
+        let mut second = TcpStream::connect("127.0.0.1:9001").await.unwrap();
+        second.write_all(b"2").await.unwrap();
+
+        // second connection isn't handled while the first holds the slot
+        let mut buffer = [0; 1];
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(100), second.read_exact(&mut buffer)).await;
+        assert!(waiting.is_err());
+
+        first.write_all(b"1").await.unwrap();
+        first.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"1", &buffer);
+        drop(first);
+
+        second.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"2", &buffer);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9002"),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, mut shutdown_signal| async move {
+                    // an idle connection only goes away once told to
+                    let mut buffer = [0; 1];
+                    tokio::select! {
+                        _ = stream.read(&mut buffer) => {},
+                        _ = shutdown_signal.recv() => {},
+                    }
+                    stream.write_all(b"bye").await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(TcpStream::connect("127.0.0.1:9002").await.unwrap());
+        }
+        // let the server pick up every connection before pulling the plug
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.shutdown();
+
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+        for mut client in clients {
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).await.unwrap();
+            assert_eq!("bye", reply);
+        }
+
+        // nothing is listening anymore
+        assert!(TcpStream::connect("127.0.0.1:9002").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9003"),
+            metrics_address: Some(String::from("127.0.0.1:9004")),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, mut shutdown_signal| async move {
+                    // stay open until the client hangs up so it counts as active
+                    let mut buffer = [0; 1];
+                    tokio::select! {
+                        _ = stream.read(&mut buffer) => {},
+                        _ = shutdown_signal.recv() => {},
+                    }
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let open = TcpStream::connect("127.0.0.1:9003").await.unwrap();
+        let mut closed = TcpStream::connect("127.0.0.1:9003").await.unwrap();
+        closed.write_all(b"x").await.unwrap();
+        let mut rest = Vec::new();
+        closed.read_to_end(&mut rest).await.unwrap();
+        // give the finished handler a moment to be counted out
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut scrape = TcpStream::connect("127.0.0.1:9004").await.unwrap();
+        scrape
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        scrape.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nconnections_total 2\n"));
+        assert!(response.contains("\nactive_connections 1\n"));
+
+        drop(open);
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_health_probe() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9018"),
+            health_address: Some(String::from("127.0.0.1:9019")),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(&config, ready_sender, server_shutdown, |_, _, _| async {}).await
+        });
+        ready_receiver.await.unwrap();
+
+        for _ in 0..2 {
+            let mut probe = TcpStream::connect("127.0.0.1:9019").await.unwrap();
+            let mut answer = String::new();
+            probe.read_to_string(&mut answer).await.unwrap();
+            assert_eq!("OK\n", answer);
+        }
+
+        // gone along with the server
+        shutdown.shutdown();
+        server_handle.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect("127.0.0.1:9019").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_admin_snapshot() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9012"),
+            admin_address: Some(String::from("127.0.0.1:9013")),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, mut shutdown_signal| async move {
+                    let mut buffer = [0; 1];
+                    tokio::select! {
+                        _ = stream.read(&mut buffer) => {},
+                        _ = shutdown_signal.recv() => {},
+                    }
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let open = TcpStream::connect("127.0.0.1:9012").await.unwrap();
+        for _ in 0..2 {
+            let mut closed = TcpStream::connect("127.0.0.1:9012").await.unwrap();
+            closed.write_all(b"x").await.unwrap();
+            let mut rest = Vec::new();
+            closed.read_to_end(&mut rest).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut admin = TcpStream::connect("127.0.0.1:9013").await.unwrap();
+        let mut snapshot = String::new();
+        admin.read_to_string(&mut snapshot).await.unwrap();
+        assert!(snapshot.starts_with("uptime_secs "));
+        assert!(snapshot.contains("\nconnections_total 3\n"));
+        assert!(snapshot.contains("\nactive_connections 1\n"));
+        assert!(snapshot.contains("\nconnections_rejected_total 0\n"));
+        let open_peer = format!("\nconnection{{peer=\"{}\"}} ", open.local_addr().unwrap());
+        assert!(snapshot.contains(&open_peer), "{}", snapshot);
+        assert_eq!(1, snapshot.matches("\nconnection{").count());
+
+        drop(open);
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        // shouts back each chunk it reads, until the client stops writing
+        async fn shout(mut stream: Stream, _: Peer, _: ShutdownSignal) {
+            let mut buffer = [0; 64];
+            loop {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        let reply = buffer[..read].to_ascii_uppercase();
+                        if stream.write_all(&reply).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let record_dir = std::env::temp_dir().join(format!("record-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&record_dir);
+        std::fs::create_dir_all(&record_dir).unwrap();
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9014"),
+            record_dir: Some(record_dir.clone()),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(&config, ready_sender, server_shutdown, shout).await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut client = TestClient::connect("127.0.0.1:9014").await;
+        client.send_line("hello").await;
+        assert_eq!(Some(String::from("HELLO")), client.read_line().await);
+        client.send_frame(b'q', 1, -1).await;
+        client.shutdown_write().await;
+        let original = [b"HELLO\n".to_vec(), client.read_to_end().await].concat();
+        // the capture's finished off before the server lets go of the connection
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+
+        let captures: Vec<_> = std::fs::read_dir(&record_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(1, captures.len());
+        assert_eq!(
+            b"hello\nq\x00\x00\x00\x01\xff\xff\xff\xff".to_vec(),
+            std::fs::read(&captures[0]).unwrap()
+        );
+        assert_eq!(original, replay(&captures[0], shout).await);
+        std::fs::remove_dir_all(&record_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_when_full() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9015"),
+            max_connections: 1,
+            when_full: WhenFull::Reject(b"busy\n".to_vec()),
+            ..ServerConfig::default()
+        };
+        let registry = config.registry.clone();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, _, _| async move {
+                    stream.write_all(b"hi\n").await.unwrap();
+                    // hold the only slot until the client goes away
+                    let mut rest = Vec::new();
+                    let _ = stream.read_to_end(&mut rest).await;
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut first = TestClient::connect("127.0.0.1:9015").await;
+        assert_eq!(Some(String::from("hi")), first.read_line().await);
+        let mut overflow = TestClient::connect("127.0.0.1:9015").await;
+        assert_eq!("busy\n", overflow.read_to_string().await);
+        assert!(metrics::render(&registry).contains("\nconnections_rejected_total 1\n"));
+
+        // the slot is free again once the first client leaves
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut next = TestClient::connect("127.0.0.1:9015").await;
+        assert_eq!(Some(String::from("hi")), next.read_line().await);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9017"),
+            proxy_protocol: true,
+            max_connections_per_ip: Some(1),
+            ..ServerConfig::default()
+        };
+        let connections = config.connections.clone();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, socket_addr, _| async move {
+                    let reply = format!("{}\n", socket_addr);
+                    stream.write_all(reply.as_bytes()).await.unwrap();
+                    // stay open until the client goes away
+                    let mut rest = Vec::new();
+                    let _ = stream.read_to_end(&mut rest).await;
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut client = TestClient::connect("127.0.0.1:9017").await;
+        client
+            .send(b"PROXY TCP4 203.0.113.7 127.0.0.1 51000 9017\r\n")
+            .await;
+        assert_eq!(
+            Some(String::from("203.0.113.7:51000")),
+            client.read_line().await
+        );
+        let peers: Vec<Peer> = connections.list().iter().map(|info| info.peer).collect();
+        assert_eq!(vec![Peer::Tcp("203.0.113.7:51000".parse().unwrap())], peers);
+
+        // the per address limit goes by the client the proxy names, not the proxy
+        let mut same_client = TestClient::connect("127.0.0.1:9017").await;
+        same_client
+            .send(b"PROXY TCP4 203.0.113.7 127.0.0.1 51001 9017\r\n")
+            .await;
+        assert!(same_client.read_to_end().await.is_empty());
+        let mut other_client = TestClient::connect("127.0.0.1:9017").await;
+        other_client
+            .send(b"PROXY TCP6 2001:db8::1 ::1 51000 9017\r\n")
+            .await;
+        assert_eq!(
+            Some(String::from("[2001:db8::1]:51000")),
+            other_client.read_line().await
+        );
+
+        let mut unknown = TestClient::connect("127.0.0.1:9017").await;
+        unknown.send(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(
+            Some(unknown.local_addr().to_string()),
+            unknown.read_line().await
+        );
+
+        let mut malformed = TestClient::connect("127.0.0.1:9017").await;
+        malformed.send(b"PROXY TCP4 203.0.113.7\r\n").await;
+        assert!(malformed.read_to_end().await.is_empty());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_duration() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9016"),
+            ..ServerConfig::default()
+        };
+        let registry = config.registry.clone();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, _, _| async move {
+                    let mut rest = Vec::new();
+                    let _ = stream.read_to_end(&mut rest).await;
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let client = TestClient::connect("127.0.0.1:9016").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let rendered = metrics::render(&registry);
+        assert!(rendered.contains("connection_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(rendered.contains("connection_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(rendered.contains("connection_duration_seconds_count 1\n"));
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_ip() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9005"),
+            max_connections_per_ip: Some(2),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, mut shutdown_signal| async move {
+                    // say hello, then hold the connection open until the client hangs up
+                    stream.write_all(b"hi").await.unwrap();
+                    let mut buffer = [0; 1];
+                    tokio::select! {
+                        _ = stream.read(&mut buffer) => {},
+                        _ = shutdown_signal.recv() => {},
+                    }
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        async fn greeting(stream: &mut TcpStream) -> Vec<u8> {
+            let mut greeting = Vec::new();
+            let mut buffer = [0; 2];
+            if let Ok(Ok(read)) =
+                tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await
+            {
+                greeting.extend_from_slice(&buffer[..read]);
+            }
+            greeting
+        }
+
+        let mut first = TcpStream::connect("127.0.0.1:9005").await.unwrap();
+        let mut second = TcpStream::connect("127.0.0.1:9005").await.unwrap();
+        assert_eq!(b"hi", &greeting(&mut first).await[..]);
+        assert_eq!(b"hi", &greeting(&mut second).await[..]);
+        // one over the limit, closed without reaching the handler
+        let mut third = TcpStream::connect("127.0.0.1:9005").await.unwrap();
+        assert!(greeting(&mut third).await.is_empty());
+
+        // another address on the loopback still gets in
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut elsewhere = socket
+            .connect("127.0.0.1:9005".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(b"hi", &greeting(&mut elsewhere).await[..]);
+
+        // hanging up frees a slot for the first address again
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut fourth = TcpStream::connect("127.0.0.1:9005").await.unwrap();
+        assert_eq!(b"hi", &greeting(&mut fourth).await[..]);
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic_is_logged() {
+        let (logs, _guard) = capture_logs(tracing::Level::INFO);
+
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9006"),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, _| async move {
+                    // echoes a byte, unless it's a `!`
+                    let mut buffer = [0; 1];
+                    stream.read_exact(&mut buffer).await.unwrap();
+                    if &buffer == b"!" {
+                        panic!("test handler told to panic");
+                    }
+                    stream.write_all(&buffer).await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut panicking = TcpStream::connect("127.0.0.1:9006").await.unwrap();
+        let panicking_addr = panicking.local_addr().unwrap();
+        panicking.write_all(b"!").await.unwrap();
+        let mut rest = Vec::new();
+        let _ = panicking.read_to_end(&mut rest).await;
+        assert!(rest.is_empty());
+
+        // the server is still taking connections
+        let mut fine = TcpStream::connect("127.0.0.1:9006").await.unwrap();
+        fine.write_all(b"1").await.unwrap();
+        let mut buffer = [0; 1];
+        fine.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"1", &buffer);
+
+        let logged = tokio::time::timeout(Duration::from_secs(5), async {
+            while !logs.contents().contains("panicked") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        let contents = logs.contents();
+        assert!(logged.is_ok(), "{}", contents);
+        let panic_line = contents
+            .lines()
+            .find(|line| line.contains("panicked"))
+            .unwrap();
+        assert!(panic_line.contains("ERROR"), "{}", panic_line);
+        assert!(
+            panic_line.contains(&panicking_addr.to_string()),
+            "{}",
+            panic_line
+        );
+        assert!(
+            panic_line.contains("test handler told to panic"),
+            "{}",
+            panic_line
+        );
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_acceptors() {
+        let (logs, _guard) = capture_logs(tracing::Level::INFO);
+
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9020"),
+            acceptors: 4,
+            ..ServerConfig::default()
+        };
+        let registry = config.registry.clone();
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, _| async move {
+                    stream.write_all(b"hi").await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        // all at once, so there's more arriving than one acceptor can take in a go
+        let mut clients = JoinSet::new();
+        for _ in 0..32 {
+            clients.spawn(async {
+                let mut stream = TcpStream::connect("127.0.0.1:9020").await.unwrap();
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply).await.unwrap();
+                reply
+            });
+        }
+        while let Some(reply) = clients.join_next().await {
+            assert_eq!("hi", reply.unwrap());
+        }
+
+        let contents = logs.contents();
+        let acceptors: Vec<&str> = contents
+            .lines()
+            .filter(|line| line.contains("Accepted connection"))
+            .map(|line| line.split_whitespace().nth(2).unwrap())
+            .collect();
+        assert_eq!(32, acceptors.len(), "{}", contents);
+        // each line is in its acceptor's span, like `acceptor{id=2}:`
+        let mut used = acceptors.clone();
+        used.sort();
+        used.dedup();
+        assert!(used.len() > 1, "{:?}", used);
+        assert!(used.iter().all(|span| span.starts_with("acceptor{id=")));
+        assert!(metrics::render(&registry).contains("\nconnections_total 32\n"));
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9007"),
+            drain_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |mut stream, _, _| async move {
+                    // ignores the shutdown and takes far longer than the drain allows
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    stream.write_all(b"done").await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut client = TcpStream::connect("127.0.0.1:9007").await.unwrap();
+        // let the server pick up the connection before pulling the plug
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.shutdown();
+
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server didn't give up on the slow handler")
+            .expect("Server panicked");
+        // aborting the handler dropped its stream without anything being written
+        let mut reply = Vec::new();
+        let _ = client.read_to_end(&mut reply).await;
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9008"),
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(45)),
+            ..ServerConfig::default()
+        };
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, _, _| async move {
+                    // report back what the accepted stream ended up with
+                    let Stream::Tcp(stream) = &mut stream else {
+                        panic!("Listening on tcp but got {:?}", stream);
+                    };
+                    let socket = SockRef::from(&*stream);
+                    let reply = format!(
+                        "{} {} {:?}",
+                        stream.nodelay().unwrap(),
+                        socket.keepalive().unwrap(),
+                        socket.tcp_keepalive_time().unwrap()
+                    );
+                    stream.write_all(reply.as_bytes()).await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut stream = TcpStream::connect("127.0.0.1:9008").await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert_eq!("true true 45s", reply);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_first_byte_timeout() {
+        let config = ServerConfig {
+            address: String::from("127.0.0.1:9009"),
+            first_byte_timeout: Some(Duration::from_millis(200)),
+            ..ServerConfig::default()
+        };
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, _, _| async move {
+                    // echoes the first byte, it's still there for the handler after the wait
+                    let mut buffer = [0; 1];
+                    stream.read_exact(&mut buffer).await.unwrap();
+                    stream.write_all(&buffer).await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        // says something in time
+        let mut prompt = TcpStream::connect("127.0.0.1:9009").await.unwrap();
+        prompt.write_all(b"x").await.unwrap();
+        let mut reply = Vec::new();
+        prompt.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(b"x", &reply[..]);
+
+        // says nothing and gets dropped without ever reaching the handler
+        let mut silent = TcpStream::connect("127.0.0.1:9009").await.unwrap();
+        let started = std::time::Instant::now();
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut reply))
+            .await
+            .expect("Silent client wasn't dropped")
+            .unwrap();
+        assert!(reply.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        server_handle.abort();
+    }
+
+    // answers with the address the client connected from
+    async fn start_peer_echo(config: ServerConfig) {
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                ShutdownToken::new(),
+                |mut stream, socket_addr, _| async move {
+                    let reply = socket_addr.to_string();
+                    stream.write_all(reply.as_bytes()).await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+    }
+
+    async fn peer_seen_by_server(address: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(address).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn test_accept_backs_off() {
+        let mut backoff = AcceptBackoff::default();
+        let mut calls = 0;
+        let started = Instant::now();
+        // the first four accepts fail, as if out of file descriptors
+        let accepted = accept(
+            || {
+                calls += 1;
+                let result = if calls <= 4 {
+                    Err(io::Error::other("too many open files"))
+                } else {
+                    Ok(calls)
+                };
+                async move { result }
+            },
+            &mut backoff,
+        )
+        .await;
+        assert_eq!(5, accepted);
+        // 5 + 10 + 20 + 40ms of waiting, rather than retrying straight away
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(75), "{:?}", elapsed);
+
+        // recovered, so the next failure starts from the shortest pause again
+        assert_eq!(ACCEPT_BACKOFF_MIN, backoff.failed());
+        for _ in 0..100 {
+            backoff.failed();
+        }
+        assert_eq!(ACCEPT_BACKOFF_MAX, backoff.failed());
+    }
+
+    #[tokio::test]
+    async fn test_bind_backlog() {
+        for address in ["127.0.0.1:0", "localhost:0", "[::1]:0"] {
+            let config = ServerConfig {
+                address: String::from(address),
+                backlog: 1,
+                ..ServerConfig::default()
+            };
+            let listener = bind(&config)
+                .await
+                .expect("Couldn't bind with a small backlog");
+            let address = listener.local_addr().unwrap();
+            let (connected, accepted) =
+                tokio::join!(TcpStream::connect(address), listener.accept());
+            assert_eq!(
+                connected.unwrap().local_addr().unwrap(),
+                accepted.unwrap().1
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("server-{}.sock", std::process::id()));
+        let shutdown = ShutdownToken::new();
+        let config = ServerConfig {
+            address: format!("unix:{}", path.display()),
+            // unix clients don't have an address to be limited by
+            max_connections_per_ip: Some(1),
+            ..ServerConfig::default()
+        };
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_shutdown = shutdown.clone();
+        let server = tokio::spawn(async move {
+            run_tcp_server(
+                &config,
+                ready_sender,
+                server_shutdown,
+                |stream, peer, _| async move {
+                    assert!(matches!(stream, Stream::Unix(_)));
+                    assert!(matches!(peer, Peer::Unix(_)));
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                },
+            )
+            .await
+        });
+        ready_receiver.await.unwrap();
+
+        let mut first = UnixStream::connect(&path).await.unwrap();
+        let mut second = UnixStream::connect(&path).await.unwrap();
+        for client in [&mut first, &mut second] {
+            client.write_all(b"hello\n").await.unwrap();
+            let mut reply = [0; 6];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(b"hello\n", &reply);
+        }
+        drop(first);
+        drop(second);
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("Server didn't stop after shutdown")
+            .expect("Server panicked");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_ipv6() {
+        start_peer_echo(ServerConfig {
+            address: String::from("[::1]:9010"),
+            ..ServerConfig::default()
+        })
+        .await;
+        let reply = peer_seen_by_server("[::1]:9010").await.unwrap();
+        assert!(reply.starts_with("[::1]:"), "{}", reply);
+        // v6 only unless asked
+        assert!(peer_seen_by_server("127.0.0.1:9010").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        start_peer_echo(ServerConfig {
+            address: String::from("[::]:9011"),
+            dual_stack: true,
+            ..ServerConfig::default()
+        })
+        .await;
+        let reply = peer_seen_by_server("[::1]:9011").await.unwrap();
+        assert!(reply.starts_with("[::1]:"), "{}", reply);
+        // ipv4 clients show up as v4 mapped addresses
+        let reply = peer_seen_by_server("127.0.0.1:9011").await.unwrap();
+        assert!(reply.starts_with("[::ffff:127.0.0.1]:"), "{}", reply);
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Cloneable handle used to tell a server, and every connection it's handling, to stop.
+#[derive(Debug, Clone)]
+pub struct ShutdownToken {
+    sender: broadcast::Sender<()>,
+    // remembers the shutdown for anyone that subscribes after it was sent
+    triggered: Arc<AtomicBool>,
+}
+
+/// One listener's view of a `ShutdownToken`.
+#[derive(Debug)]
+pub struct ShutdownSignal {
+    receiver: broadcast::Receiver<()>,
+    triggered: Arc<AtomicBool>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> ShutdownToken {
+        let (sender, _) = broadcast::channel(1);
+        ShutdownToken {
+            sender,
+            triggered: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        // no subscribers just means nobody is left to tell
+        let _ = self.sender.send(());
+    }
+
+    pub fn subscribe(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: self.sender.subscribe(),
+            triggered: self.triggered.clone(),
+        }
+    }
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        ShutdownToken::new()
+    }
+}
+
+impl ShutdownSignal {
+    pub fn is_shutdown(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once shutdown has been requested, straight away if it already was.
+    /// Safe to use in a `select!` and to call again after it resolved.
+    pub async fn recv(&mut self) {
+        if self.is_shutdown() {
+            return;
+        }
+        // any outcome (sent, lagged or every token dropped) means stop
+        let _ = self.receiver.recv().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shutdown_reaches_every_subscriber() {
+        let token = ShutdownToken::new();
+        let mut first = token.subscribe();
+        let mut second = token.clone().subscribe();
+        assert!(!first.is_shutdown());
+
+        token.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), first.recv())
+            .await
+            .expect("First subscriber never saw the shutdown");
+        tokio::time::timeout(Duration::from_secs(1), second.recv())
+            .await
+            .expect("Second subscriber never saw the shutdown");
+        // still resolves when asked again
+        tokio::time::timeout(Duration::from_secs(1), first.recv())
+            .await
+            .expect("Shutdown was forgotten");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after_shutdown() {
+        let token = ShutdownToken::new();
+        token.shutdown();
+        let mut late = token.subscribe();
+        assert!(late.is_shutdown());
+        tokio::time::timeout(Duration::from_secs(1), late.recv())
+            .await
+            .expect("Late subscriber never saw the shutdown");
+    }
+
+    #[tokio::test]
+    async fn test_no_shutdown() {
+        let token = ShutdownToken::new();
+        let mut signal = token.subscribe();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), signal.recv()).await;
+        assert!(waiting.is_err());
+    }
+}
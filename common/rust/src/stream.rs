@@ -0,0 +1,151 @@
+use crate::record::Tee;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time;
+
+/// Who's on the other end of a connection.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Peer {
+    /// A tcp client, or the client a PROXY header named for it.
+    Tcp(SocketAddr),
+    /// A client on a unix domain socket. They don't have addresses of their own, so they're
+    /// numbered in the order their listener accepted them.
+    Unix(u64),
+}
+
+impl Peer {
+    /// The client's ip address, `None` for a unix client.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(address) => Some(address.ip()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(address: SocketAddr) -> Self {
+        Peer::Tcp(address)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(address) => write!(f, "{}", address),
+            Peer::Unix(number) => write!(f, "unix#{}", number),
+        }
+    }
+}
+
+// logged all over the place, a tcp peer reads the same as the SocketAddr it used to be
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// An accepted connection, as handed to a `run_tcp_server` handler. It's a plain
+/// `AsyncRead + AsyncWrite`, so handlers can stay generic over the stream and be tested with a
+/// `TcpStream` directly.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// A client's stream with a copy of everything it sends going to a capture, see
+    /// `common::record`.
+    Recorded(Box<Tee>),
+}
+
+impl Stream {
+    /// Waits up to `deadline` for the client to send something (or hang up) without reading it.
+    /// False if nothing turned up in time.
+    pub(crate) async fn wait_for_data(&self, deadline: Duration) -> bool {
+        match self {
+            Stream::Tcp(stream) => {
+                let mut byte = [0; 1];
+                time::timeout(deadline, stream.peek(&mut byte))
+                    .await
+                    .is_ok()
+            }
+            // no peek on a unix stream, readiness is as close as it gets
+            #[cfg(unix)]
+            Stream::Unix(stream) => time::timeout(deadline, stream.readable()).await.is_ok(),
+            Stream::Recorded(tee) => Box::pin(tee.stream.wait_for_data(deadline)).await,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Recorded(tee) => Pin::new(tee.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Recorded(tee) => Pin::new(tee.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Recorded(tee) => Pin::new(tee.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Recorded(tee) => Pin::new(tee.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer() {
+        let tcp = Peer::from(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        assert_eq!("127.0.0.1:4000", tcp.to_string());
+        assert_eq!("127.0.0.1:4000", format!("{:?}", tcp));
+        assert_eq!(Some(IpAddr::from([127, 0, 0, 1])), tcp.ip());
+
+        let unix = Peer::Unix(3);
+        assert_eq!("unix#3", unix.to_string());
+        assert_eq!(None, unix.ip());
+    }
+}
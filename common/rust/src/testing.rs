@@ -0,0 +1,233 @@
+use crate::shutdown::{ShutdownSignal, ShutdownToken};
+use crate::stream::{Peer, Stream};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time;
+use tracing::subscriber::DefaultGuard;
+use tracing::Level;
+use tracing_subscriber::fmt::MakeWriter;
+
+// how long any single read waits on the server before the test fails
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client for integration tests. Everything panics on failure, and reads give up after a few
+/// seconds, so a misbehaving server fails the test instead of hanging it.
+#[derive(Debug)]
+pub struct TestClient {
+    stream: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> TestClient {
+        let stream = TcpStream::connect(address)
+            .await
+            .expect("Couldn't connect to test server");
+        TestClient {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// The address the server sees this client connecting from.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.stream
+            .get_ref()
+            .local_addr()
+            .expect("Couldn't get test socket address")
+    }
+
+    pub async fn send(&mut self, bytes: &[u8]) {
+        self.stream
+            .get_mut()
+            .write_all(bytes)
+            .await
+            .expect("Couldn't write to test socket");
+    }
+
+    /// Sends `line` with a newline on the end.
+    pub async fn send_line(&mut self, line: &str) {
+        self.send(format!("{}\n", line).as_bytes()).await;
+    }
+
+    /// A p2 style frame: a type byte and two big endian i32s.
+    pub async fn send_frame(&mut self, message_type: u8, field_1: i32, field_2: i32) {
+        let mut frame = vec![message_type];
+        frame.extend_from_slice(&field_1.to_be_bytes());
+        frame.extend_from_slice(&field_2.to_be_bytes());
+        self.send(&frame).await;
+    }
+
+    /// Closes the write side, so the server sees the end of the stream.
+    pub async fn shutdown_write(&mut self) {
+        self.stream
+            .get_mut()
+            .shutdown()
+            .await
+            .expect("Couldn't shutdown write side of test socket");
+    }
+
+    /// The next line without its newline, `None` once the server has closed the connection.
+    pub async fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        let read = time::timeout(READ_TIMEOUT, self.stream.read_line(&mut line))
+            .await
+            .expect("Timed out waiting for a line")
+            .expect("Couldn't read from test socket");
+        if read == 0 {
+            return None;
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Some(line)
+    }
+
+    pub async fn read_i32(&mut self) -> i32 {
+        time::timeout(READ_TIMEOUT, self.stream.read_i32())
+            .await
+            .expect("Timed out waiting for an i32")
+            .expect("Couldn't read from test socket")
+    }
+
+    /// Everything the server sends until it closes the connection.
+    pub async fn read_to_end(&mut self) -> Vec<u8> {
+        let mut rest = Vec::new();
+        time::timeout(READ_TIMEOUT, self.stream.read_to_end(&mut rest))
+            .await
+            .expect("Server did not close the connection")
+            .expect("Couldn't read from test socket");
+        rest
+    }
+
+    pub async fn read_to_string(&mut self) -> String {
+        String::from_utf8(self.read_to_end().await).expect("Server sent invalid utf8")
+    }
+}
+
+/// Sends a capture written under `ServerConfig::record_dir` to `handler` as one client, then
+/// closes the write side and returns everything the handler sent back before it finished. The
+/// handler gets the same kind of stream and peer `run_tcp_server` would give it.
+pub async fn replay<F, Fut>(capture: impl AsRef<Path>, handler: F) -> Vec<u8>
+where
+    F: FnOnce(Stream, Peer, ShutdownSignal) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let capture = std::fs::read(capture).expect("Couldn't read capture");
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Couldn't start replay listener");
+    let address = listener.local_addr().unwrap();
+    let shutdown = ShutdownToken::new();
+    let signal = shutdown.subscribe();
+    let session = tokio::spawn(async move {
+        let (stream, peer) = listener.accept().await.unwrap();
+        handler(Stream::Tcp(stream), Peer::Tcp(peer), signal).await;
+    });
+
+    let mut stream = TcpStream::connect(address)
+        .await
+        .expect("Couldn't connect to replay listener");
+    // the handler may hang up partway through, same as it did for the original client
+    let _ = stream.write_all(&capture).await;
+    let _ = stream.shutdown().await;
+    let mut responses = Vec::new();
+    let _ = time::timeout(READ_TIMEOUT, stream.read_to_end(&mut responses))
+        .await
+        .expect("Handler didn't close the connection");
+    session.await.expect("Handler panicked");
+    drop(shutdown);
+    responses
+}
+
+/// Everything logged through it, kept in memory so tests can check on it. Clones share the same
+/// buffer, so one can be handed to a subscriber as its writer and the other read afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for CapturedLogs {
+    type Writer = CapturedLogs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Captures everything logged at `level` or above on the current thread, as plain text without
+/// colours, until the guard is dropped. Work moved to other threads logs through whatever they
+/// have as their default instead.
+pub fn capture_logs(level: Level) -> (CapturedLogs, DefaultGuard) {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // echo back whatever arrives, until the client stops writing
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_line("hello").await;
+        client.send_frame(b'Q', 1, -1).await;
+        client.send_line("").await;
+        client.shutdown_write().await;
+
+        assert_eq!(Some(String::from("hello")), client.read_line().await);
+        let mut frame = [0; 9];
+        client.stream.read_exact(&mut frame).await.unwrap();
+        assert_eq!([b'Q', 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff], frame);
+        assert_eq!(Some(String::new()), client.read_line().await);
+        assert_eq!(None, client.read_line().await);
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_capture_logs() {
+        let (logs, guard) = capture_logs(Level::INFO);
+        tracing::debug!("too quiet");
+        tracing::info!("captured");
+        drop(guard);
+        tracing::info!("after the guard");
+
+        let contents = logs.contents();
+        assert_eq!(1, contents.lines().count(), "{}", contents);
+        assert!(contents.ends_with("captured\n"), "{}", contents);
+    }
+}
@@ -0,0 +1,110 @@
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use tokio::net::{lookup_host, UdpSocket};
+
+/// Binds a udp socket the way `run_tcp_server` binds its listener: an ipv6 address like
+/// `[::]:8000` is only dual stack when `dual_stack` asks for it, and a name is looked up and the
+/// first address that binds is used.
+pub async fn bind_udp(address: &str, dual_stack: bool) -> io::Result<UdpSocket> {
+    reject_unix(address)?;
+    let socket = first_bound(lookup_host(address).await?, dual_stack)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// `bind_udp` for a server that reads its socket on a plain thread.
+pub fn bind_udp_blocking(address: &str, dual_stack: bool) -> io::Result<std::net::UdpSocket> {
+    reject_unix(address)?;
+    first_bound(address.to_socket_addrs()?, dual_stack)
+}
+
+// a `unix:` address would otherwise fail to resolve with a much less helpful error
+fn reject_unix(address: &str) -> io::Result<()> {
+    if address.starts_with("unix:") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets are only for tcp servers",
+        ));
+    }
+    Ok(())
+}
+
+fn first_bound(
+    addresses: impl Iterator<Item = SocketAddr>,
+    dual_stack: bool,
+) -> io::Result<std::net::UdpSocket> {
+    let mut last_error = None;
+    for address in addresses {
+        match bind_address(address, dual_stack) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "address didn't resolve to anything",
+        )
+    }))
+}
+
+fn bind_address(address: SocketAddr, dual_stack: bool) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sends from a fresh socket at `from` to `server` and returns who the server saw
+    async fn sender_seen(server: &UdpSocket, from: &str) -> SocketAddr {
+        let port = server.local_addr().unwrap().port();
+        let client = UdpSocket::bind(from).await.unwrap();
+        let target = SocketAddr::new(client.local_addr().unwrap().ip(), port);
+        client.send_to(b"hi", target).await.unwrap();
+        let mut buffer = [0; 2];
+        let (_, sender) = server.recv_from(&mut buffer).await.unwrap();
+        sender
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp() {
+        let server = bind_udp("127.0.0.1:0", false).await.unwrap();
+        assert!(sender_seen(&server, "127.0.0.1:0").await.is_ipv4());
+
+        let server = bind_udp("[::1]:0", false).await.unwrap();
+        assert!(sender_seen(&server, "[::1]:0").await.is_ipv6());
+
+        let socket = bind_udp_blocking("localhost:0", false).unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_dual_stack() {
+        let server = bind_udp("[::]:0", true).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hi", ("127.0.0.1", port)).await.unwrap();
+        let mut buffer = [0; 2];
+        let (_, sender) = server.recv_from(&mut buffer).await.unwrap();
+        // ipv4 senders show up as v4 mapped addresses
+        assert!(
+            sender.to_string().starts_with("[::ffff:127.0.0.1]:"),
+            "{}",
+            sender
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_unix() {
+        let error = bind_udp("unix:/tmp/udp.sock", false).await.unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, error.kind());
+        assert!(bind_udp_blocking("unix:/tmp/udp.sock", false).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::stream::{Peer, Stream};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::UnixListener;
+use tracing::info;
+
+/// The socket path in a `unix:/path/to/socket` address, `None` for anything else.
+pub fn socket_path(address: &str) -> Option<&Path> {
+    address.strip_prefix("unix:").map(Path::new)
+}
+
+/// A listening unix domain socket. Its file is removed once it's dropped, however the server
+/// stops, so the next one can bind the same path.
+#[derive(Debug)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    // clients accepted so far, which numbers the next one's `Peer::Unix`
+    accepted: AtomicU64,
+}
+
+impl UnixSocket {
+    /// Binds `path`, first removing a socket file left there by a server that didn't get to
+    /// clean up after itself. A socket something is still listening on is left alone and the
+    /// bind fails.
+    pub fn bind(path: &Path) -> io::Result<UnixSocket> {
+        remove_stale(path)?;
+        Ok(UnixSocket {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+            accepted: AtomicU64::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, Peer)> {
+        let (stream, _) = self.listener.accept().await?;
+        let number = self.accepted.fetch_add(1, Ordering::Relaxed);
+        Ok((Stream::Unix(stream), Peer::Unix(number)))
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            info!("Couldn't remove unix socket {:?}, {:?}", self.path, e);
+        }
+    }
+}
+
+fn remove_stale(path: &Path) -> io::Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    // anything that isn't a socket is for the bind to complain about
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Ok(());
+    }
+    info!("Removing stale unix socket {:?}", path);
+    std::fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn test_socket_path() {
+        assert_eq!(
+            Some(Path::new("/tmp/server.sock")),
+            socket_path("unix:/tmp/server.sock")
+        );
+        assert_eq!(None, socket_path("127.0.0.1:8000"));
+        assert_eq!(None, socket_path("/tmp/server.sock"));
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let path = std::env::temp_dir().join(format!("unix-accept-{}.sock", std::process::id()));
+        let socket = UnixSocket::bind(&path).unwrap();
+
+        for number in 0..2 {
+            let (client, accepted) = tokio::join!(UnixStream::connect(&path), socket.accept());
+            let mut client = client.unwrap();
+            let (mut stream, peer) = accepted.unwrap();
+            assert_eq!(Peer::Unix(number), peer);
+
+            client.write_all(b"ping").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(b"ping", &received[..]);
+            stream.write_all(b"pong").await.unwrap();
+            drop(stream);
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(b"pong", &reply[..]);
+        }
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_stale_socket() {
+        let path = std::env::temp_dir().join(format!("unix-stale-{}.sock", std::process::id()));
+        // a server that went away without removing its file
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let socket = UnixSocket::bind(&path).unwrap();
+
+        // one that's still listening keeps its socket
+        assert!(UnixSocket::bind(&path).is_err());
+        assert!(UnixStream::connect(&path).await.is_ok());
+        drop(socket);
+    }
+}
@@ -1,32 +1,402 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write};
+use common::env::env_var;
+use common::metrics::{self, IntCounter, Registry};
+use common::udp::bind_udp_blocking;
+use common::{finish_write, run_tcp_server, Peer, ServerConfig, ShutdownToken, TokenBucket};
+use std::io;
+use std::net::{TcpListener, UdpSocket};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info};
 
-fn handle_client(stream: &mut TcpStream) {
-    //println!("hello connection");
+// most bytes echoed in one write when the rate is limited
+const MAX_ECHO_CHUNK: u32 = 16 * 1024;
+
+// `bytes_echoed` is the lifetime count of bytes echoed back, across all connections.
+// `max_bytes_per_sec` caps how fast this connection's echo goes out, no cap when unset
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
+    peer: Peer,
+    bytes_echoed: &IntCounter,
+    max_bytes_per_sec: Option<u32>,
+) {
+    debug!("hello connection {:?}", peer);
     // read until stream closes send side
-    let mut buffer = Vec::new(); 
-    let result = stream.read_to_end(&mut buffer);
-    // println!("read {:?}", result);
+    let mut buffer = Vec::new();
+    let result = stream.read_to_end(&mut buffer).await;
+    debug!("read {:?}", result);
 
     // then write to stream
-    // println!("data {:?}", String::from_utf8(buffer.clone()));
-    let result = stream.write_all(&buffer);
-    // println!("result = {:?}", result);
-    let result = stream.flush();
-    // println!("result = {:?}", result);
+    let result = match max_bytes_per_sec {
+        Some(rate) => echo_limited(&mut stream, &buffer, rate).await,
+        None => stream.write_all(&buffer).await,
+    };
+    let echoed = match result {
+        Ok(()) => buffer.len(),
+        Err(e) => {
+            error!("Couldn't echo data back to {:?}: {:?}", peer, e);
+            0
+        }
+    };
+    bytes_echoed.inc_by(echoed as u64);
 
-    // send close signal to stream
-    // println!("Good bye");
-    let result = stream.shutdown(std::net::Shutdown::Both);
-    // println!("Result = {:?}", result);
+    // the client has already shut down its side, so only ours is left to close. Everything
+    // it sent was read above, so nothing unread is left to turn the close into a reset that
+    // could cut off the echo before the client reads it
+    finish_write(&mut stream).await;
+    info!(
+        "Good bye {:?}, read {} bytes, echoed {} bytes ({} total)",
+        peer,
+        buffer.len(),
+        echoed,
+        bytes_echoed.get()
+    );
 }
 
-fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8000")?;
+/// Writes `buffer` out in chunks, waiting on a bucket of `rate` bytes a second before each one.
+/// A chunk is at most a tenth of a second's worth, so the echo goes out steadily rather than
+/// in bursts, and a slow reader holds up the next chunk like it would any write.
+async fn echo_limited<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    buffer: &[u8],
+    rate: u32,
+) -> io::Result<()> {
+    let mut bucket = TokenBucket::new(rate as f64, (rate / 10).clamp(1, MAX_ECHO_CHUNK));
+    for chunk in buffer.chunks(bucket.burst() as usize) {
+        bucket.take_many(chunk.len() as u32).await;
+        stream.write_all(chunk).await?;
+    }
+    Ok(())
+}
 
-    // accept connections and process them serially
-    for stream in listener.incoming() {
-        handle_client(&mut stream?);
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener (or udp socket) too
+    dual_stack: bool,
+    // where to serve prometheus metrics from, off unless set
+    metrics_address: Option<String>,
+    // where to answer liveness probes once the echo server is ready, off unless set
+    health_address: Option<String>,
+    // bytes a second each connection's echo is held to, unlimited when unset
+    max_bytes_per_sec: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            metrics_address: None,
+            health_address: None,
+            max_bytes_per_sec: None,
+        }
     }
+}
+
+impl Config {
+    /// Start from the defaults, `BIND_ADDR` is where to listen (`[::]:8000` for ipv6) for tcp
+    /// and udp alike, or `unix:/path` for a unix socket when echoing over tcp,
+    /// `DUAL_STACK=true` lets an ipv6 one take ipv4 clients too,
+    /// `METRICS_ADDRESS` turns on `GET /metrics`, `HEALTH_ADDRESS` where tcp echoing answers
+    /// `OK` to probes and `MAX_BYTES_PER_SEC` caps how fast each connection is echoed back.
+    fn from_env() -> Config {
+        let mut config = Config {
+            metrics_address: env_var("METRICS_ADDRESS"),
+            health_address: env_var("HEALTH_ADDRESS"),
+            max_bytes_per_sec: env_var("MAX_BYTES_PER_SEC").filter(|rate| *rate > 0),
+            ..Config::default()
+        };
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        config
+    }
+}
+
+fn main() -> io::Result<()> {
+    let _guard = common::observability::init_tracing_from_env();
+    // TRANSPORT=udp echoes datagrams instead of tcp streams
+    if std::env::var("TRANSPORT").is_ok_and(|transport| transport.eq_ignore_ascii_case("udp")) {
+        let registry = Registry::new();
+        let bytes_echoed =
+            metrics::register_counter(&registry, "bytes_echoed_total", "Bytes echoed back");
+        let config = Config::from_env();
+        // served off to the side of the echo loop
+        if let Some(metrics_address) = config.metrics_address {
+            let metrics_listener = TcpListener::bind(metrics_address)?;
+            thread::spawn(move || metrics::serve_metrics_blocking(metrics_listener, registry));
+        }
+        let socket = bind_udp_blocking(&config.address, config.dual_stack)?;
+        serve_udp(&socket, &bytes_echoed);
+        return Ok(());
+    }
+    common::runtime::from_env().block_on(run());
     Ok(())
 }
+
+async fn run() {
+    let (ready_tx, _ready_rx) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_tx, shutdown).await;
+}
+
+/// Echoes every connection on `config.address`, each on its own task, until `shutdown` fires.
+/// `ready_tx` fires once the listener is up, so tests know when to connect.
+async fn serve(config: Config, ready_tx: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let server_config = ServerConfig {
+        address: config.address,
+        dual_stack: config.dual_stack,
+        metrics_address: config.metrics_address,
+        health_address: config.health_address,
+        ..ServerConfig::default()
+    };
+    let bytes_echoed = metrics::register_counter(
+        &server_config.registry,
+        "bytes_echoed_total",
+        "Bytes echoed back",
+    );
+    let max_bytes_per_sec = config.max_bytes_per_sec;
+    run_tcp_server(
+        &server_config,
+        ready_tx,
+        shutdown,
+        move |stream, peer, _shutdown_signal| {
+            let bytes_echoed = bytes_echoed.clone();
+            // an echo only answers once the client is done sending, there's nothing to cut
+            // short on shutdown
+            async move { handle_client(stream, peer, &bytes_echoed, max_bytes_per_sec).await }
+        },
+    )
+    .await;
+}
+
+// big enough for any udp payload
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Sends every datagram straight back to whoever sent it, one datagram per reply.
+fn serve_udp(socket: &UdpSocket, bytes_echoed: &IntCounter) {
+    info!("Echoing datagrams on {:?}", socket.local_addr());
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        if let Err(e) = echo_datagram(socket, &mut buffer, bytes_echoed) {
+            // nothing to tear down for udp, a bad datagram only affects itself
+            error!("Couldn't echo datagram: {:?}", e);
+        }
+    }
+}
+
+fn echo_datagram(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+    bytes_echoed: &IntCounter,
+) -> io::Result<()> {
+    let (read, sender) = socket.recv_from(buffer)?;
+    let sent = socket.send_to(&buffer[..read], sender)?;
+    bytes_echoed.inc_by(sent as u64);
+    debug!("echoed {} byte datagram to {:?}", sent, sender);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::testing::TestClient;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_bytes_echoed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind test listener");
+        let address = listener.local_addr().unwrap();
+        let bytes_echoed = IntCounter::new("bytes_echoed_total", "Bytes echoed back").unwrap();
+        let session_bytes_echoed = bytes_echoed.clone();
+        let session = tokio::spawn(async move {
+            let (stream, peer) = listener
+                .accept()
+                .await
+                .expect("Couldn't accept test client");
+            handle_client(stream, peer.into(), &session_bytes_echoed, None).await;
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send(b"hello echo").await;
+        client.shutdown_write().await;
+        assert_eq!(b"hello echo".to_vec(), client.read_to_end().await);
+        session.await.unwrap();
+        assert_eq!(10, bytes_echoed.get());
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind test listener");
+        let address = listener.local_addr().unwrap();
+        let bytes_echoed = IntCounter::new("bytes_echoed_total", "Bytes echoed back").unwrap();
+        let session_bytes_echoed = bytes_echoed.clone();
+        let session = tokio::spawn(async move {
+            let (stream, peer) = listener
+                .accept()
+                .await
+                .expect("Couldn't accept test client");
+            handle_client(stream, peer.into(), &session_bytes_echoed, None).await;
+        });
+        // more than fits in the socket buffers at once, so the echo goes out in many writes
+        let payload: Vec<u8> = (0..4 * 1024 * 1024).map(|byte| byte as u8).collect();
+
+        let mut client = TestClient::connect(address).await;
+        client.send(&payload).await;
+        // done sending, but still reading
+        client.shutdown_write().await;
+        // only returns once the server has closed its side, after the whole echo
+        let response = client.read_to_end().await;
+        session.await.unwrap();
+
+        assert_eq!(payload.len(), response.len());
+        assert!(payload == response);
+        assert_eq!(payload.len() as u64, bytes_echoed.get());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind test listener");
+        let address = listener.local_addr().unwrap();
+        let bytes_echoed = IntCounter::new("bytes_echoed_total", "Bytes echoed back").unwrap();
+        let session_bytes_echoed = bytes_echoed.clone();
+        let session = tokio::spawn(async move {
+            let (stream, peer) = listener
+                .accept()
+                .await
+                .expect("Couldn't accept test client");
+            handle_client(stream, peer.into(), &session_bytes_echoed, Some(100_000)).await;
+        });
+        let payload: Vec<u8> = (0..100_000).map(|byte| byte as u8).collect();
+
+        let mut client = TestClient::connect(address).await;
+        client.send(&payload).await;
+        client.shutdown_write().await;
+        let started = Instant::now();
+        let response = client.read_to_end().await;
+        let elapsed = started.elapsed();
+        session.await.unwrap();
+
+        assert!(payload == response);
+        assert_eq!(payload.len() as u64, bytes_echoed.get());
+        // the first tenth of a second's worth goes straight out, the rest at 100KB/s
+        assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let config = Config {
+            address: String::from("127.0.0.1:8020"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let shutdown = ShutdownToken::new();
+        let server_handle = tokio::spawn(serve(config, ready_tx, shutdown.clone()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // the spec wants at least 5 at once, each one still sending while the others connect
+        let mut clients = Vec::new();
+        for i in 0..5 {
+            let mut client = TestClient::connect("127.0.0.1:8020").await;
+            client.send(format!("client {} ", i).as_bytes()).await;
+            clients.push(client);
+        }
+        for (i, client) in clients.iter_mut().enumerate().rev() {
+            client.send(b"\x00\xff binary too").await;
+            client.shutdown_write().await;
+            assert_eq!(
+                [format!("client {} ", i).as_bytes(), b"\x00\xff binary too"].concat(),
+                client.read_to_end().await
+            );
+        }
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_serve_ipv6() {
+        let config = Config {
+            address: String::from("[::1]:8021"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let shutdown = ShutdownToken::new();
+        let server_handle = tokio::spawn(serve(config, ready_tx, shutdown.clone()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("[::1]:8021").await;
+        client.send(b"over ipv6").await;
+        client.shutdown_write().await;
+        assert_eq!(b"over ipv6".to_vec(), client.read_to_end().await);
+        // not dual stack unless asked
+        assert!(tokio::net::TcpStream::connect("127.0.0.1:8021")
+            .await
+            .is_err());
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[test]
+    fn test_udp_echo() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("Couldn't bind test socket");
+        let address = server.local_addr().unwrap();
+        let bytes_echoed = IntCounter::new("bytes_echoed_total", "Bytes echoed back").unwrap();
+
+        // two senders so replies have to find their way back to the right one
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let datagrams: Vec<(&UdpSocket, Vec<u8>)> = vec![
+            (&first, Vec::new()),
+            (&second, b"a".to_vec()),
+            (&first, vec![7; 512]),
+            (&second, (0..1400).map(|byte| byte as u8).collect()),
+            (&first, vec![42; 8000]),
+        ];
+
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let mut response = vec![0; MAX_DATAGRAM];
+        for (client, payload) in &datagrams {
+            client.send_to(payload, address).unwrap();
+            echo_datagram(&server, &mut buffer, &bytes_echoed).expect("Couldn't echo datagram");
+
+            let (read, from) = client.recv_from(&mut response).unwrap();
+            assert_eq!(address, from);
+            assert_eq!(payload[..], response[..read]);
+        }
+        let total: usize = datagrams.iter().map(|(_, payload)| payload.len()).sum();
+        assert_eq!(total as u64, bytes_echoed.get());
+    }
+}
@@ -0,0 +1,100 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prime_time::primality::{is_prime_trial, is_prime_u64, PrimeCache, Sieve};
+
+// every bucket checks this many numbers per iteration, so throughput reads as checks/sec
+const NUMBERS_PER_BUCKET: u64 = 1000;
+
+fn buckets() -> Vec<(&'static str, Vec<u64>)> {
+    let from = |start: u64| (start..start + NUMBERS_PER_BUCKET).collect::<Vec<u64>>();
+    let small = from(0);
+    let medium = from(1_000_000_000);
+    let large = from(1_000_000_000_000_000_000);
+    // what a batch request tends to look like, a bit of everything
+    let mixed = small
+        .iter()
+        .zip(&medium)
+        .zip(&large)
+        .flat_map(|((small, medium), large)| [*small, *medium, *large])
+        .take(NUMBERS_PER_BUCKET as usize)
+        .collect();
+    vec![
+        ("small", small),
+        ("medium", medium),
+        ("large", large),
+        ("mixed", mixed),
+    ]
+}
+
+fn bench_is_prime(c: &mut Criterion) {
+    let mut group = c.benchmark_group("is_prime");
+    group.throughput(Throughput::Elements(NUMBERS_PER_BUCKET));
+    let sieve = Sieve::new(1_000_000);
+    for (bucket, numbers) in buckets() {
+        group.bench_with_input(
+            BenchmarkId::new("miller_rabin", bucket),
+            &numbers,
+            |b, numbers| {
+                b.iter(|| {
+                    for number in numbers {
+                        black_box(is_prime_u64(black_box(*number)));
+                    }
+                })
+            },
+        );
+        // PRIME_ALGO=trial, sqrt(n) steps a number is hopeless for the large buckets
+        if numbers.iter().all(|number| *number < 1 << 40) {
+            group.bench_with_input(BenchmarkId::new("trial", bucket), &numbers, |b, numbers| {
+                b.iter(|| {
+                    for number in numbers {
+                        black_box(is_prime_trial(black_box(*number)));
+                    }
+                })
+            });
+        }
+        // after the first pass every lookup is a hit
+        let cache = PrimeCache::new(NUMBERS_PER_BUCKET as usize);
+        group.bench_with_input(
+            BenchmarkId::new("cached", bucket),
+            &numbers,
+            |b, numbers| {
+                b.iter(|| {
+                    for number in numbers {
+                        black_box(cache.is_prime(black_box(*number)));
+                    }
+                })
+            },
+        );
+        // only the buckets it covers
+        if numbers
+            .iter()
+            .all(|number| sieve.is_prime(*number).is_some())
+        {
+            group.bench_with_input(BenchmarkId::new("sieve", bucket), &numbers, |b, numbers| {
+                b.iter(|| {
+                    for number in numbers {
+                        black_box(sieve.is_prime(black_box(*number)));
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+// the biggest prime below u64::MAX, where trial division is at its worst. Each trial iteration
+// takes seconds, so this group keeps to criterion's smallest sample.
+fn bench_largest_prime(c: &mut Criterion) {
+    let number = 18446744073709551557;
+    let mut group = c.benchmark_group("largest_u64_prime");
+    group.sample_size(10);
+    group.bench_function("miller_rabin", |b| {
+        b.iter(|| assert!(is_prime_u64(black_box(number))))
+    });
+    group.bench_function("trial", |b| {
+        b.iter(|| assert!(is_prime_trial(black_box(number))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_is_prime, bench_largest_prime);
+criterion_main!(benches);
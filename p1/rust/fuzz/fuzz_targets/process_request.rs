@@ -0,0 +1,74 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prime_time::primality::PrimeCache;
+use prime_time::protocol::{process_request, Request, RequestError, Response};
+
+// Runs every line the way a connection would. Whatever comes in, the answer is either a
+// response to the method that was asked for or the malformed path, never a panic.
+fuzz_target!(|data: &[u8]| {
+    let cache = PrimeCache::new(16);
+    for line in data.split(|byte| *byte == b'\n') {
+        // the server only sees utf8 lines, LinesCodec rejects anything else
+        let Ok(line) = std::str::from_utf8(line) else {
+            continue;
+        };
+        let Ok(request) = serde_json::from_str::<Request>(line) else {
+            // malformed json, gets a MalformedResponse
+            continue;
+        };
+        match process_request(&request, &cache) {
+            Ok(Response::Single { method, .. }) => {
+                assert_eq!("isPrime", request.method);
+                assert_eq!("isPrime", method);
+                assert!(request.number.is_some());
+            }
+            Ok(Response::Batch { method, primes }) => {
+                assert_eq!("isPrimeBatch", request.method);
+                assert_eq!("isPrimeBatch", method);
+                assert_eq!(
+                    request
+                        .numbers
+                        .as_ref()
+                        .and_then(|numbers| numbers.as_array())
+                        .map(|numbers| numbers.len()),
+                    Some(primes.len())
+                );
+            }
+            Ok(Response::Range { method, primes, .. }) => {
+                assert_eq!("isPrimeRange", request.method);
+                assert_eq!("isPrimeRange", method);
+                assert!(primes.windows(2).all(|pair| pair[0] < pair[1]));
+            }
+            // unsupported method, missing number, something that isn't a number or bad range,
+            // also a MalformedResponse
+            Err(error) => {
+                let answerable = match request.method.as_str() {
+                    "isPrime" => request.number.is_some(),
+                    // as long as it's an array of numbers
+                    "isPrimeBatch" => {
+                        request
+                            .numbers
+                            .as_ref()
+                            .and_then(|numbers| numbers.as_array())
+                            .is_some()
+                            && !matches!(error, RequestError::NotANumber(_))
+                    }
+                    // bounds that are there can still be unusable
+                    "isPrimeRange" => {
+                        request.start.is_some()
+                            && request.end.is_some()
+                            && !matches!(
+                                error,
+                                RequestError::InvalidRange(_)
+                                    | RequestError::NotAnInteger(_)
+                                    | RequestError::NotANumber(_)
+                            )
+                    }
+                    _ => false,
+                };
+                assert!(!answerable, "{:?} should have been answered", line);
+            }
+        }
+    }
+});
@@ -0,0 +1,187 @@
+use crate::framing::Framing;
+use crate::protocol::Request;
+use serde::Serialize;
+use std::io;
+use std::str::FromStr;
+
+/// How requests and responses are serialized. Either way they're the same `Request` and
+/// `Response` underneath, only the bytes on the wire differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// What the spec asks for.
+    #[default]
+    Json,
+    /// MessagePack, with structs as maps keyed by field name like the json objects.
+    Msgpack,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> Result<Encoding, String> {
+        match encoding {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::Msgpack),
+            other => Err(format!("unknown encoding {:?}", other)),
+        }
+    }
+}
+
+impl Encoding {
+    /// A request, or why it couldn't be read as one. Either way a bad request is malformed.
+    pub fn decode(self, request: &[u8]) -> Result<Request, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(request).map_err(|e| e.to_string()),
+            Encoding::Msgpack => rmp_serde::from_slice(request).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// What verbose errors call a request that couldn't be decoded.
+    pub fn decode_error(self) -> &'static str {
+        match self {
+            Encoding::Json => "bad json",
+            Encoding::Msgpack => "bad msgpack",
+        }
+    }
+
+    pub fn encode(self, value: &impl Serialize) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            Encoding::Msgpack => rmp_serde::to_vec_named(value)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    /// The framing to use with `configured` asked for. Msgpack is binary and could have a
+    /// newline anywhere in it, so it's always length prefixed.
+    pub fn framing(self, configured: Framing) -> Framing {
+        match self {
+            Encoding::Json => configured,
+            Encoding::Msgpack => Framing::LengthPrefixed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::primality::PrimeCache;
+    use crate::protocol::{process_request, MalformedResponse, Response};
+    use serde_json::json;
+
+    // serde_json's own numbers keep their json text, which msgpack can't carry, so requests
+    // are built from plain numbers that serialize the same way in both
+    #[derive(Debug, Serialize)]
+    #[serde(untagged)]
+    enum Number {
+        Int(i64),
+        Big(u64),
+        Float(f64),
+    }
+
+    #[derive(Debug, Default, Serialize)]
+    struct TestRequest {
+        method: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        number: Option<Number>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        numbers: Option<Vec<Number>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        start: Option<Number>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        end: Option<Number>,
+    }
+
+    fn is_prime(number: Number) -> TestRequest {
+        TestRequest {
+            method: "isPrime",
+            number: Some(number),
+            ..TestRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(Encoding::Json), "json".parse());
+        assert_eq!(Ok(Encoding::Msgpack), "msgpack".parse());
+        assert!("cbor".parse::<Encoding>().is_err());
+    }
+
+    #[test]
+    fn test_msgpack_matches_json() {
+        let cache = PrimeCache::new(100);
+        for request in [
+            is_prime(Number::Int(7)),
+            is_prime(Number::Int(8)),
+            is_prime(Number::Int(-7)),
+            is_prime(Number::Float(7.5)),
+            is_prime(Number::Float(7.0)),
+            is_prime(Number::Big(18446744073709551557)),
+            TestRequest {
+                method: "isPrimeBatch",
+                numbers: Some(vec![Number::Int(2), Number::Int(4), Number::Int(97)]),
+                ..TestRequest::default()
+            },
+            TestRequest {
+                method: "isPrimeRange",
+                start: Some(Number::Int(10)),
+                end: Some(Number::Int(30)),
+                ..TestRequest::default()
+            },
+            TestRequest {
+                method: "isComposite",
+                number: Some(Number::Int(7)),
+                ..TestRequest::default()
+            },
+        ] {
+            let as_json = Encoding::Json.encode(&request).unwrap();
+            let as_msgpack = Encoding::Msgpack.encode(&request).unwrap();
+            let from_json = process_request(&Encoding::Json.decode(&as_json).unwrap(), &cache);
+            let from_msgpack =
+                process_request(&Encoding::Msgpack.decode(&as_msgpack).unwrap(), &cache);
+            assert_eq!(from_json, from_msgpack, "{:?}", request);
+
+            // and the responses say the same thing once they're decoded again
+            if let Ok(response) = from_json {
+                let json_response: serde_json::Value =
+                    serde_json::from_slice(&Encoding::Json.encode(&response).unwrap()).unwrap();
+                let msgpack_response: serde_json::Value =
+                    rmp_serde::from_slice(&Encoding::Msgpack.encode(&response).unwrap()).unwrap();
+                assert_eq!(json_response, msgpack_response, "{:?}", request);
+            }
+        }
+    }
+
+    #[test]
+    fn test_msgpack_malformed() {
+        // a map that's cut short, a bare integer and a request without a method
+        for malformed in [&b"\x82\xa6method"[..], b"\x07", b"\x81\xa6number\x07"] {
+            assert!(
+                Encoding::Msgpack.decode(malformed).is_err(),
+                "{:?}",
+                malformed
+            );
+        }
+        // an empty map, same as json's `{}`
+        assert_eq!(
+            vec![0x80],
+            Encoding::Msgpack.encode(&MalformedResponse {}).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_msgpack_echoed_number() {
+        let request = Encoding::Msgpack
+            .decode(&Encoding::Msgpack.encode(&is_prime(Number::Int(7))).unwrap())
+            .unwrap();
+        let response = Response::single(true).with_number(&request);
+        // a plain msgpack integer, not json's text for it
+        let decoded: serde_json::Value =
+            rmp_serde::from_slice(&Encoding::Msgpack.encode(&response).unwrap()).unwrap();
+        assert_eq!(
+            json!({"method": "isPrime", "prime": true, "number": 7}),
+            decoded
+        );
+    }
+}
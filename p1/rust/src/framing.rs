@@ -0,0 +1,274 @@
+use std::io;
+use std::str::FromStr;
+use tokio_util::bytes::{BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec, LinesCodec, LinesCodecError};
+
+/// How requests and responses are split up on the wire. What's inside is the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// One request per line, what the spec asks for.
+    #[default]
+    Newline,
+    /// A 4 byte big endian length, then that many bytes of request.
+    LengthPrefixed,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(framing: &str) -> Result<Framing, String> {
+        match framing {
+            "newline" => Ok(Framing::Newline),
+            "length_prefixed" => Ok(Framing::LengthPrefixed),
+            other => Err(format!("unknown framing {:?}", other)),
+        }
+    }
+}
+
+impl Framing {
+    /// `message` framed to be written straight to a client.
+    pub fn encode(self, message: &[u8]) -> Vec<u8> {
+        RequestCodec::new(self, usize::MAX).frame(message)
+    }
+}
+
+/// What ends each response when they're split up by newlines. Requests can end either way
+/// whichever this is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// Just `\n`, what the spec asks for.
+    #[default]
+    Lf,
+    /// `\r\n`, for clients that insist on it.
+    Crlf,
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(ending: &str) -> Result<LineEnding, String> {
+        match ending {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            other => Err(format!("unknown line ending {:?}", other)),
+        }
+    }
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Crlf => b"\r\n",
+        }
+    }
+}
+
+/// Turns a stream into requests and writes responses back, framed as `Framing` says. Requests
+/// come out as raw bytes for the `Encoding` to make sense of. Errors look the same whichever
+/// framing is in use, a request over `max_length` bytes is `MaxLineLengthExceeded` either way.
+#[derive(Debug)]
+pub enum RequestCodec {
+    Newline(LinesCodec, LineEnding),
+    LengthPrefixed(LengthDelimitedCodec),
+}
+
+impl RequestCodec {
+    pub fn new(framing: Framing, max_length: usize) -> RequestCodec {
+        match framing {
+            Framing::Newline => RequestCodec::Newline(
+                LinesCodec::new_with_max_length(max_length),
+                LineEnding::default(),
+            ),
+            Framing::LengthPrefixed => RequestCodec::LengthPrefixed(
+                LengthDelimitedCodec::builder()
+                    .length_field_length(4)
+                    .big_endian()
+                    .max_frame_length(max_length)
+                    .new_codec(),
+            ),
+        }
+    }
+
+    /// Ends responses with `ending` instead, if they're split up by newlines at all.
+    pub fn with_line_ending(self, ending: LineEnding) -> RequestCodec {
+        match self {
+            RequestCodec::Newline(codec, _) => RequestCodec::Newline(codec, ending),
+            length_prefixed => length_prefixed,
+        }
+    }
+
+    /// `message` framed to be written straight to a client.
+    pub fn frame(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        self.encode(message.to_vec(), &mut buffer)
+            .expect("Framing a message can't fail");
+        buffer.to_vec()
+    }
+}
+
+fn frame_to_request(
+    frame: Result<Option<BytesMut>, io::Error>,
+) -> Result<Option<Vec<u8>>, LinesCodecError> {
+    match frame {
+        Ok(frame) => Ok(frame.map(|frame| frame.to_vec())),
+        // the only bad data it reports is a frame that's too long
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            Err(LinesCodecError::MaxLineLengthExceeded)
+        }
+        Err(e) => Err(LinesCodecError::Io(e)),
+    }
+}
+
+impl Decoder for RequestCodec {
+    type Item = Vec<u8>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, LinesCodecError> {
+        match self {
+            RequestCodec::Newline(codec, _) => Ok(codec.decode(src)?.map(String::into_bytes)),
+            RequestCodec::LengthPrefixed(codec) => frame_to_request(codec.decode(src)),
+        }
+    }
+
+    /// What's left once the client has closed its side. A last line without a newline is still
+    /// a request, handed over like any other and only malformed if it isn't whole json. A
+    /// length prefixed frame that's cut short is an error, there's no telling where it ended.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, LinesCodecError> {
+        match self {
+            RequestCodec::Newline(codec, _) => Ok(codec.decode_eof(src)?.map(String::into_bytes)),
+            RequestCodec::LengthPrefixed(codec) => frame_to_request(codec.decode_eof(src)),
+        }
+    }
+}
+
+impl Encoder<Vec<u8>> for RequestCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, response: Vec<u8>, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        match self {
+            // what `LinesCodec` does, without needing the response to be a `String` first
+            RequestCodec::Newline(_, ending) => {
+                let ending = ending.as_bytes();
+                dst.reserve(response.len() + ending.len());
+                dst.put_slice(&response);
+                dst.put_slice(ending);
+                Ok(())
+            }
+            RequestCodec::LengthPrefixed(codec) => Ok(codec.encode(Bytes::from(response), dst)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut RequestCodec, bytes: &[u8]) -> Vec<Result<Vec<u8>, String>> {
+        let mut buffer = BytesMut::from(bytes);
+        let mut requests = Vec::new();
+        loop {
+            match codec.decode_eof(&mut buffer) {
+                Ok(Some(request)) => requests.push(Ok(request)),
+                Ok(None) => return requests,
+                Err(e) => {
+                    requests.push(Err(e.to_string()));
+                    return requests;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(Framing::Newline), "newline".parse());
+        assert_eq!(Ok(Framing::LengthPrefixed), "length_prefixed".parse());
+        assert!("lines".parse::<Framing>().is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(b"{}\n".to_vec(), Framing::Newline.encode(b"{}"));
+        assert_eq!(
+            b"\x00\x00\x00\x02{}".to_vec(),
+            Framing::LengthPrefixed.encode(b"{}")
+        );
+    }
+
+    #[test]
+    fn test_line_ending() {
+        assert_eq!(Ok(LineEnding::Lf), "lf".parse());
+        assert_eq!(Ok(LineEnding::Crlf), "crlf".parse());
+        assert!("cr".parse::<LineEnding>().is_err());
+
+        let mut codec = RequestCodec::new(Framing::Newline, 64).with_line_ending(LineEnding::Crlf);
+        assert_eq!(b"{}\r\n".to_vec(), codec.frame(b"{}"));
+        assert_eq!(
+            b"{}\n".to_vec(),
+            RequestCodec::new(Framing::Newline, 64).frame(b"{}")
+        );
+        // length prefixes have nothing to end
+        assert_eq!(
+            b"\x00\x00\x00\x02{}".to_vec(),
+            RequestCodec::new(Framing::LengthPrefixed, 64)
+                .with_line_ending(LineEnding::Crlf)
+                .frame(b"{}")
+        );
+
+        // requests can end either way, whichever way responses do
+        for ending in [LineEnding::Lf, LineEnding::Crlf] {
+            let mut codec = RequestCodec::new(Framing::Newline, 64).with_line_ending(ending);
+            assert_eq!(
+                vec![Ok(b"{}".to_vec()), Ok(b"[]".to_vec()), Ok(b"{}".to_vec())],
+                decode_all(&mut codec, b"{}\r\n[]\n{}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_unterminated_last_request() {
+        let mut codec = RequestCodec::new(Framing::Newline, 64);
+        assert_eq!(
+            vec![Ok(b"{}".to_vec()), Ok(b"[1]".to_vec())],
+            decode_all(&mut codec, b"{}\n[1]")
+        );
+        // only at the end, until then it might still be on its way
+        let mut buffer = BytesMut::from(&b"[1]"[..]);
+        assert_eq!(None, codec.decode(&mut buffer).unwrap());
+
+        let mut codec = RequestCodec::new(Framing::LengthPrefixed, 64);
+        let decoded = decode_all(&mut codec, b"\x00\x00\x00\x02{}\x00\x00\x00\x03[1");
+        assert_eq!(Ok(b"{}".to_vec()), decoded[0]);
+        assert!(decoded[1].is_err(), "{:?}", decoded);
+    }
+
+    #[test]
+    fn test_length_prefixed() {
+        let mut codec = RequestCodec::new(Framing::LengthPrefixed, 16);
+        // newlines inside a frame are just part of the request
+        assert_eq!(
+            vec![Ok(b"{}".to_vec()), Ok(b"[\n]".to_vec())],
+            decode_all(&mut codec, b"\x00\x00\x00\x02{}\x00\x00\x00\x03[\n]")
+        );
+
+        // nothing comes out until the whole frame's in
+        let mut buffer = BytesMut::from(&b"\x00\x00\x00\x02{"[..]);
+        assert_eq!(None, codec.decode(&mut buffer).unwrap());
+        buffer.extend_from_slice(b"}");
+        assert_eq!(Some(b"{}".to_vec()), codec.decode(&mut buffer).unwrap());
+
+        let mut codec = RequestCodec::new(Framing::LengthPrefixed, 16);
+        assert!(matches!(
+            codec.decode(&mut BytesMut::from(&b"\x00\x00\x00\x11"[..])),
+            Err(LinesCodecError::MaxLineLengthExceeded)
+        ));
+        // binary is fine, it's up to the encoding whether it makes sense
+        let mut codec = RequestCodec::new(Framing::LengthPrefixed, 16);
+        assert_eq!(
+            Some(vec![0xff]),
+            codec
+                .decode(&mut BytesMut::from(&b"\x00\x00\x00\x01\xff"[..]))
+                .unwrap()
+        );
+    }
+}
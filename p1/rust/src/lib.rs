@@ -0,0 +1,5 @@
+pub mod encoding;
+pub mod framing;
+pub mod primality;
+pub mod protocol;
+pub mod server;
@@ -0,0 +1,518 @@
+use num_bigint::BigInt;
+use num_traits::{One, Zero};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const MILLER_RABIN_BASES: [u32; 13] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41];
+
+// answers `self_check` knows for sure. 561, 1105 and 1729 fool a Fermat test, and from 2047 on
+// each composite is a strong pseudoprime to every base up to some prime, so a Miller-Rabin
+// missing bases calls one of them prime. The primes stay below 10^12 so checking them with
+// trial division is still quick
+const KNOWN_PRIMES: [u64; 12] = [
+    2,
+    3,
+    5,
+    7,
+    41,
+    43,
+    7919,
+    104729,
+    2147483647,
+    4294967291,
+    1000000007,
+    999999999989,
+];
+const KNOWN_COMPOSITES: [u64; 17] = [
+    0,
+    1,
+    4,
+    9,
+    561,
+    1105,
+    1729,
+    2047,
+    1373653,
+    25326001,
+    3215031751,
+    2152302898747,
+    3474749660383,
+    341550071728321,
+    3825123056546413051,
+    65521 * 65521,
+    1_000_003 * 1_000_033,
+];
+
+/// Checks `is_prime` against numbers with known answers, `Err` says which it got wrong.
+/// Takes a few milliseconds, even with trial division.
+pub fn self_check(is_prime: impl Fn(u64) -> bool) -> Result<(), String> {
+    for n in KNOWN_PRIMES {
+        if !is_prime(n) {
+            return Err(format!("{} is prime, but was said not to be", n));
+        }
+    }
+    for n in KNOWN_COMPOSITES {
+        if is_prime(n) {
+            return Err(format!("{} isn't prime, but was said to be", n));
+        }
+    }
+    Ok(())
+}
+
+/// Deterministic Miller-Rabin, the first 13 prime bases are more than enough for every u64.
+/// Trial division has to walk up to sqrt(n), which stalls the connection for numbers
+/// near u64::MAX.
+pub fn is_prime_u64(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for base in MILLER_RABIN_BASES {
+        let base = base as u64;
+        if n == base {
+            return true;
+        }
+        if n.is_multiple_of(base) {
+            return false;
+        }
+    }
+
+    // n - 1 = d * 2^s
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    'bases: for base in MILLER_RABIN_BASES {
+        let mut x = pow_mod(base as u64, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'bases;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+/// Checks every 6k ± 1 up to sqrt(n). Slow for big primes, but simple enough to trust as a
+/// reference for `is_prime_u64`.
+pub fn is_prime_trial(n: u64) -> bool {
+    if n < 4 {
+        return n >= 2;
+    }
+    if n.is_multiple_of(2) || n.is_multiple_of(3) {
+        return false;
+    }
+    let mut divisor = 5_u64;
+    // u128 so the square can't overflow for n near u64::MAX
+    while (divisor as u128) * (divisor as u128) <= n as u128 {
+        if n.is_multiple_of(divisor) || n.is_multiple_of(divisor + 2) {
+            return false;
+        }
+        divisor += 6;
+    }
+    true
+}
+
+/// Which primality test answers numbers that fit in a u64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrimeAlgo {
+    /// `is_prime_trial`
+    Trial,
+    /// `is_prime_u64`
+    #[default]
+    MillerRabin,
+}
+
+impl PrimeAlgo {
+    pub fn is_prime(self, n: u64) -> bool {
+        match self {
+            PrimeAlgo::Trial => is_prime_trial(n),
+            PrimeAlgo::MillerRabin => is_prime_u64(n),
+        }
+    }
+}
+
+impl FromStr for PrimeAlgo {
+    type Err = String;
+
+    fn from_str(algo: &str) -> Result<PrimeAlgo, String> {
+        match algo {
+            "trial" => Ok(PrimeAlgo::Trial),
+            "miller_rabin" => Ok(PrimeAlgo::MillerRabin),
+            other => Err(format!("unknown primality algorithm {:?}", other)),
+        }
+    }
+}
+
+fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 * b as u128) % modulus as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exponent: u64, modulus: u64) -> u64 {
+    let mut result = 1;
+    base %= modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base, modulus);
+        }
+        base = mul_mod(base, base, modulus);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Miller-Rabin using the first 13 primes as bases. This is deterministic below
+/// 3.3 * 10^24, past that a composite slipping through is vanishingly unlikely.
+pub fn is_prime_bigint(n: &BigInt) -> bool {
+    let one = BigInt::one();
+    let two = BigInt::from(2);
+    if n < &two {
+        return false;
+    }
+    for base in MILLER_RABIN_BASES {
+        let base = BigInt::from(base);
+        if n == &base {
+            return true;
+        }
+        if (n % &base).is_zero() {
+            return false;
+        }
+    }
+
+    // n - 1 = d * 2^s
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+    'bases: for base in MILLER_RABIN_BASES {
+        let mut x = BigInt::from(base).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                continue 'bases;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+/// Sieve of Eratosthenes up to `limit`, one bit per number, so small numbers are a lookup.
+#[derive(Debug)]
+pub struct Sieve {
+    primes: Vec<u64>,
+    limit: u32,
+}
+
+impl Sieve {
+    pub fn new(limit: u32) -> Sieve {
+        let len = limit as usize + 1;
+        // start with everything marked prime and cross off the rest
+        let mut sieve = Sieve {
+            primes: vec![u64::MAX; len.div_ceil(64)],
+            limit,
+        };
+        for n in 0..len.min(2) {
+            sieve.clear(n);
+        }
+        let mut n = 2;
+        while n * n < len {
+            if sieve.is_prime(n as u64) == Some(true) {
+                for multiple in (n * n..len).step_by(n) {
+                    sieve.clear(multiple);
+                }
+            }
+            n += 1;
+        }
+        sieve
+    }
+
+    fn clear(&mut self, number: usize) {
+        self.primes[number / 64] &= !(1 << (number % 64));
+    }
+
+    /// `None` past the limit, the caller has to work those out another way.
+    pub fn is_prime(&self, number: u64) -> Option<bool> {
+        if number > self.limit as u64 {
+            return None;
+        }
+        let number = number as usize;
+        Some(self.primes[number / 64] & (1 << (number % 64)) != 0)
+    }
+}
+
+/// Primality results shared by every connection. Once full, an arbitrary entry is
+/// evicted to make room for the newest result. Numbers covered by the sieve skip the
+/// cache entirely, everything else is worked out with `algo`.
+#[derive(Debug)]
+pub struct PrimeCache {
+    results: Mutex<HashMap<u64, bool>>,
+    capacity: usize,
+    hits: AtomicU64,
+    sieve: Option<Sieve>,
+    algo: PrimeAlgo,
+}
+
+impl PrimeCache {
+    pub fn new(capacity: usize) -> PrimeCache {
+        PrimeCache {
+            results: Mutex::new(HashMap::new()),
+            capacity,
+            hits: AtomicU64::new(0),
+            sieve: None,
+            algo: PrimeAlgo::default(),
+        }
+    }
+
+    /// Builds the sieve up front, which takes a moment for big limits.
+    pub fn with_sieve(capacity: usize, sieve_limit: u32) -> PrimeCache {
+        PrimeCache {
+            sieve: Some(Sieve::new(sieve_limit)),
+            ..PrimeCache::new(capacity)
+        }
+    }
+
+    /// Works out misses with `algo` instead of the default Miller-Rabin.
+    pub fn with_algo(self, algo: PrimeAlgo) -> PrimeCache {
+        PrimeCache { algo, ..self }
+    }
+
+    pub fn is_prime(&self, number: u64) -> bool {
+        if let Some(prime) = self.sieve.as_ref().and_then(|sieve| sieve.is_prime(number)) {
+            return prime;
+        }
+        if let Some(&prime) = self
+            .results
+            .lock()
+            .expect("Prime cache lock poisoned")
+            .get(&number)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return prime;
+        }
+
+        // don't hold the lock while doing the expensive part
+        let prime = self.algo.is_prime(number);
+        if self.capacity > 0 {
+            let mut results = self.results.lock().expect("Prime cache lock poisoned");
+            if results.len() >= self.capacity {
+                if let Some(&evicted) = results.keys().next() {
+                    results.remove(&evicted);
+                }
+            }
+            results.insert(number, prime);
+        }
+        prime
+    }
+
+    /// Every prime from `start` to `end` inclusive. Numbers past the sieve are worked out with
+    /// `algo` but not cached, a big range would push everything else out of the cache.
+    pub fn primes_in(&self, start: u64, end: u64) -> Vec<u64> {
+        (start..=end)
+            .filter(|number| self.compute(*number))
+            .collect()
+    }
+
+    /// `self_check` on the sieve and `algo` together, the way misses are worked out.
+    pub fn self_check(&self) -> Result<(), String> {
+        self_check(|number| self.compute(number))
+    }
+
+    // the answer from the sieve or `algo`, without the cache
+    fn compute(&self, number: u64) -> bool {
+        self.sieve
+            .as_ref()
+            .and_then(|sieve| sieve.is_prime(number))
+            .unwrap_or_else(|| self.algo.is_prime(number))
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_is_prime_bigint_small() {
+        for n in 0..1000_u64 {
+            assert_eq!(
+                primes::is_prime(n),
+                is_prime_bigint(&BigInt::from(n)),
+                "{}",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn test_prime_cache_capacity() {
+        let cache = PrimeCache::new(2);
+        for number in 0..10 {
+            cache.is_prime(number);
+        }
+        assert_eq!(2, cache.results.lock().unwrap().len());
+
+        // disabled cache never remembers anything
+        let cache = PrimeCache::new(0);
+        cache.is_prime(13);
+        cache.is_prime(13);
+        assert_eq!(0, cache.hits());
+    }
+
+    #[test]
+    fn test_sieve() {
+        let sieve = Sieve::new(1_000_000);
+        for n in 0..=1_000_000_u64 {
+            assert_eq!(Some(primes::is_prime(n)), sieve.is_prime(n), "{}", n);
+        }
+        assert_eq!(None, sieve.is_prime(1_000_001));
+
+        // limits that aren't a multiple of 64, right up to the edge
+        for limit in [0, 1, 2, 63, 64, 65, 97] {
+            let sieve = Sieve::new(limit);
+            for n in 0..=limit as u64 {
+                assert_eq!(Some(primes::is_prime(n)), sieve.is_prime(n), "{}", n);
+            }
+            assert_eq!(None, sieve.is_prime(limit as u64 + 1));
+        }
+    }
+
+    #[test]
+    fn test_prime_cache_with_sieve() {
+        let cache = PrimeCache::with_sieve(100, 1000);
+        // answered by the sieve, never cached
+        assert!(cache.is_prime(997));
+        assert!(cache.is_prime(997));
+        assert_eq!(0, cache.hits());
+        assert!(cache.results.lock().unwrap().is_empty());
+        // past the sieve falls back to the cache
+        assert!(cache.is_prime(7919));
+        assert!(cache.is_prime(7919));
+        assert_eq!(1, cache.hits());
+    }
+
+    #[test]
+    fn test_is_prime_u64() {
+        for n in 0..10_000_u64 {
+            assert_eq!(primes::is_prime(n), is_prime_u64(n), "{}", n);
+        }
+        // largest primes below 2^32 and 2^64
+        assert!(is_prime_u64(4294967291));
+        assert!(is_prime_u64(18446744073709551557));
+        // carmichael number and strong pseudoprimes to the smaller bases
+        assert!(!is_prime_u64(561));
+        assert!(!is_prime_u64(2047));
+        assert!(!is_prime_u64(3215031751));
+        assert!(!is_prime_u64(3825123056546413051));
+        assert!(!is_prime_u64(u64::MAX));
+    }
+
+    #[test]
+    fn test_algos_agree() {
+        for n in 0..100_000_u64 {
+            assert_eq!(is_prime_trial(n), is_prime_u64(n), "{}", n);
+        }
+        // primes, squares of primes and products of two primes, where the trial loop ends
+        for n in [
+            4294967291,
+            65521 * 65521,
+            65521 * 65537,
+            1_000_000_007,
+            1_000_003 * 1_000_033,
+            3215031751,
+            3825123056546413051,
+        ] {
+            assert_eq!(is_prime_trial(n), is_prime_u64(n), "{}", n);
+        }
+        assert_eq!(Ok(PrimeAlgo::Trial), "trial".parse());
+        assert_eq!(Ok(PrimeAlgo::MillerRabin), "miller_rabin".parse());
+        assert!("fermat".parse::<PrimeAlgo>().is_err());
+    }
+
+    #[test]
+    fn test_primes_in() {
+        let with_sieve = PrimeCache::with_sieve(100, 100);
+        let without = PrimeCache::new(100);
+        // across the end of the sieve
+        for cache in [&with_sieve, &without] {
+            assert_eq!(vec![89, 97, 101, 103, 107, 109], cache.primes_in(85, 110));
+            assert_eq!(vec![2], cache.primes_in(0, 2));
+            assert!(cache.primes_in(0, 1).is_empty());
+            assert_eq!(
+                vec![18446744073709551557],
+                cache.primes_in(u64::MAX - 60, u64::MAX)
+            );
+        }
+        assert!(without.results.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_self_check() {
+        assert_eq!(Ok(()), self_check(is_prime_u64));
+        assert_eq!(Ok(()), self_check(is_prime_trial));
+        for cache in [
+            PrimeCache::new(100),
+            PrimeCache::new(100).with_algo(PrimeAlgo::Trial),
+            PrimeCache::with_sieve(100, 1_000_000),
+        ] {
+            assert_eq!(Ok(()), cache.self_check());
+        }
+
+        // Miller-Rabin with too few bases
+        let base_2_only = |n: u64| {
+            if n < 3 || n.is_multiple_of(2) {
+                return n == 2;
+            }
+            let s = (n - 1).trailing_zeros();
+            let d = (n - 1) >> s;
+            let mut x = pow_mod(2, d, n);
+            if x == 1 || x == n - 1 {
+                return true;
+            }
+            for _ in 1..s {
+                x = mul_mod(x, x, n);
+                if x == n - 1 {
+                    return true;
+                }
+            }
+            false
+        };
+        assert_eq!(
+            Err(String::from("2047 isn't prime, but was said to be")),
+            self_check(base_2_only)
+        );
+        // a sieve that lost a prime
+        let mut cache = PrimeCache::with_sieve(100, 1_000_000);
+        cache.sieve.as_mut().unwrap().clear(7919);
+        assert_eq!(
+            Err(String::from("7919 is prime, but was said not to be")),
+            cache.self_check()
+        );
+    }
+
+    #[test]
+    fn test_prime_cache_with_algo() {
+        let cache = PrimeCache::new(100).with_algo(PrimeAlgo::Trial);
+        assert_eq!(PrimeAlgo::Trial, cache.algo);
+        assert!(cache.is_prime(7919));
+        assert!(!cache.is_prime(7917));
+    }
+
+    proptest! {
+        // trial division is too slow to cover all of u64 here, 2^40 keeps it to a million steps
+        #[test]
+        fn prop_algos_agree(n in 0..1_u64 << 40) {
+            prop_assert_eq!(is_prime_trial(n), is_prime_u64(n));
+        }
+    }
+}
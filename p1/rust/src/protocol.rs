@@ -0,0 +1,888 @@
+use crate::primality::{is_prime_bigint, PrimeCache};
+use num_bigint::{BigInt, Sign};
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::debug;
+
+/// Most numbers an `isPrimeRange` request can cover, unless the server is configured otherwise.
+pub const DEFAULT_MAX_RANGE: u64 = 10_000;
+
+/// Most digits a number asked about can have, unless the server is configured otherwise.
+pub const DEFAULT_MAX_DIGITS: usize = 1_000;
+
+// leave a comment here
+pub fn process_request(request: &Request, cache: &PrimeCache) -> Result<Response, RequestError> {
+    process_request_capped(request, cache, DEFAULT_MAX_RANGE, DEFAULT_MAX_DIGITS)
+}
+
+/// `process_request`, with `isPrimeRange` requests limited to `max_range` numbers and the
+/// numbers in `isPrime` and `isPrimeBatch` requests to `max_digits` digits.
+pub fn process_request_capped(
+    request: &Request,
+    cache: &PrimeCache,
+    max_range: u64,
+    max_digits: usize,
+) -> Result<Response, RequestError> {
+    match request.method.as_str() {
+        "isPrime" => {
+            let number = request.number.as_ref().ok_or(RequestError::MissingNumber)?;
+            check_digits(number, max_digits)?;
+            Ok(Response::single(is_prime_number(number, cache)))
+        }
+        "isPrimeBatch" => {
+            let numbers = batch_numbers(request.numbers.as_ref())?;
+            for number in &numbers {
+                check_digits(number, max_digits)?;
+            }
+            Ok(Response::batch(
+                numbers
+                    .into_iter()
+                    .map(|number| is_prime_number(number, cache))
+                    .collect(),
+            ))
+        }
+        "isPrimeRange" => {
+            let start = range_bound(request.start.as_ref())?;
+            let end = range_bound(request.end.as_ref())?;
+            if start > end {
+                return Err(RequestError::InvalidRange(format!("{} > {}", start, end)));
+            }
+            let len = end.abs_diff(start).saturating_add(1);
+            if len > max_range as u128 {
+                return Err(RequestError::InvalidRange(format!(
+                    "{} numbers, over the limit of {}",
+                    len, max_range
+                )));
+            }
+            // nothing below 2 is prime
+            let primes = match u64::try_from(start.max(0)) {
+                Ok(first) if end >= 0 => cache.primes_in(first, end as u64),
+                _ => Vec::new(),
+            };
+            Ok(Response::range(primes, len as usize))
+        }
+        method => Err(RequestError::UnsupportedMethod(method.to_string())),
+    }
+}
+
+/// The numbers in an `isPrimeBatch`, which have to be an array of json numbers. Only checked
+/// here, so other methods answer whatever ends up in a `numbers` field.
+fn batch_numbers(
+    numbers: Option<&serde_json::Value>,
+) -> Result<Vec<&serde_json::value::Number>, RequestError> {
+    let elements = match numbers {
+        Some(serde_json::Value::Array(elements)) => elements,
+        Some(serde_json::Value::Null) | None => return Err(RequestError::MissingNumber),
+        Some(other) => return Err(RequestError::NotANumber(other.to_string())),
+    };
+    elements
+        .iter()
+        .map(|element| match element {
+            serde_json::Value::Number(number) => Ok(number),
+            // one bad element and none of them get answered
+            other => Err(RequestError::NotANumber(other.to_string())),
+        })
+        .collect()
+}
+
+/// Turns down a number written with more than `max_digits` digits before it gets anywhere near
+/// `is_prime_bigint`, which gets slow long before a request line gets too long to read.
+fn check_digits(number: &serde_json::value::Number, max_digits: usize) -> Result<(), RequestError> {
+    let digits = number
+        .to_string()
+        .bytes()
+        .filter(u8::is_ascii_digit)
+        .count();
+    if digits > max_digits {
+        return Err(RequestError::TooManyDigits(digits));
+    }
+    Ok(())
+}
+
+/// Either end of an `isPrimeRange`, which has to be an integer. Going through i128 keeps
+/// negative starts and ends all the way up to u64::MAX. Like `numbers`, only checked for an
+/// `isPrimeRange`.
+fn range_bound(bound: Option<&serde_json::Value>) -> Result<i128, RequestError> {
+    let number = match bound {
+        Some(serde_json::Value::Number(number)) => number,
+        Some(serde_json::Value::Null) | None => return Err(RequestError::MissingNumber),
+        Some(other) => return Err(RequestError::NotANumber(other.to_string())),
+    };
+    let bound = number
+        .to_string()
+        .parse::<i128>()
+        .map_err(|_| RequestError::NotAnInteger(number.to_string()))?;
+    if bound > u64::MAX as i128 || bound < i64::MIN as i128 {
+        return Err(RequestError::InvalidRange(format!("{} is too big", number)));
+    }
+    Ok(bound)
+}
+
+/// What a json number is as far as primality goes, judged by the token the client wrote.
+#[derive(Debug, PartialEq)]
+pub enum NumberKind {
+    /// A whole number that fits a u64, `-0` included since it's just 0.
+    Small(u64),
+    /// A whole number too big for a u64.
+    Big(BigInt),
+    /// A whole number below zero.
+    Negative,
+    /// Written with a fraction or an exponent, like `7.0` or `1e3`, even if the value is whole.
+    Float,
+}
+
+/// serde_json is built with `arbitrary_precision`, so `number` still holds the token the
+/// client sent. Integers that don't fit in a u64 are parsed from that token as a BigInt
+/// rather than being rounded through an f64. Tokens json doesn't allow, like `07`, never get
+/// this far, the request they're in doesn't parse.
+pub fn classify(number: &serde_json::value::Number) -> NumberKind {
+    if let Some(number) = number.as_u64() {
+        return NumberKind::Small(number);
+    }
+    match number.to_string().parse::<BigInt>() {
+        Ok(big) if big.sign() == Sign::Minus => NumberKind::Negative,
+        // `-0` doesn't parse as a u64 but is one
+        Ok(big) => match u64::try_from(&big) {
+            Ok(small) => NumberKind::Small(small),
+            Err(_) => NumberKind::Big(big),
+        },
+        Err(_) => NumberKind::Float,
+    }
+}
+
+/// Numbers that are false without ever being checked are logged at debug, on the wire they
+/// look the same as any composite.
+pub fn is_prime_number(number: &serde_json::value::Number, cache: &PrimeCache) -> bool {
+    match classify(number) {
+        NumberKind::Small(small) => cache.is_prime(small),
+        NumberKind::Big(big) => is_prime_bigint(&big),
+        NumberKind::Negative => {
+            debug!("{} is negative, so it isn't prime", number);
+            false
+        }
+        NumberKind::Float => {
+            debug!(
+                "{} is a float, so it isn't prime even if it's a whole number",
+                number
+            );
+            false
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    pub method: String,
+    // isPrime
+    pub number: Option<serde_json::value::Number>,
+    // isPrimeBatch, left as it came until an isPrimeBatch needs it
+    pub numbers: Option<serde_json::Value>,
+    // isPrimeRange, both inclusive and left as they came too
+    pub start: Option<serde_json::Value>,
+    pub end: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    Single {
+        method: String,
+        prime: bool,
+        // the number that was asked about, only there when the server echoes numbers back
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "serialize_number"
+        )]
+        number: Option<serde_json::value::Number>,
+    },
+    Batch {
+        method: String,
+        primes: Vec<bool>,
+    },
+    Range {
+        method: String,
+        primes: Vec<u64>,
+        // every number in the range was checked, not just the primes sent back
+        #[serde(skip)]
+        checked: usize,
+    },
+}
+
+/// Json gets the number just as the client wrote it. Binary formats can't carry json's text
+/// for it, and only ever send numbers that fit a u64, i64 or f64 to begin with.
+fn serialize_number<S: Serializer>(
+    number: &Option<serde_json::value::Number>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match number {
+        Some(number) if !serializer.is_human_readable() => {
+            if let Some(number) = number.as_u64() {
+                serializer.serialize_u64(number)
+            } else if let Some(number) = number.as_i64() {
+                serializer.serialize_i64(number)
+            } else {
+                serializer.serialize_f64(number.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        number => number.serialize(serializer),
+    }
+}
+
+impl Response {
+    pub fn single(prime: bool) -> Response {
+        Response::Single {
+            method: String::from("isPrime"),
+            prime,
+            number: None,
+        }
+    }
+
+    /// Answers in the same order as the request's `numbers`
+    pub fn batch(primes: Vec<bool>) -> Response {
+        Response::Batch {
+            method: String::from("isPrimeBatch"),
+            primes,
+        }
+    }
+
+    /// The primes in a range of `checked` numbers, in ascending order
+    pub fn range(primes: Vec<u64>, checked: usize) -> Response {
+        Response::Range {
+            method: String::from("isPrimeRange"),
+            primes,
+            checked,
+        }
+    }
+
+    /// Adds the number `request` asked about to an `isPrime` response, written just as the
+    /// client sent it, so pipelining clients can match answers up without relying on order.
+    /// Other responses are left as they are.
+    pub fn with_number(self, request: &Request) -> Response {
+        match self {
+            Response::Single { method, prime, .. } => Response::Single {
+                method,
+                prime,
+                number: request.number.clone(),
+            },
+            other => other,
+        }
+    }
+
+    /// How many numbers went into this response
+    pub fn checked(&self) -> usize {
+        match self {
+            Response::Single { .. } => 1,
+            Response::Batch { primes, .. } => primes.len(),
+            Response::Range { checked, .. } => *checked,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MalformedResponse {}
+
+/// Why a well-formed JSON request still couldn't be answered. Over the wire every
+/// variant gets the same `MalformedResponse`, unless the server sends verbose errors.
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    UnsupportedMethod(String),
+    // the field holding the number(s) for the method is missing or null
+    MissingNumber,
+    // something where the method needs a number that isn't one, as the client wrote it
+    NotANumber(String),
+    // an isPrimeRange bound that isn't a whole number, as the client wrote it
+    NotAnInteger(String),
+    // an isPrimeRange that isn't in order, or covers too many numbers
+    InvalidRange(String),
+    // a number with more digits than the server checks, and how many it had
+    TooManyDigits(usize),
+}
+
+impl RequestError {
+    /// A fixed description of the kind of error, without the details, for verbose errors.
+    pub fn summary(&self) -> &'static str {
+        match self {
+            RequestError::UnsupportedMethod(_) => "unknown method",
+            RequestError::MissingNumber => "missing number",
+            RequestError::NotANumber(_) => "not a number",
+            RequestError::NotAnInteger(_) => "number not an integer",
+            RequestError::InvalidRange(_) => "invalid range",
+            RequestError::TooManyDigits(_) => "number too long",
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::UnsupportedMethod(method) => {
+                write!(f, "unsupported method {:?}", method)
+            }
+            RequestError::MissingNumber => write!(f, "missing number"),
+            RequestError::NotANumber(value) => write!(f, "{} isn't a number", value),
+            RequestError::NotAnInteger(number) => write!(f, "{} isn't an integer", number),
+            RequestError::InvalidRange(reason) => write!(f, "invalid range, {}", reason),
+            RequestError::TooManyDigits(digits) => write!(f, "number has {} digits", digits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use common::testing::capture_logs;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_not_checked_logged() {
+        let (logs, _guard) = capture_logs(tracing::Level::DEBUG);
+
+        let cache = PrimeCache::new(100);
+        for number in ["7.0", "-5", "7", "8"] {
+            let request: Request =
+                serde_json::from_str(&format!(r#"{{"method":"isPrime","number":{}}}"#, number))
+                    .unwrap();
+            process_request(&request, &cache).unwrap();
+        }
+
+        let logs = logs.contents();
+        let lines: Vec<&str> = logs.lines().collect();
+        // only the two that were turned down without a check, 7 and 8 are nothing unusual
+        assert_eq!(2, lines.len(), "{}", logs);
+        assert!(lines[0].ends_with("7.0 is a float, so it isn't prime even if it's a whole number"));
+        assert!(lines[1].ends_with("-5 is negative, so it isn't prime"));
+    }
+
+    #[test]
+    fn test_process_request_happy() {
+        let cache = PrimeCache::new(100);
+        let request = Request {
+            method: "isPrime".into(),
+            number: Some(serde_json::value::Number::from(10)),
+            numbers: None,
+            start: None,
+            end: None,
+        };
+        let result = process_request(&request, &cache);
+        assert!(result.is_ok());
+        assert_eq!(Response::single(false), result.unwrap());
+
+        let request = Request {
+            method: "isPrime".into(),
+            number: Some(serde_json::value::Number::from(13)),
+            numbers: None,
+            start: None,
+            end: None,
+        };
+        let result = process_request(&request, &cache);
+        assert!(result.is_ok());
+        assert_eq!(Response::single(true), result.unwrap());
+
+        let request = Request {
+            method: "isPrime".into(),
+            number: Some(serde_json::value::Number::from(-13)),
+            numbers: None,
+            start: None,
+            end: None,
+        };
+        let result = process_request(&request, &cache);
+        assert!(result.is_ok());
+        assert_eq!(Response::single(false), result.unwrap());
+
+        let request = Request {
+            method: "isPrime".into(),
+            number: Some(
+                serde_json::value::Number::from_f64(13.0).expect("Could not create f64 for number"),
+            ),
+            numbers: None,
+            start: None,
+            end: None,
+        };
+        let result = process_request(&request, &cache);
+        assert!(result.is_ok());
+        assert_eq!(Response::single(false), result.unwrap());
+    }
+
+    #[test]
+    fn test_process_request_big_integers() {
+        let cache = PrimeCache::new(100);
+        // 25 digit prime, too big for as_u64 but it still has to come back prime
+        let request: Request =
+            serde_json::from_str("{\"method\":\"isPrime\",\"number\":1000000000000000000000007}")
+                .expect("Could not deserialize str");
+        assert!(request.number.as_ref().unwrap().as_u64().is_none());
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+
+        // the first prime past u64::MAX
+        let request: Request =
+            serde_json::from_str("{\"method\":\"isPrime\",\"number\":18446744073709551629}")
+                .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+
+        // 40 digit prime
+        let request: Request = serde_json::from_str(
+            "{\"method\":\"isPrime\",\"number\":1000000000000000000000000000000000000003}",
+        )
+        .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+
+        // 40 digit composite, 10000000000000000051 * 100000000000000000039
+        let request: Request = serde_json::from_str(
+            "{\"method\":\"isPrime\",\"number\":1000000000000000005490000000000000001989}",
+        )
+        .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(false)),
+            process_request(&request, &cache)
+        );
+
+        // negative
+        let request: Request = serde_json::from_str(
+            "{\"method\":\"isPrime\",\"number\":-1000000000000000000000000000000000000003}",
+        )
+        .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(false)),
+            process_request(&request, &cache)
+        );
+
+        // not an integer
+        let request: Request = serde_json::from_str(
+            "{\"method\":\"isPrime\",\"number\":1000000000000000000000000000000000000003.0}",
+        )
+        .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(false)),
+            process_request(&request, &cache)
+        );
+    }
+
+    #[test]
+    fn test_process_request_too_many_digits() {
+        let cache = PrimeCache::new(100);
+        let capped = |request: &str| {
+            let request: Request =
+                serde_json::from_str(request).expect("Could not deserialize str");
+            process_request_capped(&request, &cache, DEFAULT_MAX_RANGE, 25)
+        };
+
+        // 25 digits is right at the cap and still gets checked
+        assert_eq!(
+            Ok(Response::single(true)),
+            capped("{\"method\":\"isPrime\",\"number\":1000000000000000000000007}")
+        );
+        // 40 digits is over it, however cheap it would have been
+        assert_eq!(
+            Err(RequestError::TooManyDigits(40)),
+            capped("{\"method\":\"isPrime\",\"number\":1000000000000000000000000000000000000003}")
+        );
+        // one number over the cap and none of the batch gets answered
+        assert_eq!(
+            Err(RequestError::TooManyDigits(40)),
+            capped(
+                "{\"method\":\"isPrimeBatch\",\"numbers\":[7,1000000000000000000000000000000000000003]}"
+            )
+        );
+
+        // the default is plenty for the usual big numbers, and not for a huge one
+        let huge = format!(
+            "{{\"method\":\"isPrime\",\"number\":1{}}}",
+            "0".repeat(5000)
+        );
+        let request: Request = serde_json::from_str(&huge).expect("Could not deserialize str");
+        assert_eq!(
+            Err(RequestError::TooManyDigits(5001)),
+            process_request(&request, &cache)
+        );
+    }
+
+    #[test]
+    fn test_process_request_malformed() {
+        let cache = PrimeCache::new(100);
+        let request = Request {
+            method: "invalidMethod".into(),
+            number: Some(serde_json::value::Number::from(10)),
+            numbers: None,
+            start: None,
+            end: None,
+        };
+        let result = process_request(&request, &cache);
+        assert_eq!(
+            Err(RequestError::UnsupportedMethod("invalidMethod".into())),
+            result
+        );
+    }
+
+    #[test]
+    fn test_process_request_missing_number() {
+        let cache = PrimeCache::new(100);
+        let request: Request =
+            serde_json::from_str("{\"method\":\"isPrime\"}").expect("Could not deserialize str");
+        assert_eq!(
+            Err(RequestError::MissingNumber),
+            process_request(&request, &cache)
+        );
+
+        let request: Request = serde_json::from_str("{\"method\":\"isPrimeBatch\",\"number\":7}")
+            .expect("Could not deserialize str");
+        assert_eq!(
+            Err(RequestError::MissingNumber),
+            process_request(&request, &cache)
+        );
+    }
+
+    #[test]
+    fn test_process_request_batch() {
+        let cache = PrimeCache::new(100);
+        let request: Request = serde_json::from_str(
+            "{\"method\":\"isPrimeBatch\",\"numbers\":[2,10,-7,7.0,13,1000000000000000000000000000000000000003]}",
+        )
+        .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::batch(vec![true, false, false, false, true, true])),
+            process_request(&request, &cache)
+        );
+
+        let request: Request = serde_json::from_str("{\"method\":\"isPrimeBatch\",\"numbers\":[]}")
+            .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::batch(vec![])),
+            process_request(&request, &cache)
+        );
+        assert_eq!(
+            "{\"method\":\"isPrimeBatch\",\"primes\":[]}",
+            serde_json::to_string(&Response::batch(vec![])).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_process_request_range() {
+        let cache = PrimeCache::with_sieve(100, 1000);
+        let range = |start: &str, end: &str, max_range| {
+            let request: Request = serde_json::from_str(&format!(
+                "{{\"method\":\"isPrimeRange\",\"start\":{},\"end\":{}}}",
+                start, end
+            ))
+            .expect("Could not deserialize str");
+            process_request_capped(&request, &cache, max_range, DEFAULT_MAX_DIGITS)
+        };
+
+        let response = range("10", "30", 100).unwrap();
+        assert_eq!(Response::range(vec![11, 13, 17, 19, 23, 29], 21), response);
+        assert_eq!(21, response.checked());
+        assert_eq!(
+            "{\"method\":\"isPrimeRange\",\"primes\":[11,13,17,19,23,29]}",
+            serde_json::to_string(&response).unwrap()
+        );
+        // a single number, and ranges with nothing or only negatives in them
+        assert_eq!(Ok(Response::range(vec![7], 1)), range("7", "7", 100));
+        assert_eq!(Ok(Response::range(vec![], 4)), range("24", "27", 100));
+        assert_eq!(Ok(Response::range(vec![], 11)), range("-20", "-10", 100));
+        assert_eq!(Ok(Response::range(vec![2, 3], 9)), range("-5", "3", 100));
+        // right up against u64::MAX, past the sieve
+        assert_eq!(
+            Ok(Response::range(vec![18446744073709551557], 59)),
+            range("18446744073709551557", "18446744073709551615", 100)
+        );
+
+        // inverted
+        assert!(matches!(
+            range("30", "10", 100),
+            Err(RequestError::InvalidRange(_))
+        ));
+        // over the cap, by one
+        assert_eq!(
+            Ok(Response::range(vec![2, 3, 5, 7], 10)),
+            range("1", "10", 10)
+        );
+        assert!(matches!(
+            range("0", "10", 10),
+            Err(RequestError::InvalidRange(_))
+        ));
+        assert!(matches!(
+            range("-9223372036854775808", "18446744073709551615", u64::MAX),
+            Err(RequestError::InvalidRange(_))
+        ));
+        // not integers
+        assert_eq!(
+            Err(RequestError::NotAnInteger(String::from("1.5"))),
+            range("1.5", "10", 100)
+        );
+        assert!(matches!(
+            range("1", "1e3", 100),
+            Err(RequestError::NotAnInteger(_))
+        ));
+        // too big for a u64
+        assert!(matches!(
+            range("1", "18446744073709551616", 100),
+            Err(RequestError::InvalidRange(_))
+        ));
+
+        let request: Request =
+            serde_json::from_str("{\"method\":\"isPrimeRange\",\"start\":1}").unwrap();
+        assert_eq!(
+            Err(RequestError::MissingNumber),
+            process_request(&request, &cache)
+        );
+        assert_eq!(
+            Err(RequestError::NotANumber(String::from("\"a\""))),
+            range("\"a\"", "10", 100)
+        );
+    }
+
+    #[test]
+    fn test_is_prime_ignores_range() {
+        let cache = PrimeCache::new(100);
+        let request: Request = serde_json::from_str(
+            "{\"method\":\"isPrime\",\"number\":7,\"start\":\"a\",\"end\":[]}",
+        )
+        .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let cache = PrimeCache::new(100);
+        for (token, kind, prime) in [
+            ("7", NumberKind::Small(7), true),
+            ("0", NumberKind::Small(0), false),
+            ("-0", NumberKind::Small(0), false),
+            (
+                "18446744073709551557",
+                NumberKind::Small(18446744073709551557),
+                true,
+            ),
+            (
+                "18446744073709551629",
+                NumberKind::Big("18446744073709551629".parse().unwrap()),
+                true,
+            ),
+            (
+                "18446744073709551616",
+                NumberKind::Big("18446744073709551616".parse().unwrap()),
+                false,
+            ),
+            ("-7", NumberKind::Negative, false),
+            ("-18446744073709551629", NumberKind::Negative, false),
+            // whole numbers, but not written as integers
+            ("7.0", NumberKind::Float, false),
+            ("-0.0", NumberKind::Float, false),
+            ("1e3", NumberKind::Float, false),
+            ("7E0", NumberKind::Float, false),
+            ("7e-0", NumberKind::Float, false),
+            ("7.5", NumberKind::Float, false),
+        ] {
+            let request: Request =
+                serde_json::from_str(&format!(r#"{{"method":"isPrime","number":{}}}"#, token))
+                    .unwrap();
+            let number = request.number.as_ref().unwrap();
+            assert_eq!(kind, classify(number), "{}", token);
+            assert_eq!(prime, is_prime_number(number, &cache), "{}", token);
+        }
+
+        // leading zeros aren't json, so the whole request is malformed
+        for token in ["07", "-07", "00", "007.0"] {
+            let request = format!(r#"{{"method":"isPrime","number":{}}}"#, token);
+            assert!(
+                serde_json::from_str::<Request>(&request).is_err(),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn test_serde_batch_malformed_element() {
+        let cache = PrimeCache::new(100);
+        // one bad element and the whole batch goes unanswered
+        let request: Request =
+            serde_json::from_str("{\"method\":\"isPrimeBatch\",\"numbers\":[2,\"3\"]}")
+                .expect("Could not deserialize str");
+        assert_eq!(
+            Err(RequestError::NotANumber(String::from("\"3\""))),
+            process_request(&request, &cache)
+        );
+
+        let request: Request = serde_json::from_str("{\"method\":\"isPrimeBatch\",\"numbers\":7}")
+            .expect("Could not deserialize str");
+        assert_eq!(
+            Err(RequestError::NotANumber(String::from("7"))),
+            process_request(&request, &cache)
+        );
+    }
+
+    #[test]
+    fn test_is_prime_ignores_numbers() {
+        let cache = PrimeCache::new(100);
+        // numbers is only isPrimeBatch's, whatever's in it an isPrime still gets answered
+        let request: Request =
+            serde_json::from_str("{\"method\":\"isPrime\",\"number\":7,\"numbers\":\"x\"}")
+                .expect("Could not deserialize str");
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+    }
+
+    #[test]
+    fn test_prime_cache() {
+        let cache = PrimeCache::new(100);
+        let request = Request {
+            method: "isPrime".into(),
+            number: Some(serde_json::value::Number::from(7919)),
+            numbers: None,
+            start: None,
+            end: None,
+        };
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+        assert_eq!(0, cache.hits());
+
+        // second lookup is served from the cache
+        assert_eq!(
+            Ok(Response::single(true)),
+            process_request(&request, &cache)
+        );
+        assert_eq!(1, cache.hits());
+    }
+
+    // every shape of json number a client could send, as the raw token
+    fn number_token() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<u64>().prop_map(|number| number.to_string()),
+            any::<i64>().prop_map(|number| number.to_string()),
+            // debug formatting always keeps the fraction or exponent, so these stay non-integers
+            any::<f64>()
+                .prop_filter("json has no inf or nan", |number| number.is_finite())
+                .prop_map(|number| format!("{:?}", number)),
+            "-?[1-9][0-9]{20,60}",
+            "-?(0|[1-9][0-9]{0,4})\\.[0-9]{1,5}",
+        ]
+    }
+
+    fn is_prime_request(token: &str) -> Request {
+        Request {
+            method: "isPrime".into(),
+            number: Some(token.parse().expect("Generated an invalid json number")),
+            numbers: None,
+            start: None,
+            end: None,
+        }
+    }
+
+    #[test]
+    fn test_with_number() {
+        let cache = PrimeCache::new(100);
+        // spec compliant unless asked for
+        let response = process_request(&is_prime_request("7"), &cache).unwrap();
+        assert_eq!(
+            "{\"method\":\"isPrime\",\"prime\":true}",
+            serde_json::to_string(&response).unwrap()
+        );
+        for (token, response) in [
+            ("7", "{\"method\":\"isPrime\",\"prime\":true,\"number\":7}"),
+            ("8.0", "{\"method\":\"isPrime\",\"prime\":false,\"number\":8.0}"),
+            (
+                "170141183460469231731687303715884105727",
+                "{\"method\":\"isPrime\",\"prime\":true,\"number\":170141183460469231731687303715884105727}",
+            ),
+        ] {
+            let request = is_prime_request(token);
+            let echoed = process_request(&request, &cache)
+                .unwrap()
+                .with_number(&request);
+            assert_eq!(response, serde_json::to_string(&echoed).unwrap());
+        }
+        // only isPrime has a single number to echo
+        let batch = Response::batch(vec![true]);
+        assert_eq!(
+            batch,
+            Response::batch(vec![true]).with_number(&is_prime_request("7"))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_process_request_is_well_behaved(token in number_token()) {
+            let cache = PrimeCache::new(100);
+            let response = process_request(&is_prime_request(&token), &cache);
+            let prime = match response {
+                Ok(Response::Single { method, prime, .. }) => {
+                    prop_assert_eq!("isPrime", method);
+                    prime
+                }
+                other => panic!("Unexpected response {:?} for {}", other, token),
+            };
+            let positive_integer = !token.starts_with('0')
+                && token.chars().all(|c| c.is_ascii_digit());
+            if !positive_integer {
+                prop_assert!(!prime, "{} can't be prime", token);
+            }
+        }
+
+        // kept small enough that the trial division in primes stays quick
+        #[test]
+        fn prop_process_request_matches_primes(number in 0..u32::MAX as u64) {
+            let cache = PrimeCache::new(100);
+            let response = process_request(&is_prime_request(&number.to_string()), &cache);
+            prop_assert_eq!(Ok(Response::single(primes::is_prime(number))), response);
+        }
+    }
+
+    #[test]
+    fn test_primes() {
+        assert!(primes::is_prime(13));
+    }
+
+    #[test]
+    fn test_serde_positive_whole_number() {
+        let request_str = "{\"method\":\"isPrime\",\"number\":10}";
+        let request_deserialized: Request =
+            serde_json::from_str(request_str).expect("Could not deserialize str");
+        // can be both unsigned and signed
+        assert!(request_deserialized.number.as_ref().unwrap().is_u64());
+        assert!(request_deserialized.number.as_ref().unwrap().is_i64());
+    }
+
+    #[test]
+    fn test_serde_negative_whole_number() {
+        let request_str = "{\"method\":\"isPrime\",\"number\":-10}";
+        let request_deserialized: Request =
+            serde_json::from_str(request_str).expect("Could not deserialize str");
+        // has to be signed
+        assert!(request_deserialized.number.as_ref().unwrap().is_i64());
+        // can't be unsigned
+        assert!(!request_deserialized.number.as_ref().unwrap().is_u64());
+    }
+
+    #[test]
+    fn test_serde_positive_float_number() {
+        let request_str = "{\"method\":\"isPrime\",\"number\":10.0}";
+        let request_deserialized: Request =
+            serde_json::from_str(request_str).expect("Could not deserialize str");
+        assert!(request_deserialized.number.as_ref().unwrap().is_f64());
+    }
+
+    #[test]
+    fn test_serde_negative_float_number() {
+        let request_str = "{\"method\":\"isPrime\",\"number\":-10.0}";
+        let request_deserialized: Request =
+            serde_json::from_str(request_str).expect("Could not deserialize str");
+        assert!(request_deserialized.number.as_ref().unwrap().is_f64());
+    }
+}
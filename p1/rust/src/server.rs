@@ -0,0 +1,1673 @@
+use crate::encoding::Encoding;
+use crate::framing::{Framing, LineEnding, RequestCodec};
+use crate::primality::{PrimeAlgo, PrimeCache};
+use crate::protocol::{
+    process_request_capped, MalformedResponse, Request, DEFAULT_MAX_DIGITS, DEFAULT_MAX_RANGE,
+};
+use common::env::env_var;
+use common::metrics::{self, IntCounter, Registry};
+use common::{
+    finish_write, run_tcp_server, Connections, Peer, ServerConfig, ShutdownSignal, ShutdownToken,
+    TokenBucket, WhenFull,
+};
+use futures::{Sink, SinkExt, StreamExt};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync;
+use tokio::time;
+use tokio_util::codec::{Framed, LinesCodecError};
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+// sent to clients turned away with `reject_when_busy`, or over their rate limit with
+// `RateLimitPolicy::Close`, encoded and framed like any other response
+const BUSY_RESPONSE: ErrorResponse = ErrorResponse {
+    error: "server busy",
+};
+
+// says what was wrong, in place of a `MalformedResponse` with `verbose_errors`
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: &'static str,
+}
+
+/// What happens to a connection that sends requests faster than `rate_limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitPolicy {
+    /// Hold its next request back until it's allowed, which stops reading from it meanwhile.
+    #[default]
+    Delay,
+    /// Tell it the server is busy and close it.
+    Close,
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<RateLimitPolicy, String> {
+        match policy {
+            "delay" => Ok(RateLimitPolicy::Delay),
+            "close" => Ok(RateLimitPolicy::Close),
+            other => Err(format!("unknown rate limit policy {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    pub dual_stack: bool,
+    // connections handled at once before the server stops accepting
+    pub max_connections: usize,
+    // connections the OS holds waiting to be accepted
+    pub backlog: u32,
+    // tasks accepting connections side by side
+    pub acceptors: usize,
+    // past `max_connections`, tell new clients the server is busy and close them rather than
+    // leaving them waiting for a slot
+    pub reject_when_busy: bool,
+    // connections a single IP address can have open at once, unlimited unless set
+    pub max_connections_per_ip: Option<usize>,
+    // how long to wait for the next complete line before dropping the client, a line that's
+    // still trickling in counts against it too
+    pub read_timeout: Duration,
+    // how requests and responses are split up, newlines unless a client wants length prefixes
+    pub framing: Framing,
+    // how requests and responses are serialized, msgpack is always length prefixed
+    pub encoding: Encoding,
+    // what ends each response with newline framing, requests can end with either
+    pub line_ending: LineEnding,
+    // longest request line, or length prefixed request, we'll buffer before giving up on the
+    // client
+    pub max_line_length: usize,
+    // longest line that's handed to the json parser, anything bigger is malformed without
+    // being looked at. Nesting makes some valid lines slow to parse, not just big
+    pub max_request_size: usize,
+    // max number of results kept in the shared prime cache, 0 turns it off
+    pub prime_cache_size: usize,
+    // numbers up to this are looked up in a sieve built at startup, 0 turns it off
+    pub sieve_limit: u32,
+    // how numbers past the sieve are checked, both give the same answers
+    pub prime_algo: PrimeAlgo,
+    // most numbers one isPrimeRange request can cover, bigger ranges are malformed
+    pub max_prime_range: u64,
+    // most digits a number asked about can have, longer ones are malformed rather than checked
+    pub max_number_digits: usize,
+    // send the number back in isPrime responses, which the spec doesn't, to help clients that
+    // pipeline requests match up the answers
+    pub echo_number: bool,
+    // check the primality test against known answers before serving, and refuse to start
+    // if it gets any wrong
+    pub self_check: bool,
+    // requests a second one connection gets on average, unlimited unless set
+    pub rate_limit: Option<f64>,
+    // requests a connection can send at once before `rate_limit` kicks in
+    pub rate_limit_burst: u32,
+    // what happens to a connection going faster than `rate_limit`
+    pub rate_limit_policy: RateLimitPolicy,
+    // malformed requests a connection can send before it's closed, the spec wants 1
+    pub max_malformed: usize,
+    // answer malformed requests with an error saying what was wrong instead of the spec's `{}`,
+    // for debugging clients locally
+    pub verbose_errors: bool,
+    // how long a new connection has to send its first byte, no limit when unset
+    pub first_byte_timeout: Option<Duration>,
+    // how long connections get to finish after shutdown before they're cut off
+    pub drain_timeout: Duration,
+    // where to serve prometheus metrics from, off unless set
+    pub metrics_address: Option<String>,
+    // where to serve a plain text stats snapshot from, off unless set
+    pub admin_address: Option<String>,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    pub health_address: Option<String>,
+    // where to record everything clients send, one file per connection, off unless set
+    pub record_dir: Option<PathBuf>,
+    // clients come through a load balancer that starts each connection with a PROXY header
+    pub proxy_protocol: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            backlog: 1024,
+            acceptors: 1,
+            reject_when_busy: false,
+            max_connections_per_ip: None,
+            read_timeout: Duration::from_secs(30),
+            framing: Framing::Newline,
+            encoding: Encoding::Json,
+            line_ending: LineEnding::Lf,
+            max_line_length: 1024 * 1024,
+            max_request_size: 64 * 1024,
+            prime_cache_size: 100_000,
+            sieve_limit: 1_000_000,
+            prime_algo: PrimeAlgo::MillerRabin,
+            max_prime_range: DEFAULT_MAX_RANGE,
+            max_number_digits: DEFAULT_MAX_DIGITS,
+            echo_number: false,
+            self_check: false,
+            rate_limit: None,
+            rate_limit_burst: 10,
+            rate_limit_policy: RateLimitPolicy::Delay,
+            max_malformed: 1,
+            verbose_errors: false,
+            first_byte_timeout: Some(Duration::from_secs(10)),
+            drain_timeout: Duration::from_secs(30),
+            metrics_address: None,
+            admin_address: None,
+            health_address: None,
+            record_dir: None,
+            proxy_protocol: false,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once,
+    /// `LISTEN_BACKLOG` how many more can wait to be accepted,
+    /// `ACCEPTORS` how many tasks accept them side by side,
+    /// `REJECT_WHEN_BUSY=true` turns clients past that away with a busy message,
+    /// `MAX_CONNECTIONS_PER_IP` how many of those can come from one address,
+    /// `READ_TIMEOUT_SECS` controls how long a client gets to finish each line,
+    /// `FIRST_BYTE_TIMEOUT_SECS` how long a new client gets to send anything,
+    /// `FRAMING` (`newline` or `length_prefixed`) how requests and responses are framed,
+    /// `ENCODING` (`json` or `msgpack`) how they're serialized,
+    /// `LINE_ENDING` (`lf` or `crlf`) what ends each newline framed response,
+    /// `MAX_LINE_LENGTH` the longest request line accepted,
+    /// `MAX_REQUEST_SIZE` the longest line that gets parsed as json,
+    /// `PRIME_CACHE_SIZE` how many primality results are remembered,
+    /// `SIEVE_LIMIT` how far up the startup sieve goes,
+    /// `PRIME_ALGO` (`trial` or `miller_rabin`) how numbers past the sieve are checked,
+    /// `MAX_PRIME_RANGE` how many numbers an `isPrimeRange` request can cover,
+    /// `MAX_NUMBER_DIGITS` how many digits a number asked about can have,
+    /// `ECHO_NUMBER=true` adds the number asked about to `isPrime` responses,
+    /// `SELF_CHECK=true` checks the primality test is right before serving,
+    /// `RATE_LIMIT` how many requests a second a connection gets, `RATE_LIMIT_BURST` how many
+    /// it can send at once and `RATE_LIMIT_POLICY` (`delay` or `close`) what happens past that,
+    /// `MAX_MALFORMED` how many malformed requests a client gets before it's closed,
+    /// `VERBOSE_ERRORS=true` tells clients what was wrong with a malformed request,
+    /// `DRAIN_TIMEOUT_SECS` how long connections get to finish once shutting down,
+    /// `PROXY_PROTOCOL=true` reads who each client really is from a PROXY v1 header,
+    /// `METRICS_ADDRESS` where to serve `GET /metrics`, `ADMIN_ADDRESS` where to serve a stats
+    /// snapshot, `HEALTH_ADDRESS` where to answer liveness probes and `RECORD_DIR` where to
+    /// record what clients send, to replay while debugging.
+    pub fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(backlog) = env_var("LISTEN_BACKLOG") {
+            config.backlog = backlog;
+        }
+        if let Some(acceptors) = env_var("ACCEPTORS") {
+            config.acceptors = acceptors;
+        }
+        if let Some(reject_when_busy) = env_var("REJECT_WHEN_BUSY") {
+            config.reject_when_busy = reject_when_busy;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS_PER_IP") {
+            config.max_connections_per_ip = Some(max_connections);
+        }
+        if let Some(secs) = env_var("READ_TIMEOUT_SECS") {
+            config.read_timeout = Duration::from_secs(secs);
+        }
+        if let Some(framing) = env_var("FRAMING") {
+            config.framing = framing;
+        }
+        if let Some(encoding) = env_var("ENCODING") {
+            config.encoding = encoding;
+        }
+        if let Some(line_ending) = env_var("LINE_ENDING") {
+            config.line_ending = line_ending;
+        }
+        if let Some(length) = env_var("MAX_LINE_LENGTH") {
+            config.max_line_length = length;
+        }
+        if let Some(size) = env_var("MAX_REQUEST_SIZE") {
+            config.max_request_size = size;
+        }
+        if let Some(size) = env_var("PRIME_CACHE_SIZE") {
+            config.prime_cache_size = size;
+        }
+        if let Some(limit) = env_var("SIEVE_LIMIT") {
+            config.sieve_limit = limit;
+        }
+        if let Some(algo) = env_var("PRIME_ALGO") {
+            config.prime_algo = algo;
+        }
+        if let Some(max_range) = env_var("MAX_PRIME_RANGE") {
+            config.max_prime_range = max_range;
+        }
+        if let Some(max_digits) = env_var("MAX_NUMBER_DIGITS") {
+            config.max_number_digits = max_digits;
+        }
+        if let Some(echo_number) = env_var("ECHO_NUMBER") {
+            config.echo_number = echo_number;
+        }
+        if let Some(self_check) = env_var("SELF_CHECK") {
+            config.self_check = self_check;
+        }
+        if let Some(rate) = env_var("RATE_LIMIT") {
+            config.rate_limit = Some(rate);
+        }
+        if let Some(burst) = env_var("RATE_LIMIT_BURST") {
+            config.rate_limit_burst = burst;
+        }
+        if let Some(policy) = env_var("RATE_LIMIT_POLICY") {
+            config.rate_limit_policy = policy;
+        }
+        if let Some(max_malformed) = env_var("MAX_MALFORMED") {
+            config.max_malformed = max_malformed;
+        }
+        if let Some(verbose_errors) = env_var("VERBOSE_ERRORS") {
+            config.verbose_errors = verbose_errors;
+        }
+        if let Some(secs) = env_var("FIRST_BYTE_TIMEOUT_SECS") {
+            config.first_byte_timeout = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = env_var("DRAIN_TIMEOUT_SECS") {
+            config.drain_timeout = Duration::from_secs(secs);
+        }
+        if let Some(address) = env_var("METRICS_ADDRESS") {
+            config.metrics_address = Some(address);
+        }
+        if let Some(address) = env_var("ADMIN_ADDRESS") {
+            config.admin_address = Some(address);
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        if let Some(dir) = env_var("RECORD_DIR") {
+            config.record_dir = Some(dir);
+        }
+        if let Some(proxy_protocol) = env_var("PROXY_PROTOCOL") {
+            config.proxy_protocol = proxy_protocol;
+        }
+        config
+    }
+
+    /// How requests are split up and responses framed, taking up to `max_length` bytes a request.
+    fn request_codec(&self, max_length: usize) -> RequestCodec {
+        RequestCodec::new(self.encoding.framing(self.framing), max_length)
+            .with_line_ending(self.line_ending)
+    }
+}
+
+/// Totals across every connection, bumped as requests come in.
+#[derive(Debug, Clone)]
+struct Metrics {
+    primes_checked: IntCounter,
+    malformed_requests: IntCounter,
+    rate_limited: IntCounter,
+}
+
+impl Metrics {
+    fn register(registry: &Registry) -> Metrics {
+        Metrics {
+            primes_checked: metrics::register_counter(
+                registry,
+                "primes_checked_total",
+                "Numbers checked for primality",
+            ),
+            malformed_requests: metrics::register_counter(
+                registry,
+                "malformed_requests_total",
+                "Requests answered with a malformed response",
+            ),
+            rate_limited: metrics::register_counter(
+                registry,
+                "rate_limited_requests_total",
+                "Requests that went over their connection's rate limit",
+            ),
+        }
+    }
+}
+
+// the server's idea of who the client is, which behind a proxy isn't the socket's peer
+#[instrument(skip_all, fields(peer_addr = %peer_addr))]
+async fn process<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    socket: S,
+    peer_addr: Peer,
+    config: Arc<Config>,
+    cache: Arc<PrimeCache>,
+    metrics: Metrics,
+    mut shutdown: ShutdownSignal,
+) {
+    info!("processing {:?}", peer_addr);
+    // malformed requests from this connection so far
+    let mut malformed = 0;
+    let mut bucket = config
+        .rate_limit
+        .map(|rate| TokenBucket::new(rate, config.rate_limit_burst));
+    let mut requests = Framed::new(socket, config.request_codec(config.max_line_length));
+    loop {
+        // only wait between requests, one that's already in hand gets answered first
+        let next_line = tokio::select! {
+            // the deadline restarts for every complete line, so a client dribbling one out a
+            // byte at a time doesn't get to hold the connection forever
+            next_line = time::timeout(config.read_timeout, requests.next()) => next_line,
+            _ = shutdown.recv() => {
+                info!("Server shutting down, closing connection");
+                finish_write(requests.get_mut()).await;
+                break;
+            }
+        };
+        let request_raw = match next_line {
+            Ok(Some(Ok(request_raw))) => request_raw,
+            Ok(Some(Err(LinesCodecError::MaxLineLengthExceeded))) => {
+                info!(
+                    "Malformed response, line longer than {} bytes",
+                    config.max_line_length
+                );
+                malformed += 1;
+                metrics.malformed_requests.inc();
+                if reject(&mut requests, &config, malformed, "request too long").await {
+                    break;
+                }
+                continue;
+            }
+            // the client is done. A last request without a newline has already come through
+            // above, see `RequestCodec::decode_eof`, so nothing it sent goes unanswered
+            Ok(_) => break,
+            Err(_) => {
+                info!("No line received within {:?}, closing", config.read_timeout);
+                finish_write(requests.get_mut()).await;
+                break;
+            }
+        };
+        // every line counts, malformed ones cost something to look at too
+        if let Some(bucket) = &mut bucket {
+            if !bucket.try_take() {
+                metrics.rate_limited.inc();
+                match config.rate_limit_policy {
+                    RateLimitPolicy::Delay => {
+                        tokio::select! {
+                            _ = bucket.take() => {}
+                            _ = shutdown.recv() => {
+                                info!("Server shutting down, closing rate limited connection");
+                                break;
+                            }
+                        }
+                    }
+                    RateLimitPolicy::Close => {
+                        warn!("Over the rate limit, closing connection");
+                        let busy = write_response(&mut requests, config.encoding, &BUSY_RESPONSE);
+                        if let Err(e) = busy.await {
+                            info!("Couldn't write busy response: {:?}", e);
+                        } else {
+                            finish_write(requests.get_mut()).await;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        if request_raw.len() > config.max_request_size {
+            info!(
+                "Malformed response, request of {} bytes is over {}",
+                request_raw.len(),
+                config.max_request_size
+            );
+            malformed += 1;
+            metrics.malformed_requests.inc();
+            if reject(&mut requests, &config, malformed, "request too long").await {
+                break;
+            }
+            continue;
+        }
+        info!("New Line: {:?}", String::from_utf8_lossy(&request_raw));
+        let request: Request = match config.encoding.decode(&request_raw) {
+            Ok(request) => request,
+            Err(e) => {
+                info!(
+                    "Malformed response, bad serialization {} {:?}",
+                    e,
+                    String::from_utf8_lossy(&request_raw)
+                );
+                malformed += 1;
+                metrics.malformed_requests.inc();
+                if reject(
+                    &mut requests,
+                    &config,
+                    malformed,
+                    config.encoding.decode_error(),
+                )
+                .await
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+        info!("parsed request {:?}", request);
+
+        // big numbers can take a while, keep them off the async worker threads.
+        // awaiting here before reading the next line keeps responses in order.
+        let cache_handle = cache.clone();
+        let max_range = config.max_prime_range;
+        let max_digits = config.max_number_digits;
+        let (request, result) = tokio::task::spawn_blocking(move || {
+            let result = process_request_capped(&request, &cache_handle, max_range, max_digits);
+            (request, result)
+        })
+        .await
+        .expect("Primality check panicked");
+        match result {
+            Ok(response) => {
+                let response = if config.echo_number {
+                    response.with_number(&request)
+                } else {
+                    response
+                };
+                info!("response: {:?}", response);
+                metrics.primes_checked.inc_by(response.checked() as u64);
+                // write back to client
+                if let Err(e) = write_response(&mut requests, config.encoding, &response).await {
+                    info!("Couldn't write response: {:?}", e);
+                    break;
+                }
+                info!("response write: done");
+            }
+            Err(e) => {
+                info!("Malformed response, {} {:?}", e, request);
+                malformed += 1;
+                metrics.malformed_requests.inc();
+                if reject(&mut requests, &config, malformed, e.summary()).await {
+                    break;
+                }
+            }
+        }
+    }
+    info!(
+        "No more lines, exited loop after {} malformed requests. Prime cache hits so far: {}",
+        malformed,
+        cache.hits()
+    );
+}
+
+/// Every kind of malformed request is answered the same way, with a `MalformedResponse`, unless
+/// `verbose_errors` is on and the client is told what was wrong with it. Once the connection has
+/// sent `max_malformed` of them the write side is shut down too, so the client sees the end of
+/// the stream after it. True when the connection is done.
+async fn reject<S: AsyncRead + AsyncWrite + Unpin>(
+    requests: &mut Framed<S, RequestCodec>,
+    config: &Config,
+    malformed: usize,
+    error: &'static str,
+) -> bool {
+    let written = if config.verbose_errors {
+        write_response(requests, config.encoding, &ErrorResponse { error }).await
+    } else {
+        write_response(requests, config.encoding, &MalformedResponse {}).await
+    };
+    if let Err(e) = written {
+        info!("Couldn't write malformed response: {:?}", e);
+        return true;
+    }
+    if malformed < config.max_malformed {
+        return false;
+    }
+    warn!("{} malformed requests, closing connection", malformed);
+    finish_write(requests.get_mut()).await;
+    info!("Shutdown write side");
+    true
+}
+
+/// Writes `value` as a single response and flushes it out to the client.
+async fn write_response<S>(
+    sink: &mut S,
+    encoding: Encoding,
+    value: &impl Serialize,
+) -> Result<(), LinesCodecError>
+where
+    S: Sink<Vec<u8>, Error = LinesCodecError> + Unpin,
+{
+    let response = encoding.encode(value)?;
+    sink.send(response).await
+}
+
+#[instrument(skip_all, fields(address = %config.address))]
+pub async fn serve_async(
+    config: Config,
+    ready_tx: sync::oneshot::Sender<bool>,
+    shutdown: ShutdownToken,
+) {
+    let registry = Registry::new();
+    let metrics = Metrics::register(&registry);
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        backlog: config.backlog,
+        acceptors: config.acceptors,
+        when_full: if config.reject_when_busy {
+            WhenFull::Reject(
+                config.request_codec(usize::MAX).frame(
+                    &config
+                        .encoding
+                        .encode(&BUSY_RESPONSE)
+                        .expect("Couldn't encode busy response"),
+                ),
+            )
+        } else {
+            WhenFull::Queue
+        },
+        max_connections_per_ip: config.max_connections_per_ip,
+        // every response is one small line the client is waiting on
+        nodelay: true,
+        keepalive: None,
+        first_byte_timeout: config.first_byte_timeout,
+        drain_timeout: config.drain_timeout,
+        metrics_address: config.metrics_address.clone(),
+        admin_address: config.admin_address.clone(),
+        health_address: config.health_address.clone(),
+        registry,
+        connections: Connections::new(),
+        record_dir: config.record_dir.clone(),
+        proxy_protocol: config.proxy_protocol,
+    };
+    let cache = if config.sieve_limit > 0 {
+        PrimeCache::with_sieve(config.prime_cache_size, config.sieve_limit)
+    } else {
+        PrimeCache::new(config.prime_cache_size)
+    };
+    let cache = cache.with_algo(config.prime_algo);
+    if config.self_check {
+        if let Err(e) = cache.self_check() {
+            panic!("Primality self check failed, not serving: {}", e);
+        }
+        info!("Primality self check passed");
+    }
+    let cache = Arc::new(cache);
+    let config = Arc::new(config);
+    // numbers every connection in the order they're accepted, so one's logs can be picked out
+    // from everyone else's
+    let connection_ids = AtomicU64::new(0);
+    run_tcp_server(
+        &server_config,
+        ready_tx,
+        shutdown,
+        move |socket, peer, shutdown_signal| {
+            let config = config.clone();
+            let cache = cache.clone();
+            let metrics = metrics.clone();
+            let id = connection_ids.fetch_add(1, Ordering::Relaxed);
+            async move {
+                info!("Accepted connection from {:?}", peer);
+                process(socket, peer, config, cache, metrics, shutdown_signal).await;
+                info!("Closed connection from {:?}", peer);
+            }
+            .instrument(info_span!("connection", id))
+        },
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod integration_tests {
+
+    use super::*;
+
+    use common::testing::{capture_logs, TestClient};
+    use num_bigint::BigInt;
+    use num_traits::One;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net;
+
+    #[test]
+    fn test_server() {
+        // run this to see logs:
+        // cargo test server -- --nocapture
+        // TRACE is a bit chatty, set it here if you want it
+        let _guard = common::observability::init_tracing(tracing::Level::INFO);
+
+        // ready signal
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+
+        let rt = tokio::runtime::Runtime::new().expect("Unable to create tokio runtime for test.");
+        rt.spawn(async {
+            info!("Spawned test server.");
+            serve_async(Config::default(), ready_tx, ShutdownToken::new()).await;
+            info!("test server shutdown.");
+        });
+
+        rt.block_on(async {
+            // wait for server to be ready
+            ready_rx
+                .await
+                .expect("Failure while waiting for ready signal");
+
+            // send request to server running, the last line doesn't need a newline
+            let mut client = TestClient::connect("127.0.0.1:8000").await;
+            client.send(b"{\"method\":\"isPrime\",\"number\":10}").await;
+            client.shutdown_write().await;
+            info!("Close stream");
+
+            assert_eq!(
+                Some(String::from("{\"method\":\"isPrime\",\"prime\":false}")),
+                client.read_line().await
+            );
+        });
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_last_request() {
+        let config = Config {
+            address: String::from("127.0.0.1:8023"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // a whole request with no newline before the close is answered like any other
+        let mut client = TestClient::connect("127.0.0.1:8023").await;
+        client.send(b"{\"method\":\"isPrime\",\"number\":7}").await;
+        client.shutdown_write().await;
+        assert_eq!(
+            b"{\"method\":\"isPrime\",\"prime\":true}\n".to_vec(),
+            client.read_to_end().await
+        );
+
+        // one that's cut off is malformed, not ignored
+        let mut client = TestClient::connect("127.0.0.1:8023").await;
+        client
+            .send(b"{\"method\":\"isPrime\",\"number\":8}\n{\"method\":\"isPrime\",\"num")
+            .await;
+        client.shutdown_write().await;
+        assert_eq!(
+            b"{\"method\":\"isPrime\",\"prime\":false}\n{}\n".to_vec(),
+            client.read_to_end().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let config = Config {
+            address: String::from("127.0.0.1:8001"),
+            read_timeout: Duration::from_millis(100),
+            // only the read timeout is under test here
+            first_byte_timeout: None,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // connect and never send a line, the server closes without sending anything
+        let mut client = TestClient::connect("127.0.0.1:8001").await;
+        assert!(client.read_to_end().await.is_empty());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_slow_loris() {
+        let config = Config {
+            address: String::from("127.0.0.1:8008"),
+            read_timeout: Duration::from_millis(300),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // a byte every 50ms is never idle for long, but the line never finishes in time
+        let client = net::TcpStream::connect("127.0.0.1:8008").await.unwrap();
+        let (mut reader, mut writer) = client.into_split();
+        let started = std::time::Instant::now();
+        let dribble = tokio::spawn(async move {
+            for byte in b"{\"method\":\"isPrime\",\"number\":7}".iter().cycle() {
+                if writer.write_all(&[*byte]).await.is_err() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(5), reader.read_to_end(&mut response))
+            .await
+            .expect("Slow client wasn't dropped")
+            .unwrap();
+        assert!(response.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        dribble.abort();
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_ipv6() {
+        let config = Config {
+            address: String::from("[::1]:8009"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("[::1]:8009").await;
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_admin_snapshot() {
+        let config = Config {
+            address: String::from("127.0.0.1:8010"),
+            admin_address: Some(String::from("127.0.0.1:8011")),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8010").await;
+        for number in [2, 3, 4] {
+            client
+                .send_line(&format!("{{\"method\":\"isPrime\",\"number\":{}}}", number))
+                .await;
+            client.read_line().await.expect("Missing response");
+        }
+
+        let mut admin = net::TcpStream::connect("127.0.0.1:8011").await.unwrap();
+        let mut snapshot = String::new();
+        admin.read_to_string(&mut snapshot).await.unwrap();
+        assert!(snapshot.contains("\nactive_connections 1\n"));
+        assert!(snapshot.contains("\nprimes_checked_total 3\n"));
+        assert!(snapshot.contains("\nmalformed_requests_total 0\n"));
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_health_probe() {
+        let config = Config {
+            address: String::from("127.0.0.1:8024"),
+            health_address: Some(String::from("127.0.0.1:8025")),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut probe = net::TcpStream::connect("127.0.0.1:8025").await.unwrap();
+        let mut answer = String::new();
+        probe.read_to_string(&mut answer).await.unwrap();
+        assert_eq!("OK\n", answer);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reject_when_busy() {
+        let config = Config {
+            address: String::from("127.0.0.1:8012"),
+            max_connections: 1,
+            reject_when_busy: true,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8012").await;
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+        // the one slot is taken
+        let mut overflow = TestClient::connect("127.0.0.1:8012").await;
+        assert_eq!(
+            "{\"error\":\"server busy\"}\n",
+            overflow.read_to_string().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_echo_number() {
+        let config = Config {
+            address: String::from("127.0.0.1:8014"),
+            echo_number: true,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // pipelined, each answer says which request it's for
+        let mut client = TestClient::connect("127.0.0.1:8014").await;
+        client
+            .send(b"{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"isPrime\",\"number\":8}\n")
+            .await;
+        assert_eq!(
+            Some(String::from(
+                "{\"method\":\"isPrime\",\"prime\":true,\"number\":7}"
+            )),
+            client.read_line().await
+        );
+        assert_eq!(
+            Some(String::from(
+                "{\"method\":\"isPrime\",\"prime\":false,\"number\":8}"
+            )),
+            client.read_line().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_verbose_errors() {
+        let config = Config {
+            address: String::from("127.0.0.1:8026"),
+            max_request_size: 100,
+            max_malformed: 10,
+            verbose_errors: true,
+            max_number_digits: 30,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8026").await;
+        for (request, error) in [
+            (String::from("{\"method\":\"isPrime\""), "bad json"),
+            (
+                String::from("{\"method\":\"isEven\",\"number\":7}"),
+                "unknown method",
+            ),
+            (
+                String::from("{\"method\":\"isPrimeRange\",\"start\":1.5,\"end\":10}"),
+                "number not an integer",
+            ),
+            (String::from("{\"method\":\"isPrime\"}"), "missing number"),
+            (
+                String::from("{\"method\":\"isPrimeRange\",\"start\":10,\"end\":1}"),
+                "invalid range",
+            ),
+            (
+                format!("{{\"method\":\"isPrime\",\"number\":1{}}}", "0".repeat(30)),
+                "number too long",
+            ),
+            ("x".repeat(200), "request too long"),
+        ] {
+            client.send_line(&request).await;
+            assert_eq!(
+                Some(format!("{{\"error\":\"{}\"}}", error)),
+                client.read_line().await,
+                "{}",
+                request
+            );
+        }
+        // good requests are answered as usual in between
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_delay() {
+        let config = Config {
+            address: String::from("127.0.0.1:8015"),
+            rate_limit: Some(20.0),
+            rate_limit_burst: 5,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8015").await;
+        let started = time::Instant::now();
+        client
+            .send(
+                "{\"method\":\"isPrime\",\"number\":7}\n"
+                    .repeat(25)
+                    .as_bytes(),
+            )
+            .await;
+        for _ in 0..25 {
+            assert_eq!(
+                Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+                client.read_line().await
+            );
+        }
+        // the first 5 go straight through, the other 20 at 20 a second
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(950), "{:?}", elapsed);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_close() {
+        let config = Config {
+            address: String::from("127.0.0.1:8016"),
+            rate_limit: Some(1.0),
+            rate_limit_burst: 2,
+            rate_limit_policy: RateLimitPolicy::Close,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8016").await;
+        client
+            .send(
+                "{\"method\":\"isPrime\",\"number\":7}\n"
+                    .repeat(5)
+                    .as_bytes(),
+            )
+            .await;
+        assert_eq!(
+            "{\"method\":\"isPrime\",\"prime\":true}\n".repeat(2) + "{\"error\":\"server busy\"}\n",
+            client.read_to_string().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_ids() {
+        let (logs, _guard) = capture_logs(tracing::Level::INFO);
+
+        let config = Config {
+            address: String::from("127.0.0.1:8022"),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, shutdown.clone()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // both open at once, so their logs interleave
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let mut client = TestClient::connect("127.0.0.1:8022").await;
+            client
+                .send_line("{\"method\":\"isPrime\",\"number\":7}")
+                .await;
+            assert!(client.read_line().await.is_some());
+            clients.push(client);
+        }
+        let peers: Vec<_> = clients.iter().map(TestClient::local_addr).collect();
+        drop(clients);
+        shutdown.shutdown();
+        server_handle.await.unwrap();
+
+        let logs = logs.contents();
+        for (id, peer) in peers.iter().enumerate() {
+            let tag = format!("connection{{id={}}}", id);
+            for event in ["Accepted", "Closed"] {
+                let event = format!("{} connection from {:?}", event, peer);
+                let line = logs
+                    .lines()
+                    .find(|line| line.contains(&event))
+                    .unwrap_or_else(|| panic!("No {:?} in {}", event, logs));
+                assert!(line.contains(&tag), "{}", line);
+            }
+            assert!(
+                logs.contains(&format!("{}:process{{peer_addr={}}}", tag, peer)),
+                "{}",
+                logs
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let (logs, _guard) = capture_logs(tracing::Level::INFO);
+
+        let config = Config {
+            address: String::from("127.0.0.1:8013"),
+            proxy_protocol: true,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8013").await;
+        client
+            .send(b"PROXY TCP4 198.51.100.23 127.0.0.1 40000 8013\r\n")
+            .await;
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+        let logs = logs.contents();
+        assert!(
+            logs.lines()
+                .any(|line| line.contains("peer_addr=198.51.100.23:40000")
+                    && line.contains("response write: done")),
+            "{}",
+            logs
+        );
+
+        // without a header there's no telling who the client is. It's closed with the request
+        // unread, so the client may see a reset rather than the end of the stream
+        let mut unproxied = net::TcpStream::connect("127.0.0.1:8013").await.unwrap();
+        unproxied
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let read = time::timeout(Duration::from_secs(5), unproxied.read_to_end(&mut response))
+            .await
+            .expect("Server did not close the connection");
+        assert!(response.is_empty(), "{:?} {:?}", read, response);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let config = Config {
+            address: String::from("127.0.0.1:8006"),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, shutdown.clone()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8006").await;
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+
+        // the client is now idle, shutdown should close it and let serve return
+        shutdown.shutdown();
+        time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+        assert!(client.read_to_end().await.is_empty());
+    }
+
+    // #[tokio::test] is single threaded, so without the blocking pool the slow
+    // request would hold up every other connection
+    #[tokio::test]
+    async fn test_slow_request_does_not_starve() {
+        let config = Config {
+            address: String::from("127.0.0.1:8003"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        async fn is_prime(number: String) -> String {
+            let mut client = TestClient::connect("127.0.0.1:8003").await;
+            client
+                .send_line(&format!("{{\"method\":\"isPrime\",\"number\":{}}}", number))
+                .await;
+            client.read_line().await.expect("There is no response data")
+        }
+
+        // mersenne prime 2^1279 - 1, every miller-rabin round has to run
+        let slow_number = ((BigInt::one() << 1279_u32) - BigInt::one()).to_string();
+        let slow_handle = tokio::spawn(is_prime(slow_number));
+        time::sleep(Duration::from_millis(20)).await;
+
+        let fast_response = time::timeout(Duration::from_secs(5), is_prime(String::from("13")))
+            .await
+            .expect("Fast request was starved by the slow one");
+        assert_eq!("{\"method\":\"isPrime\",\"prime\":true}", fast_response);
+        assert!(!slow_handle.is_finished());
+
+        let slow_response = slow_handle.await.unwrap();
+        assert_eq!("{\"method\":\"isPrime\",\"prime\":true}", slow_response);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let config = Config {
+            address: String::from("127.0.0.1:8005"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8005").await;
+        // every request goes out in one write, before any response is read
+        let numbers = [2, 4, 7919, 7920, 13, 1, 97];
+        let requests: String = numbers
+            .iter()
+            .map(|number| format!("{{\"method\":\"isPrime\",\"number\":{}}}\n", number))
+            .collect();
+        client.send(requests.as_bytes()).await;
+        client.shutdown_write().await;
+
+        let expected: String = [true, false, true, false, true, false, true]
+            .iter()
+            .map(|prime| format!("{{\"method\":\"isPrime\",\"prime\":{}}}\n", prime))
+            .collect();
+        assert_eq!(expected, client.read_to_string().await);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_framing() {
+        // the same requests, a malformed one last, sent both ways
+        let requests = [
+            "{\"method\":\"isPrime\",\"number\":7}",
+            "{\"method\":\"isPrime\",\"number\":8.5}",
+            "{\"method\":\"isPrime\"}",
+        ];
+        let mut responses = Vec::new();
+        for (framing, address) in [
+            (Framing::Newline, "127.0.0.1:8017"),
+            (Framing::LengthPrefixed, "127.0.0.1:8018"),
+        ] {
+            let config = Config {
+                address: String::from(address),
+                framing,
+                ..Config::default()
+            };
+            let (ready_tx, ready_rx) = sync::oneshot::channel();
+            let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+            ready_rx
+                .await
+                .expect("Failure while waiting for ready signal");
+
+            let mut client = TestClient::connect(address).await;
+            let framed: Vec<u8> = requests
+                .iter()
+                .flat_map(|request| framing.encode(request.as_bytes()))
+                .collect();
+            client.send(&framed).await;
+            let received = client.read_to_end().await;
+            // unframed again, to compare the json alone
+            let mut unframed = Vec::new();
+            let mut rest = &received[..];
+            while !rest.is_empty() {
+                let end = match framing {
+                    Framing::Newline => {
+                        let end = rest.iter().position(|byte| *byte == b'\n').unwrap();
+                        unframed.push(String::from_utf8(rest[..end].to_vec()).unwrap());
+                        end + 1
+                    }
+                    Framing::LengthPrefixed => {
+                        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                        unframed.push(String::from_utf8(rest[4..4 + len].to_vec()).unwrap());
+                        4 + len
+                    }
+                };
+                rest = &rest[end..];
+            }
+            responses.push(unframed);
+
+            server_handle.abort();
+        }
+        assert_eq!(
+            vec![
+                "{\"method\":\"isPrime\",\"prime\":true}",
+                "{\"method\":\"isPrime\",\"prime\":false}",
+                "{}",
+            ],
+            responses[0]
+        );
+        assert_eq!(responses[0], responses[1]);
+    }
+
+    #[tokio::test]
+    async fn test_self_check() {
+        let config = Config {
+            address: String::from("127.0.0.1:8021"),
+            prime_algo: PrimeAlgo::Trial,
+            self_check: true,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Server didn't start after passing its self check");
+
+        let mut client = TestClient::connect("127.0.0.1:8021").await;
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7919}")
+            .await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_line_ending() {
+        let config = Config {
+            address: String::from("127.0.0.1:8020"),
+            line_ending: LineEnding::Crlf,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // requests ending either way, then a malformed one
+        let mut client = TestClient::connect("127.0.0.1:8020").await;
+        client
+            .send(
+                b"{\"method\":\"isPrime\",\"number\":7}\r\n\
+                  {\"method\":\"isPrime\",\"number\":8}\n\
+                  {\"method\":\"isPrime\"}\r\n",
+            )
+            .await;
+        assert_eq!(
+            b"{\"method\":\"isPrime\",\"prime\":true}\r\n\
+              {\"method\":\"isPrime\",\"prime\":false}\r\n\
+              {}\r\n"
+                .to_vec(),
+            client.read_to_end().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_msgpack() {
+        let config = Config {
+            address: String::from("127.0.0.1:8019"),
+            encoding: Encoding::Msgpack,
+            echo_number: true,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // {"method": "isPrime", "number": 7}, {"method": "isPrime", "number": 8}, then a
+        // map that's cut short
+        let requests = [
+            &b"\x82\xa6method\xa7isPrime\xa6number\x07"[..],
+            b"\x82\xa6method\xa7isPrime\xa6number\x08",
+            b"\x82\xa6method",
+        ];
+        let mut client = TestClient::connect("127.0.0.1:8019").await;
+        let framed: Vec<u8> = requests
+            .iter()
+            .flat_map(|request| Framing::LengthPrefixed.encode(request))
+            .collect();
+        client.send(&framed).await;
+
+        let mut responses = Vec::new();
+        let received = client.read_to_end().await;
+        let mut rest = &received[..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let response: serde_json::Value = rmp_serde::from_slice(&rest[4..4 + len]).unwrap();
+            responses.push(response);
+            rest = &rest[4 + len..];
+        }
+        assert_eq!(
+            vec![
+                serde_json::json!({"method": "isPrime", "prime": true, "number": 7}),
+                serde_json::json!({"method": "isPrime", "prime": false, "number": 8}),
+                serde_json::json!({}),
+            ],
+            responses
+        );
+
+        server_handle.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("prime-time-{}.sock", std::process::id()));
+        let config = Config {
+            address: format!("unix:{}", path.display()),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, shutdown.clone()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!("{\"method\":\"isPrime\",\"prime\":true}\n", response);
+
+        shutdown.shutdown();
+        server_handle.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_json() {
+        let config = Config {
+            address: String::from("127.0.0.1:8004"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8004").await;
+        // one good request, then garbage, then a good request that never gets answered
+        client
+            .send(b"{\"method\":\"isPrime\",\"number\":7}\nnot json\n{\"method\":\"isPrime\",\"number\":7}\n")
+            .await;
+
+        assert_eq!(
+            "{\"method\":\"isPrime\",\"prime\":true}\n{}\n",
+            client.read_to_string().await
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_line_length() {
+        let config = Config {
+            address: String::from("127.0.0.1:8002"),
+            max_line_length: 64,
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        let mut client = TestClient::connect("127.0.0.1:8002").await;
+        // valid json, just far too long
+        client
+            .send_line(&format!(
+                "{{\"method\":\"isPrime\",\"number\":{}}}",
+                "1".repeat(100)
+            ))
+            .await;
+
+        assert_eq!("{}\n", client.read_to_string().await);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests_close_the_same_way() {
+        let config = Config {
+            address: String::from("127.0.0.1:8007"),
+            ..Config::default()
+        };
+        let (ready_tx, ready_rx) = sync::oneshot::channel();
+        let server_handle = tokio::spawn(serve_async(config, ready_tx, ShutdownToken::new()));
+        ready_rx
+            .await
+            .expect("Failure while waiting for ready signal");
+
+        // one that isn't json at all and one that's json with a method we don't know
+        let mut closes = Vec::new();
+        for bad_line in ["{\"method\":", "{\"method\":\"isComposite\",\"number\":7}"] {
+            let mut client = TestClient::connect("127.0.0.1:8007").await;
+            client.send_line(bad_line).await;
+            // anything after the bad line is never answered
+            client
+                .send_line("{\"method\":\"isPrime\",\"number\":7}")
+                .await;
+            let response = client.read_line().await;
+            let after = client.read_to_end().await;
+            closes.push((response, after));
+        }
+        assert_eq!(Some(String::from("{}")), closes[0].0);
+        assert!(closes[0].1.is_empty());
+        assert_eq!(closes[0], closes[1]);
+
+        server_handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::protocol::Response;
+    use common::env::parse_var;
+    use common::testing::{capture_logs, TestClient};
+    use tokio::net;
+
+    #[test]
+    fn test_parse_var() {
+        let (logs, _guard) = capture_logs(tracing::Level::WARN);
+        assert_eq!(
+            Some(PrimeAlgo::MillerRabin),
+            parse_var("PRIME_ALGO", Some(String::from("miller_rabin")))
+        );
+        assert_eq!(None, parse_var::<PrimeAlgo>("PRIME_ALGO", None));
+        assert!(logs.contents().is_empty());
+
+        // a typo falls back to the default, but says so
+        assert_eq!(
+            None,
+            parse_var::<PrimeAlgo>("PRIME_ALGO", Some(String::from("millerrabin")))
+        );
+        let contents = logs.contents();
+        assert!(
+            contents.contains(
+                r#"Ignoring PRIME_ALGO="millerrabin", unknown primality algorithm "millerrabin""#
+            ),
+            "{}",
+            contents
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_response() {
+        let mut sink = tokio_util::codec::FramedWrite::new(
+            Vec::new(),
+            RequestCodec::new(Framing::Newline, usize::MAX),
+        );
+        write_response(&mut sink, Encoding::Json, &Response::single(true))
+            .await
+            .expect("Couldn't write line");
+        write_response(&mut sink, Encoding::Json, &MalformedResponse {})
+            .await
+            .expect("Couldn't write line");
+        assert_eq!(
+            "{\"method\":\"isPrime\",\"prime\":true}\n{}\n",
+            String::from_utf8(sink.into_inner()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_threshold() {
+        let listener = net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let config = Config {
+            max_malformed: 3,
+            ..Config::default()
+        };
+        let metrics = Metrics::register(&Registry::new());
+        let session_metrics = metrics.clone();
+        let session = tokio::spawn(async move {
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            process(
+                socket,
+                peer_addr.into(),
+                Arc::new(config),
+                Arc::new(PrimeCache::new(100)),
+                session_metrics,
+                ShutdownToken::new().subscribe(),
+            )
+            .await;
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_line("not json").await;
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        client
+            .send_line("{\"method\":\"isComposite\",\"number\":7}")
+            .await;
+        client.send_line("{\"method\":\"isPrime\"}").await;
+        // past the threshold, never answered
+        client
+            .send_line("{\"method\":\"isPrime\",\"number\":7}")
+            .await;
+        assert_eq!(
+            "{}\n{\"method\":\"isPrime\",\"prime\":true}\n{}\n{}\n",
+            client.read_to_string().await
+        );
+        session.await.unwrap();
+        assert_eq!(3, metrics.malformed_requests.get());
+        assert_eq!(1, metrics.primes_checked.get());
+    }
+
+    #[tokio::test]
+    async fn test_max_request_size() {
+        let listener = net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let config = Config {
+            max_request_size: 64,
+            max_malformed: 2,
+            ..Config::default()
+        };
+        let metrics = Metrics::register(&Registry::new());
+        let session_metrics = metrics.clone();
+        let shutdown = ShutdownToken::new();
+        let session_shutdown = shutdown.subscribe();
+        let session = tokio::spawn(async move {
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            process(
+                socket,
+                peer_addr.into(),
+                Arc::new(config),
+                Arc::new(PrimeCache::new(100)),
+                session_metrics,
+                session_shutdown,
+            )
+            .await;
+        });
+
+        let mut client = TestClient::connect(address).await;
+        // right at the limit is still parsed
+        let request = "{\"method\":\"isPrime\",\"number\":7}";
+        client.send_line(&format!("{:<64}", request)).await;
+        assert_eq!(
+            Some(String::from("{\"method\":\"isPrime\",\"prime\":true}")),
+            client.read_line().await
+        );
+        // perfectly good json, but too big to look at
+        let nested = format!("{}1{}", "[".repeat(40), "]".repeat(40));
+        client
+            .send_line(&format!(
+                "{{\"method\":\"isPrime\",\"number\":7,\"extra\":{}}}",
+                nested
+            ))
+            .await;
+        client.send_line(&format!("{:<65}", request)).await;
+        assert_eq!("{}\n{}\n", client.read_to_string().await);
+        session.await.unwrap();
+        assert_eq!(2, metrics.malformed_requests.get());
+        assert_eq!(1, metrics.primes_checked.get());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::vcs::{valid_dir_name, valid_file_name};
+use std::fmt;
+
+/// A request line from a client. A `Put` is followed by `length` bytes of file contents.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Put {
+        path: String,
+        length: usize,
+    },
+    Get {
+        path: String,
+        revision: Option<usize>,
+    },
+    List {
+        dir: String,
+    },
+}
+
+/// Why a line isn't a command the store can act on. Each is sent back as an `ERR` line.
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    // the right method, the wrong arguments. Holds how it should have been used
+    Usage(&'static str),
+    IllegalFileName,
+    IllegalDirName,
+    NoSuchRevision,
+    // not a method at all, the connection is closed after saying so
+    IllegalMethod(String),
+}
+
+impl CommandError {
+    /// Whether the client's gone wrong enough that there's no point carrying on.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, CommandError::IllegalMethod(_))
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::IllegalFileName => write!(f, "illegal file name"),
+            CommandError::IllegalDirName => write!(f, "illegal dir name"),
+            CommandError::NoSuchRevision => write!(f, "no such revision"),
+            CommandError::IllegalMethod(method) => write!(f, "illegal method: {}", method),
+        }
+    }
+}
+
+pub const HELP_USAGE: &str = "HELP|GET|PUT|LIST";
+const PUT_USAGE: &str = "PUT file length newline data";
+const GET_USAGE: &str = "GET file [revision]";
+const LIST_USAGE: &str = "LIST dir";
+
+/// Methods are case insensitive, arguments are separated by any amount of whitespace.
+pub fn parse(line: &str) -> Result<Command, CommandError> {
+    let mut words = line.split_ascii_whitespace();
+    let method = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+    match method.to_ascii_lowercase().as_str() {
+        "help" => Ok(Command::Help),
+        "put" => {
+            let [path, length] = args[..] else {
+                return Err(CommandError::Usage(PUT_USAGE));
+            };
+            let length = length.parse().map_err(|_| CommandError::Usage(PUT_USAGE))?;
+            Ok(Command::Put {
+                path: file_name(path)?,
+                length,
+            })
+        }
+        "get" => {
+            let (path, revision) = match args[..] {
+                [path] => (path, None),
+                [path, revision] => (path, Some(revision)),
+                _ => return Err(CommandError::Usage(GET_USAGE)),
+            };
+            let path = file_name(path)?;
+            let revision = revision.map(parse_revision).transpose()?;
+            Ok(Command::Get { path, revision })
+        }
+        "list" => {
+            let [dir] = args[..] else {
+                return Err(CommandError::Usage(LIST_USAGE));
+            };
+            if !valid_dir_name(dir) {
+                return Err(CommandError::IllegalDirName);
+            }
+            Ok(Command::List {
+                dir: dir.to_string(),
+            })
+        }
+        _ => Err(CommandError::IllegalMethod(method.to_string())),
+    }
+}
+
+fn file_name(path: &str) -> Result<String, CommandError> {
+    if !valid_file_name(path) {
+        return Err(CommandError::IllegalFileName);
+    }
+    Ok(path.to_string())
+}
+
+/// `r3` or plain `3`.
+fn parse_revision(revision: &str) -> Result<usize, CommandError> {
+    revision
+        .strip_prefix('r')
+        .unwrap_or(revision)
+        .parse()
+        .map_err(|_| CommandError::NoSuchRevision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(Command::Help), parse("HELP"));
+        assert_eq!(Ok(Command::Help), parse("help me please"));
+        assert_eq!(
+            Ok(Command::Put {
+                path: String::from("/a.txt"),
+                length: 12
+            }),
+            parse("pUt /a.txt 12")
+        );
+        assert_eq!(
+            Ok(Command::Get {
+                path: String::from("/a.txt"),
+                revision: None
+            }),
+            parse("GET   /a.txt")
+        );
+        assert_eq!(
+            Ok(Command::Get {
+                path: String::from("/a.txt"),
+                revision: Some(2)
+            }),
+            parse("GET /a.txt r2")
+        );
+        assert_eq!(
+            Ok(Command::Get {
+                path: String::from("/a.txt"),
+                revision: Some(2)
+            }),
+            parse("GET /a.txt 2")
+        );
+        assert_eq!(
+            Ok(Command::List {
+                dir: String::from("/")
+            }),
+            parse("LIST /")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Err(CommandError::Usage(PUT_USAGE)), parse("PUT /a.txt"));
+        assert_eq!(Err(CommandError::Usage(PUT_USAGE)), parse("PUT /a.txt ten"));
+        assert_eq!(Err(CommandError::Usage(GET_USAGE)), parse("GET"));
+        assert_eq!(Err(CommandError::Usage(GET_USAGE)), parse("GET /a r1 r2"));
+        assert_eq!(Err(CommandError::Usage(LIST_USAGE)), parse("LIST"));
+        assert_eq!(Err(CommandError::IllegalFileName), parse("PUT a.txt 1"));
+        assert_eq!(Err(CommandError::IllegalFileName), parse("GET /a/"));
+        assert_eq!(Err(CommandError::IllegalDirName), parse("LIST dir"));
+        assert_eq!(Err(CommandError::NoSuchRevision), parse("GET /a.txt rev"));
+        assert_eq!(Err(CommandError::NoSuchRevision), parse("GET /a.txt r-1"));
+
+        let error = parse("DELETE /a.txt").unwrap_err();
+        assert!(error.is_fatal());
+        assert_eq!("illegal method: DELETE", error.to_string());
+        assert_eq!(Err(CommandError::IllegalMethod(String::new())), parse("  "));
+        assert!(!CommandError::IllegalFileName.is_fatal());
+        assert_eq!(
+            "usage: PUT file length newline data",
+            CommandError::Usage(PUT_USAGE).to_string()
+        );
+    }
+}
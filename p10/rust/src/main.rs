@@ -0,0 +1,422 @@
+mod command;
+mod vcs;
+
+use command::{Command, HELP_USAGE};
+use common::env::env_var;
+use common::observability::init_tracing_from_env;
+use common::{run_tcp_server, Peer, ServerConfig, ShutdownSignal, ShutdownToken};
+use std::fmt::Write as _;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info};
+use vcs::{is_text, Store};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    dual_stack: bool,
+    // connections handled at once before the server stops accepting
+    max_connections: usize,
+    // longest command line we'll take from a client
+    max_line_length: usize,
+    // biggest file a PUT can store, bigger ones are read and thrown away
+    max_file_size: usize,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            max_line_length: 1024,
+            max_file_size: 1024 * 1024,
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once,
+    /// `MAX_LINE_LENGTH` the longest command line accepted,
+    /// `MAX_FILE_SIZE` the biggest file that can be put and
+    /// `HEALTH_ADDRESS` where to answer liveness probes.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(length) = env_var("MAX_LINE_LENGTH") {
+            config.max_line_length = length;
+        }
+        if let Some(size) = env_var("MAX_FILE_SIZE") {
+            config.max_file_size = size;
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        health_address: config.health_address.clone(),
+        ..ServerConfig::default()
+    };
+    // every client sees the same files
+    let store = Arc::new(Mutex::new(Store::new()));
+    let config = Arc::new(config);
+    run_tcp_server(
+        &server_config,
+        ready_signal,
+        shutdown,
+        move |stream, remote_addr, shutdown_signal| {
+            let store = store.clone();
+            let config = config.clone();
+            async move {
+                handle_client(stream, remote_addr, store, config, shutdown_signal).await;
+            }
+        },
+    )
+    .await;
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    remote_addr: Peer,
+    store: Arc<Mutex<Store>>,
+    config: Arc<Config>,
+    mut shutdown: ShutdownSignal,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    // buffered, the data after a PUT comes out of the same buffer as the lines
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        if let Err(e) = writer.write_all(b"READY\n").await {
+            info!("Error writing to {:?} : {:?}", remote_addr, e);
+            break;
+        }
+        line.clear();
+        // one byte over the limit is enough to know it's too long
+        let mut limited = (&mut reader).take(config.max_line_length as u64 + 1);
+        let read = tokio::select! {
+            read = limited.read_until(b'\n', &mut line) => read,
+            _ = shutdown.recv() => break,
+        };
+        match read {
+            Ok(0) => break,
+            Ok(_) if !line.ends_with(b"\n") => {
+                if line.len() > config.max_line_length {
+                    info!("Line from {:?} is too long, closing", remote_addr);
+                }
+                // otherwise they hung up part way through a line
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                info!("Error reading from {:?} : {:?}", remote_addr, e);
+                break;
+            }
+        }
+        let line = String::from_utf8_lossy(&line);
+        debug!("{:?} sent {:?}", remote_addr, line);
+        let (response, close) = match command::parse(&line) {
+            Ok(command) => match execute(command, &mut reader, &store, &config).await {
+                Ok(response) => (response, false),
+                Err(e) => {
+                    info!("Error reading a file from {:?} : {:?}", remote_addr, e);
+                    break;
+                }
+            },
+            Err(e) => (format!("ERR {}\n", e).into_bytes(), e.is_fatal()),
+        };
+        if let Err(e) = writer.write_all(&response).await {
+            info!("Error writing to {:?} : {:?}", remote_addr, e);
+            break;
+        }
+        if close {
+            info!("{:?} sent an illegal method, closing", remote_addr);
+            break;
+        }
+    }
+    info!("Closing connection for {:?}", remote_addr);
+}
+
+/// Carries out `command` against the store and returns the response to send. Reading a PUT's
+/// data is the only thing that can fail, and that leaves the connection out of step.
+async fn execute<R: AsyncRead + Unpin>(
+    command: Command,
+    reader: &mut R,
+    store: &Mutex<Store>,
+    config: &Config,
+) -> io::Result<Vec<u8>> {
+    let response = match command {
+        Command::Help => format!("OK usage: {}\n", HELP_USAGE),
+        Command::Put { path, length } => {
+            let mut data = reader.take(length as u64);
+            if length > config.max_file_size {
+                tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+            }
+            let mut contents = Vec::new();
+            data.read_to_end(&mut contents).await?;
+            if data.limit() > 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if length > config.max_file_size {
+                String::from("ERR file too large\n")
+            } else if !is_text(&contents) {
+                String::from("ERR text files only\n")
+            } else {
+                let revision = store.lock().unwrap().put(&path, contents);
+                format!("OK r{}\n", revision)
+            }
+        }
+        Command::Get { path, revision } => match store.lock().unwrap().get(&path, revision) {
+            Ok(contents) => {
+                let mut response = format!("OK {}\n", contents.len()).into_bytes();
+                response.extend_from_slice(contents);
+                return Ok(response);
+            }
+            Err(e) => format!("ERR {}\n", e),
+        },
+        Command::List { dir } => {
+            let listing = store.lock().unwrap().list(&dir);
+            let mut response = format!("OK {}\n", listing.len());
+            for entry in listing {
+                let _ = writeln!(response, "{}", entry);
+            }
+            response
+        }
+    };
+    Ok(response.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::testing::TestClient;
+
+    async fn start_server(address: &str) -> ShutdownToken {
+        let config = Config {
+            address: address.to_string(),
+            max_file_size: 64,
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+        shutdown
+    }
+
+    /// Sends `request` and returns the lines that come back before the next `READY`.
+    async fn request(client: &mut TestClient, request: &[u8]) -> Vec<String> {
+        client.send(request).await;
+        let mut lines = Vec::new();
+        loop {
+            match client.read_line().await {
+                Some(line) if line == "READY" => return lines,
+                Some(line) => lines.push(line),
+                None => panic!("Server hung up after {:?}", lines),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_then_get() {
+        let address = "127.0.0.1:8001";
+        let shutdown = start_server(address).await;
+
+        let mut client = TestClient::connect(address).await;
+        assert_eq!(Some(String::from("READY")), client.read_line().await);
+        assert_eq!(
+            vec!["OK usage: HELP|GET|PUT|LIST"],
+            request(&mut client, b"help\n").await
+        );
+        assert_eq!(
+            vec!["OK r1"],
+            request(&mut client, b"PUT /test.txt 13\nhello\nworld!\n").await
+        );
+        assert_eq!(
+            vec!["OK 13", "hello", "world!"],
+            request(&mut client, b"GET /test.txt\n").await
+        );
+        assert_eq!(
+            vec!["ERR no such file"],
+            request(&mut client, b"GET /other.txt\n").await
+        );
+
+        // everyone shares the same files
+        let mut other = TestClient::connect(address).await;
+        assert_eq!(Some(String::from("READY")), other.read_line().await);
+        assert_eq!(
+            vec!["OK 13", "hello", "world!"],
+            request(&mut other, b"get /test.txt r1\n").await
+        );
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_revisions() {
+        let address = "127.0.0.1:8002";
+        let shutdown = start_server(address).await;
+
+        let mut client = TestClient::connect(address).await;
+        assert_eq!(Some(String::from("READY")), client.read_line().await);
+        assert_eq!(
+            vec!["OK r1"],
+            request(&mut client, b"PUT /a 4\none\n").await
+        );
+        assert_eq!(
+            vec!["OK r2"],
+            request(&mut client, b"PUT /a 4\ntwo\n").await
+        );
+        assert_eq!(
+            vec!["OK r2"],
+            request(&mut client, b"PUT /a 4\ntwo\n").await
+        );
+        assert_eq!(
+            vec!["OK 4", "one"],
+            request(&mut client, b"GET /a r1\n").await
+        );
+        assert_eq!(
+            vec!["OK 4", "two"],
+            request(&mut client, b"GET /a 2\n").await
+        );
+        assert_eq!(vec!["OK 4", "two"], request(&mut client, b"GET /a\n").await);
+        assert_eq!(
+            vec!["ERR no such revision"],
+            request(&mut client, b"GET /a r3\n").await
+        );
+        assert_eq!(
+            vec!["ERR no such revision"],
+            request(&mut client, b"GET /a latest\n").await
+        );
+        // thrown away, and the line after the data is still read as a command
+        assert_eq!(
+            vec!["ERR text files only"],
+            request(&mut client, b"PUT /a 3\n\x00\x01\x02").await
+        );
+        let too_big = [b"PUT /a 65\n".as_slice(), &[b'x'; 65]].concat();
+        assert_eq!(
+            vec!["ERR file too large"],
+            request(&mut client, &too_big).await
+        );
+        assert_eq!(vec!["OK 4", "two"], request(&mut client, b"GET /a\n").await);
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let address = "127.0.0.1:8003";
+        let shutdown = start_server(address).await;
+
+        let mut client = TestClient::connect(address).await;
+        assert_eq!(Some(String::from("READY")), client.read_line().await);
+        request(&mut client, b"PUT /a.txt 1\na").await;
+        request(&mut client, b"PUT /dir/b.txt 1\nb").await;
+        request(&mut client, b"PUT /dir/b.txt 2\nbb").await;
+        request(&mut client, b"PUT /dir/sub/c.txt 1\nc").await;
+
+        assert_eq!(
+            vec!["OK 2", "a.txt r1", "dir/ DIR"],
+            request(&mut client, b"LIST /\n").await
+        );
+        assert_eq!(
+            vec!["OK 2", "b.txt r2", "sub/ DIR"],
+            request(&mut client, b"LIST /dir/\n").await
+        );
+        assert_eq!(vec!["OK 0"], request(&mut client, b"LIST /empty\n").await);
+        assert_eq!(
+            vec!["ERR usage: LIST dir"],
+            request(&mut client, b"LIST\n").await
+        );
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_paths() {
+        let address = "127.0.0.1:8004";
+        let shutdown = start_server(address).await;
+
+        let mut client = TestClient::connect(address).await;
+        assert_eq!(Some(String::from("READY")), client.read_line().await);
+        // no data is read for a put that's turned down on its name
+        assert_eq!(
+            vec!["ERR illegal file name"],
+            request(&mut client, b"PUT no-slash 3\n").await
+        );
+        assert_eq!(
+            vec!["ERR illegal file name"],
+            request(&mut client, b"GET /a//b\n").await
+        );
+        assert_eq!(
+            vec!["ERR illegal file name"],
+            request(&mut client, b"GET /dir/\n").await
+        );
+        assert_eq!(
+            vec!["ERR illegal file name"],
+            request(&mut client, b"PUT /a$b 1\n").await
+        );
+        assert_eq!(
+            vec!["ERR illegal dir name"],
+            request(&mut client, b"LIST dir\n").await
+        );
+        assert_eq!(
+            vec!["ERR usage: PUT file length newline data"],
+            request(&mut client, b"PUT /a.txt\n").await
+        );
+
+        // an unknown method gets an error and the connection closes
+        client.send(b"DELETE /a.txt\n").await;
+        assert_eq!(
+            "ERR illegal method: DELETE\n",
+            client.read_to_string().await
+        );
+
+        shutdown.shutdown();
+    }
+}
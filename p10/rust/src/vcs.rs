@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Every revision of every file anyone has put, kept in memory for as long as the server runs.
+#[derive(Debug, Default)]
+pub struct Store {
+    // path -> contents of each revision, r1 first
+    files: BTreeMap<String, Vec<Vec<u8>>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VcsError {
+    NoSuchFile,
+    NoSuchRevision,
+}
+
+impl fmt::Display for VcsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VcsError::NoSuchFile => write!(f, "no such file"),
+            VcsError::NoSuchRevision => write!(f, "no such revision"),
+        }
+    }
+}
+
+/// One line of a `LIST`, a file with its latest revision or a directory with something in it.
+#[derive(Debug, PartialEq)]
+pub enum Listing {
+    File { name: String, revision: usize },
+    Dir { name: String },
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listing::File { name, revision } => write!(f, "{} r{}", name, revision),
+            Listing::Dir { name } => write!(f, "{}/ DIR", name),
+        }
+    }
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store::default()
+    }
+
+    /// Stores `data` as the newest revision of `path` and returns its number, counting from 1.
+    /// The same contents as the newest revision get that revision back rather than a new one.
+    pub fn put(&mut self, path: &str, data: Vec<u8>) -> usize {
+        let revisions = self.files.entry(path.to_string()).or_default();
+        if revisions.last() != Some(&data) {
+            revisions.push(data);
+        }
+        revisions.len()
+    }
+
+    /// The contents of `revision` of `path`, the newest one when it's `None`.
+    pub fn get(&self, path: &str, revision: Option<usize>) -> Result<&[u8], VcsError> {
+        let revisions = self.files.get(path).ok_or(VcsError::NoSuchFile)?;
+        let revision = revision.unwrap_or(revisions.len());
+        revision
+            .checked_sub(1)
+            .and_then(|index| revisions.get(index))
+            .map(Vec::as_slice)
+            .ok_or(VcsError::NoSuchRevision)
+    }
+
+    /// What's directly inside `dir`, sorted by name. Directories only exist for as long as
+    /// there's a file somewhere under them. A name that's both a file and a directory is listed
+    /// as the file.
+    pub fn list(&self, dir: &str) -> Vec<Listing> {
+        let prefix = if dir.ends_with('/') {
+            dir.to_string()
+        } else {
+            format!("{}/", dir)
+        };
+        let mut entries = BTreeMap::new();
+        for (path, revisions) in self.files.range(prefix.clone()..) {
+            let Some(rest) = path.strip_prefix(&prefix) else {
+                // sorted, so nothing further on is under `dir` either
+                break;
+            };
+            match rest.split_once('/') {
+                Some((name, _)) => {
+                    entries.entry(name.to_string()).or_insert(Listing::Dir {
+                        name: name.to_string(),
+                    });
+                }
+                None => {
+                    entries.insert(
+                        rest.to_string(),
+                        Listing::File {
+                            name: rest.to_string(),
+                            revision: revisions.len(),
+                        },
+                    );
+                }
+            }
+        }
+        entries.into_values().collect()
+    }
+}
+
+/// An absolute path like `/a/b.txt`: no empty components and nothing but letters, digits, `.`,
+/// `_` and `-` in them.
+pub fn valid_file_name(path: &str) -> bool {
+    match path.strip_prefix('/') {
+        Some(rest) => rest.split('/').all(valid_component),
+        None => false,
+    }
+}
+
+/// Like a file name, but it can end in `/` and `/` on its own is the root.
+pub fn valid_dir_name(path: &str) -> bool {
+    if path == "/" {
+        return true;
+    }
+    valid_file_name(path.strip_suffix('/').unwrap_or(path))
+}
+
+fn valid_component(component: &str) -> bool {
+    !component.is_empty()
+        && component
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(&byte))
+}
+
+/// Files have to be text: printable ascii plus newlines and tabs.
+pub fn is_text(data: &[u8]) -> bool {
+    data.iter()
+        .all(|byte| (b' '..=b'~').contains(byte) || *byte == b'\n' || *byte == b'\t')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_then_get() {
+        let mut store = Store::new();
+        assert_eq!(Err(VcsError::NoSuchFile), store.get("/a.txt", None));
+        assert_eq!(1, store.put("/a.txt", b"hello\n".to_vec()));
+        assert_eq!(Ok(&b"hello\n"[..]), store.get("/a.txt", None));
+        assert_eq!(Ok(&b"hello\n"[..]), store.get("/a.txt", Some(1)));
+        // paths are case sensitive
+        assert_eq!(Err(VcsError::NoSuchFile), store.get("/A.txt", None));
+    }
+
+    #[test]
+    fn test_revisions() {
+        let mut store = Store::new();
+        assert_eq!(1, store.put("/a.txt", b"one\n".to_vec()));
+        assert_eq!(2, store.put("/a.txt", b"two\n".to_vec()));
+        // nothing changed, so no new revision
+        assert_eq!(2, store.put("/a.txt", b"two\n".to_vec()));
+        // going back to older contents is still a change
+        assert_eq!(3, store.put("/a.txt", b"one\n".to_vec()));
+
+        assert_eq!(Ok(&b"one\n"[..]), store.get("/a.txt", None));
+        assert_eq!(Ok(&b"two\n"[..]), store.get("/a.txt", Some(2)));
+        assert_eq!(Err(VcsError::NoSuchRevision), store.get("/a.txt", Some(0)));
+        assert_eq!(Err(VcsError::NoSuchRevision), store.get("/a.txt", Some(4)));
+        // each file has its own revisions
+        assert_eq!(1, store.put("/b.txt", b"two\n".to_vec()));
+    }
+
+    #[test]
+    fn test_list() {
+        let mut store = Store::new();
+        store.put("/a.txt", b"a".to_vec());
+        store.put("/dir/b.txt", b"b".to_vec());
+        store.put("/dir/b.txt", b"bb".to_vec());
+        store.put("/dir/sub/c.txt", b"c".to_vec());
+        store.put("/dir/sub/d.txt", b"d".to_vec());
+        // a sibling whose name starts the same isn't inside `/dir`
+        store.put("/dir2/e.txt", b"e".to_vec());
+
+        let root: Vec<String> = store.list("/").iter().map(Listing::to_string).collect();
+        assert_eq!(vec!["a.txt r1", "dir/ DIR", "dir2/ DIR"], root);
+        let dir: Vec<String> = store.list("/dir").iter().map(Listing::to_string).collect();
+        assert_eq!(vec!["b.txt r2", "sub/ DIR"], dir);
+        assert_eq!(store.list("/dir"), store.list("/dir/"));
+        assert!(store.list("/nothing").is_empty());
+        // a file isn't a directory
+        assert!(store.list("/a.txt").is_empty());
+    }
+
+    #[test]
+    fn test_file_and_dir_with_one_name() {
+        let mut store = Store::new();
+        store.put("/x", b"file".to_vec());
+        store.put("/x/y", b"inside".to_vec());
+        assert_eq!(
+            vec![Listing::File {
+                name: String::from("x"),
+                revision: 1
+            }],
+            store.list("/")
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        for valid in ["/a", "/a.txt", "/dir/sub/File-1_2.rs", "/.hidden"] {
+            assert!(valid_file_name(valid), "{}", valid);
+            assert!(valid_dir_name(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "/",
+            "a.txt",
+            "/a/",
+            "//a",
+            "/a//b",
+            "/a b",
+            "/a*",
+            "/caf\u{e9}",
+            "/a\n",
+        ] {
+            assert!(!valid_file_name(invalid), "{:?}", invalid);
+        }
+        assert!(valid_dir_name("/"));
+        assert!(valid_dir_name("/dir/"));
+        for invalid in ["", "dir", "//", "/dir//", "/a b/"] {
+            assert!(!valid_dir_name(invalid), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_is_text() {
+        assert!(is_text(b"fn main() {\n\tprintln!(\"hi\");\n}\n"));
+        assert!(is_text(b""));
+        assert!(!is_text(b"\x00binary"));
+        assert!(!is_text(b"carriage\r\n"));
+        assert!(!is_text("caf\u{e9}".as_bytes()));
+    }
+}
@@ -0,0 +1,435 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const PROTOCOL: &str = "pestcontrol";
+pub const VERSION: u32 = 1;
+
+// type, length and checksum, the least a message can be
+const OVERHEAD: usize = 6;
+// well beyond anything a real site visit or set of targets needs
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Every message in the protocol. Clients and authorities speak the same one, so the server
+/// sends and receives all of them.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Hello { protocol: String, version: u32 },
+    Error { message: String },
+    Ok,
+    DialAuthority { site: u32 },
+    TargetPopulations { site: u32, populations: Vec<Target> },
+    CreatePolicy { species: String, action: Action },
+    DeletePolicy { policy: u32 },
+    PolicyResult { policy: u32 },
+    SiteVisit { site: u32, populations: Vec<Count> },
+}
+
+impl Message {
+    /// The `Hello` both ends send first.
+    pub fn hello() -> Message {
+        Message::Hello {
+            protocol: PROTOCOL.to_string(),
+            version: VERSION,
+        }
+    }
+
+    fn message_type(&self) -> u8 {
+        match self {
+            Message::Hello { .. } => 0x50,
+            Message::Error { .. } => 0x51,
+            Message::Ok => 0x52,
+            Message::DialAuthority { .. } => 0x53,
+            Message::TargetPopulations { .. } => 0x54,
+            Message::CreatePolicy { .. } => 0x55,
+            Message::DeletePolicy { .. } => 0x56,
+            Message::PolicyResult { .. } => 0x57,
+            Message::SiteVisit { .. } => 0x58,
+        }
+    }
+
+    pub fn is_valid_hello(&self) -> bool {
+        matches!(self, Message::Hello { protocol, version } if protocol == PROTOCOL && *version == VERSION)
+    }
+}
+
+/// The range an authority wants a species kept within, inclusive at both ends.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Target {
+    pub species: String,
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Count {
+    pub species: String,
+    pub count: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Action {
+    Cull,
+    Conserve,
+}
+
+/// Decodes and encodes whole messages. Nothing is consumed from the buffer until the length
+/// field says a message has fully arrived, and then its checksum is checked before its content
+/// is looked at.
+#[derive(Debug, Default)]
+pub struct PestCodec;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// the content is all there by the time it's parsed, so running out is a malformed message
+fn need(buf: &[u8], len: usize) -> io::Result<()> {
+    if buf.remaining() < len {
+        return Err(invalid("message content is too short"));
+    }
+    Ok(())
+}
+
+fn read_u8(buf: &mut &[u8]) -> io::Result<u8> {
+    need(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+fn read_u32(buf: &mut &[u8]) -> io::Result<u32> {
+    need(buf, 4)?;
+    Ok(buf.get_u32())
+}
+
+fn read_str(buf: &mut &[u8]) -> io::Result<String> {
+    let len = read_u32(buf)? as usize;
+    need(buf, len)?;
+    let text = std::str::from_utf8(&buf[..len])
+        .map_err(|_| invalid("string isn't ascii"))?
+        .to_string();
+    buf.advance(len);
+    Ok(text)
+}
+
+// no capacity up front, the count hasn't been checked against the content yet
+fn read_array<T>(
+    buf: &mut &[u8],
+    read: impl Fn(&mut &[u8]) -> io::Result<T>,
+) -> io::Result<Vec<T>> {
+    let count = read_u32(buf)?;
+    (0..count).map(|_| read(buf)).collect()
+}
+
+fn read_action(buf: &mut &[u8]) -> io::Result<Action> {
+    match read_u8(buf)? {
+        0x90 => Ok(Action::Cull),
+        0xa0 => Ok(Action::Conserve),
+        action => Err(invalid(format!("unknown action {:#04x}", action))),
+    }
+}
+
+fn parse(message_type: u8, buf: &mut &[u8]) -> io::Result<Message> {
+    let message = match message_type {
+        0x50 => Message::Hello {
+            protocol: read_str(buf)?,
+            version: read_u32(buf)?,
+        },
+        0x51 => Message::Error {
+            message: read_str(buf)?,
+        },
+        0x52 => Message::Ok,
+        0x53 => Message::DialAuthority {
+            site: read_u32(buf)?,
+        },
+        0x54 => Message::TargetPopulations {
+            site: read_u32(buf)?,
+            populations: read_array(buf, |buf| {
+                Ok(Target {
+                    species: read_str(buf)?,
+                    min: read_u32(buf)?,
+                    max: read_u32(buf)?,
+                })
+            })?,
+        },
+        0x55 => Message::CreatePolicy {
+            species: read_str(buf)?,
+            action: read_action(buf)?,
+        },
+        0x56 => Message::DeletePolicy {
+            policy: read_u32(buf)?,
+        },
+        0x57 => Message::PolicyResult {
+            policy: read_u32(buf)?,
+        },
+        0x58 => Message::SiteVisit {
+            site: read_u32(buf)?,
+            populations: read_array(buf, |buf| {
+                Ok(Count {
+                    species: read_str(buf)?,
+                    count: read_u32(buf)?,
+                })
+            })?,
+        },
+        invalid_type => {
+            return Err(invalid(format!(
+                "unknown message type {:#04x}",
+                invalid_type
+            )))
+        }
+    };
+    Ok(message)
+}
+
+/// The byte that makes `message` add up to 0 (mod 256) once it's appended.
+fn checksum(message: &[u8]) -> u8 {
+    message
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// Whether a whole message, checksum included, adds up to 0.
+fn checksum_ok(message: &[u8]) -> bool {
+    checksum(message) == 0
+}
+
+impl Decoder for PestCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.len() < 5 {
+            return Ok(None);
+        }
+        let len = (&src[1..5]).get_u32() as usize;
+        if !(OVERHEAD..=MAX_MESSAGE_LEN).contains(&len) {
+            return Err(invalid(format!("bad message length {}", len)));
+        }
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        let message = src.split_to(len);
+        if !checksum_ok(&message) {
+            return Err(invalid("bad checksum"));
+        }
+        let mut content = &message[5..len - 1];
+        let parsed = parse(message[0], &mut content)?;
+        if !content.is_empty() {
+            return Err(invalid(format!(
+                "{} unused bytes in message content",
+                content.len()
+            )));
+        }
+        Ok(Some(parsed))
+    }
+}
+
+fn put_str(dst: &mut BytesMut, text: &str) {
+    dst.put_u32(text.len() as u32);
+    dst.put_slice(text.as_bytes());
+}
+
+impl Encoder<Message> for PestCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> io::Result<()> {
+        let start = dst.len();
+        dst.put_u8(message.message_type());
+        // filled in once the content's written
+        dst.put_u32(0);
+        match message {
+            Message::Hello { protocol, version } => {
+                put_str(dst, &protocol);
+                dst.put_u32(version);
+            }
+            Message::Error { message } => put_str(dst, &message),
+            Message::Ok => {}
+            Message::DialAuthority { site } => dst.put_u32(site),
+            Message::TargetPopulations { site, populations } => {
+                dst.put_u32(site);
+                dst.put_u32(populations.len() as u32);
+                for target in populations {
+                    put_str(dst, &target.species);
+                    dst.put_u32(target.min);
+                    dst.put_u32(target.max);
+                }
+            }
+            Message::CreatePolicy { species, action } => {
+                put_str(dst, &species);
+                dst.put_u8(match action {
+                    Action::Cull => 0x90,
+                    Action::Conserve => 0xa0,
+                });
+            }
+            Message::DeletePolicy { policy } => dst.put_u32(policy),
+            Message::PolicyResult { policy } => dst.put_u32(policy),
+            Message::SiteVisit { site, populations } => {
+                dst.put_u32(site);
+                dst.put_u32(populations.len() as u32);
+                for count in populations {
+                    put_str(dst, &count.species);
+                    dst.put_u32(count.count);
+                }
+            }
+        }
+        let len = (dst.len() - start + 1) as u32;
+        dst[start + 1..start + 5].copy_from_slice(&len.to_be_bytes());
+        dst.put_u8(checksum(&dst[start..]));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> io::Result<Vec<Message>> {
+        let mut codec = PestCodec;
+        let mut buffer = BytesMut::from(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = codec.decode(&mut buffer)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn encode(message: Message) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        PestCodec.encode(message, &mut buffer).unwrap();
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn test_decode() {
+        // examples from the spec
+        let messages = decode_all(&[
+            0x50, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x0b, 0x70, 0x65, 0x73, 0x74, 0x63,
+            0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0xce, // Hello
+            0x58, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x30, 0x39, 0x00, 0x00, 0x00, 0x02, 0x00,
+            0x00, 0x00, 0x03, 0x64, 0x6f, 0x67, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+            0x72, 0x61, 0x74, 0x00, 0x00, 0x00, 0x05, 0x8c, // SiteVisit
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                Message::hello(),
+                Message::SiteVisit {
+                    site: 12345,
+                    populations: vec![
+                        Count {
+                            species: String::from("dog"),
+                            count: 1
+                        },
+                        Count {
+                            species: String::from("rat"),
+                            count: 5
+                        },
+                    ]
+                },
+            ],
+            messages
+        );
+        assert!(messages[0].is_valid_hello());
+    }
+
+    #[test]
+    fn test_checksum() {
+        // Ok, with and without the right checksum
+        assert_eq!(
+            vec![Message::Ok],
+            decode_all(&[0x52, 0x00, 0x00, 0x00, 0x06, 0xa8]).unwrap()
+        );
+        let error = decode_all(&[0x52, 0x00, 0x00, 0x00, 0x06, 0xa9]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert_eq!("bad checksum", error.to_string());
+
+        // every encoded message adds up to 0
+        for message in [
+            Message::hello(),
+            Message::Error {
+                message: String::from("bad"),
+            },
+            Message::DeletePolicy { policy: 123 },
+        ] {
+            assert!(checksum_ok(&encode(message)));
+        }
+    }
+
+    // `bytes` with the checksum that makes them valid on the end
+    fn with_checksum(bytes: &[u8]) -> Vec<u8> {
+        [bytes, &[checksum(bytes)]].concat()
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        for malformed in [
+            // unknown type
+            with_checksum(&[0x99, 0x00, 0x00, 0x00, 0x06]),
+            // length shorter than a message can be
+            with_checksum(&[0x52, 0x00, 0x00, 0x00, 0x05]),
+            // a byte left over after the content
+            with_checksum(&[0x56, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x00, 0x01, 0x00]),
+            // a string longer than the message
+            with_checksum(&[0x51, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x05]),
+            // an array that claims more entries than there are
+            with_checksum(&[
+                0x58, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff,
+            ]),
+            // neither cull nor conserve
+            with_checksum(&[0x55, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x91]),
+        ] {
+            let error = decode_all(&malformed).unwrap_err();
+            assert_eq!(
+                io::ErrorKind::InvalidData,
+                error.kind(),
+                "{:02x?}",
+                malformed
+            );
+            assert_ne!("bad checksum", error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_decode_split_message() {
+        let message = encode(Message::DialAuthority { site: 12345 });
+        let mut codec = PestCodec;
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in message {
+            buffer.put_u8(byte);
+            if let Some(message) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(vec![Message::DialAuthority { site: 12345 }], decoded);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_encode() {
+        // examples from the spec
+        assert_eq!(
+            vec![0x53, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x30, 0x39, 0x3a],
+            encode(Message::DialAuthority { site: 12345 })
+        );
+        assert_eq!(
+            vec![
+                0x55, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x03, 0x64, 0x6f, 0x67, 0xa0, 0xc0
+            ],
+            encode(Message::CreatePolicy {
+                species: String::from("dog"),
+                action: Action::Conserve,
+            })
+        );
+        let targets = Message::TargetPopulations {
+            site: 12345,
+            populations: vec![Target {
+                species: String::from("dog"),
+                min: 1,
+                max: 3,
+            }],
+        };
+        assert_eq!(vec![targets.clone()], decode_all(&encode(targets)).unwrap());
+    }
+}
@@ -0,0 +1,472 @@
+mod codec;
+mod policy;
+
+use codec::{Message, PestCodec};
+use common::env::env_var;
+use common::observability::init_tracing_from_env;
+use common::{run_tcp_server, Peer, ServerConfig, ShutdownSignal, ShutdownToken};
+use futures::{SinkExt, StreamExt};
+use policy::{Change, Site};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex};
+use tokio_util::codec::Framed;
+use tracing::{error, info};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    dual_stack: bool,
+    // connections handled at once before the server stops accepting
+    max_connections: usize,
+    // where every site's authority is dialled
+    authority_address: String,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            authority_address: String::from("pestcontrol.protohackers.com:20547"),
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once,
+    /// `AUTHORITY_ADDRESS` is the authority server to dial and
+    /// `HEALTH_ADDRESS` where to answer liveness probes.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(authority_address) = env_var("AUTHORITY_ADDRESS") {
+            config.authority_address = authority_address;
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+fn protocol_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// A connection to one site's authority, and what we know of the site through it.
+#[derive(Debug)]
+struct Authority {
+    connection: Framed<TcpStream, PestCodec>,
+    site: Site,
+}
+
+impl Authority {
+    /// Connects, says hello and asks for `site`'s target populations.
+    async fn dial(address: &str, site: u32) -> io::Result<Authority> {
+        let stream = TcpStream::connect(address).await?;
+        let mut connection = Framed::new(stream, PestCodec);
+        connection.send(Message::hello()).await?;
+        let hello = receive(&mut connection).await?;
+        if !hello.is_valid_hello() {
+            return Err(protocol_error(format!("bad hello {:?}", hello)));
+        }
+        let populations = match request(&mut connection, Message::DialAuthority { site }).await? {
+            Message::TargetPopulations {
+                site: target_site,
+                populations,
+            } if target_site == site => populations,
+            reply => return Err(protocol_error(format!("expected targets, got {:?}", reply))),
+        };
+        info!("Site {} wants {:?}", site, populations);
+        Ok(Authority {
+            connection,
+            site: Site::new(populations),
+        })
+    }
+
+    /// Creates and deletes policies until the site's are what `counts` calls for.
+    async fn update(&mut self, counts: &HashMap<String, u32>) -> io::Result<()> {
+        for change in self.site.changes(counts) {
+            match change {
+                Change::Delete { species, policy } => {
+                    match request(&mut self.connection, Message::DeletePolicy { policy }).await? {
+                        Message::Ok => self.site.deleted(&species),
+                        reply => {
+                            return Err(protocol_error(format!("expected ok, got {:?}", reply)))
+                        }
+                    }
+                }
+                Change::Create { species, action } => {
+                    let create = Message::CreatePolicy {
+                        species: species.clone(),
+                        action,
+                    };
+                    match request(&mut self.connection, create).await? {
+                        Message::PolicyResult { policy } => {
+                            self.site.created(&species, action, policy)
+                        }
+                        reply => {
+                            return Err(protocol_error(format!(
+                                "expected a policy, got {:?}",
+                                reply
+                            )))
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn receive(connection: &mut Framed<TcpStream, PestCodec>) -> io::Result<Message> {
+    match connection.next().await {
+        Some(Ok(Message::Error { message })) => Err(protocol_error(message)),
+        Some(result) => result,
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+async fn request(
+    connection: &mut Framed<TcpStream, PestCodec>,
+    message: Message,
+) -> io::Result<Message> {
+    connection.send(message).await?;
+    receive(connection).await
+}
+
+/// Each site's authority, dialled the first time the site's visited. Visits to one site take
+/// turns, so its policies are only ever changed by one of them at a time.
+#[derive(Debug, Default)]
+struct Sites {
+    authority_address: String,
+    authorities: Mutex<HashMap<u32, Arc<Mutex<Option<Authority>>>>>,
+}
+
+impl Sites {
+    async fn visit(&self, site: u32, counts: &HashMap<String, u32>) -> io::Result<()> {
+        let slot = self
+            .authorities
+            .lock()
+            .await
+            .entry(site)
+            .or_default()
+            .clone();
+        let mut slot = slot.lock().await;
+        let authority = match slot.as_mut() {
+            Some(authority) => authority,
+            None => slot.insert(Authority::dial(&self.authority_address, site).await?),
+        };
+        let result = authority.update(counts).await;
+        if result.is_err() {
+            // no telling what state the authority's in, start again from a fresh connection
+            *slot = None;
+        }
+        result
+    }
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        health_address: config.health_address.clone(),
+        ..ServerConfig::default()
+    };
+    let sites = Arc::new(Sites {
+        authority_address: config.authority_address,
+        ..Sites::default()
+    });
+    run_tcp_server(
+        &server_config,
+        ready_signal,
+        shutdown,
+        move |stream, remote_addr, shutdown_signal| {
+            let sites = sites.clone();
+            async move {
+                handle_client(stream, remote_addr, sites, shutdown_signal).await;
+            }
+        },
+    )
+    .await;
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    remote_addr: Peer,
+    sites: Arc<Sites>,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut framed = Framed::new(stream, PestCodec);
+    if let Err(e) = framed.send(Message::hello()).await {
+        info!("Couldn't say hello to {:?} : {:?}", remote_addr, e);
+        return;
+    }
+    let mut greeted = false;
+
+    // breaks out with the error to send the client, if it did something illegal
+    let illegal = loop {
+        let message = tokio::select! {
+            message = framed.next() => message,
+            _ = shutdown.recv() => break None,
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                info!("Bad message from {:?} : {:?}", remote_addr, e);
+                break Some("illegal msg");
+            }
+            None => break None,
+        };
+        if !greeted {
+            if !message.is_valid_hello() {
+                break Some("expected hello");
+            }
+            greeted = true;
+            continue;
+        }
+        let Message::SiteVisit { site, populations } = message else {
+            break Some("unexpected msg");
+        };
+        let Some(counts) = policy::tally(populations) else {
+            break Some("conflicting counts");
+        };
+        // the client has done its part, trouble with the authority is ours alone
+        if let Err(e) = sites.visit(site, &counts).await {
+            error!("Couldn't update policies for site {} : {:?}", site, e);
+        }
+    };
+
+    if let Some(message) = illegal {
+        info!("Disconnecting {:?} : {}", remote_addr, message);
+        let _ = framed
+            .send(Message::Error {
+                message: message.to_string(),
+            })
+            .await;
+    }
+    info!("Closing connection for {:?}", remote_addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use codec::{Action, Count, Target};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    async fn start_server(address: &str, authority_address: &str) -> ShutdownToken {
+        let config = Config {
+            address: address.to_string(),
+            authority_address: authority_address.to_string(),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+        shutdown
+    }
+
+    /// An authority that wants 1 to 3 dogs at every site, agrees to everything and passes on
+    /// every policy message it gets.
+    async fn start_authority(address: &str) -> mpsc::UnboundedReceiver<Message> {
+        let listener = TcpListener::bind(address).await.unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut next_policy = 1;
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = Framed::new(stream, PestCodec);
+            connection.send(Message::hello()).await.unwrap();
+            while let Some(Ok(message)) = connection.next().await {
+                let reply = match &message {
+                    Message::Hello { .. } => continue,
+                    Message::DialAuthority { site } => Message::TargetPopulations {
+                        site: *site,
+                        populations: vec![Target {
+                            species: String::from("dog"),
+                            min: 1,
+                            max: 3,
+                        }],
+                    },
+                    Message::CreatePolicy { .. } => {
+                        next_policy += 1;
+                        Message::PolicyResult {
+                            policy: next_policy - 1,
+                        }
+                    }
+                    _ => Message::Ok,
+                };
+                let _ = sender.send(message);
+                connection.send(reply).await.unwrap();
+            }
+        });
+        receiver
+    }
+
+    async fn connect(address: &str) -> Framed<TcpStream, PestCodec> {
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut client = Framed::new(stream, PestCodec);
+        assert_eq!(Message::hello(), next(&mut client).await);
+        client
+    }
+
+    async fn next(client: &mut Framed<TcpStream, PestCodec>) -> Message {
+        time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("Timed out waiting for a message")
+            .expect("Connection closed")
+            .expect("Couldn't decode message")
+    }
+
+    fn visit(dogs: u32) -> Message {
+        Message::SiteVisit {
+            site: 12345,
+            populations: vec![Count {
+                species: String::from("dog"),
+                count: dogs,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_site_visits() {
+        let address = "127.0.0.1:8001";
+        let authority_address = "127.0.0.1:8002";
+        let mut authority = start_authority(authority_address).await;
+        let shutdown = start_server(address, authority_address).await;
+
+        let mut client = connect(address).await;
+        client.send(Message::hello()).await.unwrap();
+        client.send(visit(5)).await.unwrap();
+        // nothing to change, so nothing's sent
+        client.send(visit(4)).await.unwrap();
+        client.send(visit(0)).await.unwrap();
+
+        let mut messages = Vec::new();
+        for _ in 0..4 {
+            let message = time::timeout(Duration::from_secs(5), authority.recv())
+                .await
+                .expect("Timed out waiting for the authority")
+                .unwrap();
+            messages.push(message);
+        }
+        assert_eq!(
+            vec![
+                Message::DialAuthority { site: 12345 },
+                Message::CreatePolicy {
+                    species: String::from("dog"),
+                    action: Action::Cull
+                },
+                Message::DeletePolicy { policy: 1 },
+                Message::CreatePolicy {
+                    species: String::from("dog"),
+                    action: Action::Conserve
+                },
+            ],
+            messages
+        );
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_illegal_messages() {
+        let address = "127.0.0.1:8003";
+        // never dialled
+        let shutdown = start_server(address, "127.0.0.1:1").await;
+
+        // anything before hello
+        let mut client = connect(address).await;
+        client.send(visit(1)).await.unwrap();
+        assert!(matches!(next(&mut client).await, Message::Error { .. }));
+        assert!(client.next().await.is_none());
+
+        // the wrong protocol
+        let mut client = connect(address).await;
+        client
+            .send(Message::Hello {
+                protocol: String::from("pestcontrol"),
+                version: 2,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(next(&mut client).await, Message::Error { .. }));
+
+        // a bad checksum
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &[0x52, 0x00, 0x00, 0x00, 0x06, 0x00])
+            .await
+            .unwrap();
+        let mut client = Framed::new(stream, PestCodec);
+        assert_eq!(Message::hello(), next(&mut client).await);
+        assert!(matches!(next(&mut client).await, Message::Error { .. }));
+
+        // a species counted twice, differently
+        let mut client = connect(address).await;
+        client.send(Message::hello()).await.unwrap();
+        client
+            .send(Message::SiteVisit {
+                site: 1,
+                populations: vec![
+                    Count {
+                        species: String::from("dog"),
+                        count: 1,
+                    },
+                    Count {
+                        species: String::from("dog"),
+                        count: 2,
+                    },
+                ],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(next(&mut client).await, Message::Error { .. }));
+
+        shutdown.shutdown();
+    }
+}
@@ -0,0 +1,225 @@
+use crate::codec::{Action, Count, Target};
+use std::collections::{BTreeMap, HashMap};
+
+/// A change to make at a site's authority to bring it in line with a visit.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Delete { species: String, policy: u32 },
+    Create { species: String, action: Action },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Policy {
+    id: u32,
+    action: Action,
+}
+
+/// What one site's authority wants and the policies in place there, as far as we've told it.
+#[derive(Debug, Default)]
+pub struct Site {
+    // species -> (min, max), sorted so changes come out in a steady order
+    targets: BTreeMap<String, (u32, u32)>,
+    policies: HashMap<String, Policy>,
+}
+
+impl Site {
+    pub fn new(targets: Vec<Target>) -> Site {
+        Site {
+            targets: targets
+                .into_iter()
+                .map(|target| (target.species, (target.min, target.max)))
+                .collect(),
+            policies: HashMap::new(),
+        }
+    }
+
+    /// The changes that get every species with a target the policy `counts` call for: conserve
+    /// below `min`, cull above `max`, nothing in between. A species that wasn't counted has 0 of
+    /// it, and one the authority has no target for is left alone. A policy that's already right
+    /// is left in place, and a wrong one is deleted before its replacement is created.
+    pub fn changes(&self, counts: &HashMap<String, u32>) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (species, (min, max)) in &self.targets {
+            let count = counts.get(species).copied().unwrap_or(0);
+            let wanted = if count < *min {
+                Some(Action::Conserve)
+            } else if count > *max {
+                Some(Action::Cull)
+            } else {
+                None
+            };
+            let current = self.policies.get(species);
+            if current.map(|policy| policy.action) == wanted {
+                continue;
+            }
+            if let Some(policy) = current {
+                changes.push(Change::Delete {
+                    species: species.clone(),
+                    policy: policy.id,
+                });
+            }
+            if let Some(action) = wanted {
+                changes.push(Change::Create {
+                    species: species.clone(),
+                    action,
+                });
+            }
+        }
+        changes
+    }
+
+    /// Records the authority accepting a `Create`, under the id it gave the policy.
+    pub fn created(&mut self, species: &str, action: Action, id: u32) {
+        self.policies
+            .insert(species.to_string(), Policy { id, action });
+    }
+
+    /// Records the authority accepting a `Delete`.
+    pub fn deleted(&mut self, species: &str) {
+        self.policies.remove(species);
+    }
+}
+
+/// The count of each species in a visit. A species listed twice with different counts makes
+/// the whole visit invalid, listing it twice with the same count doesn't.
+pub fn tally(populations: Vec<Count>) -> Option<HashMap<String, u32>> {
+    let mut counts = HashMap::new();
+    for Count { species, count } in populations {
+        if *counts.entry(species).or_insert(count) != count {
+            return None;
+        }
+    }
+    Some(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site() -> Site {
+        Site::new(vec![
+            Target {
+                species: String::from("dog"),
+                min: 1,
+                max: 3,
+            },
+            Target {
+                species: String::from("rat"),
+                min: 0,
+                max: 10,
+            },
+        ])
+    }
+
+    fn counts(counts: &[(&str, u32)]) -> HashMap<String, u32> {
+        counts
+            .iter()
+            .map(|(species, count)| (species.to_string(), *count))
+            .collect()
+    }
+
+    // the authority going along with every change, numbering policies from `next_id`
+    fn apply(site: &mut Site, changes: &[Change], next_id: &mut u32) {
+        for change in changes {
+            match change {
+                Change::Delete { species, .. } => site.deleted(species),
+                Change::Create { species, action } => {
+                    site.created(species, *action, *next_id);
+                    *next_id += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let site = site();
+        assert_eq!(
+            vec![Change::Create {
+                species: String::from("dog"),
+                action: Action::Cull
+            }],
+            site.changes(&counts(&[("dog", 4), ("rat", 5)]))
+        );
+        // not counted means none were seen
+        assert_eq!(
+            vec![Change::Create {
+                species: String::from("dog"),
+                action: Action::Conserve
+            }],
+            site.changes(&counts(&[]))
+        );
+        // within bounds, and species without a target are nobody's business
+        assert!(site
+            .changes(&counts(&[("dog", 1), ("cat", 1000)]))
+            .is_empty());
+        assert!(site.changes(&counts(&[("dog", 3)])).is_empty());
+    }
+
+    #[test]
+    fn test_policy_dedup() {
+        let mut site = site();
+        let mut next_id = 1;
+        let changes = site.changes(&counts(&[("dog", 5), ("rat", 20)]));
+        assert_eq!(2, changes.len());
+        apply(&mut site, &changes, &mut next_id);
+
+        // the same visit again, or different counts still out the same side, change nothing
+        assert!(site.changes(&counts(&[("dog", 5), ("rat", 20)])).is_empty());
+        assert!(site
+            .changes(&counts(&[("dog", 100), ("rat", 11)]))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_policy_transitions() {
+        let mut site = site();
+        let mut next_id = 1;
+
+        // too many: cull
+        let changes = site.changes(&counts(&[("dog", 4)]));
+        apply(&mut site, &changes, &mut next_id);
+
+        // too few: the cull goes before the conserve comes in
+        let changes = site.changes(&counts(&[("dog", 0)]));
+        assert_eq!(
+            vec![
+                Change::Delete {
+                    species: String::from("dog"),
+                    policy: 1
+                },
+                Change::Create {
+                    species: String::from("dog"),
+                    action: Action::Conserve
+                },
+            ],
+            changes
+        );
+        apply(&mut site, &changes, &mut next_id);
+
+        // back within bounds: no policy at all
+        let changes = site.changes(&counts(&[("dog", 2)]));
+        assert_eq!(
+            vec![Change::Delete {
+                species: String::from("dog"),
+                policy: 2
+            }],
+            changes
+        );
+        apply(&mut site, &changes, &mut next_id);
+        assert!(site.changes(&counts(&[("dog", 2)])).is_empty());
+    }
+
+    #[test]
+    fn test_tally() {
+        let count = |species: &str, count| Count {
+            species: species.to_string(),
+            count,
+        };
+        assert_eq!(
+            Some(counts(&[("dog", 1), ("rat", 5)])),
+            tally(vec![count("dog", 1), count("rat", 5), count("dog", 1)])
+        );
+        assert_eq!(None, tally(vec![count("dog", 1), count("dog", 2)]));
+    }
+}
@@ -0,0 +1,149 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{SinkExt, StreamExt};
+use means_to_an_end::codec::{Message, PriceCodec, FRAME_LEN};
+use means_to_an_end::store::{PriceStore, QueryRange};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_util::codec::{Framed, FramedRead};
+
+// inserts sent per iteration, so throughput reads as frames/sec
+const FRAMES: u64 = 10_000;
+
+fn inserts() -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(FRAMES as usize * FRAME_LEN);
+    for timestamp in 0..FRAMES as i32 {
+        bytes.push(b'I');
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+        bytes.extend_from_slice(&100_i32.to_be_bytes());
+    }
+    bytes
+}
+
+// a connected pair over loopback, so reads are real syscalls
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+// how sessions used to read: a byte then two i32s, each its own read on the socket
+async fn read_fields<R: AsyncRead + Unpin>(reader: &mut R) -> Message {
+    let message_type = reader.read_u8().await.unwrap();
+    let timestamp = reader.read_i32().await.unwrap();
+    let price = reader.read_i32().await.unwrap();
+    assert_eq!(b'I', message_type);
+    Message::Insert { timestamp, price }
+}
+
+fn bench_read_inserts(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let inserts = inserts();
+    let mut group = c.benchmark_group("read_inserts");
+    group.throughput(Throughput::Elements(FRAMES));
+
+    group.bench_function(BenchmarkId::new("unbuffered", FRAMES), |b| {
+        let (mut client, mut server) = runtime.block_on(socket_pair());
+        b.iter(|| {
+            runtime.block_on(async {
+                let write = client.write_all(&inserts);
+                let read = async {
+                    for _ in 0..FRAMES {
+                        read_fields(&mut server).await;
+                    }
+                };
+                let (written, ()) = tokio::join!(write, read);
+                written.unwrap();
+            })
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("buf_reader", FRAMES), |b| {
+        let (mut client, server) = runtime.block_on(socket_pair());
+        let mut server = BufReader::new(server);
+        b.iter(|| {
+            runtime.block_on(async {
+                let write = client.write_all(&inserts);
+                let read = async {
+                    for _ in 0..FRAMES {
+                        read_fields(&mut server).await;
+                    }
+                };
+                let (written, ()) = tokio::join!(write, read);
+                written.unwrap();
+            })
+        })
+    });
+
+    // what handle_session does now
+    group.bench_function(BenchmarkId::new("framed", FRAMES), |b| {
+        let (mut client, server) = runtime.block_on(socket_pair());
+        let mut frames = FramedRead::new(server, PriceCodec::new());
+        b.iter(|| {
+            runtime.block_on(async {
+                let write = client.write_all(&inserts);
+                let read = async {
+                    for _ in 0..FRAMES {
+                        frames.next().await.unwrap().unwrap();
+                    }
+                };
+                let (written, ()) = tokio::join!(write, read);
+                written.unwrap();
+            })
+        })
+    });
+
+    group.finish();
+}
+
+// a burst of inserts and the query after them, read, stored and answered the way
+// handle_session does it, minus the limits and metrics
+fn bench_insert_burst(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut burst = inserts();
+    burst.push(b'Q');
+    burst.extend_from_slice(&0_i32.to_be_bytes());
+    burst.extend_from_slice(&(FRAMES as i32).to_be_bytes());
+    let mut group = c.benchmark_group("insert_burst");
+    group.throughput(Throughput::Elements(FRAMES + 1));
+
+    group.bench_function(BenchmarkId::new("session", FRAMES), |b| {
+        let (mut client, server) = runtime.block_on(socket_pair());
+        let mut framed = Framed::new(server, PriceCodec::new());
+        b.iter(|| {
+            runtime.block_on(async {
+                let send = async {
+                    client.write_all(&burst).await.unwrap();
+                    client.read_i32().await.unwrap()
+                };
+                let session = async {
+                    let mut store = PriceStore::new();
+                    loop {
+                        match framed.next().await.unwrap().unwrap() {
+                            Message::Insert { timestamp, price } => store.insert(timestamp, price),
+                            Message::Query { min_time, max_time } => {
+                                let query = QueryRange {
+                                    start: min_time,
+                                    end: max_time,
+                                };
+                                framed.send(store.average(query)).await.unwrap();
+                                break;
+                            }
+                            other => panic!("Unexpected {:?}", other),
+                        }
+                    }
+                };
+                let (answer, ()) = tokio::join!(send, session);
+                assert_eq!(100, answer);
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_inserts, bench_insert_burst);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use means_to_an_end::store::{PricePoint, PriceStore, QueryRange};
+
+const POINTS: i32 = 100_000;
+// queries run per iteration, so throughput reads as queries/sec
+const QUERIES: i32 = 10_000;
+
+// spread the inserts out so they don't arrive already sorted
+fn timestamps() -> impl Iterator<Item = i32> {
+    (0..POINTS).map(|timestamp| (timestamp * 7919) % POINTS)
+}
+
+// narrow windows, a handful of points each
+fn narrow(i: i32) -> QueryRange {
+    let start = (i * 104_729) % POINTS;
+    QueryRange {
+        start,
+        end: start + 10,
+    }
+}
+
+// the old approach, a scan over every point in the session
+fn scan_avg_query(storage: &[PricePoint], query: QueryRange) -> i32 {
+    let (count, sum) = storage
+        .iter()
+        .filter(|price_point| price_point.0 >= query.start && price_point.0 <= query.end)
+        .fold((0_i128, 0_i128), |acc, price_point| {
+            (acc.0 + 1, acc.1 + price_point.1 as i128)
+        });
+    if count == 0 {
+        0
+    } else {
+        (sum / count) as i32
+    }
+}
+
+fn bench_avg_query(c: &mut Criterion) {
+    let unsorted: Vec<PricePoint> = timestamps()
+        .map(|timestamp| PricePoint(timestamp, timestamp))
+        .collect();
+    let mut store = PriceStore::new();
+    for timestamp in timestamps() {
+        store.insert(timestamp, timestamp);
+    }
+    for i in 0..QUERIES {
+        assert_eq!(
+            scan_avg_query(&unsorted, narrow(i)),
+            store.average(narrow(i))
+        );
+    }
+
+    let mut group = c.benchmark_group("avg_query");
+    group.throughput(Throughput::Elements(QUERIES as u64));
+    group.bench_function(BenchmarkId::new("scan", POINTS), |b| {
+        b.iter(|| {
+            for i in 0..QUERIES {
+                black_box(scan_avg_query(&unsorted, black_box(narrow(i))));
+            }
+        })
+    });
+    // what sessions do now
+    group.bench_function(BenchmarkId::new("binary_search", POINTS), |b| {
+        b.iter(|| {
+            for i in 0..QUERIES {
+                black_box(store.average(black_box(narrow(i))));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_avg_query);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use means_to_an_end::codec::{Message, PriceCodec, FRAME_LEN};
+use tokio_util::codec::Decoder;
+
+// What the codec should make of a complete frame, worked out straight from the bytes.
+fn expected_message(frame: &[u8]) -> Option<Message> {
+    let field_1 = i32::from_be_bytes(frame[1..5].try_into().unwrap());
+    let field_2 = i32::from_be_bytes(frame[5..9].try_into().unwrap());
+    match frame[0] {
+        b'I' => Some(Message::Insert {
+            timestamp: field_1,
+            price: field_2,
+        }),
+        b'Q' => Some(Message::Query {
+            min_time: field_1,
+            max_time: field_2,
+        }),
+        b'C' => Some(Message::Count {
+            min_time: field_1,
+            max_time: field_2,
+        }),
+        _ => None,
+    }
+}
+
+fuzz_target!(|input: &[u8]| {
+    // the first byte picks how the rest gets split up, like tcp segments would
+    let Some((&chunk_size, data)) = input.split_first() else {
+        return;
+    };
+    let chunk_size = chunk_size as usize % 16 + 1;
+
+    let mut codec = PriceCodec::new();
+    let mut buffer = BytesMut::new();
+    // start of the first frame that hasn't been decoded yet
+    let mut offset = 0;
+    for chunk in data.chunks(chunk_size) {
+        buffer.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(message)) => {
+                    let frame = &data[offset..offset + FRAME_LEN];
+                    assert_eq!(expected_message(frame), Some(message));
+                    offset += FRAME_LEN;
+                }
+                Ok(None) => {
+                    assert!(buffer.len() < FRAME_LEN);
+                    break;
+                }
+                Err(_) => {
+                    // only a full frame with an unknown type is an error
+                    let frame = &data[offset..offset + FRAME_LEN];
+                    assert_eq!(None, expected_message(frame));
+                    return;
+                }
+            }
+        }
+    }
+
+    // the stream ended, leftovers are a truncated frame
+    match codec.decode_eof(&mut buffer) {
+        Ok(None) => assert_eq!(offset, data.len()),
+        Ok(Some(message)) => panic!("decoded {:?} out of a partial frame", message),
+        Err(_) => assert!(data.len() - offset < FRAME_LEN),
+    }
+});
@@ -0,0 +1,194 @@
+use bytes::{Buf, Bytes};
+use common::{BigEndianFrameCodec, Frame};
+use std::io;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Insert { timestamp: i32, price: i32 },
+    Query { min_time: i32, max_time: i32 },
+    // how many points are in the range, rather than their mean
+    Count { min_time: i32, max_time: i32 },
+}
+
+// 1 byte type, 4 bytes field_1, 4 bytes field_2
+pub const FRAME_LEN: usize = 9;
+
+impl Frame for Message {
+    const LEN: usize = FRAME_LEN;
+
+    fn decode(fields: &mut Bytes) -> io::Result<Message> {
+        let message_type = fields.get_u8();
+        let field_1 = fields.get_i32();
+        let field_2 = fields.get_i32();
+        match message_type {
+            b'I' => Ok(Message::Insert {
+                timestamp: field_1,
+                price: field_2,
+            }),
+            b'Q' => Ok(Message::Query {
+                min_time: field_1,
+                max_time: field_2,
+            }),
+            b'C' => Ok(Message::Count {
+                min_time: field_1,
+                max_time: field_2,
+            }),
+            invalid_type => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type {:?}", char::from(invalid_type)),
+            )),
+        }
+    }
+}
+
+/// Decodes 9 byte request frames into `Message`s and encodes query results as big endian i32s.
+pub type PriceCodec = BigEndianFrameCodec<Message>;
+
+#[cfg(test)]
+mod parsing_tests {
+
+    use super::*;
+
+    use bytes::{BufMut, BytesMut};
+    use common::observability::init_tracing;
+    use futures::StreamExt;
+    use std::io::Cursor;
+    use tokio_util::codec::{Decoder, FramedRead};
+    use tracing::info;
+
+    async fn read_message(bytes: Vec<u8>) -> Option<io::Result<Message>> {
+        FramedRead::new(Cursor::new(bytes), PriceCodec::new())
+            .next()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_parsing() {
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        let result = read_message(vec![
+            0x51, // Q
+            0x00, 0x00, 0x00, 0x01, // 1
+            0x00, 0x00, 0x00, 0x02, // 2
+        ])
+        .await;
+        info!("results = {:?}", result);
+        assert_eq!(
+            Message::Query {
+                min_time: 1,
+                max_time: 2
+            },
+            result.unwrap().unwrap()
+        );
+
+        let result = read_message(vec![
+            0x49, // I
+            0x00, 0x00, 0x30, 0x39, // 12345
+            0xff, 0xff, 0xff, 0x9c, // -100
+        ])
+        .await;
+        assert_eq!(
+            Message::Insert {
+                timestamp: 12345,
+                price: -100
+            },
+            result.unwrap().unwrap()
+        );
+
+        let result = read_message(vec![
+            0x43, // C
+            0xff, 0xff, 0xff, 0xff, // -1
+            0x00, 0x00, 0x00, 0x02, // 2
+        ])
+        .await;
+        assert_eq!(
+            Message::Count {
+                min_time: -1,
+                max_time: 2
+            },
+            result.unwrap().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parsing_invalid_type() {
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        let result = read_message(vec![
+            0x58, // X
+            0x00, 0x00, 0x00, 0x01, // 1
+            0x00, 0x00, 0x00, 0x02, // 2
+        ])
+        .await;
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            result.unwrap().unwrap_err().kind()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parsing_empty() {
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        let result = read_message(vec![]).await;
+        info!("results = {:?}", result);
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_parsing_truncated() {
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        // not enough bytes for a whole frame
+        let result = read_message(vec![0x51, 0x00, 0x00, 0x00]).await;
+        info!("results = {:?}", result);
+        assert_eq!(
+            io::ErrorKind::UnexpectedEof,
+            result.unwrap().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_decode_eof() {
+        let mut codec = PriceCodec::new();
+
+        // closed between frames
+        let mut buffer = BytesMut::from(
+            &[
+                0x49, // I
+                0x00, 0x00, 0x00, 0x05, // 5
+                0x00, 0x00, 0x00, 0x64, // 100
+            ][..],
+        );
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_some());
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+
+        // closed mid frame
+        let mut buffer = BytesMut::from(&[0x49, 0x00, 0x00, 0x00, 0x05, 0x00][..]);
+        let result = codec.decode_eof(&mut buffer);
+        assert_eq!(io::ErrorKind::UnexpectedEof, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_decode_split_frame() {
+        let frame = [
+            0x49, // I
+            0x00, 0x00, 0x00, 0x05, // 5
+            0x00, 0x00, 0x00, 0x64, // 100
+        ];
+        let mut codec = PriceCodec::new();
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        // feed one byte at a time, like a client with tiny TCP segments
+        for byte in frame {
+            buffer.put_u8(byte);
+            if let Some(message) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(
+            vec![Message::Insert {
+                timestamp: 5,
+                price: 100
+            }],
+            decoded
+        );
+        assert!(buffer.is_empty());
+    }
+}
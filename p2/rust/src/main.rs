@@ -1,229 +1,494 @@
+use common::env::env_var;
+use common::metrics::{self, IntCounter, Registry};
+use common::observability::init_tracing_from_env;
+use common::{
+    finish_write, run_tcp_server, Connections, Peer, ServerConfig, ShutdownSignal, ShutdownToken,
+    WhenFull,
+};
+use futures::{SinkExt, StreamExt};
+use means_to_an_end::codec::{Message, PriceCodec, FRAME_LEN};
+use means_to_an_end::store::{PriceStore, QueryRange};
 use std::io;
-use std::net::SocketAddr;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
-use tracing::dispatcher::DefaultGuard;
-use tracing::{debug, error, info};
-use tracing_subscriber::prelude::*;
-
-#[tokio::main]
-async fn main() {
-    let _guard = setup_tracing(tracing::Level::DEBUG);
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::codec::Framed;
+use tracing::{dispatcher, error, info, info_span, instrument, warn, Dispatch, Instrument, Span};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
     info!("Hello, world!");
 
     let (ready_sender, _ready_receiver) = oneshot::channel();
-    serve(ready_sender).await;
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
 }
 
-/**
- * Once the guard is dropped, the global default is reset.
- * This helps with testing because there can only be 1 global default at a time.
- * Using `init()` will fail, so that's why we use `set_default()`
- */
-fn setup_tracing(level: tracing::Level) -> DefaultGuard {
-    let guard = tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_filter(tracing_subscriber::filter::LevelFilter::from_level(level)),
-        )
-        .set_default();
-    info!("Tracing has been setup");
-    guard
+/// What a session does with inserts once it's holding `max_points`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum FullPolicy {
+    /// Drop them with a warning. Inserts get no response, so the client can't tell.
+    #[default]
+    Ignore,
+    /// Close the session.
+    Close,
 }
 
-// Time, Price
-#[derive(Debug)]
-struct PricePoint(i32, i32);
+impl FromStr for FullPolicy {
+    type Err = String;
 
-fn handle_insert(storage: &mut Vec<PricePoint>, point: PricePoint) {
-    debug!("inserting: {:?}", point);
-    storage.push(point);
+    fn from_str(policy: &str) -> Result<FullPolicy, String> {
+        match policy {
+            "ignore" => Ok(FullPolicy::Ignore),
+            "close" => Ok(FullPolicy::Close),
+            other => Err(format!("unknown full session policy {:?}", other)),
+        }
+    }
 }
 
-#[derive(Debug)]
-struct QueryRange {
-    start: i32,
-    end: i32,
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    dual_stack: bool,
+    // connections handled at once before the server stops accepting
+    max_connections: usize,
+    // connections the OS holds waiting to be accepted
+    backlog: u32,
+    // tasks accepting connections side by side
+    acceptors: usize,
+    // connections a single IP address can have open at once, unlimited unless set
+    max_connections_per_ip: Option<usize>,
+    // how long a session can go without a complete message before it's closed
+    idle_timeout: Duration,
+    // how far behind a session's newest timestamp points are kept, everything when unset
+    retention_window: Option<u32>,
+    // most points a session holds at once, no limit when unset
+    max_points: Option<usize>,
+    // what happens to inserts past `max_points`
+    full_policy: FullPolicy,
+    // how long a new connection has to send its first byte, no limit when unset
+    first_byte_timeout: Option<Duration>,
+    // how long connections get to finish after shutdown before they're cut off
+    drain_timeout: Duration,
+    // where to serve prometheus metrics from, off unless set
+    metrics_address: Option<String>,
+    // where to serve a plain text stats snapshot from, off unless set
+    admin_address: Option<String>,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+    // where to record everything clients send, one file per connection, off unless set
+    record_dir: Option<PathBuf>,
+    // clients come through a load balancer that starts each connection with a PROXY header
+    proxy_protocol: bool,
+    // queries spanning at least this many points are averaged off the async worker threads
+    blocking_query_points: usize,
+    // most points a min/max/median/variance/stddev will look at before giving up, no limit when
+    // unset. The spec's average always takes in the whole range
+    query_cap: Option<usize>,
 }
 
-fn handle_avg_query(storage: &Vec<PricePoint>, query: QueryRange) -> i32 {
-    debug!("query: {:?}", query);
-    if query.start > query.end {
-        return 0;
-    }
-
-    let result = storage
-        .iter()
-        .filter(|price_point| price_point.0 >= query.start && price_point.0 <= query.end)
-        .fold((0_i64, 0_i64), |acc, price_point| {
-            (acc.0 + 1, acc.1 + price_point.1 as i64)
-        });
-    let count = result.0;
-    if count == 0 {
-        return 0;
-    } else {
-        (result.1 / count) as i32
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            backlog: 1024,
+            acceptors: 1,
+            max_connections_per_ip: None,
+            idle_timeout: Duration::from_secs(60),
+            retention_window: None,
+            max_points: None,
+            full_policy: FullPolicy::Ignore,
+            first_byte_timeout: Some(Duration::from_secs(10)),
+            drain_timeout: Duration::from_secs(30),
+            metrics_address: None,
+            admin_address: None,
+            health_address: None,
+            record_dir: None,
+            proxy_protocol: false,
+            blocking_query_points: 100_000,
+            query_cap: None,
+        }
     }
 }
 
-async fn read_message(
-    stream: &mut (impl AsyncRead + std::marker::Unpin),
-) -> io::Result<(char, i32, i32)> {
-    // for the read stream, read the 9 bytes
-    let message_type = stream.read_u8().await?;
-    let field_1 = stream.read_i32().await?;
-    let field_2 = stream.read_i32().await?;
-
-    // TODO: we probably want to check read len is 0 for EOF, instead of UnexpectedEof error.
-    //    It's kinda hard to judge thou, since the docs for read also say that len == 0 may not be
-    //    trust worthy...
-    //    ref: https://docs.rs/tokio/1.21.2/tokio/io/trait.AsyncReadExt.html#return
-    Ok((char::from(message_type), field_1, field_2))
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once,
+    /// `LISTEN_BACKLOG` how many more can wait to be accepted,
+    /// `ACCEPTORS` how many tasks accept them side by side,
+    /// `MAX_CONNECTIONS_PER_IP` how many of those can come from one address,
+    /// `IDLE_TIMEOUT_SECS` controls how long a client gets to finish each message,
+    /// `FIRST_BYTE_TIMEOUT_SECS` how long a new client gets to send anything,
+    /// `RETENTION_WINDOW` how far back from its newest timestamp a session keeps points,
+    /// `MAX_POINTS` how many points a session can hold and `FULL_POLICY` (`ignore` or `close`)
+    /// what happens to inserts past that,
+    /// `DRAIN_TIMEOUT_SECS` how long sessions get to finish once shutting down,
+    /// `PROXY_PROTOCOL=true` reads who each client really is from a PROXY v1 header,
+    /// `BLOCKING_QUERY_POINTS` how many points a query covers before it's averaged on the
+    /// blocking pool, `QUERY_CAP` how many points the extension aggregates will look at,
+    /// `METRICS_ADDRESS` where to serve `GET /metrics`, `ADMIN_ADDRESS` where to serve a stats
+    /// snapshot, `HEALTH_ADDRESS` where to answer liveness probes and `RECORD_DIR` where to
+    /// record what clients send, to replay while debugging.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(backlog) = env_var("LISTEN_BACKLOG") {
+            config.backlog = backlog;
+        }
+        if let Some(acceptors) = env_var("ACCEPTORS") {
+            config.acceptors = acceptors;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS_PER_IP") {
+            config.max_connections_per_ip = Some(max_connections);
+        }
+        if let Some(secs) = env_var("IDLE_TIMEOUT_SECS") {
+            config.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(window) = env_var("RETENTION_WINDOW") {
+            config.retention_window = Some(window);
+        }
+        if let Some(points) = env_var("MAX_POINTS") {
+            config.max_points = Some(points);
+        }
+        if let Some(policy) = env_var("FULL_POLICY") {
+            config.full_policy = policy;
+        }
+        if let Some(secs) = env_var("FIRST_BYTE_TIMEOUT_SECS") {
+            config.first_byte_timeout = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = env_var("DRAIN_TIMEOUT_SECS") {
+            config.drain_timeout = Duration::from_secs(secs);
+        }
+        if let Some(address) = env_var("METRICS_ADDRESS") {
+            config.metrics_address = Some(address);
+        }
+        if let Some(address) = env_var("ADMIN_ADDRESS") {
+            config.admin_address = Some(address);
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        if let Some(dir) = env_var("RECORD_DIR") {
+            config.record_dir = Some(dir);
+        }
+        if let Some(proxy_protocol) = env_var("PROXY_PROTOCOL") {
+            config.proxy_protocol = proxy_protocol;
+        }
+        if let Some(points) = env_var("BLOCKING_QUERY_POINTS") {
+            config.blocking_query_points = points;
+        }
+        if let Some(points) = env_var("QUERY_CAP") {
+            config.query_cap = Some(points);
+        }
+        config
+    }
 }
 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::oneshot;
+use tokio::task;
+use tokio::time;
 
-async fn serve(ready_signal: oneshot::Sender<bool>) {
-    let listener = TcpListener::bind("0.0.0.0:8000")
-        .await
-        .expect("Couldn't start tcp listener on addres");
-    info!("Listening on address: {:?}", listener.local_addr());
-    ready_signal
-        .send(true)
-        .expect("Couldn't send ready signal after server has started");
+/// Totals across every session, bumped as messages come in.
+#[derive(Debug, Clone)]
+struct Metrics {
+    inserts: IntCounter,
+    queries: IntCounter,
+}
 
-    loop {
-        let stream = listener.accept().await;
-        match stream {
-            Ok((stream, socket_addr)) => {
-                info!("Accepted connection for {:?}", socket_addr);
-                tokio::spawn(async move { handle_session(stream, socket_addr).await });
-            }
-            Err(e) => {
-                error!("Error when listening for connection, {:?}", e);
+impl Metrics {
+    fn register(registry: &Registry) -> Metrics {
+        Metrics {
+            inserts: metrics::register_counter(registry, "inserts_total", "Prices inserted"),
+            queries: metrics::register_counter(registry, "queries_total", "Queries answered"),
+        }
+    }
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let registry = Registry::new();
+    let metrics = Metrics::register(&registry);
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        backlog: config.backlog,
+        acceptors: config.acceptors,
+        when_full: WhenFull::Queue,
+        max_connections_per_ip: config.max_connections_per_ip,
+        nodelay: false,
+        // sessions can sit quiet for a long time, this notices the peer vanishing underneath us
+        keepalive: Some(Duration::from_secs(60)),
+        first_byte_timeout: config.first_byte_timeout,
+        drain_timeout: config.drain_timeout,
+        metrics_address: config.metrics_address.clone(),
+        admin_address: config.admin_address.clone(),
+        health_address: config.health_address.clone(),
+        registry,
+        connections: Connections::new(),
+        record_dir: config.record_dir.clone(),
+        proxy_protocol: config.proxy_protocol,
+    };
+    let config = Arc::new(config);
+    // numbers every connection in the order they're accepted, so one's logs can be picked out
+    // from everyone else's
+    let connection_ids = AtomicU64::new(0);
+    run_tcp_server(
+        &server_config,
+        ready_signal,
+        shutdown,
+        move |stream, remote_addr, shutdown_signal| {
+            let config = config.clone();
+            let metrics = metrics.clone();
+            let id = connection_ids.fetch_add(1, Ordering::Relaxed);
+            async move {
+                info!("Accepted connection from {:?}", remote_addr);
+                handle_session(stream, remote_addr, config, metrics, shutdown_signal).await;
+                info!("Closed connection from {:?}", remote_addr);
             }
-        };
+            .instrument(info_span!("connection", id))
+        },
+    )
+    .await;
+}
+
+/// Averages `query` over `store`, on the blocking pool when it covers at least
+/// `blocking_points` points so a huge scan doesn't hold up the other sessions sharing this
+/// worker. The store moves in and out with it since the session owns it.
+async fn average(
+    store: PriceStore,
+    query: QueryRange,
+    blocking_points: usize,
+) -> (PriceStore, i32) {
+    if store.in_range(&query).len() < blocking_points {
+        let ret = store.average(query);
+        return (store, ret);
     }
+    // keeps the store's own logging in the session's span, with the subscriber that goes with it
+    // since tests only set theirs for the current thread
+    let span = Span::current();
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    task::spawn_blocking(move || {
+        dispatcher::with_default(&dispatch, move || {
+            let ret = span.in_scope(|| store.average(query));
+            (store, ret)
+        })
+    })
+    .await
+    .expect("Average query panicked")
+}
+
+/// What a session got up to, logged when it ends.
+#[derive(Debug, Default, PartialEq)]
+struct SessionStats {
+    inserts: u64,
+    queries: u64,
+    // points still held by the session when it closed
+    points: usize,
 }
 
-async fn handle_session(mut stream: TcpStream, remote_addr: SocketAddr) {
-    let mut storage: Vec<PricePoint> = Vec::new();
-    let (mut read_s, mut write_s) = stream.split();
+// every log line from a session carries who it's for, even the ones from the store
+#[instrument(skip_all, fields(remote_addr = %remote_addr))]
+async fn handle_session<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    remote_addr: Peer,
+    config: Arc<Config>,
+    metrics: Metrics,
+    mut shutdown: ShutdownSignal,
+) -> SessionStats {
+    let mut stats = SessionStats::default();
+    let mut store = match config.retention_window {
+        Some(window) => PriceStore::with_retention(window),
+        None => PriceStore::new(),
+    };
+    if let Some(cap) = config.query_cap {
+        store = store.with_query_cap(cap);
+    }
+    let mut framed = Framed::new(stream, PriceCodec::new());
+    // answers still buffered when the session ends go out before it's closed, unless it ended
+    // on a message that wasn't valid
+    let mut drop_buffered = false;
     loop {
-        let message_result = read_message(&mut read_s).await;
+        // a frame that's already been read gets handled before we notice the shutdown
+        let message_result = tokio::select! {
+            // the deadline restarts for every complete message, a trickle of bytes doesn't count
+            message_result = time::timeout(config.idle_timeout, framed.next()) => message_result,
+            _ = shutdown.recv() => {
+                info!("Server shutting down, closing {:?}", remote_addr);
+                break;
+            }
+        };
+        let message_result = match message_result {
+            Ok(message_result) => message_result,
+            Err(_) => {
+                info!(
+                    "No message from {:?} within {:?}, closing",
+                    remote_addr, config.idle_timeout
+                );
+                break;
+            }
+        };
         match message_result {
-            Ok((r_type, field_1, field_2)) => {
-                match r_type {
-                    'I' => {
-                        // rename to something more meaniningful
-                        let timestamp = field_1;
-                        let price = field_2;
-                        handle_insert(&mut storage, PricePoint(timestamp, price));
-                    }
-                    'Q' => {
-                        // rename to something more meaningful
-                        let min_time = field_1;
-                        let max_time = field_2;
-                        let ret = handle_avg_query(
-                            &storage,
-                            QueryRange {
-                                start: min_time,
-                                end: max_time,
-                            },
-                        );
-                        write_s
-                            .write_i32(ret)
-                            .await
-                            .expect(format!("Error when processing {:?}", remote_addr).as_str());
-                    }
-                    invalid_type => {
-                        error!(
-                            "lmao yo get outta here with that fake type: {:?}",
-                            invalid_type
-                        );
-                        break;
-                    }
+            Some(Ok(Message::Insert { timestamp, price })) => {
+                stats.inserts += 1;
+                metrics.inserts.inc();
+                if config.max_points.is_none_or(|max| store.len() < max) {
+                    store.insert(timestamp, price);
+                } else if config.full_policy == FullPolicy::Ignore {
+                    warn!(
+                        "{:?} already has {} points, ignoring insert",
+                        remote_addr,
+                        store.len()
+                    );
+                } else {
+                    // queries before this still get their answers
+                    warn!(
+                        "{:?} already has {} points, closing",
+                        remote_addr,
+                        store.len()
+                    );
+                    break;
                 }
             }
-            Err(e) => {
+            Some(Ok(Message::Query { min_time, max_time })) => {
+                stats.queries += 1;
+                metrics.queries.inc();
+                let query = QueryRange {
+                    start: min_time,
+                    end: max_time,
+                };
+                let ret;
+                (store, ret) = average(store, query, config.blocking_query_points).await;
+                // buffered up, it goes out with the rest of the batch
+                if let Err(e) = framed.feed(ret).await {
+                    // client has gone away, nothing left to answer
+                    info!("Error writing response for {:?} : {:?}", remote_addr, e);
+                    break;
+                }
+            }
+            Some(Ok(Message::Count { min_time, max_time })) => {
+                stats.queries += 1;
+                metrics.queries.inc();
+                let ret = store.count(QueryRange {
+                    start: min_time,
+                    end: max_time,
+                });
+                if let Err(e) = framed.feed(ret).await {
+                    info!("Error writing response for {:?} : {:?}", remote_addr, e);
+                    break;
+                }
+            }
+            // An unknown type byte ends the session. The spec leaves this up to us and there's
+            // no error response in the protocol, so the client just sees the connection close.
+            // Nothing is sent back, not even for queries that came in before it.
+            Some(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                error!(
+                    "lmao yo get outta here with that fake type, closing {:?} : {:?}",
+                    remote_addr, e
+                );
+                drop_buffered = true;
+                break;
+            }
+            // the client hung up partway through a frame. Not a normal disconnect, whatever
+            // it was sending is lost, but there's nobody left to tell so the session just ends
+            Some(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                error!("Truncated frame from {:?}, closing : {}", remote_addr, e);
+                break;
+            }
+            Some(Err(e)) => {
                 info!("Error reading for {:?} : {:?}", remote_addr, e);
                 break;
             }
+            None => {
+                info!("Connection closed for {:?}", remote_addr);
+                break;
+            }
+        }
+        // Frames that have already arrived in full are handled back to back, responses only go
+        // out once we'd have to wait on the socket for more. They're still written in the order
+        // their queries came in.
+        if framed.read_buffer().len() < FRAME_LEN {
+            if let Err(e) = SinkExt::<i32>::flush(&mut framed).await {
+                info!("Error writing response for {:?} : {:?}", remote_addr, e);
+                break;
+            }
         }
     }
+    if !drop_buffered {
+        if let Err(e) = SinkExt::<i32>::flush(&mut framed).await {
+            info!("Error writing response for {:?} : {:?}", remote_addr, e);
+        }
+    }
+    finish_write(framed.get_mut()).await;
+    stats.points = store.len();
+    info!(
+        "Session summary for {:?}: {} inserts, {} queries, {} points stored",
+        remote_addr, stats.inserts, stats.queries, stats.points
+    );
+    stats
 }
 
 #[cfg(test)]
 mod integration_tests {
     use super::*;
 
-    use tokio::net::TcpSocket;
+    use common::observability::init_tracing;
+    use common::testing::{capture_logs, TestClient};
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+    use tracing::debug;
 
     #[tokio::test]
     async fn test_problem() {
-        let _guard = setup_tracing(tracing::Level::INFO);
+        let _guard = init_tracing(tracing::Level::INFO);
         let (ready_sender, ready_receiver) = oneshot::channel();
+        let config = Config {
+            address: String::from("127.0.0.1:8001"),
+            ..Config::default()
+        };
         let server_handle = tokio::spawn(async {
-            serve(ready_sender).await;
+            serve(config, ready_sender, ShutdownToken::new()).await;
         });
         let _ready_signal = ready_receiver.await;
 
         let client_handle = tokio::spawn(async {
-            // todo: send request to server
-            let socket = TcpSocket::new_v4().unwrap();
-            let address = "127.0.0.1:8000".parse().unwrap();
-            let mut stream = socket
-                .connect(address)
-                .await
-                .expect("Couldn't connect to test server");
-
-            // insert data
-            // 1 byte = type
-            // 4 bytes = time
-            let base_insert_record = vec![0x49];
-            // first insert
-            let mut insert_record = base_insert_record.clone();
-            insert_record.extend_from_slice(&0_i32.to_be_bytes()); // time
-            insert_record.extend_from_slice(&100_i32.to_be_bytes()); // price
-            debug!("record={:?}", insert_record);
-            stream
-                .write_all(&insert_record)
-                .await
-                .expect("Couldn't write to insert to socket");
-            stream.flush().await.expect("Couldn't flush test socket");
-
-            // second insert
-            let mut insert_record = base_insert_record.clone();
-            insert_record.extend_from_slice(&1_i32.to_be_bytes()); // time
-            insert_record.extend_from_slice(&0_i32.to_be_bytes()); // price
-            debug!("record={:?}", insert_record);
-            stream
-                .write_all(&insert_record)
-                .await
-                .expect("Couldn't write to insert to socket");
-            stream.flush().await.expect("Couldn't flush test socket");
-
-            // query from 0 to 10
-            let query_record = vec![0x51, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10];
-            debug!("query={:?}", query_record);
-            stream
-                .write_all(&query_record)
-                .await
-                .expect("Couldn't write to query to socket");
-            stream.flush().await.expect("Couldn't flush test socket");
-
-            debug!("Written to inserts.");
-
-            stream
-                .shutdown()
-                .await
-                .expect("Couldn't shutdown write side of test socket");
+            let mut client = TestClient::connect("127.0.0.1:8001").await;
+            // insert data, then query from 0 to 16
+            client.send_frame(b'I', 0, 100).await;
+            client.send_frame(b'I', 1, 0).await;
+            client.send_frame(b'Q', 0, 0x10).await;
+            client.shutdown_write().await;
             info!("Closed stream");
 
-            let query_response = stream.read_i32().await;
-
-            assert_eq!(query_response.is_ok(), true);
-            assert_eq!(50, query_response.unwrap());
+            assert_eq!(50, client.read_i32().await);
         });
         let client_result = client_handle.await;
         debug!("client_result={:?}", client_result);
@@ -231,6 +496,565 @@ mod integration_tests {
 
         server_handle.abort();
     }
+
+    // the test runtime only has one thread, so a scan that stayed on it would hold up everything
+    #[tokio::test]
+    async fn test_large_query_does_not_block_other_sessions() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let config = Config {
+            address: String::from("127.0.0.1:8006"),
+            blocking_query_points: 1000,
+            ..Config::default()
+        };
+        let server_handle = tokio::spawn(async {
+            serve(config, ready_sender, ShutdownToken::new()).await;
+        });
+        let _ready_signal = ready_receiver.await;
+
+        const POINTS: i32 = 1_000_000;
+        let mut large = TestClient::connect("127.0.0.1:8006").await;
+        let mut frames = Vec::with_capacity(POINTS as usize * FRAME_LEN);
+        for timestamp in 0..POINTS {
+            frames.push(b'I');
+            frames.extend_from_slice(&timestamp.to_be_bytes());
+            frames.extend_from_slice(&1_i32.to_be_bytes());
+        }
+        large.send(&frames).await;
+        // answered once every insert ahead of it has been stored
+        large.send_frame(b'C', 0, POINTS).await;
+        assert_eq!(POINTS, large.read_i32().await);
+
+        let mut small = TestClient::connect("127.0.0.1:8006").await;
+        small.send_frame(b'I', 0, 10).await;
+
+        // a run of full range scans, which would all go back to back if they stayed on the runtime
+        const SCANS: usize = 50;
+        let mut queries = Vec::with_capacity(SCANS * FRAME_LEN);
+        for _ in 0..SCANS {
+            queries.push(b'Q');
+            queries.extend_from_slice(&0_i32.to_be_bytes());
+            queries.extend_from_slice(&POINTS.to_be_bytes());
+        }
+        let large_sent = Instant::now();
+        large.send(&queries).await;
+        let small_sent = Instant::now();
+        small.send_frame(b'Q', 0, 0).await;
+        assert_eq!(10, small.read_i32().await);
+        let small_waited = small_sent.elapsed();
+
+        for _ in 0..SCANS {
+            assert_eq!(1, large.read_i32().await);
+        }
+        let large_waited = large_sent.elapsed();
+        assert!(
+            small_waited < large_waited / 2,
+            "small query took {:?}, large ones {:?}",
+            small_waited,
+            large_waited
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_client_closes_before_response() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await;
+        });
+
+        // fire off a pile of queries and hang up without reading any responses
+        let mut client = TestClient::connect(address).await;
+        for _ in 0..1000 {
+            client.send_frame(b'Q', 0, 0x10).await;
+        }
+        drop(client);
+
+        // the session should wind down on its own rather than panic
+        let session_result = session_handle.await;
+        assert!(session_result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_count_query() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_frame(b'I', 10, 0).await;
+        client.send_frame(b'I', 20, 0).await;
+        client.send_frame(b'I', 30, 5).await;
+        // inclusive at both ends
+        client.send_frame(b'C', 10, 20).await;
+        assert_eq!(2, client.read_i32().await);
+        // an average of 0 from two points, and from none
+        client.send_frame(b'Q', 10, 20).await;
+        assert_eq!(0, client.read_i32().await);
+        client.send_frame(b'C', 40, 50).await;
+        assert_eq!(0, client.read_i32().await);
+        client.send_frame(b'Q', 40, 50).await;
+        assert_eq!(0, client.read_i32().await);
+        // start > end counts nothing
+        client.send_frame(b'C', 30, 10).await;
+        assert_eq!(0, client.read_i32().await);
+        client.send_frame(b'C', i32::MIN, i32::MAX).await;
+        assert_eq!(3, client.read_i32().await);
+        client.shutdown_write().await;
+
+        let stats = session_handle.await.unwrap();
+        assert_eq!(6, stats.queries);
+    }
+
+    // read_i32 would agree with any codec that reads and writes the same way, so check the
+    // bytes themselves
+    #[tokio::test]
+    async fn test_query_response_is_big_endian() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_frame(b'I', 1, 0x01020304).await;
+        client.send_frame(b'I', 2, -0x01020304).await;
+        client.send_frame(b'Q', 1, 1).await;
+        // a sign extension mistake would show up in the high bytes
+        client.send_frame(b'Q', 2, 2).await;
+        client.shutdown_write().await;
+
+        assert_eq!(
+            vec![0x01, 0x02, 0x03, 0x04, 0xfe, 0xfd, 0xfc, 0xfc],
+            client.read_to_end().await
+        );
+        assert!(session_handle.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_session_stats() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_frame(b'I', 12345, 101).await;
+        client.send_frame(b'I', 12345, 101).await;
+        client.send_frame(b'Q', 0, 0x4000).await;
+        client.send_frame(b'I', 12345, 101).await;
+        client.send_frame(b'Q', 0, 0x4000).await;
+        // two answers before hanging up
+        client.read_i32().await;
+        client.read_i32().await;
+        drop(client);
+
+        let stats = session_handle.await.unwrap();
+        assert_eq!(
+            SessionStats {
+                inserts: 3,
+                queries: 2,
+                points: 3,
+            },
+            stats
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_points() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        for policy in [FullPolicy::Ignore, FullPolicy::Close] {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Couldn't start test listener");
+            let address = listener.local_addr().unwrap();
+            let session_handle = tokio::spawn(async move {
+                let (stream, remote_addr) = listener.accept().await.unwrap();
+                let config = Config {
+                    max_points: Some(3),
+                    full_policy: policy,
+                    ..Config::default()
+                };
+                handle_session(
+                    stream,
+                    remote_addr.into(),
+                    Arc::new(config),
+                    Metrics::register(&Registry::new()),
+                    ShutdownToken::new().subscribe(),
+                )
+                .await
+            });
+
+            let mut client = TestClient::connect(address).await;
+            for timestamp in 1..=3 {
+                client.send_frame(b'I', timestamp, 10).await;
+            }
+            client.send_frame(b'Q', 0, 100).await;
+            // one too many, then a query that's only answered if the session is still going
+            client.send_frame(b'I', 4, 1000).await;
+            client.send_frame(b'Q', 0, 100).await;
+            client.shutdown_write().await;
+
+            let received = client.read_to_end().await;
+            let stats = session_handle.await.unwrap();
+            assert_eq!(3, stats.points);
+            let answers: Vec<i32> = received
+                .chunks(4)
+                .map(|answer| i32::from_be_bytes(answer.try_into().unwrap()))
+                .collect();
+            match policy {
+                // the extra point never made it into the average
+                FullPolicy::Ignore => assert_eq!(vec![10, 10], answers),
+                // the query before the cap was still answered
+                FullPolicy::Close => assert_eq!(vec![10], answers),
+            }
+        }
+        assert_eq!(Ok(FullPolicy::Close), "close".parse());
+        assert!("block".parse::<FullPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_buffered_answers_before_close() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            let config = Config {
+                max_points: Some(1),
+                full_policy: FullPolicy::Close,
+                ..Config::default()
+            };
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(config),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await
+        });
+
+        // sent in one go, so the answer is still buffered when the extra insert ends the session.
+        // The client keeps sending open, the end of the stream has to come from the server
+        let mut client = TestClient::connect(address).await;
+        let mut frames = Vec::new();
+        for (kind, first, second) in [(b'I', 1, 10), (b'Q', 0, 100), (b'I', 2, 20)] {
+            frames.push(kind);
+            frames.extend_from_slice(&i32::to_be_bytes(first));
+            frames.extend_from_slice(&i32::to_be_bytes(second));
+        }
+        client.send(&frames).await;
+
+        assert_eq!(10_i32.to_be_bytes().to_vec(), client.read_to_end().await);
+        session_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_span() {
+        let (logs, _guard) = capture_logs(tracing::Level::DEBUG);
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let shutdown = ShutdownToken::new();
+        let session_shutdown = shutdown.clone();
+        let sessions_handle = tokio::spawn(async move {
+            let mut sessions = tokio::task::JoinSet::new();
+            for _ in 0..2 {
+                let (stream, remote_addr) = listener.accept().await.unwrap();
+                sessions.spawn(handle_session(
+                    stream,
+                    remote_addr.into(),
+                    Arc::new(Config::default()),
+                    Metrics::register(&Registry::new()),
+                    session_shutdown.subscribe(),
+                ));
+            }
+            while sessions.join_next().await.is_some() {}
+        });
+
+        // two sessions going back and forth, so their logs interleave
+        let mut first = TestClient::connect(address).await;
+        let mut second = TestClient::connect(address).await;
+        for timestamp in 0..3 {
+            first.send_frame(b'I', timestamp, 10).await;
+            second.send_frame(b'I', 1000 + timestamp, 20).await;
+            first.send_frame(b'Q', 0, 10).await;
+            second.send_frame(b'Q', 1000, 1010).await;
+            assert_eq!(10, first.read_i32().await);
+            assert_eq!(20, second.read_i32().await);
+        }
+        let first_addr = first.local_addr().to_string();
+        let second_addr = second.local_addr().to_string();
+        drop(first);
+        drop(second);
+        sessions_handle.await.unwrap();
+
+        let logs = logs.contents();
+        let first_tag = format!("handle_session{{remote_addr={}}}", first_addr);
+        let second_tag = format!("handle_session{{remote_addr={}}}", second_addr);
+        let mut tagged = (0, 0);
+        for line in logs.lines() {
+            if line.contains("inserting: PricePoint(100") || line.contains("end: 1010") {
+                assert!(line.contains(&second_tag), "{}", line);
+                tagged.1 += 1;
+            } else if line.contains("inserting: PricePoint(") || line.contains("end: 10 ") {
+                assert!(line.contains(&first_tag), "{}", line);
+                tagged.0 += 1;
+            } else {
+                assert!(
+                    line.contains(&first_tag) || line.contains(&second_tag),
+                    "{}",
+                    line
+                );
+            }
+        }
+        // every insert and query was seen
+        assert_eq!((6, 6), tagged);
+    }
+
+    #[tokio::test]
+    async fn test_connection_ids() {
+        let (logs, _guard) = capture_logs(tracing::Level::INFO);
+
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let config = Config {
+            address: String::from("127.0.0.1:8007"),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        ready_receiver.await.unwrap();
+
+        // two connections open at once, closed in the opposite order
+        let mut first = TestClient::connect("127.0.0.1:8007").await;
+        first.send_frame(b'Q', 0, 10).await;
+        assert_eq!(0, first.read_i32().await);
+        let mut second = TestClient::connect("127.0.0.1:8007").await;
+        second.send_frame(b'Q', 0, 10).await;
+        assert_eq!(0, second.read_i32().await);
+        let peers = [first.local_addr(), second.local_addr()];
+        drop(second);
+        drop(first);
+        shutdown.shutdown();
+        server_handle.await.unwrap();
+
+        let logs = logs.contents();
+        for (id, peer) in peers.iter().enumerate() {
+            let accepted = format!("Accepted connection from {:?}", peer);
+            let closed = format!("Closed connection from {:?}", peer);
+            let tag = format!("connection{{id={}}}", id);
+            for event in [accepted, closed] {
+                let line = logs
+                    .lines()
+                    .find(|line| line.contains(&event))
+                    .unwrap_or_else(|| panic!("No {:?} in {}", event, logs));
+                assert!(line.contains(&tag), "{}", line);
+            }
+            // and everything in between is tagged too
+            let session_tag = format!("{}:handle_session{{remote_addr={}}}", tag, peer);
+            assert!(logs.contains(&session_tag), "{}", logs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame() {
+        let (logs, _guard) = capture_logs(tracing::Level::INFO);
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let shutdown = ShutdownToken::new();
+        let session_shutdown = shutdown.subscribe();
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                session_shutdown,
+            )
+            .await
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_frame(b'I', 1, 100).await;
+        // the start of a second insert, then hang up
+        client.send(&[b'I', 0, 0, 0]).await;
+        client.shutdown_write().await;
+        assert!(client.read_to_end().await.is_empty());
+
+        let stats = session_handle.await.expect("Session panicked");
+        assert_eq!(1, stats.inserts);
+        let logs = logs.contents();
+        assert!(
+            logs.contains("Truncated frame from") && logs.contains("truncated frame, 4 of 9 bytes"),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("Connection closed for"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let config = Arc::new(Config {
+            idle_timeout: Duration::from_millis(100),
+            ..Config::default()
+        });
+
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                config,
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await;
+        });
+
+        // insert one point and then go quiet
+        let mut client = TestClient::connect(address).await;
+        client.send_frame(b'I', 12345, 101).await;
+
+        assert!(client.read_to_end().await.is_empty());
+        assert!(session_handle.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_batched_responses_stay_in_order() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await
+        });
+
+        // one write, so the whole lot is buffered together on the server
+        let mut burst = Vec::new();
+        for (message_type, field_1, field_2) in [
+            (b'I', 1, 10),
+            (b'Q', 0, 10),
+            (b'I', 2, 20),
+            (b'Q', 0, 10),
+            (b'C', 0, 10),
+            (b'I', 3, 60),
+            (b'Q', 0, 10),
+        ] {
+            burst.push(message_type);
+            burst.extend_from_slice(&i32::to_be_bytes(field_1));
+            burst.extend_from_slice(&i32::to_be_bytes(field_2));
+        }
+        let mut client = TestClient::connect(address).await;
+        client.send(&burst).await;
+        for expected in [10, 15, 2, 30] {
+            assert_eq!(expected, client.read_i32().await);
+        }
+        client.shutdown_write().await;
+        assert_eq!(3, session_handle.await.unwrap().inserts);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_type_closes_connection() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                ShutdownToken::new().subscribe(),
+            )
+            .await;
+        });
+
+        let mut client = TestClient::connect(address).await;
+        client.send_frame(b'I', 12345, 101).await;
+        client.send_frame(0x00, 0, 0).await;
+
+        // closed without a response
+        assert!(client.read_to_end().await.is_empty());
+        assert!(session_handle.await.is_ok());
+    }
 }
 
 #[cfg(test)]
@@ -238,138 +1062,195 @@ mod server_tests {
 
     use super::*;
 
+    use common::observability::init_tracing;
+    use common::testing::TestClient;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
     #[tokio::test]
     async fn test_server_startup() {
-        let _guard = setup_tracing(tracing::Level::DEBUG);
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        let shutdown = ShutdownToken::new();
         let (ready_sender, ready_receiver) = oneshot::channel();
-        let server_handle = tokio::spawn(async {
-            serve(ready_sender).await;
-        });
+        let config = Config {
+            address: String::from("127.0.0.1:8002"),
+            ..Config::default()
+        };
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
 
         let ready_signal = ready_receiver.await;
         assert_eq!(Ok(true), ready_signal);
 
-        server_handle.abort();
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
     }
-}
 
-#[cfg(test)]
-mod parsing_tests {
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
 
-    use super::*;
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        let path =
+            std::env::temp_dir().join(format!("means-to-an-end-{}.sock", std::process::id()));
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let config = Config {
+            address: format!("unix:{}", path.display()),
+            ..Config::default()
+        };
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
 
-    use std::io::Cursor;
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut frames = Vec::new();
+        for (kind, first, second) in [(b'I', 0, 100), (b'I', 1, 0), (b'Q', 0, 0x10)] {
+            frames.push(kind);
+            frames.extend_from_slice(&i32::to_be_bytes(first));
+            frames.extend_from_slice(&i32::to_be_bytes(second));
+        }
+        client.write_all(&frames).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(50i32.to_be_bytes().to_vec(), response);
 
-    #[tokio::test]
-    async fn test_parsing() {
-        let _guard = setup_tracing(tracing::Level::DEBUG);
-        let mut reader = Cursor::new(vec![
-            0x51, // Q
-            0x00, 0x00, 0x00, 0x01, // 1
-            0x00, 0x00, 0x00, 0x02, // 2
-        ]);
-        let result = read_message(&mut reader).await;
-        info!("results = {:?}", result);
-        assert_eq!(('Q', 1, 2), result.unwrap());
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+        assert!(!path.exists());
     }
 
     #[tokio::test]
-    async fn test_parsing_empty() {
-        let _guard = setup_tracing(tracing::Level::DEBUG);
-        let mut reader = Cursor::new(vec![]);
-        let result = read_message(&mut reader).await;
-        info!("results = {:?}", result);
-        assert_eq!(result.is_err(), true);
-    }
-}
+    async fn test_metrics_endpoint() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let config = Config {
+            address: String::from("127.0.0.1:8003"),
+            metrics_address: Some(String::from("127.0.0.1:8004")),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
 
-#[cfg(test)]
-mod storage_tests {
+        let mut client = TestClient::connect("127.0.0.1:8003").await;
+        for _ in 0..3 {
+            client.send_frame(b'I', 12345, 101).await;
+        }
+        client.send_frame(b'Q', 0, 0x4000).await;
+        // messages are handled in order, so once this is back every count is in
+        client.read_i32().await;
 
-    use super::*;
+        let mut scrape = TcpStream::connect("127.0.0.1:8004")
+            .await
+            .expect("Couldn't connect to metrics endpoint");
+        scrape
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut metrics = String::new();
+        scrape.read_to_string(&mut metrics).await.unwrap();
+        assert!(metrics.contains("\ninserts_total 3\n"), "{}", metrics);
+        assert!(metrics.contains("\nqueries_total 1\n"), "{}", metrics);
+        assert!(metrics.contains("\nactive_connections 1\n"), "{}", metrics);
+
+        drop(client);
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
 
     #[tokio::test]
-    async fn test() {
-        let _guard = setup_tracing(tracing::Level::DEBUG);
-
-        {
-            // inclusive on edges
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, 100));
-            handle_insert(&mut storage, PricePoint(0, 0));
-            let avg = handle_avg_query(&storage, QueryRange { start: 0, end: 1 });
-            assert_eq!(50, avg);
-        }
-
-        {
-            // ignore outside range
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, 100));
-            handle_insert(&mut storage, PricePoint(2, 0));
-            let avg = handle_avg_query(&storage, QueryRange { start: 0, end: 1 });
-            assert_eq!(100, avg);
-        }
-
-        {
-            // happy path
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, 1));
-            handle_insert(&mut storage, PricePoint(2, 2));
-            handle_insert(&mut storage, PricePoint(3, 3));
-            handle_insert(&mut storage, PricePoint(4, 4));
-            let avg = handle_avg_query(&storage, QueryRange { start: 0, end: 4 });
-            assert_eq!(2, avg);
-        }
-
-        {
-            // fractional
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, 1));
-            handle_insert(&mut storage, PricePoint(2, 2));
-            handle_insert(&mut storage, PricePoint(2, 2));
-            let avg = handle_avg_query(&storage, QueryRange { start: 0, end: 2 });
-            assert_eq!(1, avg);
-        }
-
-        {
-            // fractional + negative
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, -1));
-            handle_insert(&mut storage, PricePoint(2, -2));
-            handle_insert(&mut storage, PricePoint(2, -2));
-            let avg = handle_avg_query(&storage, QueryRange { start: 0, end: 2 });
-            assert_eq!(-1, avg);
-        }
-
-        {
-            // no inserts
-            let storage: Vec<PricePoint> = Vec::new();
-            let avg = handle_avg_query(&storage, QueryRange { start: 0, end: 2 });
-            assert_eq!(0, avg);
-        }
-
-        {
-            // no elements in range
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, 1));
-            handle_insert(&mut storage, PricePoint(2, 2));
-            let avg = handle_avg_query(
-                &storage,
-                QueryRange {
-                    start: 100,
-                    end: 2000,
-                },
-            );
-            assert_eq!(0, avg);
-        }
-
-        {
-            // start > end, which is invalid
-            let mut storage: Vec<PricePoint> = Vec::new();
-            handle_insert(&mut storage, PricePoint(1, 1));
-            handle_insert(&mut storage, PricePoint(2, 2));
-            let avg = handle_avg_query(&storage, QueryRange { start: 200, end: 1 });
-            assert_eq!(0, avg);
+    async fn test_max_connections_per_ip() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let config = Config {
+            address: String::from("127.0.0.1:8005"),
+            max_connections_per_ip: Some(2),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+
+        let mut first = TestClient::connect("127.0.0.1:8005").await;
+        let mut second = TestClient::connect("127.0.0.1:8005").await;
+        for client in [&mut first, &mut second] {
+            client.send_frame(b'I', 1, 10).await;
+            client.send_frame(b'Q', 0, 2).await;
+            assert_eq!(10, client.read_i32().await);
         }
+        // a third from the same address is hung up on straight away
+        let mut third = TestClient::connect("127.0.0.1:8005").await;
+        assert!(third.read_to_end().await.is_empty());
+
+        // somewhere else on the loopback still gets served
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut elsewhere = socket
+            .connect("127.0.0.1:8005".parse().unwrap())
+            .await
+            .expect("Couldn't connect from 127.0.0.2");
+        let mut frame = vec![b'I'];
+        frame.extend_from_slice(&1_i32.to_be_bytes());
+        frame.extend_from_slice(&20_i32.to_be_bytes());
+        frame.push(b'Q');
+        frame.extend_from_slice(&0_i32.to_be_bytes());
+        frame.extend_from_slice(&2_i32.to_be_bytes());
+        elsewhere.write_all(&frame).await.unwrap();
+        let mean = tokio::time::timeout(Duration::from_secs(5), elsewhere.read_i32())
+            .await
+            .expect("No response from server")
+            .unwrap();
+        assert_eq!(20, mean);
+
+        drop((first, second, elsewhere));
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_session_shutdown() {
+        let _guard = init_tracing(tracing::Level::DEBUG);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't start test listener");
+        let address = listener.local_addr().unwrap();
+        let shutdown = ShutdownToken::new();
+        let session_shutdown = shutdown.subscribe();
+        let session_handle = tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            handle_session(
+                stream,
+                remote_addr.into(),
+                Arc::new(Config::default()),
+                Metrics::register(&Registry::new()),
+                session_shutdown,
+            )
+            .await;
+        });
+
+        // an idle client would otherwise keep the session around forever
+        let mut client = TestClient::connect(address).await;
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), session_handle)
+            .await
+            .expect("Session did not stop after shutdown")
+            .expect("Session panicked");
+
+        assert!(client.read_to_end().await.is_empty());
     }
 }
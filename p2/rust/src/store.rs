@@ -0,0 +1,662 @@
+use tracing::debug;
+
+// Time, Price
+#[derive(Debug)]
+pub struct PricePoint(pub i32, pub i32);
+
+#[derive(Debug)]
+pub struct QueryRange {
+    pub start: i32,
+    pub end: i32,
+}
+
+/// One session's prices, kept sorted by timestamp so queries can binary search their window.
+#[derive(Debug, Default)]
+pub struct PriceStore {
+    points: Vec<PricePoint>,
+    // points older than the newest timestamp minus this are dropped, kept forever when unset
+    retention_window: Option<u32>,
+    // most points an extension aggregate will look at, no limit when unset. `average` and
+    // `count` always take in the whole range
+    query_cap: Option<usize>,
+}
+
+/// What an extension aggregate gives back instead of an answer when its range holds more than
+/// the store's `query_cap` points, with how many there were.
+#[derive(Debug, PartialEq)]
+pub struct OverQueryCap(pub usize);
+
+impl PriceStore {
+    pub fn new() -> PriceStore {
+        PriceStore::default()
+    }
+
+    /// Only keeps points within `window` of the newest timestamp inserted so far, so a long
+    /// running session can't grow without bound. Queries only see what's been kept.
+    pub fn with_retention(window: u32) -> PriceStore {
+        PriceStore {
+            points: Vec::new(),
+            retention_window: Some(window),
+            query_cap: None,
+        }
+    }
+
+    /// Points with equal timestamps stay in the order they were inserted.
+    pub fn insert(&mut self, timestamp: i32, price: i32) {
+        let point = PricePoint(timestamp, price);
+        debug!("inserting: {:?}", point);
+        let index = self
+            .points
+            .partition_point(|price_point| price_point.0 <= timestamp);
+        self.points.insert(index, point);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let (Some(window), Some(newest)) = (self.retention_window, self.points.last()) else {
+            return;
+        };
+        // i64 so windows reaching past i32::MIN don't wrap
+        let oldest_kept = newest.0 as i64 - window as i64;
+        let expired = self
+            .points
+            .partition_point(|price_point| (price_point.0 as i64) < oldest_kept);
+        if expired > 0 {
+            debug!("evicting {} points older than {}", expired, oldest_kept);
+            self.points.drain(..expired);
+        }
+    }
+
+    /// Mean price over the inclusive range, truncated towards zero (so -1.5 is -1, not -2 as
+    /// flooring would give). An empty range, or one where start > end, averages to 0. The spec
+    /// wants every point in range counted, so unlike the extension aggregates this isn't capped.
+    pub fn average(&self, query: QueryRange) -> i32 {
+        debug!("query: {:?}", query);
+        let (count, sum) = self
+            .in_range(&query)
+            .iter()
+            // i128 so that a session full of large prices can't overflow the sum
+            .fold((0_i128, 0_i128), |acc, price_point| {
+                (acc.0 + 1, acc.1 + price_point.1 as i128)
+            });
+        if count == 0 {
+            0
+        } else {
+            // integer division truncates towards zero, which is what the spec asks for.
+            // `div_euclid` would floor negative means instead
+            (sum / count) as i32
+        }
+    }
+
+    /// Points in the inclusive range, with the same rules as `average`, so an empty range
+    /// can be told apart from one that averages to 0. Capped at i32::MAX to fit the response.
+    pub fn count(&self, query: QueryRange) -> i32 {
+        debug!("count: {:?}", query);
+        i32::try_from(self.in_range(&query).len()).unwrap_or(i32::MAX)
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The points in the inclusive range, oldest first. Empty when start > end.
+    pub fn in_range(&self, query: &QueryRange) -> &[PricePoint] {
+        if query.start > query.end {
+            return &[];
+        }
+        // sorted by timestamp, so the range is one contiguous slice
+        let first = self
+            .points
+            .partition_point(|price_point| price_point.0 < query.start);
+        let last = self
+            .points
+            .partition_point(|price_point| price_point.0 <= query.end);
+        &self.points[first..last]
+    }
+}
+
+// Other aggregates over the same inclusive ranges as `average`. They aren't reachable over
+// the wire, they're here for local experiments against a store. Each one checks how many points
+// are in range before looking at any of them, past `query_cap` it answers `OverQueryCap`.
+impl PriceStore {
+    /// Caps how many points the extension aggregates will look at in one query.
+    pub fn with_query_cap(self, query_cap: usize) -> PriceStore {
+        PriceStore {
+            query_cap: Some(query_cap),
+            ..self
+        }
+    }
+
+    /// The mean `average` truncates, or `None` when there's nothing in range (including
+    /// start > end) instead of 0.
+    pub fn average_exact(&self, query: QueryRange) -> Result<Option<f64>, OverQueryCap> {
+        let prices = self.capped_range(&query)?;
+        if prices.is_empty() {
+            return Ok(None);
+        }
+        let sum: i128 = prices.iter().map(|price_point| price_point.1 as i128).sum();
+        Ok(Some(sum as f64 / prices.len() as f64))
+    }
+
+    pub fn min_price(&self, query: QueryRange) -> Result<i32, OverQueryCap> {
+        Ok(self
+            .capped_range(&query)?
+            .iter()
+            .map(|price_point| price_point.1)
+            .min()
+            .unwrap_or(0))
+    }
+
+    pub fn max_price(&self, query: QueryRange) -> Result<i32, OverQueryCap> {
+        Ok(self
+            .capped_range(&query)?
+            .iter()
+            .map(|price_point| price_point.1)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Population variance (squared distance from the mean, over the count), truncated and
+    /// capped at i32::MAX. 0 for an empty range, like `average`.
+    pub fn variance(&self, query: QueryRange) -> Result<i32, OverQueryCap> {
+        Ok(i32::try_from(self.variance_floor(&query)?).unwrap_or(i32::MAX))
+    }
+
+    /// Square root of `variance`, truncated. Worked out from the uncapped variance, so it
+    /// stays right for spreads too wide for the variance itself to fit in an i32.
+    pub fn stddev(&self, query: QueryRange) -> Result<i32, OverQueryCap> {
+        Ok(i32::try_from(self.variance_floor(&query)?.isqrt()).unwrap_or(i32::MAX))
+    }
+
+    // n·Σx² - (Σx)² over n², all in integers so nothing is lost before the final truncation.
+    // i128 has room for that with far more points than a session could hold
+    fn variance_floor(&self, query: &QueryRange) -> Result<u128, OverQueryCap> {
+        let prices = self.capped_range(query)?;
+        if prices.is_empty() {
+            return Ok(0);
+        }
+        let count = prices.len() as i128;
+        let (sum, sum_of_squares) = prices.iter().fold((0_i128, 0_i128), |acc, price_point| {
+            let price = price_point.1 as i128;
+            (acc.0 + price, acc.1 + price * price)
+        });
+        Ok(((count * sum_of_squares - sum * sum) / (count * count)) as u128)
+    }
+
+    /// For an even count this is the mean of the two middle prices, truncated like `average`.
+    pub fn median_price(&self, query: QueryRange) -> Result<i32, OverQueryCap> {
+        let mut prices: Vec<i32> = self
+            .capped_range(&query)?
+            .iter()
+            .map(|price_point| price_point.1)
+            .collect();
+        if prices.is_empty() {
+            return Ok(0);
+        }
+        prices.sort_unstable();
+        let middle = prices.len() / 2;
+        if prices.len() % 2 == 1 {
+            Ok(prices[middle])
+        } else {
+            Ok(((prices[middle - 1] as i64 + prices[middle] as i64) / 2) as i32)
+        }
+    }
+
+    /// `in_range`, unless that's more than `query_cap` points. Finding the range is only a
+    /// couple of binary searches, so this costs nothing however big it is.
+    fn capped_range(&self, query: &QueryRange) -> Result<&[PricePoint], OverQueryCap> {
+        let prices = self.in_range(query);
+        match self.query_cap {
+            Some(cap) if prices.len() > cap => Err(OverQueryCap(prices.len())),
+            _ => Ok(prices),
+        }
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+
+    use super::*;
+
+    use common::observability::init_tracing;
+    use proptest::prelude::*;
+
+    #[tokio::test]
+    async fn test() {
+        let _guard = init_tracing(tracing::Level::DEBUG);
+
+        {
+            // inclusive on edges
+            let mut store = PriceStore::new();
+            store.insert(1, 100);
+            store.insert(0, 0);
+            let avg = store.average(QueryRange { start: 0, end: 1 });
+            assert_eq!(50, avg);
+        }
+
+        {
+            // ignore outside range
+            let mut store = PriceStore::new();
+            store.insert(1, 100);
+            store.insert(2, 0);
+            let avg = store.average(QueryRange { start: 0, end: 1 });
+            assert_eq!(100, avg);
+        }
+
+        {
+            // happy path
+            let mut store = PriceStore::new();
+            store.insert(1, 1);
+            store.insert(2, 2);
+            store.insert(3, 3);
+            store.insert(4, 4);
+            let avg = store.average(QueryRange { start: 0, end: 4 });
+            assert_eq!(2, avg);
+        }
+
+        {
+            // fractional
+            let mut store = PriceStore::new();
+            store.insert(1, 1);
+            store.insert(2, 2);
+            store.insert(2, 2);
+            let avg = store.average(QueryRange { start: 0, end: 2 });
+            assert_eq!(1, avg);
+        }
+
+        {
+            // fractional + negative
+            let mut store = PriceStore::new();
+            store.insert(1, -1);
+            store.insert(2, -2);
+            store.insert(2, -2);
+            let avg = store.average(QueryRange { start: 0, end: 2 });
+            assert_eq!(-1, avg);
+        }
+
+        {
+            // no inserts
+            let store = PriceStore::new();
+            let avg = store.average(QueryRange { start: 0, end: 2 });
+            assert_eq!(0, avg);
+        }
+
+        {
+            // no elements in range
+            let mut store = PriceStore::new();
+            store.insert(1, 1);
+            store.insert(2, 2);
+            let avg = store.average(QueryRange {
+                start: 100,
+                end: 2000,
+            });
+            assert_eq!(0, avg);
+        }
+
+        {
+            // large prices over a wide range don't overflow
+            let mut store = PriceStore::new();
+            for timestamp in 0..100_000 {
+                store.insert(timestamp * 1000, i32::MAX);
+            }
+            let avg = store.average(QueryRange {
+                start: i32::MIN,
+                end: i32::MAX,
+            });
+            assert_eq!(i32::MAX, avg);
+
+            for timestamp in 0..100_000 {
+                store.insert(-timestamp * 1000, i32::MIN);
+            }
+            let avg = store.average(QueryRange {
+                start: i32::MIN,
+                end: i32::MAX,
+            });
+            // (MAX + MIN) / 2 = -1 / 2, truncated
+            assert_eq!(0, avg);
+        }
+
+        {
+            // start > end, which is invalid
+            let mut store = PriceStore::new();
+            store.insert(1, 1);
+            store.insert(2, 2);
+            let avg = store.average(QueryRange { start: 200, end: 1 });
+            assert_eq!(0, avg);
+        }
+
+        {
+            // out of order inserts
+            let mut store = PriceStore::new();
+            store.insert(30, 3);
+            store.insert(10, 1);
+            store.insert(20, 2);
+            store.insert(40, 4);
+            let avg = store.average(QueryRange { start: 15, end: 35 });
+            assert_eq!(2, avg);
+            let avg = store.average(QueryRange { start: 10, end: 10 });
+            assert_eq!(1, avg);
+        }
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let mut store = PriceStore::new();
+        store.insert(1, 30);
+        store.insert(2, -10);
+        store.insert(3, 20);
+        store.insert(4, 50);
+        store.insert(100, 1000);
+
+        // odd count, 30 -10 20
+        let range = || QueryRange { start: 1, end: 3 };
+        assert_eq!(3, store.count(range()));
+        assert_eq!(Ok(-10), store.min_price(range()));
+        assert_eq!(Ok(30), store.max_price(range()));
+        assert_eq!(Ok(20), store.median_price(range()));
+
+        // even count, -10 20 30 50
+        let range = || QueryRange { start: 1, end: 4 };
+        assert_eq!(4, store.count(range()));
+        assert_eq!(Ok(-10), store.min_price(range()));
+        assert_eq!(Ok(50), store.max_price(range()));
+        assert_eq!(Ok(25), store.median_price(range()));
+
+        // even count with a fractional middle, -10 20
+        let range = || QueryRange { start: 2, end: 3 };
+        assert_eq!(Ok(5), store.median_price(range()));
+        // truncated towards zero, -10 -5 -> -7
+        let mut negative = PriceStore::new();
+        negative.insert(1, -10);
+        negative.insert(2, -5);
+        assert_eq!(
+            Ok(-7),
+            negative.median_price(QueryRange { start: 0, end: 5 })
+        );
+
+        // the middle two can't overflow
+        let mut large = PriceStore::new();
+        large.insert(1, i32::MAX);
+        large.insert(2, i32::MAX);
+        assert_eq!(
+            Ok(i32::MAX),
+            large.median_price(QueryRange { start: 0, end: 5 })
+        );
+    }
+
+    #[test]
+    fn test_aggregates_empty() {
+        let mut store = PriceStore::new();
+        assert_eq!(0, store.count(QueryRange { start: 0, end: 10 }));
+        assert_eq!(Ok(0), store.median_price(QueryRange { start: 0, end: 10 }));
+
+        store.insert(5, 100);
+        for range in [
+            // nothing in range
+            || QueryRange { start: 6, end: 10 },
+            // start > end
+            || QueryRange { start: 10, end: 0 },
+        ] {
+            assert_eq!(0, store.count(range()));
+            assert_eq!(Ok(0), store.min_price(range()));
+            assert_eq!(Ok(0), store.max_price(range()));
+            assert_eq!(Ok(0), store.median_price(range()));
+        }
+    }
+
+    #[test]
+    fn test_query_cap() {
+        let mut store = PriceStore::new().with_query_cap(3);
+        for timestamp in 1..=5 {
+            store.insert(timestamp, timestamp * 10);
+        }
+
+        // at the cap, answered as usual
+        let range = || QueryRange { start: 1, end: 3 };
+        assert_eq!(Ok(20), store.median_price(range()));
+        assert_eq!(Ok(10), store.min_price(range()));
+        // one over, flagged instead of answered
+        let range = || QueryRange { start: 1, end: 4 };
+        assert_eq!(Err(OverQueryCap(4)), store.median_price(range()));
+        assert_eq!(Err(OverQueryCap(4)), store.min_price(range()));
+        assert_eq!(Err(OverQueryCap(4)), store.max_price(range()));
+        assert_eq!(Err(OverQueryCap(4)), store.variance(range()));
+        assert_eq!(Err(OverQueryCap(4)), store.stddev(range()));
+        assert_eq!(Err(OverQueryCap(4)), store.average_exact(range()));
+        // the spec's query still takes in everything
+        assert_eq!(25, store.average(range()));
+        assert_eq!(5, store.count(QueryRange { start: 0, end: 10 }));
+    }
+
+    #[test]
+    fn test_average_truncates_towards_zero() {
+        let range = || QueryRange { start: 0, end: 10 };
+        for (prices, expected) in [
+            (vec![-1, -2], -1),
+            (vec![0, -1], 0),
+            (vec![-1, -2, -2], -1),
+            (vec![-2, -2, -1], -1),
+            (vec![-1, -1, -1, -2], -1),
+            (vec![1, 2], 1),
+            (vec![0, 1], 0),
+            // sums that only fit in the i128 still truncate the same way
+            (vec![i32::MIN, i32::MIN, i32::MIN + 1], i32::MIN + 1),
+            (vec![i32::MIN, i32::MAX], 0),
+            (vec![i32::MAX, i32::MAX - 1], i32::MAX - 1),
+        ] {
+            let mut store = PriceStore::new();
+            for (timestamp, price) in prices.iter().enumerate() {
+                store.insert(timestamp as i32, *price);
+            }
+            assert_eq!(expected, store.average(range()), "{:?}", prices);
+        }
+    }
+
+    #[test]
+    fn test_variance() {
+        let range = || QueryRange { start: 0, end: 100 };
+        let mut store = PriceStore::new();
+        for timestamp in 0..10 {
+            store.insert(timestamp, 42);
+        }
+        assert_eq!(Ok(0), store.variance(range()));
+        assert_eq!(Ok(0), store.stddev(range()));
+
+        // the textbook example: mean 5, variance 4, stddev 2
+        let mut store = PriceStore::new();
+        for (timestamp, price) in [2, 4, 4, 4, 5, 5, 7, 9].into_iter().enumerate() {
+            store.insert(timestamp as i32, price);
+        }
+        assert_eq!(Ok(4), store.variance(range()));
+        assert_eq!(Ok(2), store.stddev(range()));
+
+        // 1 2 3 4: variance 1.25, stddev 1.118...
+        let mut store = PriceStore::new();
+        for price in 1..=4 {
+            store.insert(price, -price);
+        }
+        assert_eq!(Ok(1), store.variance(range()));
+        assert_eq!(Ok(1), store.stddev(range()));
+        // only 1 and 2 in range: variance 0.25
+        assert_eq!(Ok(0), store.variance(QueryRange { start: 1, end: 2 }));
+
+        // too spread out for the variance to fit, the stddev still does
+        let mut store = PriceStore::new();
+        store.insert(1, i32::MIN);
+        store.insert(2, i32::MAX);
+        assert_eq!(Ok(i32::MAX), store.variance(range()));
+        assert_eq!(Ok(i32::MAX), store.stddev(range()));
+
+        // empty and start > end ranges
+        assert_eq!(Ok(0), store.variance(QueryRange { start: 3, end: 10 }));
+        assert_eq!(Ok(0), store.stddev(QueryRange { start: 3, end: 10 }));
+        assert_eq!(Ok(0), store.variance(QueryRange { start: 2, end: 1 }));
+        assert_eq!(Ok(0), store.stddev(QueryRange { start: 2, end: 1 }));
+    }
+
+    #[test]
+    fn test_average_exact() {
+        let range = || QueryRange { start: 0, end: 10 };
+        let mut store = PriceStore::new();
+        assert_eq!(Ok(None), store.average_exact(range()));
+
+        store.insert(1, 1);
+        store.insert(2, 2);
+        store.insert(2, 2);
+        assert_eq!(1, store.average(range()));
+        assert_eq!(Ok(Some(5.0 / 3.0)), store.average_exact(range()));
+
+        store.insert(3, 3);
+        assert_eq!(2, store.average(range()));
+        assert_eq!(Ok(Some(2.0)), store.average_exact(range()));
+
+        let mut negative = PriceStore::new();
+        negative.insert(1, -1);
+        negative.insert(2, -2);
+        // truncated towards zero, not floored
+        assert_eq!(-1, negative.average(range()));
+        assert_eq!(Ok(Some(-1.5)), negative.average_exact(range()));
+
+        // same empty cases where `average` says 0
+        assert_eq!(
+            Ok(None),
+            store.average_exact(QueryRange { start: 6, end: 10 })
+        );
+        assert_eq!(
+            Ok(None),
+            store.average_exact(QueryRange { start: 10, end: 0 })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_average_truncates_average_exact(
+            points in prop::collection::vec((-100..100_i32, any::<i32>()), 1..50),
+            start in -100..100_i32,
+            end in -100..100_i32,
+        ) {
+            let mut store = PriceStore::new();
+            for (timestamp, price) in points {
+                store.insert(timestamp, price);
+            }
+            let range = || QueryRange { start, end };
+            match store.average_exact(range()).unwrap() {
+                Some(exact) => prop_assert_eq!(exact.trunc() as i32, store.average(range())),
+                None => prop_assert_eq!(0, store.count(range())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_keeps_order() {
+        let mut store = PriceStore::new();
+        assert!(store.is_empty());
+        store.insert(5, 1);
+        store.insert(1, 2);
+        store.insert(5, 3);
+        store.insert(3, 4);
+        store.insert(5, 5);
+
+        let points: Vec<(i32, i32)> = store
+            .points
+            .iter()
+            .map(|point| (point.0, point.1))
+            .collect();
+        // equal timestamps keep their insertion order
+        assert_eq!(vec![(1, 2), (3, 4), (5, 1), (5, 3), (5, 5)], points);
+    }
+
+    #[test]
+    fn test_retention_window() {
+        let mut store = PriceStore::with_retention(100);
+        for timestamp in (0..=1000).step_by(10) {
+            store.insert(timestamp, timestamp);
+        }
+        // only 900 through 1000 are within 100 of the newest
+        assert_eq!(11, store.len());
+        assert_eq!(0, store.count(QueryRange { start: 0, end: 899 }));
+        assert_eq!(
+            950,
+            store.average(QueryRange {
+                start: 0,
+                end: 1000
+            })
+        );
+
+        // anything already too old is dropped straight away
+        store.insert(5, 5);
+        assert_eq!(11, store.len());
+        // moving the newest forward drops everything but the edge of the window
+        store.insert(1100, 1100);
+        assert_eq!(2, store.len());
+        assert_eq!(
+            1,
+            store.count(QueryRange {
+                start: 0,
+                end: 1000
+            })
+        );
+
+        // a big jump forward clears out everything before it
+        store.insert(i32::MAX, 1);
+        assert_eq!(1, store.len());
+
+        // no window, nothing is evicted
+        let mut store = PriceStore::new();
+        for timestamp in (i32::MIN..=i32::MAX).step_by(1 << 24) {
+            store.insert(timestamp, 0);
+        }
+        assert_eq!(256, store.len());
+    }
+
+    #[test]
+    fn test_retention_window_near_min() {
+        // the window reaches past i32::MIN without wrapping around
+        let mut store = PriceStore::with_retention(u32::MAX);
+        store.insert(i32::MIN, 1);
+        store.insert(i32::MAX, 2);
+        assert_eq!(2, store.len());
+        store.insert(0, 3);
+        assert_eq!(3, store.len());
+    }
+
+    // mostly small values so that ranges actually catch points, with the extremes mixed in
+    fn interesting_i32() -> impl Strategy<Value = i32> {
+        prop_oneof![-100..100_i32, any::<i32>(), Just(i32::MIN), Just(i32::MAX)]
+    }
+
+    // straightforward f64 mean, exact for the few hundred i32s generated here
+    fn reference_average(points: &[(i32, i32)], start: i32, end: i32) -> i32 {
+        let prices: Vec<f64> = points
+            .iter()
+            .filter(|(timestamp, _)| start <= *timestamp && *timestamp <= end)
+            .map(|(_, price)| *price as f64)
+            .collect();
+        if start > end || prices.is_empty() {
+            return 0;
+        }
+        (prices.iter().sum::<f64>() / prices.len() as f64).trunc() as i32
+    }
+
+    proptest! {
+        #[test]
+        fn prop_average_matches_reference(
+            points in prop::collection::vec((interesting_i32(), interesting_i32()), 0..200),
+            start in interesting_i32(),
+            end in interesting_i32(),
+        ) {
+            let mut store = PriceStore::new();
+            for (timestamp, price) in &points {
+                store.insert(*timestamp, *price);
+            }
+            prop_assert_eq!(
+                reference_average(&points, start, end),
+                store.average(QueryRange { start, end })
+            );
+        }
+    }
+}
@@ -0,0 +1,680 @@
+use common::env::env_var;
+use common::observability::init_tracing_from_env;
+use common::{run_tcp_server, Peer, ServerConfig, ShutdownSignal, ShutdownToken};
+use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{error, info};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    dual_stack: bool,
+    // connections handled at once before the server stops accepting
+    max_connections: usize,
+    // longest line we'll take from a client, the spec asks for at least 1000 characters
+    max_line_length: usize,
+    // tell everyone how many are in the room whenever someone joins or leaves
+    announce_online: bool,
+    // lines a client can fall behind by before it's disconnected
+    client_backlog: usize,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            max_line_length: 1000,
+            announce_online: false,
+            client_backlog: 1024,
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once,
+    /// `MAX_LINE_LENGTH` the longest line accepted,
+    /// `ANNOUNCE_ONLINE=true` announces how many are online as people come and go,
+    /// `CLIENT_BACKLOG` how far behind a client can fall before it's disconnected and
+    /// `HEALTH_ADDRESS` where to answer liveness probes.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(length) = env_var("MAX_LINE_LENGTH") {
+            config.max_line_length = length;
+        }
+        if let Some(announce_online) = env_var("ANNOUNCE_ONLINE") {
+            config.announce_online = announce_online;
+        }
+        if let Some(backlog) = env_var("CLIENT_BACKLOG") {
+            config.client_backlog = backlog;
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+const WELCOME: &str = "Welcome to budgetchat! What shall I call you?";
+
+#[derive(Debug, PartialEq)]
+enum JoinError {
+    NameTaken,
+}
+
+/// Someone in the room, as the room sees them.
+#[derive(Debug)]
+struct Member {
+    // tells this member apart from anyone who takes the name after them
+    id: u64,
+    // lines waiting to be written to them
+    queue: mpsc::Sender<String>,
+    // fired when they've fallen too far behind and are dropped from the room
+    kick: ShutdownToken,
+}
+
+/// What a client gets for joining the room.
+#[derive(Debug)]
+struct Membership {
+    id: u64,
+    // who was already here
+    present: Vec<String>,
+    // everything said in the room from the join on, for the client to write out
+    queue: mpsc::Receiver<String>,
+    kicked: ShutdownSignal,
+}
+
+/// Everyone who has picked a name. Each member has their own bounded queue, so a slow reader
+/// only ever holds up itself: one whose queue is full is dropped from the room rather than
+/// making everyone else wait.
+#[derive(Debug)]
+struct Room {
+    members: Mutex<BTreeMap<String, Member>>,
+    next_id: AtomicU64,
+    // follow every join and leave with how many are online now
+    announce_online: bool,
+    client_backlog: usize,
+}
+
+impl Room {
+    fn new(announce_online: bool, client_backlog: usize) -> Room {
+        Room {
+            members: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            announce_online,
+            // a channel has to have room for something
+            client_backlog: client_backlog.max(1),
+        }
+    }
+
+    /// Adds `name` to the room and announces it. Everything said from this point on ends up in
+    /// the membership's queue.
+    async fn join(&self, name: &str) -> Result<Membership, JoinError> {
+        let mut members = self.members.lock().await;
+        if members.contains_key(name) {
+            return Err(JoinError::NameTaken);
+        }
+        let present = members.keys().cloned().collect();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, queue) = mpsc::channel(self.client_backlog);
+        let kick = ShutdownToken::new();
+        let kicked = kick.subscribe();
+        members.insert(
+            name.to_string(),
+            Member {
+                id,
+                queue: sender,
+                kick,
+            },
+        );
+        let mut lines = VecDeque::from([(
+            Some(name.to_string()),
+            format!("* {} has entered the room", name),
+        )]);
+        if let Some(online) = self.online(&members) {
+            lines.push_back((None, online));
+        }
+        self.deliver(&mut members, lines);
+        Ok(Membership {
+            id,
+            present,
+            queue,
+            kicked,
+        })
+    }
+
+    /// Takes the member that joined as `id` out of the room and tells everyone. Nothing to do
+    /// when they were already dropped for falling behind, that's been announced.
+    async fn leave(&self, name: &str, id: u64) {
+        let mut members = self.members.lock().await;
+        if members.get(name).map(|member| member.id) != Some(id) {
+            return;
+        }
+        members.remove(name);
+        let mut lines = VecDeque::from([(
+            Some(name.to_string()),
+            format!("* {} has left the room", name),
+        )]);
+        if let Some(online) = self.online(&members) {
+            lines.push_back((None, online));
+        }
+        self.deliver(&mut members, lines);
+    }
+
+    /// A line for everyone but `from`.
+    async fn send(&self, from: &str, text: String) {
+        let mut members = self.members.lock().await;
+        self.deliver(
+            &mut members,
+            VecDeque::from([(Some(from.to_string()), text)]),
+        );
+    }
+
+    /// Queues each line for everyone except whoever it's from, `None` being the server and
+    /// meaning everyone. Members who've fallen behind (or already gone) are dropped on the
+    /// spot and their leaving goes out like any other line, to whoever's still here. Always
+    /// called with the members lock held, so lines go out in the order things happened and
+    /// two people joining at once each get their own count.
+    fn deliver(
+        &self,
+        members: &mut BTreeMap<String, Member>,
+        mut lines: VecDeque<(Option<String>, String)>,
+    ) {
+        while let Some((from, text)) = lines.pop_front() {
+            let mut dropped = Vec::new();
+            for (name, member) in members.iter() {
+                if from.as_deref() == Some(name.as_str()) {
+                    continue;
+                }
+                if let Err(e) = member.queue.try_send(text.clone()) {
+                    info!("Dropping {:?} from the room: {:?}", name, e);
+                    dropped.push(name.clone());
+                }
+            }
+            for name in dropped {
+                if let Some(member) = members.remove(&name) {
+                    member.kick.shutdown();
+                }
+                lines.push_back((Some(name.clone()), format!("* {} has left the room", name)));
+                if let Some(online) = self.online(members) {
+                    lines.push_back((None, online));
+                }
+            }
+        }
+    }
+
+    fn online(&self, members: &BTreeMap<String, Member>) -> Option<String> {
+        self.announce_online
+            .then(|| format!("* {} users online", members.len()))
+    }
+}
+
+/// 1 to 16 ascii letters or digits.
+fn valid_name(name: &str) -> bool {
+    (1..=16).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        health_address: config.health_address.clone(),
+        ..ServerConfig::default()
+    };
+    let room = Arc::new(Room::new(config.announce_online, config.client_backlog));
+    let config = Arc::new(config);
+    run_tcp_server(
+        &server_config,
+        ready_signal,
+        shutdown,
+        move |stream, remote_addr, shutdown_signal| {
+            let room = room.clone();
+            let config = config.clone();
+            async move {
+                handle_client(stream, remote_addr, room, config, shutdown_signal).await;
+            }
+        },
+    )
+    .await;
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    remote_addr: Peer,
+    room: Arc<Room>,
+    config: Arc<Config>,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut lines = Framed::new(
+        stream,
+        LinesCodec::new_with_max_length(config.max_line_length),
+    );
+    if let Err(e) = lines.send(WELCOME).await {
+        info!("Couldn't welcome {:?} : {:?}", remote_addr, e);
+        return;
+    }
+
+    let name = tokio::select! {
+        name = lines.next() => name,
+        _ = shutdown.recv() => return,
+    };
+    let name = match name {
+        Some(Ok(name)) => name,
+        Some(Err(e)) => {
+            info!("Couldn't read a name from {:?} : {:?}", remote_addr, e);
+            return;
+        }
+        None => {
+            info!("{:?} left before picking a name", remote_addr);
+            return;
+        }
+    };
+    // a rejected client never joins, so nobody else hears about it
+    if !valid_name(&name) {
+        info!("Illegal name {:?} from {:?}", name, remote_addr);
+        let _ = lines
+            .send("* Names have to be 1 to 16 letters or digits")
+            .await;
+        return;
+    }
+    let Membership {
+        id,
+        present,
+        mut queue,
+        mut kicked,
+    } = match room.join(&name).await {
+        Ok(membership) => membership,
+        Err(JoinError::NameTaken) => {
+            info!("Name {:?} from {:?} is taken", name, remote_addr);
+            let _ = lines.send(format!("* {} is already here", name)).await;
+            return;
+        }
+    };
+    info!("{:?} joined as {:?}", remote_addr, name);
+
+    if lines
+        .send(format!("* The room contains: {}", present.join(", ")))
+        .await
+        .is_ok()
+    {
+        let chat = async {
+            loop {
+                tokio::select! {
+                    line = lines.next() => match line {
+                        Some(Ok(message)) => {
+                            room.send(&name, format!("[{}] {}", name, message)).await
+                        }
+                        Some(Err(e)) => {
+                            info!("Error reading from {:?} : {:?}", name, e);
+                            break;
+                        }
+                        None => break,
+                    },
+                    line = queue.recv() => match line {
+                        Some(line) => {
+                            if let Err(e) = lines.send(line).await {
+                                info!("Error writing to {:?} : {:?}", name, e);
+                                break;
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = shutdown.recv() => break,
+                }
+            }
+        };
+        // being dropped from the room cuts the client off even mid write, that's most likely
+        // where a client that stopped reading is stuck
+        tokio::select! {
+            _ = chat => {}
+            _ = kicked.recv() => info!("{:?} fell too far behind, disconnecting", name),
+        }
+    }
+
+    room.leave(&name, id).await;
+    info!("{:?} left", name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::time;
+
+    type Client = Framed<TcpStream, LinesCodec>;
+
+    async fn start_server(address: &str) -> ShutdownToken {
+        start_server_with(Config {
+            address: address.to_string(),
+            ..Config::default()
+        })
+        .await
+    }
+
+    async fn start_server_with(config: Config) -> ShutdownToken {
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+        shutdown
+    }
+
+    async fn next_line(client: &mut Client) -> Option<String> {
+        time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("Timed out waiting for a line")
+            .map(|line| line.expect("Couldn't read line"))
+    }
+
+    async fn connect(address: &str, name: &str) -> Client {
+        let stream = TcpStream::connect(address)
+            .await
+            .expect("Couldn't connect to test server");
+        let mut client = Framed::new(stream, LinesCodec::new());
+        assert_eq!(Some(String::from(WELCOME)), next_line(&mut client).await);
+        client.send(name).await.unwrap();
+        client
+    }
+
+    // connects and returns the room listing
+    async fn join(address: &str, name: &str) -> (Client, String) {
+        let mut client = connect(address, name).await;
+        let listing = next_line(&mut client).await.expect("No room listing");
+        (client, listing)
+    }
+
+    // nothing arrives for a little while
+    async fn assert_quiet(client: &mut Client) {
+        let next = time::timeout(Duration::from_millis(100), client.next()).await;
+        assert!(next.is_err(), "Unexpected line {:?}", next);
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("alice"));
+        assert!(valid_name("Bob42"));
+        assert!(valid_name("a"));
+        assert!(valid_name("abcdefghijklmnop"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("abcdefghijklmnopq"));
+        assert!(!valid_name("bad name"));
+        assert!(!valid_name("no_underscores"));
+        assert!(!valid_name("émile"));
+    }
+
+    #[tokio::test]
+    async fn test_name_validation() {
+        let address = "127.0.0.1:8001";
+        let shutdown = start_server(address).await;
+        let (mut alice, listing) = join(address, "alice").await;
+        assert_eq!("* The room contains: ", listing);
+
+        for name in ["", "way2long4anyone2use", "not ok!", "alice"] {
+            let mut client = connect(address, name).await;
+            // an error message and then the connection is closed
+            assert!(next_line(&mut client)
+                .await
+                .expect("No error message")
+                .starts_with('*'));
+            assert_eq!(None, next_line(&mut client).await);
+        }
+        // nobody hears about rejected clients
+        assert_quiet(&mut alice).await;
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave() {
+        let address = "127.0.0.1:8002";
+        let shutdown = start_server(address).await;
+        let (mut alice, _) = join(address, "alice").await;
+        let (mut bob, listing) = join(address, "bob").await;
+        assert_eq!("* The room contains: alice", listing);
+        assert_eq!(
+            Some(String::from("* bob has entered the room")),
+            next_line(&mut alice).await
+        );
+        let (charlie, listing) = join(address, "charlie").await;
+        assert_eq!("* The room contains: alice, bob", listing);
+        assert_eq!(
+            Some(String::from("* charlie has entered the room")),
+            next_line(&mut bob).await
+        );
+        assert_eq!(
+            Some(String::from("* charlie has entered the room")),
+            next_line(&mut alice).await
+        );
+
+        drop(charlie);
+        assert_eq!(
+            Some(String::from("* charlie has left the room")),
+            next_line(&mut alice).await
+        );
+        assert_eq!(
+            Some(String::from("* charlie has left the room")),
+            next_line(&mut bob).await
+        );
+
+        // the name is free again once its owner has left
+        let (_, listing) = join(address, "charlie").await;
+        assert_eq!("* The room contains: alice, bob", listing);
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_message_fan_out() {
+        let address = "127.0.0.1:8003";
+        let shutdown = start_server(address).await;
+        let (mut alice, _) = join(address, "alice").await;
+        let (mut bob, _) = join(address, "bob").await;
+        let (mut charlie, _) = join(address, "charlie").await;
+        // skip the join announcements
+        for _ in 0..2 {
+            next_line(&mut alice).await;
+        }
+        next_line(&mut bob).await;
+
+        alice.send("hi everyone").await.unwrap();
+        assert_eq!(
+            Some(String::from("[alice] hi everyone")),
+            next_line(&mut bob).await
+        );
+        assert_eq!(
+            Some(String::from("[alice] hi everyone")),
+            next_line(&mut charlie).await
+        );
+        // senders don't get their own messages back
+        assert_quiet(&mut alice).await;
+
+        charlie.send("hey alice").await.unwrap();
+        assert_eq!(
+            Some(String::from("[charlie] hey alice")),
+            next_line(&mut alice).await
+        );
+        assert_eq!(
+            Some(String::from("[charlie] hey alice")),
+            next_line(&mut bob).await
+        );
+        assert_quiet(&mut charlie).await;
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_announce_online() {
+        let address = "127.0.0.1:8004";
+        let shutdown = start_server_with(Config {
+            address: address.to_string(),
+            announce_online: true,
+            ..Config::default()
+        })
+        .await;
+        let line = |text: &str| Some(String::from(text));
+
+        // the count comes after the listing for whoever joined, and after the join for the rest
+        let (mut alice, _) = join(address, "alice").await;
+        assert_eq!(line("* 1 users online"), next_line(&mut alice).await);
+        let (mut bob, _) = join(address, "bob").await;
+        assert_eq!(line("* 2 users online"), next_line(&mut bob).await);
+        assert_eq!(
+            line("* bob has entered the room"),
+            next_line(&mut alice).await
+        );
+        assert_eq!(line("* 2 users online"), next_line(&mut alice).await);
+        let (mut charlie, _) = join(address, "charlie").await;
+        assert_eq!(line("* 3 users online"), next_line(&mut charlie).await);
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(
+                line("* charlie has entered the room"),
+                next_line(client).await
+            );
+            assert_eq!(line("* 3 users online"), next_line(client).await);
+        }
+
+        drop(bob);
+        for client in [&mut alice, &mut charlie] {
+            assert_eq!(line("* bob has left the room"), next_line(client).await);
+            assert_eq!(line("* 2 users online"), next_line(client).await);
+        }
+        drop(alice);
+        assert_eq!(
+            line("* alice has left the room"),
+            next_line(&mut charlie).await
+        );
+        assert_eq!(line("* 1 users online"), next_line(&mut charlie).await);
+        assert_quiet(&mut charlie).await;
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_joins() {
+        let address = "127.0.0.1:8005";
+        let shutdown = start_server_with(Config {
+            address: address.to_string(),
+            announce_online: true,
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = join(address, "alice").await;
+        assert_eq!(
+            Some(String::from("* 1 users online")),
+            next_line(&mut alice).await
+        );
+
+        let ((_bob, _), (_charlie, _)) =
+            tokio::join!(join(address, "bob"), join(address, "charlie"));
+        // whichever got in first, alice sees both counts once each and in order
+        let mut counts = Vec::new();
+        for _ in 0..4 {
+            let line = next_line(&mut alice).await.unwrap();
+            if line.ends_with("users online") {
+                counts.push(line);
+            }
+        }
+        assert_eq!(vec!["* 2 users online", "* 3 users online"], counts);
+        assert_quiet(&mut alice).await;
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_client() {
+        let address = "127.0.0.1:8006";
+        let shutdown = start_server_with(Config {
+            address: address.to_string(),
+            client_backlog: 8,
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = join(address, "alice").await;
+        let (mut bob, _) = join(address, "bob").await;
+        // never reads a thing once it's in
+        let (_carol, _) = join(address, "carol").await;
+        next_line(&mut alice).await;
+        next_line(&mut alice).await;
+        next_line(&mut bob).await;
+
+        // bob keeps up line by line while carol's socket buffers and then her queue fill up.
+        // That's a few MB, so it takes a while
+        let message = "x".repeat(990);
+        let mut sent = 0;
+        loop {
+            alice.send(&message).await.unwrap();
+            sent += 1;
+            let line = next_line(&mut bob).await.unwrap();
+            if line == "* carol has left the room" {
+                break;
+            }
+            assert_eq!(format!("[alice] {}", message), line);
+            assert!(sent < 100_000, "carol was never dropped");
+        }
+        // the line sent after the one that did carol in was still on its way
+        assert_eq!(
+            Some(format!("[alice] {}", message)),
+            next_line(&mut bob).await
+        );
+        assert_eq!(
+            Some(String::from("* carol has left the room")),
+            next_line(&mut alice).await
+        );
+
+        // everyone else carries on, and the name is free again
+        alice.send("still here").await.unwrap();
+        assert_eq!(
+            Some(String::from("[alice] still here")),
+            next_line(&mut bob).await
+        );
+        let (_, listing) = join(address, "carol").await;
+        assert_eq!("* The room contains: alice, bob", listing);
+
+        shutdown.shutdown();
+    }
+}
@@ -0,0 +1,356 @@
+use common::env::env_var;
+use common::health::serve_health;
+use common::observability::init_tracing_from_env;
+use common::udp::bind_udp;
+use common::ShutdownToken;
+use std::collections::HashMap;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same socket too
+    dual_stack: bool,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6),
+    /// `DUAL_STACK=true` lets an ipv6 socket take ipv4 clients too,
+    /// `HEALTH_ADDRESS` is where to answer liveness probes over tcp.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+// requests and responses both have to be shorter than this
+const MAX_PACKET: usize = 1000;
+
+const VERSION_KEY: &[u8] = b"version";
+const VERSION: &[u8] = b"Ken's Key-Value Store 1.0";
+
+#[derive(Debug, PartialEq)]
+enum Request<'a> {
+    Insert { key: &'a [u8], value: &'a [u8] },
+    Retrieve { key: &'a [u8] },
+}
+
+impl Request<'_> {
+    /// Anything with an `=` is an insert, split on the first one. Everything else is a retrieve.
+    fn parse(packet: &[u8]) -> Request<'_> {
+        match packet.iter().position(|byte| *byte == b'=') {
+            Some(split) => Request::Insert {
+                key: &packet[..split],
+                value: &packet[split + 1..],
+            },
+            None => Request::Retrieve { key: packet },
+        }
+    }
+}
+
+/// Keys and values are kept as raw bytes, nothing says they have to be utf8.
+#[derive(Debug, Default)]
+struct Store {
+    values: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Store {
+    /// The response to send back for `packet`, if any. Inserts are never answered, and
+    /// neither are retrieves for keys that were never inserted.
+    fn handle(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        match Request::parse(packet) {
+            // the version is read only
+            Request::Insert { key, .. } if key == VERSION_KEY => None,
+            Request::Insert { key, value } => {
+                self.values.insert(key.to_vec(), value.to_vec());
+                None
+            }
+            Request::Retrieve { key } => {
+                let value = if key == VERSION_KEY {
+                    VERSION
+                } else {
+                    self.values.get(key)?
+                };
+                let mut response = Vec::with_capacity(key.len() + 1 + value.len());
+                response.extend_from_slice(key);
+                response.push(b'=');
+                response.extend_from_slice(value);
+                Some(response)
+            }
+        }
+    }
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let socket = bind_udp(&config.address, config.dual_stack)
+        .await
+        .expect("Couldn't bind udp socket on address");
+    info!("Listening on address: {:?}", socket.local_addr());
+    let health_listener = match &config.health_address {
+        Some(health_address) => Some(
+            TcpListener::bind(health_address)
+                .await
+                .expect("Couldn't start health listener on address"),
+        ),
+        None => None,
+    };
+    ready_signal
+        .send(true)
+        .expect("Couldn't send ready signal after server has started");
+    if let Some(health_listener) = health_listener {
+        tokio::spawn(serve_health(health_listener, shutdown.subscribe()));
+    }
+
+    let mut store = Store::default();
+    let mut shutdown_signal = shutdown.subscribe();
+    // the biggest legal packet leaves a byte spare, anything that fills the buffer is too big
+    let mut buffer = [0; MAX_PACKET];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received,
+            _ = shutdown_signal.recv() => break,
+        };
+        let (read, sender) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving datagram, {:?}", e);
+                continue;
+            }
+        };
+        if read >= MAX_PACKET {
+            info!("Ignoring oversized packet from {:?}", sender);
+            continue;
+        }
+        debug!(
+            "{:?} sent {:?}",
+            sender,
+            String::from_utf8_lossy(&buffer[..read])
+        );
+        let Some(response) = store.handle(&buffer[..read]) else {
+            continue;
+        };
+        if response.len() >= MAX_PACKET {
+            info!("Response for {:?} is too big to send", sender);
+            continue;
+        }
+        if let Err(e) = socket.send_to(&response, sender).await {
+            error!("Couldn't respond to {:?} : {:?}", sender, e);
+        }
+    }
+    info!("Server stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::observability::init_tracing;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpStream, UdpSocket};
+    use tokio::time;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Request::Insert {
+                key: b"foo",
+                value: b"bar"
+            },
+            Request::parse(b"foo=bar")
+        );
+        // split on the first equals only
+        assert_eq!(
+            Request::Insert {
+                key: b"foo",
+                value: b"bar=baz"
+            },
+            Request::parse(b"foo=bar=baz")
+        );
+        assert_eq!(
+            Request::Insert {
+                key: b"",
+                value: b"foo"
+            },
+            Request::parse(b"=foo")
+        );
+        assert_eq!(
+            Request::Insert {
+                key: b"foo",
+                value: b""
+            },
+            Request::parse(b"foo=")
+        );
+        assert_eq!(Request::Retrieve { key: b"foo" }, Request::parse(b"foo"));
+        assert_eq!(Request::Retrieve { key: b"" }, Request::parse(b""));
+    }
+
+    #[test]
+    fn test_insert_and_retrieve() {
+        let mut store = Store::default();
+        assert_eq!(None, store.handle(b"foo=bar"));
+        assert_eq!(Some(b"foo=bar".to_vec()), store.handle(b"foo"));
+        // never inserted
+        assert_eq!(None, store.handle(b"bar"));
+
+        assert_eq!(None, store.handle(b"message=Hello,=world!"));
+        assert_eq!(
+            Some(b"message=Hello,=world!".to_vec()),
+            store.handle(b"message")
+        );
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let mut store = Store::default();
+        store.handle(b"foo=bar");
+        store.handle(b"foo=baz");
+        assert_eq!(Some(b"foo=baz".to_vec()), store.handle(b"foo"));
+    }
+
+    #[test]
+    fn test_empty_values() {
+        let mut store = Store::default();
+        store.handle(b"foo=");
+        assert_eq!(Some(b"foo=".to_vec()), store.handle(b"foo"));
+        // the empty key is a key like any other
+        store.handle(b"=empty");
+        assert_eq!(Some(b"=empty".to_vec()), store.handle(b""));
+    }
+
+    #[test]
+    fn test_version() {
+        let mut store = Store::default();
+        assert_eq!(
+            Some(b"version=Ken's Key-Value Store 1.0".to_vec()),
+            store.handle(b"version")
+        );
+        assert_eq!(None, store.handle(b"version=hacked"));
+        assert_eq!(
+            Some(b"version=Ken's Key-Value Store 1.0".to_vec()),
+            store.handle(b"version")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let config = Config {
+            address: String::from("127.0.0.1:8001"),
+            dual_stack: false,
+            health_address: Some(String::from("127.0.0.1:8002")),
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+
+        // answered over tcp, off to the side of the datagrams
+        let mut probe = TcpStream::connect("127.0.0.1:8002").await.unwrap();
+        let mut answer = String::new();
+        probe.read_to_string(&mut answer).await.unwrap();
+        assert_eq!("OK\n", answer);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect("127.0.0.1:8001").await.unwrap();
+        let mut response = [0; MAX_PACKET];
+
+        client.send(b"foo=bar").await.unwrap();
+        // too big, dropped without being stored
+        let mut oversized = b"foo=".to_vec();
+        oversized.resize(MAX_PACKET, b'x');
+        client.send(&oversized).await.unwrap();
+        client.send(b"foo").await.unwrap();
+        let read = time::timeout(Duration::from_secs(5), client.recv(&mut response))
+            .await
+            .expect("No response from server")
+            .unwrap();
+        assert_eq!(b"foo=bar", &response[..read]);
+
+        client.send(b"version").await.unwrap();
+        let read = time::timeout(Duration::from_secs(5), client.recv(&mut response))
+            .await
+            .expect("No response from server")
+            .unwrap();
+        assert_eq!(b"version=Ken's Key-Value Store 1.0", &response[..read]);
+
+        shutdown.shutdown();
+        time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
+
+    #[tokio::test]
+    async fn test_server_ipv6() {
+        let config = Config {
+            address: String::from("[::1]:8003"),
+            dual_stack: false,
+            health_address: None,
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        client.connect("[::1]:8003").await.unwrap();
+        client.send(b"version").await.unwrap();
+        let mut response = [0; MAX_PACKET];
+        let read = time::timeout(Duration::from_secs(5), client.recv(&mut response))
+            .await
+            .expect("No response from server")
+            .unwrap();
+        assert_eq!(b"version=Ken's Key-Value Store 1.0", &response[..read]);
+
+        shutdown.shutdown();
+        time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
+}
@@ -0,0 +1,249 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ClientMessage {
+    Plate { plate: String, timestamp: u32 },
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<u16> },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ServerMessage {
+    Error { msg: String },
+    Ticket(Ticket),
+    Heartbeat,
+}
+
+/// `speed` is in hundredths of a mile per hour, `mile1`/`timestamp1` is always the earlier
+/// observation.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    pub speed: u16,
+}
+
+/// Decodes client messages and encodes server messages. Messages vary in length, so nothing is
+/// consumed from the buffer until a whole one has arrived.
+#[derive(Debug, Default)]
+pub struct SpeedCodec;
+
+fn read_u8(buf: &mut &[u8]) -> Option<u8> {
+    (buf.remaining() >= 1).then(|| buf.get_u8())
+}
+
+fn read_u16(buf: &mut &[u8]) -> Option<u16> {
+    (buf.remaining() >= 2).then(|| buf.get_u16())
+}
+
+fn read_u32(buf: &mut &[u8]) -> Option<u32> {
+    (buf.remaining() >= 4).then(|| buf.get_u32())
+}
+
+fn read_str(buf: &mut &[u8]) -> Option<String> {
+    let len = read_u8(buf)? as usize;
+    if buf.remaining() < len {
+        return None;
+    }
+    // plates are meant to be ascii, anything else is kept rather than rejected
+    let text = String::from_utf8_lossy(&buf[..len]).into_owned();
+    buf.advance(len);
+    Some(text)
+}
+
+/// `Ok(None)` when `buf` doesn't hold a whole message yet.
+fn parse(buf: &mut &[u8]) -> io::Result<Option<ClientMessage>> {
+    let Some(message_type) = read_u8(buf) else {
+        return Ok(None);
+    };
+    let message = match message_type {
+        0x20 => (|| {
+            Some(ClientMessage::Plate {
+                plate: read_str(buf)?,
+                timestamp: read_u32(buf)?,
+            })
+        })(),
+        0x40 => read_u32(buf).map(|interval| ClientMessage::WantHeartbeat { interval }),
+        0x80 => (|| {
+            Some(ClientMessage::IAmCamera {
+                road: read_u16(buf)?,
+                mile: read_u16(buf)?,
+                limit: read_u16(buf)?,
+            })
+        })(),
+        0x81 => (|| {
+            let numroads = read_u8(buf)?;
+            let roads = (0..numroads)
+                .map(|_| read_u16(buf))
+                .collect::<Option<Vec<_>>>()?;
+            Some(ClientMessage::IAmDispatcher { roads })
+        })(),
+        invalid_type => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type {:#04x}", invalid_type),
+            ))
+        }
+    };
+    Ok(message)
+}
+
+impl Decoder for SpeedCodec {
+    type Item = ClientMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<ClientMessage>> {
+        let mut buf = &src[..];
+        let Some(message) = parse(&mut buf)? else {
+            return Ok(None);
+        };
+        let consumed = src.len() - buf.len();
+        src.advance(consumed);
+        Ok(Some(message))
+    }
+}
+
+fn put_str(dst: &mut BytesMut, text: &str) {
+    // strings are length prefixed with a single byte
+    let bytes = &text.as_bytes()[..text.len().min(u8::MAX as usize)];
+    dst.put_u8(bytes.len() as u8);
+    dst.put_slice(bytes);
+}
+
+impl Encoder<ServerMessage> for SpeedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: ServerMessage, dst: &mut BytesMut) -> io::Result<()> {
+        match message {
+            ServerMessage::Error { msg } => {
+                dst.put_u8(0x10);
+                put_str(dst, &msg);
+            }
+            ServerMessage::Ticket(ticket) => {
+                dst.put_u8(0x21);
+                put_str(dst, &ticket.plate);
+                dst.put_u16(ticket.road);
+                dst.put_u16(ticket.mile1);
+                dst.put_u32(ticket.timestamp1);
+                dst.put_u16(ticket.mile2);
+                dst.put_u32(ticket.timestamp2);
+                dst.put_u16(ticket.speed);
+            }
+            ServerMessage::Heartbeat => dst.put_u8(0x41),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> io::Result<Vec<ClientMessage>> {
+        let mut codec = SpeedCodec;
+        let mut buffer = BytesMut::from(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = codec.decode(&mut buffer)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn encode(message: ServerMessage) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        SpeedCodec.encode(message, &mut buffer).unwrap();
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn test_decode() {
+        // examples from the spec
+        let messages = decode_all(&[
+            0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8, // Plate
+            0x40, 0x00, 0x00, 0x00, 0x0a, // WantHeartbeat
+            0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c, // IAmCamera
+            0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88, // IAmDispatcher
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                ClientMessage::Plate {
+                    plate: String::from("UN1X"),
+                    timestamp: 1000
+                },
+                ClientMessage::WantHeartbeat { interval: 10 },
+                ClientMessage::IAmCamera {
+                    road: 66,
+                    mile: 100,
+                    limit: 60
+                },
+                ClientMessage::IAmDispatcher {
+                    roads: vec![66, 368, 5000]
+                },
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid_type() {
+        let result = decode_all(&[0x21, 0x00]);
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_decode_split_message() {
+        let message = [0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8];
+        let mut codec = SpeedCodec;
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        // nothing comes out, or gets consumed, until the last byte is in
+        for byte in message {
+            buffer.put_u8(byte);
+            if let Some(message) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(
+            vec![ClientMessage::Plate {
+                plate: String::from("UN1X"),
+                timestamp: 1000
+            }],
+            decoded
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            vec![0x10, 0x03, 0x62, 0x61, 0x64],
+            encode(ServerMessage::Error {
+                msg: String::from("bad")
+            })
+        );
+        assert_eq!(
+            vec![
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+                0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+            ],
+            encode(ServerMessage::Ticket(Ticket {
+                plate: String::from("UN1X"),
+                road: 66,
+                mile1: 100,
+                timestamp1: 123456,
+                mile2: 110,
+                timestamp2: 123816,
+                speed: 10000,
+            }))
+        );
+        assert_eq!(vec![0x41], encode(ServerMessage::Heartbeat));
+    }
+}
@@ -0,0 +1,475 @@
+mod codec;
+mod traffic;
+
+use codec::{ClientMessage, ServerMessage, SpeedCodec, Ticket};
+use common::env::env_var;
+use common::observability::init_tracing_from_env;
+use common::{run_tcp_server, Peer, ServerConfig, ShutdownSignal, ShutdownToken};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{self, Instant, Interval};
+use tokio_util::codec::Framed;
+use tracing::{error, info};
+use traffic::Traffic;
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    dual_stack: bool,
+    // connections handled at once before the server stops accepting, cameras and dispatchers alike
+    max_connections: usize,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once and `HEALTH_ADDRESS` is
+    /// where to answer liveness probes.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Camera {
+    road: u16,
+    mile: u16,
+    limit: u16,
+}
+
+/// Who a client has said it is, it only gets to say so once.
+#[derive(Debug)]
+enum Role {
+    Unidentified,
+    Camera(Camera),
+    Dispatcher { id: u64, roads: Vec<u16> },
+}
+
+/// Connected dispatchers by road, and tickets waiting on a road that doesn't have one yet.
+#[derive(Debug, Default)]
+struct Dispatch {
+    next_id: u64,
+    dispatchers: HashMap<u16, Vec<(u64, mpsc::UnboundedSender<Ticket>)>>,
+    pending: HashMap<u16, Vec<Ticket>>,
+}
+
+impl Dispatch {
+    fn issue(&mut self, mut ticket: Ticket) {
+        if let Some(dispatchers) = self.dispatchers.get_mut(&ticket.road) {
+            // a closed channel is a dispatcher on its way out, try the next one
+            while let Some((_, sender)) = dispatchers.first() {
+                match sender.send(ticket) {
+                    Ok(()) => return,
+                    Err(mpsc::error::SendError(unsent)) => {
+                        ticket = unsent;
+                        dispatchers.remove(0);
+                    }
+                }
+            }
+        }
+        self.pending.entry(ticket.road).or_default().push(ticket);
+    }
+}
+
+/// Everything shared between connections: what the cameras have seen and who to send tickets to.
+#[derive(Debug, Default)]
+struct Headquarters {
+    traffic: Mutex<Traffic>,
+    dispatch: Mutex<Dispatch>,
+}
+
+impl Headquarters {
+    async fn observe(&self, camera: Camera, plate: &str, timestamp: u32) {
+        let tickets = self.traffic.lock().await.observe(
+            camera.road,
+            camera.mile,
+            camera.limit,
+            plate,
+            timestamp,
+        );
+        if tickets.is_empty() {
+            return;
+        }
+        let mut dispatch = self.dispatch.lock().await;
+        for ticket in tickets {
+            info!("Issuing {:?}", ticket);
+            dispatch.issue(ticket);
+        }
+    }
+
+    /// Signs up a dispatcher for `roads`. Any tickets already waiting on those roads are
+    /// delivered straight away.
+    async fn register(&self, roads: &[u16]) -> (u64, mpsc::UnboundedReceiver<Ticket>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut dispatch = self.dispatch.lock().await;
+        let id = dispatch.next_id;
+        dispatch.next_id += 1;
+        for road in roads {
+            dispatch
+                .dispatchers
+                .entry(*road)
+                .or_default()
+                .push((id, sender.clone()));
+            for ticket in dispatch.pending.remove(road).unwrap_or_default() {
+                // the receiver is still in hand, so this can't fail
+                let _ = sender.send(ticket);
+            }
+        }
+        (id, receiver)
+    }
+
+    async fn unregister(&self, id: u64, roads: &[u16]) {
+        let mut dispatch = self.dispatch.lock().await;
+        for road in roads {
+            if let Some(dispatchers) = dispatch.dispatchers.get_mut(road) {
+                dispatchers.retain(|(dispatcher, _)| *dispatcher != id);
+            }
+        }
+    }
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        health_address: config.health_address.clone(),
+        ..ServerConfig::default()
+    };
+    let headquarters = Arc::new(Headquarters::default());
+    run_tcp_server(
+        &server_config,
+        ready_signal,
+        shutdown,
+        move |stream, remote_addr, shutdown_signal| {
+            let headquarters = headquarters.clone();
+            async move {
+                handle_client(stream, remote_addr, headquarters, shutdown_signal).await;
+            }
+        },
+    )
+    .await;
+}
+
+// never fires without a heartbeat to send
+async fn next_heartbeat(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// never fires for clients that aren't dispatchers
+async fn next_ticket(tickets: &mut Option<mpsc::UnboundedReceiver<Ticket>>) -> Option<Ticket> {
+    match tickets {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    remote_addr: Peer,
+    headquarters: Arc<Headquarters>,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut framed = Framed::new(stream, SpeedCodec);
+    let mut role = Role::Unidentified;
+    let mut heartbeat_requested = false;
+    let mut heartbeat = None;
+    let mut tickets = None;
+
+    // breaks out with the error to send the client, if it did something illegal
+    let illegal = loop {
+        let message = tokio::select! {
+            message = framed.next() => message,
+            Some(ticket) = next_ticket(&mut tickets) => {
+                if let Err(e) = framed.send(ServerMessage::Ticket(ticket)).await {
+                    info!("Couldn't send ticket to {:?} : {:?}", remote_addr, e);
+                    break None;
+                }
+                continue;
+            }
+            _ = next_heartbeat(&mut heartbeat) => {
+                if let Err(e) = framed.send(ServerMessage::Heartbeat).await {
+                    info!("Couldn't send heartbeat to {:?} : {:?}", remote_addr, e);
+                    break None;
+                }
+                continue;
+            }
+            _ = shutdown.recv() => break None,
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                info!("Bad message from {:?} : {:?}", remote_addr, e);
+                break Some("illegal msg");
+            }
+            None => break None,
+        };
+        match message {
+            ClientMessage::Plate { plate, timestamp } => match role {
+                Role::Camera(camera) => headquarters.observe(camera, &plate, timestamp).await,
+                _ => break Some("not a camera"),
+            },
+            ClientMessage::WantHeartbeat { interval } => {
+                if heartbeat_requested {
+                    break Some("heartbeat already requested");
+                }
+                heartbeat_requested = true;
+                // interval is in deciseconds, 0 means no heartbeats
+                if interval > 0 {
+                    let period = Duration::from_millis(interval as u64 * 100);
+                    heartbeat = Some(time::interval_at(Instant::now() + period, period));
+                }
+            }
+            ClientMessage::IAmCamera { road, mile, limit } => {
+                if !matches!(role, Role::Unidentified) {
+                    break Some("already identified");
+                }
+                info!(
+                    "{:?} is a camera on road {} at mile {}",
+                    remote_addr, road, mile
+                );
+                role = Role::Camera(Camera { road, mile, limit });
+            }
+            ClientMessage::IAmDispatcher { roads } => {
+                if !matches!(role, Role::Unidentified) {
+                    break Some("already identified");
+                }
+                info!("{:?} is a dispatcher for roads {:?}", remote_addr, roads);
+                let (id, receiver) = headquarters.register(&roads).await;
+                tickets = Some(receiver);
+                role = Role::Dispatcher { id, roads };
+            }
+        }
+    };
+
+    if let Some(msg) = illegal {
+        info!("Disconnecting {:?} : {}", remote_addr, msg);
+        let _ = framed
+            .send(ServerMessage::Error {
+                msg: msg.to_string(),
+            })
+            .await;
+    }
+    if let Role::Dispatcher { id, roads } = role {
+        headquarters.unregister(id, &roads).await;
+    }
+    info!("Closing connection for {:?}", remote_addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn start_server(address: &str) -> ShutdownToken {
+        let config = Config {
+            address: address.to_string(),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+        shutdown
+    }
+
+    async fn read_bytes(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        time::timeout(Duration::from_secs(5), stream.read_exact(&mut buffer))
+            .await
+            .expect("Timed out waiting for the server")
+            .expect("Couldn't read from server");
+        buffer
+    }
+
+    // an error message, then the server hangs up
+    async fn assert_error(stream: &mut TcpStream) {
+        let header = read_bytes(stream, 2).await;
+        assert_eq!(0x10, header[0]);
+        read_bytes(stream, header[1] as usize).await;
+        let mut rest = Vec::new();
+        time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .expect("Server didn't hang up")
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ticket_dispatch() {
+        let address = "127.0.0.1:8001";
+        let shutdown = start_server(address).await;
+
+        let mut camera_1 = TcpStream::connect(address).await.unwrap();
+        camera_1
+            .write_all(&[
+                0x80, 0x00, 0x7b, 0x00, 0x08, 0x00,
+                0x3c, // IAmCamera{road: 123, mile: 8, limit: 60}
+                0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00,
+                0x00, // Plate{plate: "UN1X", timestamp: 0}
+            ])
+            .await
+            .unwrap();
+        let mut camera_2 = TcpStream::connect(address).await.unwrap();
+        camera_2
+            .write_all(&[
+                0x80, 0x00, 0x7b, 0x00, 0x09, 0x00,
+                0x3c, // IAmCamera{road: 123, mile: 9, limit: 60}
+                0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00,
+                0x2d, // Plate{plate: "UN1X", timestamp: 45}
+            ])
+            .await
+            .unwrap();
+        // give the ticket time to be issued, with nobody around to take it yet
+        time::sleep(Duration::from_millis(50)).await;
+
+        let mut dispatcher = TcpStream::connect(address).await.unwrap();
+        dispatcher
+            .write_all(&[0x81, 0x01, 0x00, 0x7b]) // IAmDispatcher{roads: [123]}
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, // Ticket{plate: "UN1X",
+                0x00, 0x7b, // road: 123,
+                0x00, 0x08, 0x00, 0x00, 0x00, 0x00, // mile1: 8, timestamp1: 0,
+                0x00, 0x09, 0x00, 0x00, 0x00, 0x2d, // mile2: 9, timestamp2: 45,
+                0x1f, 0x40, // speed: 8000}
+            ],
+            read_bytes(&mut dispatcher, 22).await
+        );
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let address = "127.0.0.1:8002";
+        let shutdown = start_server(address).await;
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        // every 0.1 seconds
+        client
+            .write_all(&[0x40, 0x00, 0x00, 0x00, 0x01])
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            assert_eq!(vec![0x41], read_bytes(&mut client, 1).await);
+        }
+        // only one request per client
+        client
+            .write_all(&[0x40, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        // heartbeats that were already on the wire don't count against it
+        let mut next = read_bytes(&mut client, 1).await;
+        while next == vec![0x41] {
+            next = read_bytes(&mut client, 1).await;
+        }
+        assert_eq!(vec![0x10], next);
+
+        // asking for none means none arrive
+        let mut quiet = TcpStream::connect(address).await.unwrap();
+        quiet
+            .write_all(&[0x40, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        let mut buffer = [0; 1];
+        let nothing =
+            time::timeout(Duration::from_millis(300), quiet.read_exact(&mut buffer)).await;
+        assert!(nothing.is_err());
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_illegal_messages() {
+        let address = "127.0.0.1:8003";
+        let shutdown = start_server(address).await;
+
+        // unknown message type
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&[0x99]).await.unwrap();
+        assert_error(&mut client).await;
+
+        // plates only come from cameras
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(&[0x20, 0x01, 0x41, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        assert_error(&mut client).await;
+
+        // identifying twice
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(&[
+                0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, // IAmCamera
+                0x81, 0x01, 0x00, 0x01, // IAmDispatcher
+            ])
+            .await
+            .unwrap();
+        assert_error(&mut client).await;
+
+        shutdown.shutdown();
+    }
+}
@@ -0,0 +1,179 @@
+use crate::codec::Ticket;
+use std::collections::{HashMap, HashSet};
+
+const SECONDS_PER_DAY: u32 = 86400;
+
+#[derive(Debug, Clone, Copy)]
+struct Observation {
+    mile: u16,
+    timestamp: u32,
+}
+
+/// Every plate seen on every road, and the days each plate has already been ticketed for.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    // (road, plate) -> observations sorted by timestamp
+    observations: HashMap<(u16, String), Vec<Observation>>,
+    ticketed_days: HashMap<String, HashSet<u32>>,
+}
+
+impl Traffic {
+    /// Records a camera seeing `plate` and returns any tickets it earns. Observations can arrive
+    /// out of order, so the new one is checked against its neighbours on either side in time.
+    pub fn observe(
+        &mut self,
+        road: u16,
+        mile: u16,
+        limit: u16,
+        plate: &str,
+        timestamp: u32,
+    ) -> Vec<Ticket> {
+        let observations = self
+            .observations
+            .entry((road, plate.to_string()))
+            .or_default();
+        let index = observations.partition_point(|seen| seen.timestamp <= timestamp);
+        let observation = Observation { mile, timestamp };
+        observations.insert(index, observation);
+
+        let mut pairs = Vec::new();
+        if index > 0 {
+            pairs.push((observations[index - 1], observation));
+        }
+        if let Some(next) = observations.get(index + 1) {
+            pairs.push((observation, *next));
+        }
+
+        let mut tickets = Vec::new();
+        for (first, second) in pairs {
+            let Some(speed) = speed(first, second) else {
+                continue;
+            };
+            // caught at limit + 0.5 mph or more
+            if speed < limit as u64 * 100 + 50 {
+                continue;
+            }
+            if let Some(ticket) = self.ticket(road, plate, first, second, speed) {
+                tickets.push(ticket);
+            }
+        }
+        tickets
+    }
+
+    /// A ticket for the pair, unless the car has already been ticketed on any day it covers.
+    fn ticket(
+        &mut self,
+        road: u16,
+        plate: &str,
+        first: Observation,
+        second: Observation,
+        speed: u64,
+    ) -> Option<Ticket> {
+        let days = first.timestamp / SECONDS_PER_DAY..=second.timestamp / SECONDS_PER_DAY;
+        let ticketed = self.ticketed_days.entry(plate.to_string()).or_default();
+        if days.clone().any(|day| ticketed.contains(&day)) {
+            return None;
+        }
+        ticketed.extend(days);
+        Some(Ticket {
+            plate: plate.to_string(),
+            road,
+            mile1: first.mile,
+            timestamp1: first.timestamp,
+            mile2: second.mile,
+            timestamp2: second.timestamp,
+            speed: speed.min(u16::MAX as u64) as u16,
+        })
+    }
+}
+
+/// Average speed between two observations in hundredths of a mile per hour, `None` when they
+/// were made at the same moment.
+fn speed(first: Observation, second: Observation) -> Option<u64> {
+    let seconds = (second.timestamp - first.timestamp) as u64;
+    if seconds == 0 {
+        return None;
+    }
+    let miles = first.mile.abs_diff(second.mile) as u64;
+    Some(miles * 3600 * 100 / seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_between_cameras() {
+        let mut traffic = Traffic::default();
+        // 1 mile in 45 seconds is 80 mph
+        assert!(traffic.observe(123, 8, 60, "UN1X", 0).is_empty());
+        assert_eq!(
+            vec![Ticket {
+                plate: String::from("UN1X"),
+                road: 123,
+                mile1: 8,
+                timestamp1: 0,
+                mile2: 9,
+                timestamp2: 45,
+                speed: 8000,
+            }],
+            traffic.observe(123, 9, 60, "UN1X", 45)
+        );
+
+        // driving the other way, reported out of order, still puts the earlier one first
+        assert!(traffic
+            .observe(7, 10, 60, "RE05BKG", 86400 + 100)
+            .is_empty());
+        assert_eq!(
+            vec![Ticket {
+                plate: String::from("RE05BKG"),
+                road: 7,
+                mile1: 11,
+                timestamp1: 86400 + 40,
+                mile2: 10,
+                timestamp2: 86400 + 100,
+                speed: 6000,
+            }],
+            traffic.observe(7, 11, 59, "RE05BKG", 86400 + 40)
+        );
+    }
+
+    #[test]
+    fn test_within_limit() {
+        let mut traffic = Traffic::default();
+        // 1 mile in 60 seconds is exactly 60 mph
+        traffic.observe(1, 0, 60, "SLOW", 0);
+        assert!(traffic.observe(1, 1, 60, "SLOW", 60).is_empty());
+        // just under the half mph of leeway
+        traffic.observe(2, 0, 60, "CLOSE", 0);
+        assert!(traffic.observe(2, 1000, 60, "CLOSE", 59_750).is_empty());
+        // same moment, no speed to speak of
+        traffic.observe(3, 0, 60, "TWIN", 0);
+        assert!(traffic.observe(3, 1, 60, "TWIN", 0).is_empty());
+        // different roads don't pair up
+        traffic.observe(4, 0, 60, "ROADS", 0);
+        assert!(traffic.observe(5, 10, 60, "ROADS", 1).is_empty());
+    }
+
+    #[test]
+    fn test_one_ticket_per_day() {
+        let mut traffic = Traffic::default();
+        traffic.observe(1, 0, 60, "FAST", 0);
+        assert_eq!(1, traffic.observe(1, 10, 60, "FAST", 60).len());
+        // speeding again the same day, on another road
+        traffic.observe(2, 0, 60, "FAST", 1000);
+        assert!(traffic.observe(2, 10, 60, "FAST", 1060).is_empty());
+
+        // a ticket spanning midnight uses up both days
+        traffic.observe(3, 0, 60, "NIGHT", 86400 - 30);
+        assert_eq!(1, traffic.observe(3, 10, 60, "NIGHT", 86400 + 30).len());
+        traffic.observe(4, 0, 60, "NIGHT", 2 * 86400 - 100);
+        assert!(traffic
+            .observe(4, 10, 60, "NIGHT", 2 * 86400 - 40)
+            .is_empty());
+
+        // the next day is fair game
+        traffic.observe(5, 0, 60, "FAST", 86400);
+        assert_eq!(1, traffic.observe(5, 10, 60, "FAST", 86400 + 60).len());
+    }
+}
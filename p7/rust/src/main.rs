@@ -0,0 +1,234 @@
+mod packet;
+mod session;
+
+use common::env::env_var;
+use common::health::serve_health;
+use common::observability::init_tracing_from_env;
+use common::udp::bind_udp;
+use common::ShutdownToken;
+use packet::Packet;
+use session::Sessions;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::time;
+use tracing::{debug, error, info};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same socket too
+    dual_stack: bool,
+    // how long sent data can go unacknowledged before it's sent again
+    retransmit_timeout: Duration,
+    // how long a peer can go without acknowledging anything before its session is dropped
+    session_expiry: Duration,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            retransmit_timeout: Duration::from_secs(3),
+            session_expiry: Duration::from_secs(60),
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6),
+    /// `DUAL_STACK=true` lets an ipv6 socket take ipv4 clients too,
+    /// `RETRANSMIT_TIMEOUT_SECS` controls how often unacknowledged data is resent,
+    /// `SESSION_EXPIRY_SECS` how long a silent peer keeps its session and
+    /// `HEALTH_ADDRESS` where to answer liveness probes over tcp.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(secs) = env_var("RETRANSMIT_TIMEOUT_SECS") {
+            config.retransmit_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var("SESSION_EXPIRY_SECS") {
+            config.session_expiry = Duration::from_secs(secs);
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+// requests and responses both have to be shorter than this
+const MAX_PACKET: usize = 1000;
+
+// how often sessions are checked for retransmission and expiry
+const TICK: Duration = Duration::from_millis(100);
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let socket = bind_udp(&config.address, config.dual_stack)
+        .await
+        .expect("Couldn't bind udp socket on address");
+    info!("Listening on address: {:?}", socket.local_addr());
+    let health_listener = match &config.health_address {
+        Some(health_address) => Some(
+            TcpListener::bind(health_address)
+                .await
+                .expect("Couldn't start health listener on address"),
+        ),
+        None => None,
+    };
+    ready_signal
+        .send(true)
+        .expect("Couldn't send ready signal after server has started");
+    if let Some(health_listener) = health_listener {
+        tokio::spawn(serve_health(health_listener, shutdown.subscribe()));
+    }
+
+    let mut sessions = Sessions::new(config.retransmit_timeout, config.session_expiry);
+    let mut shutdown_signal = shutdown.subscribe();
+    let mut ticks = time::interval(TICK);
+    // the biggest legal packet leaves a byte spare, anything that fills the buffer is too big
+    let mut buffer = [0; MAX_PACKET];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received,
+            _ = ticks.tick() => {
+                for (peer, packet) in sessions.tick(Instant::now()) {
+                    send(&socket, peer, &packet).await;
+                }
+                continue;
+            }
+            _ = shutdown_signal.recv() => break,
+        };
+        let (read, peer) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving datagram, {:?}", e);
+                continue;
+            }
+        };
+        if read >= MAX_PACKET {
+            info!("Ignoring oversized packet from {:?}", peer);
+            continue;
+        }
+        let Some(packet) = Packet::parse(&buffer[..read]) else {
+            debug!(
+                "Ignoring invalid packet from {:?} : {:?}",
+                peer,
+                String::from_utf8_lossy(&buffer[..read])
+            );
+            continue;
+        };
+        debug!("{:?} sent {:?}", peer, packet);
+        for reply in sessions.handle(packet, peer, Instant::now()) {
+            send(&socket, peer, &reply).await;
+        }
+    }
+    info!("Server stopped with {} sessions open", sessions.len());
+}
+
+async fn send(socket: &UdpSocket, peer: SocketAddr, packet: &Packet) {
+    debug!("Sending {:?} to {:?}", packet, peer);
+    if let Err(e) = socket.send_to(&packet.encode(), peer).await {
+        error!("Couldn't send to {:?} : {:?}", peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::observability::init_tracing;
+    async fn receive(client: &UdpSocket) -> Packet {
+        let mut buffer = [0; MAX_PACKET];
+        let read = time::timeout(Duration::from_secs(5), client.recv(&mut buffer))
+            .await
+            .expect("No response from server")
+            .unwrap();
+        Packet::parse(&buffer[..read]).expect("Server sent an invalid packet")
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let _guard = init_tracing(tracing::Level::INFO);
+        let config = Config {
+            address: String::from("127.0.0.1:8001"),
+            retransmit_timeout: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        let server_handle = tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect("127.0.0.1:8001").await.unwrap();
+
+        // junk is ignored
+        client.send(b"/connect/").await.unwrap();
+        client.send(b"/connect/12345/").await.unwrap();
+        assert_eq!(
+            Packet::Ack {
+                session: 12345,
+                length: 0
+            },
+            receive(&client).await
+        );
+        client.send(b"/data/12345/0/hello\\/\n/").await.unwrap();
+        assert_eq!(
+            Packet::Ack {
+                session: 12345,
+                length: 7
+            },
+            receive(&client).await
+        );
+        let reversed = Packet::Data {
+            session: 12345,
+            pos: 0,
+            data: b"/olleh\n".to_vec(),
+        };
+        assert_eq!(reversed, receive(&client).await);
+        // without an ack it comes round again
+        assert_eq!(reversed, receive(&client).await);
+        client.send(b"/ack/12345/7/").await.unwrap();
+
+        client.send(b"/close/12345/").await.unwrap();
+        assert_eq!(Packet::Close { session: 12345 }, receive(&client).await);
+
+        shutdown.shutdown();
+        time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not stop after shutdown")
+            .expect("Server panicked");
+    }
+}
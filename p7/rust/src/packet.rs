@@ -0,0 +1,209 @@
+use std::mem;
+
+// numeric fields have to be smaller than this
+const MAX_NUMBER: u32 = 2_147_483_648;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Packet {
+    Connect {
+        session: u32,
+    },
+    Data {
+        session: u32,
+        pos: u32,
+        data: Vec<u8>,
+    },
+    Ack {
+        session: u32,
+        length: u32,
+    },
+    Close {
+        session: u32,
+    },
+}
+
+impl Packet {
+    /// `None` for anything that isn't a well formed LRCP message, the caller just drops those.
+    pub fn parse(packet: &[u8]) -> Option<Packet> {
+        let fields = split_fields(packet)?;
+        match fields.as_slice() {
+            [kind, session] if kind == b"connect" => Some(Packet::Connect {
+                session: number(session)?,
+            }),
+            [kind, session, pos, data] if kind == b"data" => Some(Packet::Data {
+                session: number(session)?,
+                pos: number(pos)?,
+                data: data.clone(),
+            }),
+            [kind, session, length] if kind == b"ack" => Some(Packet::Ack {
+                session: number(session)?,
+                length: number(length)?,
+            }),
+            [kind, session] if kind == b"close" => Some(Packet::Close {
+                session: number(session)?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Packet::Connect { session } => format!("/connect/{}/", session).into_bytes(),
+            Packet::Data { session, pos, data } => {
+                let mut packet = format!("/data/{}/{}/", session, pos).into_bytes();
+                for byte in data {
+                    if matches!(byte, b'/' | b'\\') {
+                        packet.push(b'\\');
+                    }
+                    packet.push(*byte);
+                }
+                packet.push(b'/');
+                packet
+            }
+            Packet::Ack { session, length } => format!("/ack/{}/{}/", session, length).into_bytes(),
+            Packet::Close { session } => format!("/close/{}/", session).into_bytes(),
+        }
+    }
+}
+
+/// Splits `/a/b/c/` into its unescaped fields. It has to start and end with a `/`, and a `\` can
+/// only escape a `/` or another `\`.
+fn split_fields(packet: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut bytes = packet.strip_prefix(b"/")?.iter();
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => match bytes.next()? {
+                escaped @ (b'/' | b'\\') => field.push(*escaped),
+                _ => return None,
+            },
+            b'/' => fields.push(mem::take(&mut field)),
+            byte => field.push(*byte),
+        }
+    }
+    // anything left over wasn't closed off with a `/`
+    if !field.is_empty() {
+        return None;
+    }
+    Some(fields)
+}
+
+fn number(field: &[u8]) -> Option<u32> {
+    if field.is_empty() || !field.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(field)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|number| *number < MAX_NUMBER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Some(Packet::Connect { session: 1234567 }),
+            Packet::parse(b"/connect/1234567/")
+        );
+        assert_eq!(
+            Some(Packet::Data {
+                session: 1234567,
+                pos: 0,
+                data: b"hello\n".to_vec()
+            }),
+            Packet::parse(b"/data/1234567/0/hello\n/")
+        );
+        assert_eq!(
+            Some(Packet::Ack {
+                session: 1234567,
+                length: 6
+            }),
+            Packet::parse(b"/ack/1234567/6/")
+        );
+        assert_eq!(
+            Some(Packet::Close { session: 1234567 }),
+            Packet::parse(b"/close/1234567/")
+        );
+        // empty data is still data
+        assert_eq!(
+            Some(Packet::Data {
+                session: 1,
+                pos: 5,
+                data: vec![]
+            }),
+            Packet::parse(b"/data/1/5//")
+        );
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        assert_eq!(
+            Some(Packet::Data {
+                session: 1,
+                pos: 0,
+                data: b"foo/bar\\baz".to_vec()
+            }),
+            Packet::parse(b"/data/1/0/foo\\/bar\\\\baz/")
+        );
+        // an unescaped slash is one field too many
+        assert_eq!(None, Packet::parse(b"/data/1/0/foo/bar/"));
+        // nothing else can be escaped
+        assert_eq!(None, Packet::parse(b"/data/1/0/foo\\nbar/"));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for packet in [
+            &b""[..],
+            b"/",
+            b"connect/1/",
+            b"/connect/1",
+            b"/connect/1/2/",
+            b"/connect//",
+            b"/connect/-1/",
+            b"/connect/+1/",
+            b"/connect/2147483648/",
+            b"/ack/1/",
+            b"/data/1/0/",
+            b"/hello/1/",
+        ] {
+            assert_eq!(
+                None,
+                Packet::parse(packet),
+                "{:?}",
+                String::from_utf8_lossy(packet)
+            );
+        }
+        assert_eq!(
+            Some(Packet::Connect {
+                session: 2147483647
+            }),
+            Packet::parse(b"/connect/2147483647/")
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            b"/ack/1/6/".to_vec(),
+            Packet::Ack {
+                session: 1,
+                length: 6
+            }
+            .encode()
+        );
+        assert_eq!(b"/close/1/".to_vec(), Packet::Close { session: 1 }.encode());
+        let data = Packet::Data {
+            session: 1,
+            pos: 2,
+            data: b"a/b\\c\n".to_vec(),
+        };
+        assert_eq!(b"/data/1/2/a\\/b\\\\c\n/".to_vec(), data.encode());
+        assert_eq!(Some(data.clone()), Packet::parse(&data.encode()));
+    }
+}
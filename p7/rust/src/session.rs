@@ -0,0 +1,397 @@
+use crate::packet::Packet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// unescaped bytes per data packet, small enough that escaping every one still fits in a packet
+const MAX_CHUNK: usize = 400;
+
+#[derive(Debug)]
+struct Session {
+    peer: SocketAddr,
+    // how much of the peer's stream has arrived in order
+    received: usize,
+    // the start of a line that hasn't seen its newline yet
+    line: Vec<u8>,
+    // everything sent to the peer, kept around for retransmission
+    sent: Vec<u8>,
+    // how much of `sent` the peer has acknowledged
+    acked: usize,
+    // when unacknowledged data was last (re)sent
+    last_sent: Instant,
+    // when the peer last acknowledged anything, or when it started owing us an ack
+    waiting_since: Instant,
+}
+
+impl Session {
+    fn new(peer: SocketAddr, now: Instant) -> Session {
+        Session {
+            peer,
+            received: 0,
+            line: Vec::new(),
+            sent: Vec::new(),
+            acked: 0,
+            last_sent: now,
+            waiting_since: now,
+        }
+    }
+
+    /// The application layer: every complete line is queued to go back reversed.
+    fn receive(&mut self, data: &[u8], now: Instant) {
+        if self.acked == self.sent.len() {
+            self.waiting_since = now;
+        }
+        for byte in data {
+            if *byte == b'\n' {
+                self.sent.extend(self.line.drain(..).rev());
+                self.sent.push(b'\n');
+            } else {
+                self.line.push(*byte);
+            }
+        }
+    }
+
+    /// Data packets for everything sent from `from` onwards.
+    fn data_from(&self, session: u32, from: usize) -> Vec<Packet> {
+        self.sent[from..]
+            .chunks(MAX_CHUNK)
+            .enumerate()
+            .map(|(index, chunk)| Packet::Data {
+                session,
+                pos: (from + index * MAX_CHUNK) as u32,
+                data: chunk.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// Every open LRCP session, keyed by session number. Packets go in and the packets to send back
+/// come out, so the protocol can be driven without a socket.
+#[derive(Debug)]
+pub struct Sessions {
+    sessions: HashMap<u32, Session>,
+    // resend unacknowledged data this often
+    retransmit_timeout: Duration,
+    // give up on a peer that hasn't acknowledged anything for this long
+    session_expiry: Duration,
+}
+
+impl Sessions {
+    pub fn new(retransmit_timeout: Duration, session_expiry: Duration) -> Sessions {
+        Sessions {
+            sessions: HashMap::new(),
+            retransmit_timeout,
+            session_expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn handle(&mut self, packet: Packet, peer: SocketAddr, now: Instant) -> Vec<Packet> {
+        match packet {
+            Packet::Connect { session } => {
+                self.sessions
+                    .entry(session)
+                    .or_insert_with(|| Session::new(peer, now))
+                    .peer = peer;
+                vec![Packet::Ack { session, length: 0 }]
+            }
+            Packet::Data { session, pos, data } => {
+                let Some(state) = self.sessions.get_mut(&session) else {
+                    return vec![Packet::Close { session }];
+                };
+                state.peer = peer;
+                let pos = pos as usize;
+                // past a gap, ask for what's missing by repeating the last ack
+                if pos > state.received {
+                    return vec![Packet::Ack {
+                        session,
+                        length: state.received as u32,
+                    }];
+                }
+                // a repeat can overlap what already arrived, only the rest is new
+                let new = data.get(state.received - pos..).unwrap_or_default();
+                let total = state.received + new.len();
+                if total >= 1 << 31 {
+                    info!("Session {} ran past the largest position, closing", session);
+                    self.sessions.remove(&session);
+                    return vec![Packet::Close { session }];
+                }
+                let already_sent = state.sent.len();
+                state.received = total;
+                state.receive(new, now);
+                let mut replies = vec![Packet::Ack {
+                    session,
+                    length: state.received as u32,
+                }];
+                if state.sent.len() > already_sent {
+                    if state.acked == already_sent {
+                        state.last_sent = now;
+                    }
+                    replies.extend(state.data_from(session, already_sent));
+                }
+                replies
+            }
+            Packet::Ack { session, length } => {
+                let Some(state) = self.sessions.get_mut(&session) else {
+                    return vec![Packet::Close { session }];
+                };
+                state.peer = peer;
+                let length = length as usize;
+                // a duplicate, or out of order
+                if length <= state.acked {
+                    return vec![];
+                }
+                // acknowledging data we never sent, the peer is misbehaving
+                if length > state.sent.len() {
+                    info!("Session {} acked past what was sent, closing", session);
+                    self.sessions.remove(&session);
+                    return vec![Packet::Close { session }];
+                }
+                state.acked = length;
+                state.waiting_since = now;
+                if state.acked < state.sent.len() {
+                    state.last_sent = now;
+                    return state.data_from(session, state.acked);
+                }
+                vec![]
+            }
+            Packet::Close { session } => {
+                self.sessions.remove(&session);
+                vec![Packet::Close { session }]
+            }
+        }
+    }
+
+    /// Resends anything that's gone unacknowledged for `retransmit_timeout`, and drops sessions
+    /// that have been waiting on their peer for `session_expiry`.
+    pub fn tick(&mut self, now: Instant) -> Vec<(SocketAddr, Packet)> {
+        let session_expiry = self.session_expiry;
+        self.sessions.retain(|session, state| {
+            let expired = state.acked < state.sent.len()
+                && now.duration_since(state.waiting_since) >= session_expiry;
+            if expired {
+                info!("Session {} expired", session);
+            }
+            !expired
+        });
+
+        let mut resends = Vec::new();
+        for (session, state) in self.sessions.iter_mut() {
+            if state.acked == state.sent.len()
+                || now.duration_since(state.last_sent) < self.retransmit_timeout
+            {
+                continue;
+            }
+            state.last_sent = now;
+            resends.extend(
+                state
+                    .data_from(*session, state.acked)
+                    .into_iter()
+                    .map(|packet| (state.peer, packet)),
+            );
+        }
+        resends
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5000".parse().unwrap()
+    }
+
+    fn sessions() -> Sessions {
+        Sessions::new(Duration::from_secs(3), Duration::from_secs(60))
+    }
+
+    fn data(session: u32, pos: u32, data: &[u8]) -> Packet {
+        Packet::Data {
+            session,
+            pos,
+            data: data.to_vec(),
+        }
+    }
+
+    fn ack(session: u32, length: u32) -> Packet {
+        Packet::Ack { session, length }
+    }
+
+    #[test]
+    fn test_line_reversal() {
+        let now = Instant::now();
+        let mut sessions = sessions();
+        assert_eq!(
+            vec![ack(1, 0)],
+            sessions.handle(Packet::Connect { session: 1 }, peer(), now)
+        );
+        assert_eq!(
+            vec![ack(1, 6), data(1, 0, b"olleh\n")],
+            sessions.handle(data(1, 0, b"hello\n"), peer(), now)
+        );
+        assert!(sessions.handle(ack(1, 6), peer(), now).is_empty());
+        // lines can be split across packets
+        assert_eq!(
+            vec![ack(1, 9)],
+            sessions.handle(data(1, 6, b"Hel"), peer(), now)
+        );
+        assert_eq!(
+            vec![ack(1, 13), data(1, 6, b"leH\n")],
+            sessions.handle(data(1, 9, b"\nabc"), peer(), now)
+        );
+        assert_eq!(
+            vec![Packet::Close { session: 1 }],
+            sessions.handle(Packet::Close { session: 1 }, peer(), now)
+        );
+        assert_eq!(0, sessions.len());
+    }
+
+    #[test]
+    fn test_unknown_session() {
+        let now = Instant::now();
+        let mut sessions = sessions();
+        assert_eq!(
+            vec![Packet::Close { session: 7 }],
+            sessions.handle(data(7, 0, b"hi\n"), peer(), now)
+        );
+        assert_eq!(
+            vec![Packet::Close { session: 7 }],
+            sessions.handle(ack(7, 0), peer(), now)
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_data() {
+        let now = Instant::now();
+        let mut sessions = sessions();
+        sessions.handle(Packet::Connect { session: 1 }, peer(), now);
+        // the first packet went missing, repeat the last ack so it gets resent
+        assert_eq!(
+            vec![ack(1, 0)],
+            sessions.handle(data(1, 3, b"def\n"), peer(), now)
+        );
+        assert_eq!(
+            vec![ack(1, 3)],
+            sessions.handle(data(1, 0, b"abc"), peer(), now)
+        );
+        assert_eq!(
+            vec![ack(1, 7), data(1, 0, b"fedcba\n")],
+            sessions.handle(data(1, 3, b"def\n"), peer(), now)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_packets() {
+        let now = Instant::now();
+        let mut sessions = sessions();
+        sessions.handle(Packet::Connect { session: 1 }, peer(), now);
+        // connecting again doesn't reset anything
+        sessions.handle(data(1, 0, b"ab"), peer(), now);
+        assert_eq!(
+            vec![ack(1, 0)],
+            sessions.handle(Packet::Connect { session: 1 }, peer(), now)
+        );
+        // the same data again is only acknowledged
+        assert_eq!(
+            vec![ack(1, 2)],
+            sessions.handle(data(1, 0, b"ab"), peer(), now)
+        );
+        // overlapping data only adds what's new
+        assert_eq!(
+            vec![ack(1, 4), data(1, 0, b"cba\n")],
+            sessions.handle(data(1, 1, b"bc\n"), peer(), now)
+        );
+        assert!(sessions.handle(ack(1, 4), peer(), now).is_empty());
+        // an old ack is ignored
+        assert!(sessions.handle(ack(1, 2), peer(), now).is_empty());
+        assert!(sessions.handle(ack(1, 4), peer(), now).is_empty());
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let start = Instant::now();
+        let mut sessions = sessions();
+        sessions.handle(Packet::Connect { session: 1 }, peer(), start);
+        sessions.handle(data(1, 0, b"abc\ndef\n"), peer(), start);
+
+        // not yet
+        assert!(sessions.tick(start + Duration::from_secs(2)).is_empty());
+        assert_eq!(
+            vec![(peer(), data(1, 0, b"cba\nfed\n"))],
+            sessions.tick(start + Duration::from_secs(3))
+        );
+        // a partial ack resends the rest straight away
+        assert_eq!(
+            vec![data(1, 4, b"fed\n")],
+            sessions.handle(ack(1, 4), peer(), start + Duration::from_secs(4))
+        );
+        assert!(sessions.tick(start + Duration::from_secs(6)).is_empty());
+        assert_eq!(
+            vec![(peer(), data(1, 4, b"fed\n"))],
+            sessions.tick(start + Duration::from_secs(7))
+        );
+        // fully acknowledged, nothing left to resend
+        sessions.handle(ack(1, 8), peer(), start + Duration::from_secs(8));
+        assert!(sessions.tick(start + Duration::from_secs(20)).is_empty());
+    }
+
+    #[test]
+    fn test_large_reply_is_chunked() {
+        let now = Instant::now();
+        let mut sessions = sessions();
+        sessions.handle(Packet::Connect { session: 1 }, peer(), now);
+        let mut line = vec![b'/'; 1000];
+        line.push(b'\n');
+        let replies = sessions.handle(data(1, 0, &line), peer(), now);
+        assert_eq!(ack(1, 1001), replies[0]);
+        for packet in &replies[1..] {
+            assert!(packet.encode().len() < 1000);
+        }
+        assert_eq!(
+            vec![0, 400, 800],
+            replies[1..]
+                .iter()
+                .map(|packet| match packet {
+                    Packet::Data { pos, .. } => *pos,
+                    _ => panic!("expected data, got {:?}", packet),
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let start = Instant::now();
+        let mut sessions = sessions();
+        sessions.handle(Packet::Connect { session: 1 }, peer(), start);
+        sessions.handle(Packet::Connect { session: 2 }, peer(), start);
+        sessions.handle(data(1, 0, b"abc\n"), peer(), start);
+
+        sessions.tick(start + Duration::from_secs(59));
+        assert_eq!(2, sessions.len());
+        // session 1 never acknowledged its reply, session 2 doesn't owe anything
+        sessions.tick(start + Duration::from_secs(60));
+        assert_eq!(1, sessions.len());
+        assert_eq!(
+            vec![Packet::Close { session: 1 }],
+            sessions.handle(ack(1, 4), peer(), start + Duration::from_secs(61))
+        );
+    }
+
+    #[test]
+    fn test_ack_past_sent_closes() {
+        let now = Instant::now();
+        let mut sessions = sessions();
+        sessions.handle(Packet::Connect { session: 1 }, peer(), now);
+        assert_eq!(
+            vec![Packet::Close { session: 1 }],
+            sessions.handle(ack(1, 10), peer(), now)
+        );
+        assert_eq!(0, sessions.len());
+    }
+}
@@ -0,0 +1,328 @@
+use bytes::BytesMut;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+// the spec promises cipher specs are never longer than this
+const MAX_SPEC_LEN: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    ReverseBits,
+    Xor(u8),
+    XorPos,
+    Add(u8),
+    AddPos,
+}
+
+impl Op {
+    // positions only matter mod 256, so they're truncated to a byte
+    fn apply(self, byte: u8, pos: usize) -> u8 {
+        match self {
+            Op::ReverseBits => byte.reverse_bits(),
+            Op::Xor(n) => byte ^ n,
+            Op::XorPos => byte ^ pos as u8,
+            Op::Add(n) => byte.wrapping_add(n),
+            Op::AddPos => byte.wrapping_add(pos as u8),
+        }
+    }
+
+    fn invert(self, byte: u8, pos: usize) -> u8 {
+        match self {
+            Op::Add(n) => byte.wrapping_sub(n),
+            Op::AddPos => byte.wrapping_sub(pos as u8),
+            // everything else undoes itself
+            op => op.apply(byte, pos),
+        }
+    }
+}
+
+/// The client's cipher, with a position for each direction of the stream since both start at 0.
+#[derive(Debug, Clone)]
+pub struct Cipher {
+    ops: Vec<Op>,
+    encode_pos: usize,
+    decode_pos: usize,
+}
+
+impl Cipher {
+    pub fn new(ops: Vec<Op>) -> Cipher {
+        Cipher {
+            ops,
+            encode_pos: 0,
+            decode_pos: 0,
+        }
+    }
+
+    /// Reads a spec off the front of the stream, up to and including its `00` terminator.
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Cipher> {
+        let mut ops = Vec::new();
+        let mut spec_len = 0;
+        loop {
+            spec_len += 1;
+            if spec_len > MAX_SPEC_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cipher spec is too long",
+                ));
+            }
+            // byte at a time and unbuffered, nothing past the spec can be read before the
+            // cipher is known
+            let op = match reader.read_u8().await? {
+                0x00 => return Ok(Cipher::new(ops)),
+                0x01 => Op::ReverseBits,
+                0x02 => Op::Xor(reader.read_u8().await?),
+                0x03 => Op::XorPos,
+                0x04 => Op::Add(reader.read_u8().await?),
+                0x05 => Op::AddPos,
+                invalid => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown cipher operation {:#04x}", invalid),
+                    ))
+                }
+            };
+            ops.push(op);
+        }
+    }
+
+    /// True when the cipher leaves every byte unchanged wherever it appears in the stream.
+    pub fn is_noop(&self) -> bool {
+        (0..=u8::MAX as usize).all(|pos| {
+            (0..=u8::MAX)
+                .all(|byte| self.ops.iter().fold(byte, |byte, op| op.apply(byte, pos)) == byte)
+        })
+    }
+
+    pub fn encode(&mut self, bytes: &mut [u8]) {
+        for byte in bytes {
+            *byte = self
+                .ops
+                .iter()
+                .fold(*byte, |byte, op| op.apply(byte, self.encode_pos));
+            self.encode_pos += 1;
+        }
+    }
+
+    pub fn decode(&mut self, bytes: &mut [u8]) {
+        for byte in bytes {
+            *byte = self
+                .ops
+                .iter()
+                .rev()
+                .fold(*byte, |byte, op| op.invert(byte, self.decode_pos));
+            self.decode_pos += 1;
+        }
+    }
+}
+
+/// Lines in and out of a ciphered stream. Incoming bytes are decoded in place as they arrive, so
+/// the line splitting only ever sees plain text.
+#[derive(Debug)]
+pub struct CipherCodec {
+    cipher: Cipher,
+    // how much of the read buffer has already been decoded
+    decoded: usize,
+    lines: LinesCodec,
+}
+
+impl CipherCodec {
+    pub fn new(cipher: Cipher, max_line_length: usize) -> CipherCodec {
+        CipherCodec {
+            cipher,
+            decoded: 0,
+            lines: LinesCodec::new_with_max_length(max_line_length),
+        }
+    }
+
+    fn decode_new(&mut self, src: &mut BytesMut) {
+        self.cipher.decode(&mut src[self.decoded..]);
+        self.decoded = src.len();
+    }
+}
+
+impl Decoder for CipherCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.decode_new(src);
+        let buffered = src.len();
+        let line = self.lines.decode(src)?;
+        self.decoded -= buffered - src.len();
+        Ok(line)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.decode_new(src);
+        let buffered = src.len();
+        let line = self.lines.decode_eof(src)?;
+        self.decoded -= buffered - src.len();
+        Ok(line)
+    }
+}
+
+impl Encoder<String> for CipherCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: String, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        let start = dst.len();
+        self.lines.encode(line, dst)?;
+        self.cipher.encode(&mut dst[start..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(ops: Vec<Op>, text: &[u8]) -> Vec<u8> {
+        let mut bytes = text.to_vec();
+        Cipher::new(ops).encode(&mut bytes);
+        bytes
+    }
+
+    // encoding then decoding gets the original back
+    fn assert_round_trip(ops: Vec<Op>) {
+        let text = b"4x dog,5x car\n".repeat(40);
+        let mut bytes = text.clone();
+        let mut cipher = Cipher::new(ops);
+        cipher.encode(&mut bytes);
+        cipher.decode(&mut bytes);
+        assert_eq!(text, bytes);
+    }
+
+    #[test]
+    fn test_reverse_bits() {
+        assert_eq!(
+            vec![0b1000_0000, 0b0101_0000, 0xff],
+            encoded(vec![Op::ReverseBits], &[0b0000_0001, 0b0000_1010, 0xff])
+        );
+        assert_round_trip(vec![Op::ReverseBits]);
+    }
+
+    #[test]
+    fn test_xor() {
+        assert_eq!(vec![0x69, 0x64, 0x6d], encoded(vec![Op::Xor(0x01)], b"hel"));
+        assert_round_trip(vec![Op::Xor(0xa5)]);
+    }
+
+    #[test]
+    fn test_xor_pos() {
+        assert_eq!(
+            vec![0x68, 0x64, 0x6e, 0x6f, 0x6b],
+            encoded(vec![Op::XorPos], b"hello")
+        );
+        assert_round_trip(vec![Op::XorPos]);
+    }
+
+    #[test]
+    fn test_add() {
+        // wraps past 255
+        assert_eq!(
+            vec![0x69, 0x00, 0x01],
+            encoded(vec![Op::Add(0x01)], &[0x68, 0xff, 0x00])
+        );
+        assert_round_trip(vec![Op::Add(200)]);
+    }
+
+    #[test]
+    fn test_add_pos() {
+        assert_eq!(
+            vec![0x68, 0x67, 0x70, 0x72, 0x77],
+            encoded(vec![Op::AddPos, Op::AddPos], b"hello")
+        );
+        assert_round_trip(vec![Op::AddPos]);
+    }
+
+    #[test]
+    fn test_combined() {
+        // examples from the spec
+        assert_eq!(
+            vec![0x96, 0x26, 0xb6, 0xb6, 0x76],
+            encoded(vec![Op::Xor(0x01), Op::ReverseBits], b"hello")
+        );
+        assert_round_trip(vec![Op::Xor(123), Op::AddPos, Op::ReverseBits]);
+    }
+
+    #[test]
+    fn test_noop() {
+        for ops in [
+            vec![],
+            vec![Op::Xor(0)],
+            vec![Op::Add(0)],
+            vec![Op::Xor(0xab), Op::Xor(0xab)],
+            vec![Op::ReverseBits, Op::ReverseBits],
+            vec![Op::Xor(0xa0), Op::Xor(0x0b), Op::Xor(0xab)],
+            vec![Op::XorPos, Op::XorPos],
+            vec![Op::Add(1), Op::Add(255)],
+        ] {
+            assert!(Cipher::new(ops.clone()).is_noop(), "{:?}", ops);
+        }
+        for ops in [
+            vec![Op::ReverseBits],
+            vec![Op::Xor(1)],
+            vec![Op::XorPos],
+            vec![Op::AddPos],
+            vec![Op::Xor(1), Op::ReverseBits],
+        ] {
+            assert!(!Cipher::new(ops.clone()).is_noop(), "{:?}", ops);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_spec() {
+        let mut spec = &[0x02, 0x7b, 0x05, 0x01, 0x00, 0xff][..];
+        let cipher = Cipher::read(&mut spec).await.unwrap();
+        assert_eq!(vec![Op::Xor(123), Op::AddPos, Op::ReverseBits], cipher.ops);
+        // stops at the terminator
+        assert_eq!(&[0xff], spec);
+
+        let mut spec = &[0x02, 0x7b, 0x06, 0x00][..];
+        let result = Cipher::read(&mut spec).await;
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+
+        let mut spec = &[0x01; 100][..];
+        let result = Cipher::read(&mut spec).await;
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_codec() {
+        // the example session from the spec
+        let cipher = Cipher::new(vec![Op::Xor(123), Op::AddPos, Op::ReverseBits]);
+        let mut codec = CipherCodec::new(cipher, 1000);
+
+        let mut incoming = BytesMut::new();
+        // split part way through a line
+        incoming.extend_from_slice(&[0xf2, 0x20, 0xba, 0x44, 0x18]);
+        assert_eq!(None, codec.decode(&mut incoming).unwrap());
+        incoming.extend_from_slice(&[0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e]);
+        assert_eq!(
+            Some(String::from("4x dog,5x car")),
+            codec.decode(&mut incoming).unwrap()
+        );
+
+        let mut outgoing = BytesMut::new();
+        codec.encode(String::from("5x car"), &mut outgoing).unwrap();
+        assert_eq!(
+            &[0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee][..],
+            &outgoing[..]
+        );
+
+        incoming.extend_from_slice(&[
+            0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+        ]);
+        assert_eq!(
+            Some(String::from("3x rat,2x cat")),
+            codec.decode(&mut incoming).unwrap()
+        );
+        let mut outgoing = BytesMut::new();
+        codec.encode(String::from("3x rat"), &mut outgoing).unwrap();
+        assert_eq!(
+            &[0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e][..],
+            &outgoing[..]
+        );
+    }
+}
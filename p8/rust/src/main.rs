@@ -0,0 +1,271 @@
+mod cipher;
+
+use cipher::{Cipher, CipherCodec};
+use common::env::env_var;
+use common::observability::init_tracing_from_env;
+use common::{run_tcp_server, Peer, ServerConfig, ShutdownSignal, ShutdownToken};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+use tokio_util::codec::Framed;
+use tracing::{error, info};
+
+fn main() {
+    let _guard = init_tracing_from_env();
+    common::runtime::from_env().block_on(run());
+}
+
+async fn run() {
+    let (ready_sender, _ready_receiver) = oneshot::channel();
+    let shutdown = ShutdownToken::new();
+    let ctrl_c_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for ctrl-c: {:?}", e);
+            return;
+        }
+        info!("Got ctrl-c, shutting down");
+        ctrl_c_shutdown.shutdown();
+    });
+    serve(Config::from_env(), ready_sender, shutdown).await;
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    address: String,
+    // with an ipv6 address, take ipv4 clients on the same listener too
+    dual_stack: bool,
+    // connections handled at once before the server stops accepting
+    max_connections: usize,
+    // longest decoded line we'll take from a client
+    max_line_length: usize,
+    // where to answer liveness probes with `OK` once serving, off unless set
+    health_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("0.0.0.0:8000"),
+            dual_stack: false,
+            max_connections: 1024,
+            max_line_length: 5000,
+            health_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the defaults and override anything set in the environment:
+    /// `BIND_ADDR` is where to listen (`[::]:8000` for ipv6, `unix:/path` for a unix socket),
+    /// `DUAL_STACK=true` lets an ipv6 listener take ipv4 clients too,
+    /// `MAX_CONNECTIONS` caps how many clients are served at once,
+    /// `MAX_LINE_LENGTH` the longest line accepted and
+    /// `HEALTH_ADDRESS` where to answer liveness probes.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(address) = env_var("BIND_ADDR") {
+            config.address = address;
+        }
+        if let Some(dual_stack) = env_var("DUAL_STACK") {
+            config.dual_stack = dual_stack;
+        }
+        if let Some(max_connections) = env_var("MAX_CONNECTIONS") {
+            config.max_connections = max_connections;
+        }
+        if let Some(length) = env_var("MAX_LINE_LENGTH") {
+            config.max_line_length = length;
+        }
+        if let Some(address) = env_var("HEALTH_ADDRESS") {
+            config.health_address = Some(address);
+        }
+        config
+    }
+}
+
+/// The toy there are the most copies of, as it was written (`15x dog on a string`). `None` if
+/// any toy isn't written as `<copies>x <name>`.
+fn most_copies(line: &str) -> Option<&str> {
+    let mut best = None;
+    for toy in line.split(',') {
+        let (copies, _) = toy.split_once("x ")?;
+        let copies: u64 = copies.parse().ok()?;
+        if best.is_none_or(|(most, _)| copies > most) {
+            best = Some((copies, toy));
+        }
+    }
+    best.map(|(_, toy)| toy)
+}
+
+async fn serve(config: Config, ready_signal: oneshot::Sender<bool>, shutdown: ShutdownToken) {
+    let server_config = ServerConfig {
+        address: config.address.clone(),
+        dual_stack: config.dual_stack,
+        max_connections: config.max_connections,
+        health_address: config.health_address.clone(),
+        ..ServerConfig::default()
+    };
+    let config = Arc::new(config);
+    run_tcp_server(
+        &server_config,
+        ready_signal,
+        shutdown,
+        move |stream, remote_addr, shutdown_signal| {
+            let config = config.clone();
+            async move {
+                handle_client(stream, remote_addr, config, shutdown_signal).await;
+            }
+        },
+    )
+    .await;
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
+    remote_addr: Peer,
+    config: Arc<Config>,
+    mut shutdown: ShutdownSignal,
+) {
+    let cipher = tokio::select! {
+        cipher = Cipher::read(&mut stream) => cipher,
+        _ = shutdown.recv() => return,
+    };
+    let cipher = match cipher {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            info!(
+                "Couldn't read a cipher spec from {:?} : {:?}",
+                remote_addr, e
+            );
+            return;
+        }
+    };
+    if cipher.is_noop() {
+        info!("{:?} sent a no-op cipher, disconnecting", remote_addr);
+        return;
+    }
+
+    let mut lines = Framed::new(stream, CipherCodec::new(cipher, config.max_line_length));
+    loop {
+        let line = tokio::select! {
+            line = lines.next() => line,
+            _ = shutdown.recv() => break,
+        };
+        let line = match line {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                info!("Error reading from {:?} : {:?}", remote_addr, e);
+                break;
+            }
+            None => break,
+        };
+        let Some(toy) = most_copies(&line) else {
+            info!("Couldn't make sense of {:?} from {:?}", line, remote_addr);
+            break;
+        };
+        if let Err(e) = lines.send(toy.to_string()).await {
+            info!("Error writing to {:?} : {:?}", remote_addr, e);
+            break;
+        }
+    }
+    info!("Closing connection for {:?}", remote_addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time;
+
+    async fn start_server(address: &str) -> ShutdownToken {
+        let config = Config {
+            address: address.to_string(),
+            ..Config::default()
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(serve(config, ready_sender, shutdown.clone()));
+        assert_eq!(Ok(true), ready_receiver.await);
+        shutdown
+    }
+
+    #[test]
+    fn test_most_copies() {
+        assert_eq!(
+            Some("15x dog on a string"),
+            most_copies("10x toy car,15x dog on a string,4x inflatable motorcycle")
+        );
+        assert_eq!(Some("1x one"), most_copies("1x one"));
+        // the first one wins a tie
+        assert_eq!(Some("5x car"), most_copies("5x car,5x boat"));
+        // names can have an x in them
+        assert_eq!(Some("3x xbox x"), most_copies("2x box,3x xbox x"));
+        assert_eq!(None, most_copies(""));
+        assert_eq!(None, most_copies("lots of toys"));
+        assert_eq!(None, most_copies("10x toy car,many marbles"));
+    }
+
+    #[tokio::test]
+    async fn test_toy_selection() {
+        let address = "127.0.0.1:8001";
+        let shutdown = start_server(address).await;
+
+        // the example session from the spec, xor(123),addpos,reversebits
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(&[0x02, 0x7b, 0x05, 0x01, 0x00])
+            .await
+            .unwrap();
+        client
+            .write_all(&[
+                0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+            ])
+            .await
+            .unwrap();
+        let mut response = [0; 7];
+        time::timeout(Duration::from_secs(5), client.read_exact(&mut response))
+            .await
+            .expect("No response from server")
+            .unwrap();
+        assert_eq!([0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee], response);
+
+        client
+            .write_all(&[
+                0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+            ])
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(5), client.read_exact(&mut response))
+            .await
+            .expect("No response from server")
+            .unwrap();
+        assert_eq!([0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e], response);
+
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_noop_cipher_disconnects() {
+        let address = "127.0.0.1:8002";
+        let shutdown = start_server(address).await;
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        // xor(1),xor(1)
+        client
+            .write_all(&[0x02, 0x01, 0x02, 0x01, 0x00])
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("Server didn't hang up")
+            .unwrap();
+        assert!(response.is_empty());
+
+        shutdown.shutdown();
+    }
+}